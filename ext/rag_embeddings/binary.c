@@ -0,0 +1,189 @@
+#include <ruby.h>     // Ruby API
+#include <string.h>   // For memcpy, memset
+#include "embedding.h"
+#include "simd.h"
+#include "binary.h"
+
+// Binary quantization of an embedding, see binary.h
+typedef struct {
+  uint32_t dim;       // Dimension of the original embedding (number of meaningful bits)
+  uint64_t bits[];    // rag_binary_words(dim) words
+} binary_embedding_t;
+
+// Bytes of the header of a binary blob: the dimension as little-endian uint32
+#define BINARY_BLOB_HEADER sizeof(uint32_t)
+
+static VALUE cBinaryEmbedding;
+
+static void binary_free(void *ptr) {
+  if (ptr) {
+    xfree(ptr);
+  }
+}
+
+static size_t binary_memsize(const void *ptr) {
+  const binary_embedding_t *b = (const binary_embedding_t *)ptr;
+  return b ? sizeof(binary_embedding_t) + rag_binary_words(b->dim) * sizeof(uint64_t) : 0;
+}
+
+static const rb_data_type_t binary_type = {
+  "RagEmbeddings/BinaryEmbedding",
+  {0, binary_free, binary_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+void rag_binary_pack(const float *values, uint32_t dim, uint64_t *bits) {
+  memset(bits, 0, rag_binary_words(dim) * sizeof(uint64_t));
+
+  for (uint32_t i = 0; i < dim; ++i) {
+    if (values[i] > 0.0f) {
+      bits[i / 64] |= 1ULL << (i % 64);
+    }
+  }
+}
+
+static binary_embedding_t *binary_alloc(uint32_t dim) {
+  binary_embedding_t *b = xmalloc(sizeof(binary_embedding_t) + rag_binary_words(dim) * sizeof(uint64_t));
+  b->dim = dim;
+  return b;
+}
+
+// Instance method: embedding.binarize
+// Returns a BinaryEmbedding with the sign of each value (also reachable as quantize(:binary))
+VALUE rag_embedding_binarize(VALUE self) {
+  embedding_t *emb;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, emb);
+
+  float *scratch = NULL;
+  const float *values = rag_embedding_floats(emb, &scratch);
+
+  binary_embedding_t *b = binary_alloc(emb->dim);
+  rag_binary_pack(values, emb->dim, b->bits);
+  xfree(scratch);
+
+  return TypedData_Wrap_Struct(cBinaryEmbedding, &binary_type, b);
+}
+
+// Copy words between uint64_t and a little-endian byte buffer
+static void words_from_le_bytes(uint64_t *dst, const char *src, size_t words) {
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  for (size_t i = 0; i < words; ++i) {
+    uint64_t word;
+    memcpy(&word, src + i * sizeof(uint64_t), sizeof(uint64_t));
+    dst[i] = __builtin_bswap64(word);
+  }
+#else
+  memcpy(dst, src, words * sizeof(uint64_t));
+#endif
+}
+
+static void words_to_le_bytes(char *dst, const uint64_t *src, size_t words) {
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  for (size_t i = 0; i < words; ++i) {
+    uint64_t word = __builtin_bswap64(src[i]);
+    memcpy(dst + i * sizeof(uint64_t), &word, sizeof(uint64_t));
+  }
+#else
+  memcpy(dst, src, words * sizeof(uint64_t));
+#endif
+}
+
+// Class method: RagEmbeddings::BinaryEmbedding.from_blob(string)
+// Restores a binary embedding from the layout produced by to_blob
+static VALUE binary_from_blob(VALUE klass, VALUE rb_blob) {
+  StringValue(rb_blob);
+
+  long byte_len = RSTRING_LEN(rb_blob);
+  const char *src = RSTRING_PTR(rb_blob);
+
+  if (byte_len < (long)BINARY_BLOB_HEADER) {
+    rb_raise(rb_eArgError, "Blob too short for a binary embedding: %ld bytes", byte_len);
+  }
+
+  uint32_t dim;
+  memcpy(&dim, src, sizeof(dim));
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  dim = __builtin_bswap32(dim);
+#endif
+
+  rag_check_dimension((long)dim);
+
+  size_t words = rag_binary_words(dim);
+  if ((size_t)byte_len != BINARY_BLOB_HEADER + words * sizeof(uint64_t)) {
+    rb_raise(rb_eArgError, "Blob length %ld does not match %u dimensions", byte_len, dim);
+  }
+
+  binary_embedding_t *b = binary_alloc(dim);
+  words_from_le_bytes(b->bits, src + BINARY_BLOB_HEADER, words);
+
+  return TypedData_Wrap_Struct(klass, &binary_type, b);
+}
+
+// Instance method: binary.to_blob
+// Packs the dimension (little-endian uint32) followed by the words (little-endian uint64)
+static VALUE binary_to_blob(VALUE self) {
+  binary_embedding_t *b;
+  TypedData_Get_Struct(self, binary_embedding_t, &binary_type, b);
+
+  size_t words = rag_binary_words(b->dim);
+  VALUE blob = rb_str_new(NULL, (long)(BINARY_BLOB_HEADER + words * sizeof(uint64_t)));
+  char *dst = RSTRING_PTR(blob);
+
+  uint32_t dim = b->dim;
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  dim = __builtin_bswap32(dim);
+#endif
+  memcpy(dst, &dim, sizeof(dim));
+  words_to_le_bytes(dst + BINARY_BLOB_HEADER, b->bits, words);
+
+  return blob;
+}
+
+// Instance method: binary.dim
+static VALUE binary_dim(VALUE self) {
+  binary_embedding_t *b;
+  TypedData_Get_Struct(self, binary_embedding_t, &binary_type, b);
+  return UINT2NUM(b->dim);
+}
+
+// Instance method: binary.to_a
+// Returns the bits as an array of 0 and 1
+static VALUE binary_to_a(VALUE self) {
+  binary_embedding_t *b;
+  TypedData_Get_Struct(self, binary_embedding_t, &binary_type, b);
+
+  VALUE arr = rb_ary_new_capa(b->dim);
+  for (uint32_t i = 0; i < b->dim; ++i) {
+    rb_ary_store(arr, i, INT2FIX((b->bits[i / 64] >> (i % 64)) & 1));
+  }
+  return arr;
+}
+
+// Instance method: binary.hamming_distance(other_binary)
+// Number of dimensions whose sign differs, computed with popcount
+static VALUE binary_hamming_distance(VALUE self, VALUE other) {
+  binary_embedding_t *a, *b;
+  TypedData_Get_Struct(self, binary_embedding_t, &binary_type, a);
+  TypedData_Get_Struct(other, binary_embedding_t, &binary_type, b);
+
+  if (a->dim != b->dim) {
+    rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", a->dim, b->dim);
+  }
+
+  return ULL2NUM(rag_hamming(a->bits, b->bits, rag_binary_words(a->dim)));
+}
+
+void rag_init_binary(VALUE mRag) {
+  cBinaryEmbedding = rb_define_class_under(mRag, "BinaryEmbedding", rb_cObject);
+  rb_undef_alloc_func(cBinaryEmbedding);
+
+  rb_define_method(rag_cEmbedding, "binarize", rag_embedding_binarize, 0);
+
+  rb_define_singleton_method(cBinaryEmbedding, "from_blob", binary_from_blob, 1);
+
+  rb_define_method(cBinaryEmbedding, "dim", binary_dim, 0);
+  rb_define_method(cBinaryEmbedding, "to_a", binary_to_a, 0);
+  rb_define_method(cBinaryEmbedding, "to_blob", binary_to_blob, 0);
+  rb_define_method(cBinaryEmbedding, "hamming_distance", binary_hamming_distance, 1);
+}
@@ -0,0 +1,18 @@
+#ifndef RAG_EMBEDDINGS_BINARY_H
+#define RAG_EMBEDDINGS_BINARY_H
+
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For uint32_t, uint64_t
+
+// Sign-bit binary quantization: 1 bit per dimension (set when the value is > 0),
+// packed little-endian into 64-bit words. Unused bits of the last word are 0
+
+// Number of 64-bit words needed for dim bits
+static inline size_t rag_binary_words(uint32_t dim) {
+  return ((size_t)dim + 63) / 64;
+}
+
+// Pack the signs of dim floats into rag_binary_words(dim) words
+void rag_binary_pack(const float *values, uint32_t dim, uint64_t *bits);
+
+#endif
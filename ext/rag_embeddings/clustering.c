@@ -0,0 +1,81 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For uint32_t, uint64_t
+#include <string.h>   // For memcpy
+#include "embedding.h"
+#include "kmeans.h"
+
+// Module method: RagEmbeddings.kmeans(embeddings, k:, iterations: 25, seed: 0, metric: :cosine)
+// Clusters a list of embeddings (Embedding objects, arrays or blobs) or an EmbeddingMatrix
+// into k groups, with k-means++ seeding.
+// metric: :cosine compares directions and returns unit-length centroids; :euclidean uses the L2 distance.
+// Returns {centroids: [Embedding, ...], assignments: [cluster of each item], iterations: n}
+static VALUE rag_kmeans_method(int argc, VALUE *argv, VALUE self) {
+  VALUE rb_embeddings, opts;
+  rb_scan_args(argc, argv, "1:", &rb_embeddings, &opts);
+
+  VALUE kwargs[4] = {Qundef, Qundef, Qundef, Qundef};
+  ID kw_ids[4] = {rb_intern("k"), rb_intern("iterations"), rb_intern("seed"), rb_intern("metric")};
+  rb_get_kwargs(NIL_P(opts) ? rb_hash_new() : opts, kw_ids, 1, 3, kwargs);
+
+  uint32_t k = rag_uint_option(kwargs[0], 0, "k");
+  int iterations = (int)rag_uint_option(kwargs[1], 25, "iterations");
+  uint64_t seed = (kwargs[2] == Qundef || NIL_P(kwargs[2])) ? 0 : NUM2ULL(kwargs[2]);
+  rag_metric_t metric = kwargs[3] == Qundef ? RAG_METRIC_COSINE : rag_metric_from_value(kwargs[3]);
+
+  if (metric != RAG_METRIC_COSINE && metric != RAG_METRIC_EUCLIDEAN) {
+    rb_raise(rb_eArgError, "kmeans supports only the :cosine and :euclidean metrics");
+  }
+
+  // Lists are copied into a contiguous matrix first
+  VALUE matrix = rb_embeddings;
+  if (RB_TYPE_P(rb_embeddings, T_ARRAY)) {
+    VALUE cMatrix = rb_const_get(self, rb_intern("EmbeddingMatrix"));
+    matrix = rb_funcall(cMatrix, rb_intern("from_arrays"), 1, rb_embeddings);
+  }
+
+  uint32_t dim;
+  size_t n;
+  int *busy;
+  const float *rows = rag_matrix_rows(matrix, &dim, &n, &busy);
+
+  if (k > n) {
+    rb_raise(rb_eArgError, "k (%u) is larger than the number of embeddings (%zu)", k, n);
+  }
+
+  // ALLOCV keeps the buffers reachable by the GC if building the result raises
+  VALUE centroids_buffer, assignments_buffer;
+  float *centroids = ALLOCV_N(float, centroids_buffer, (size_t)k * dim);
+  uint32_t *assignments = ALLOCV_N(uint32_t, assignments_buffer, n);
+
+  rag_rng_t rng;
+  rag_rng_seed(&rng, seed);
+  int ran = rag_kmeans(rows, n, dim, k, iterations, metric == RAG_METRIC_COSINE, &rng, centroids, assignments, busy);
+
+  VALUE rb_centroids = rb_ary_new_capa(k);
+  for (uint32_t c = 0; c < k; ++c) {
+    embedding_t *emb = rag_embedding_alloc(dim, EMBEDDING_DTYPE_F32);
+    memcpy(emb->values, centroids + (size_t)c * dim, (size_t)dim * sizeof(float));
+    rb_ary_store(rb_centroids, c, TypedData_Wrap_Struct(rag_cEmbedding, &rag_embedding_type, emb));
+  }
+
+  VALUE rb_assignments = rb_ary_new_capa((long)n);
+  for (size_t i = 0; i < n; ++i) {
+    rb_ary_store(rb_assignments, (long)i, UINT2NUM(assignments[i]));
+  }
+
+  ALLOCV_END(centroids_buffer);
+  ALLOCV_END(assignments_buffer);
+
+  VALUE result = rb_hash_new();
+  rb_hash_aset(result, ID2SYM(rb_intern("centroids")), rb_centroids);
+  rb_hash_aset(result, ID2SYM(rb_intern("assignments")), rb_assignments);
+  rb_hash_aset(result, ID2SYM(rb_intern("iterations")), INT2NUM(ran));
+
+  // Keep the matrix alive until the rows are no longer read
+  RB_GC_GUARD(matrix);
+  return result;
+}
+
+void rag_init_clustering(VALUE mRag) {
+  rb_define_singleton_method(mRag, "kmeans", rag_kmeans_method, -1);
+}
@@ -1,14 +1,82 @@
 #include <ruby.h>     // Ruby API
-#include <stdint.h>   // For integer types like uint16_t
+#include <ruby/ractor.h> // For rb_ractor_main_p
+#include <stdint.h>   // For integer types like uint32_t
 #include <stdlib.h>   // For memory allocation functions
 #include <math.h>     // For math functions like sqrt
+#include <string.h>   // For memcpy
+#include "simd.h"     // SIMD distance kernels with runtime CPU dispatch
+#include "metrics.h"  // Cosine, dot product and distance metrics
+#include "embedding.h" // embedding_t and helpers shared with the other source files
+#include "topk.h"     // Bounded heap for top-k searches
+#include "binary.h"   // Sign-bit packing for the binary prefilter
+#include "parallel.h" // Scans without the GVL, split across native threads
 
-// Main data structure for storing embeddings
-// Flexible array member (values[]) allows variable length arrays
-typedef struct {
-  uint16_t dim;       // Dimension of the embedding vector
-  float values[];     // Flexible array member to store the actual values
-} embedding_t;
+VALUE rag_cEmbedding = Qnil;
+VALUE rag_cEmbeddingMatrix = Qnil;
+
+// Values of an embedding as float32, decoding f16/bf16 into a scratch buffer
+const float *rag_embedding_floats(const embedding_t *emb, float **scratch) {
+  if (emb->dtype == EMBEDDING_DTYPE_F32) {
+    return emb->values;
+  }
+
+  float *buffer = ALLOC_N(float, emb->dim);
+  for (uint32_t i = 0; i < emb->dim; ++i) {
+    buffer[i] = embedding_get(emb, i);
+  }
+
+  *scratch = buffer;
+  return buffer;
+}
+
+// Convert a dtype name (:f32, :f16, :bf16 or their long forms) to its C enum
+static uint8_t dtype_from_value(VALUE rb_dtype) {
+  ID id = rb_to_id(rb_dtype);
+
+  if (id == rb_intern("f32") || id == rb_intern("float32")) return EMBEDDING_DTYPE_F32;
+  if (id == rb_intern("f16") || id == rb_intern("float16")) return EMBEDDING_DTYPE_F16;
+  if (id == rb_intern("bf16") || id == rb_intern("bfloat16")) return EMBEDDING_DTYPE_BF16;
+
+  rb_raise(rb_eArgError, "Unknown dtype: %s", rb_id2name(id));
+}
+
+// Read the optional dtype: keyword, defaulting to f32
+static uint8_t dtype_from_opts(VALUE opts) {
+  if (NIL_P(opts)) return EMBEDDING_DTYPE_F32;
+
+  ID kw = rb_intern("dtype");
+  VALUE rb_dtype;
+  rb_get_kwargs(opts, &kw, 0, 1, &rb_dtype);
+
+  return rb_dtype == Qundef ? EMBEDDING_DTYPE_F32 : dtype_from_value(rb_dtype);
+}
+
+// Symbol for a dtype, as returned by Embedding#dtype
+static VALUE dtype_to_sym(uint8_t dtype) {
+  switch (dtype) {
+    case EMBEDDING_DTYPE_F16:  return ID2SYM(rb_intern("f16"));
+    case EMBEDDING_DTYPE_BF16: return ID2SYM(rb_intern("bf16"));
+    default:                   return ID2SYM(rb_intern("f32"));
+  }
+}
+
+// Largest dimension accepted when building embeddings
+// Guards against accidentally huge arrays; configurable with Embedding.max_dim=
+#define EMBEDDING_DEFAULT_MAX_DIM (1u << 20)
+static uint32_t embedding_max_dim = EMBEDDING_DEFAULT_MAX_DIM;
+
+// Validate a requested dimension against the max-dimension guard
+void rag_check_dimension(long dim) {
+  if (dim > (long)embedding_max_dim) {
+    rb_raise(rb_eArgError, "Array too large: maximum %u dimensions allowed", embedding_max_dim);
+  }
+
+  // Dimensions given as integers (e.g. EmbeddingMatrix.from_blob) may also be negative
+  if (dim < 1) {
+    if (dim == 0) rb_raise(rb_eArgError, "Cannot create embedding from empty array");
+    rb_raise(rb_eArgError, "Dimension must be positive, got %ld", dim);
+  }
+}
 
 // Callback for freeing memory when Ruby's GC collects our object
 static void embedding_free(void *ptr) {
@@ -20,201 +88,1867 @@ static void embedding_free(void *ptr) {
 // Callback to report memory usage to Ruby's GC
 static size_t embedding_memsize(const void *ptr) {
   const embedding_t *emb = (const embedding_t *)ptr;
-  return emb ? sizeof(embedding_t) + emb->dim * sizeof(float) : 0;
+  return emb ? sizeof(embedding_t) + (size_t)emb->dim * dtype_size(emb->dtype) : 0;
 }
 
 // Type information for Ruby's GC:
 // Tells Ruby how to manage our C data structure
-static const rb_data_type_t embedding_type = {
+const rb_data_type_t rag_embedding_type = {
   "RagEmbeddings/Embedding",               // Type name
   {0, embedding_free, embedding_memsize,}, // Functions: mark, free, size
   0, 0,                                    // Parent type, data
-  RUBY_TYPED_FREE_IMMEDIATELY              // Flags for immediate cleanup
+  RUBY_TYPED_FREE_IMMEDIATELY |            // Flags for immediate cleanup
+  RUBY_TYPED_FROZEN_SHAREABLE              // and sharing between Ractors once frozen
 };
 
-// Class method: RagEmbeddings::Embedding.from_array([1.0, 2.0, ...])
-// Creates a new embedding from a Ruby array
-static VALUE embedding_from_array(VALUE klass, VALUE rb_array) {
-  Check_Type(rb_array, T_ARRAY);           // Ensure argument is a Ruby array
+// Allocate an embedding with room for dim values of the given type
+embedding_t *rag_embedding_alloc(uint32_t dim, uint8_t dtype) {
+  embedding_t *ptr = xmalloc(sizeof(embedding_t) + (size_t)dim * dtype_size(dtype));
+  ptr->dim = dim;
+  ptr->dtype = dtype;
+  ptr->normalized = 0;
+  ptr->norm = -1.0;
+  return ptr;
+}
 
-  long array_len = RARRAY_LEN(rb_array);
+// Store value at index i, raising ArgumentError (and freeing ptr) when the stored value is not finite.
+// A finite double can still overflow float32 or the 16-bit types
+static void embedding_store_checked(embedding_t *ptr, uint32_t i, double value, int allow_nan) {
+  embedding_set(ptr, i, (float)value);
+  if (allow_nan || isfinite(embedding_get(ptr, i))) return;
 
-  // Validate array length fits in uint16_t (max 65535 dimensions)
-  if (array_len > UINT16_MAX) {
-    rb_raise(rb_eArgError, "Array too large: maximum %d dimensions allowed", UINT16_MAX);
+  uint8_t dtype = ptr->dtype;
+  xfree(ptr);
+  if (isfinite(value)) {
+    rb_raise(rb_eArgError, "Array element at index %u (%g) is out of range for %s",
+             i, value, rb_id2name(SYM2ID(dtype_to_sym(dtype))));
   }
+  rb_raise(rb_eArgError, "Array element at index %u is not finite (%s)",
+           i, isnan(value) ? "NaN" : (value > 0 ? "Infinity" : "-Infinity"));
+}
+
+// Class method: RagEmbeddings::Embedding.from_array([1.0, 2.0, ...], dtype: :f32, allow_nan: false)
+// Creates a new embedding from a Ruby array
+// dtype: :f16 or :bf16 stores the values in 16 bits, halving the memory used
+// NaN and Infinity raise ArgumentError naming the index, as do values too large for the dtype:
+// they would silently turn every similarity into NaN. allow_nan: true stores them as given
+static VALUE embedding_from_array(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_array, opts;
+  rb_scan_args(argc, argv, "1:", &rb_array, &opts);
 
-  // Prevent zero-length embeddings
-  if (array_len == 0) {
-    rb_raise(rb_eArgError, "Cannot create embedding from empty array");
+  Check_Type(rb_array, T_ARRAY);           // Ensure argument is a Ruby array
+
+  // dtype: and allow_nan: keywords
+  VALUE kwargs[2] = {Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[2] = {rb_intern("dtype"), rb_intern("allow_nan")};
+    rb_get_kwargs(opts, kw_ids, 0, 2, kwargs);
   }
+  uint8_t dtype = kwargs[0] == Qundef ? EMBEDDING_DTYPE_F32 : dtype_from_value(kwargs[0]);
+  int allow_nan = kwargs[1] != Qundef && RTEST(kwargs[1]);
 
-  uint16_t dim = (uint16_t)array_len;
+  long array_len = RARRAY_LEN(rb_array);
 
-  // Allocate memory for struct + array of floats
-  embedding_t *ptr = xmalloc(sizeof(embedding_t) + dim * sizeof(float));
-  ptr->dim = dim;
+  // Validate array length against the max-dimension guard and prevent zero-length embeddings
+  rag_check_dimension(array_len);
+
+  uint32_t dim = (uint32_t)array_len;
+
+  // Allocate memory for struct + array of values
+  embedding_t *ptr = rag_embedding_alloc(dim, dtype);
 
   // Copy values from Ruby array to our C array
   // Using RARRAY_CONST_PTR for better performance when available
   const VALUE *array_ptr = RARRAY_CONST_PTR(rb_array);
-  for (uint16_t i = 0; i < dim; ++i) {
+  for (uint32_t i = 0; i < dim; ++i) {
     VALUE val = array_ptr[i];
 
     // Ensure the value is numeric
     if (!RB_FLOAT_TYPE_P(val) && !RB_INTEGER_TYPE_P(val)) {
       xfree(ptr);  // Clean up allocated memory before raising exception
-      rb_raise(rb_eTypeError, "Array element at index %d is not numeric", i);
+      rb_raise(rb_eTypeError, "Array element at index %u is not numeric", i);
     }
 
-    ptr->values[i] = (float)NUM2DBL(val);
+    embedding_store_checked(ptr, i, NUM2DBL(val), allow_nan);
   }
 
   // Wrap our C struct in a Ruby object
-  VALUE obj = TypedData_Wrap_Struct(klass, &embedding_type, ptr);
+  VALUE obj = TypedData_Wrap_Struct(klass, &rag_embedding_type, ptr);
   return obj;
 }
 
+// Copy n little-endian float32 values from a byte buffer into floats
+// A plain memcpy on little-endian hosts, byte-swapped on big-endian ones
+void rag_floats_from_le_bytes(float *dst, const char *src, size_t n) {
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  for (size_t i = 0; i < n; ++i) {
+    uint32_t bits;
+    memcpy(&bits, src + i * sizeof(float), sizeof(float));
+    bits = __builtin_bswap32(bits);
+    memcpy(dst + i, &bits, sizeof(float));
+  }
+#else
+  memcpy(dst, src, n * sizeof(float));
+#endif
+}
+
+// Copy n floats into a byte buffer as little-endian float32
+void rag_floats_to_le_bytes(char *dst, const float *src, size_t n) {
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  for (size_t i = 0; i < n; ++i) {
+    uint32_t bits;
+    memcpy(&bits, src + i, sizeof(float));
+    bits = __builtin_bswap32(bits);
+    memcpy(dst + i * sizeof(float), &bits, sizeof(float));
+  }
+#else
+  memcpy(dst, src, n * sizeof(float));
+#endif
+}
+
+// Copy n little-endian 16-bit codes from a byte buffer
+static void halves_from_le_bytes(uint16_t *dst, const char *src, size_t n) {
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  for (size_t i = 0; i < n; ++i) {
+    uint16_t bits;
+    memcpy(&bits, src + i * sizeof(uint16_t), sizeof(uint16_t));
+    dst[i] = __builtin_bswap16(bits);
+  }
+#else
+  memcpy(dst, src, n * sizeof(uint16_t));
+#endif
+}
+
+// Copy n 16-bit codes into a byte buffer as little-endian
+static void halves_to_le_bytes(char *dst, const uint16_t *src, size_t n) {
+#if defined(__BYTE_ORDER__) && __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__
+  for (size_t i = 0; i < n; ++i) {
+    uint16_t bits = __builtin_bswap16(src[i]);
+    memcpy(dst + i * sizeof(uint16_t), &bits, sizeof(uint16_t));
+  }
+#else
+  memcpy(dst, src, n * sizeof(uint16_t));
+#endif
+}
+
+// Number of values in a packed blob of the given type,
+// raising if the length is not a multiple of the value size
+static long blob_dimension(VALUE rb_blob, uint8_t dtype) {
+  long byte_len = RSTRING_LEN(rb_blob);
+  long value_size = (long)dtype_size(dtype);
+
+  if (byte_len % value_size != 0) {
+    rb_raise(rb_eArgError, "Blob length %ld is not a multiple of %ld bytes", byte_len, value_size);
+  }
+
+  return byte_len / value_size;
+}
+
+// Decode a packed blob of the given type into n floats
+static void floats_from_blob(float *dst, const char *src, size_t n, uint8_t dtype) {
+  if (dtype == EMBEDDING_DTYPE_F32) {
+    rag_floats_from_le_bytes(dst, src, n);
+    return;
+  }
+
+  for (size_t i = 0; i < n; ++i) {
+    uint16_t bits;
+    halves_from_le_bytes(&bits, src + i * sizeof(uint16_t), 1);
+    dst[i] = dtype == EMBEDDING_DTYPE_F16 ? rag_f16_to_f32(bits) : rag_bf16_to_f32(bits);
+  }
+}
+
+// Wrap a new embedding holding the values of a packed blob of the given type
+static VALUE embedding_wrap_blob(VALUE klass, VALUE rb_blob, uint8_t dtype) {
+  long dim = blob_dimension(rb_blob, dtype);
+  rag_check_dimension(dim);
+
+  embedding_t *ptr = rag_embedding_alloc((uint32_t)dim, dtype);
+  if (dtype == EMBEDDING_DTYPE_F32) {
+    rag_floats_from_le_bytes(ptr->values, RSTRING_PTR(rb_blob), (size_t)dim);
+  } else {
+    halves_from_le_bytes(EMBEDDING_HALVES(ptr), RSTRING_PTR(rb_blob), (size_t)dim);
+  }
+
+  return TypedData_Wrap_Struct(klass, &rag_embedding_type, ptr);
+}
+
+// Class method: RagEmbeddings::Embedding.from_blob("\x00\x00\x80\x3F...", dtype: :f32)
+// Creates a new embedding from a packed little-endian string
+// (float32 is the layout of Array#pack("e*"); f16/bf16 blobs hold 2 bytes per value),
+// copying the bytes directly into the struct. Values are not checked: see finite?
+static VALUE embedding_from_blob(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_blob, opts;
+  rb_scan_args(argc, argv, "1:", &rb_blob, &opts);
+
+  StringValue(rb_blob);
+  return embedding_wrap_blob(klass, rb_blob, dtype_from_opts(opts));
+}
+
+// Class method: RagEmbeddings::Embedding.from_float32_blob(blob, dtype: :f32, allow_nan: false)
+// Creates an embedding of the given dtype from a packed little-endian float32 string,
+// validating every value like from_array: NaN, Infinity and values out of range for the dtype
+// raise ArgumentError unless allow_nan: true
+static VALUE embedding_from_float32_blob(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_blob, opts;
+  rb_scan_args(argc, argv, "1:", &rb_blob, &opts);
+
+  StringValue(rb_blob);
+
+  VALUE kwargs[2] = {Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[2] = {rb_intern("dtype"), rb_intern("allow_nan")};
+    rb_get_kwargs(opts, kw_ids, 0, 2, kwargs);
+  }
+  uint8_t dtype = kwargs[0] == Qundef ? EMBEDDING_DTYPE_F32 : dtype_from_value(kwargs[0]);
+  int allow_nan = kwargs[1] != Qundef && RTEST(kwargs[1]);
+
+  long dim = blob_dimension(rb_blob, EMBEDDING_DTYPE_F32);
+  rag_check_dimension(dim);
+
+  embedding_t *ptr = rag_embedding_alloc((uint32_t)dim, dtype);
+  const char *src = RSTRING_PTR(rb_blob);
+  for (uint32_t i = 0; i < (uint32_t)dim; ++i) {
+    float value;
+    rag_floats_from_le_bytes(&value, src + i * sizeof(float), 1);
+    embedding_store_checked(ptr, i, value, allow_nan);
+  }
+
+  return TypedData_Wrap_Struct(klass, &rag_embedding_type, ptr);
+}
+
+// Encode the values of an embedding as a little-endian blob of another type
+static VALUE embedding_convert_blob(const embedding_t *ptr, uint8_t dtype) {
+  VALUE blob = rb_str_new(NULL, (long)((size_t)ptr->dim * dtype_size(dtype)));
+  char *dst = RSTRING_PTR(blob);
+
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    float value = embedding_get(ptr, i);
+    if (dtype == EMBEDDING_DTYPE_F32) {
+      rag_floats_to_le_bytes(dst + i * sizeof(float), &value, 1);
+    } else {
+      uint16_t bits = dtype == EMBEDDING_DTYPE_F16 ? rag_f32_to_f16(value) : rag_f32_to_bf16(value);
+      halves_to_le_bytes(dst + i * sizeof(uint16_t), &bits, 1);
+    }
+  }
+
+  return blob;
+}
+
+// Instance method: embedding.to_blob or embedding.to_blob(dtype: :f32)
+// Returns the values as a little-endian binary string in the embedding's dtype
+// (4 bytes per value for f32, 2 bytes for f16 and bf16), or converted to the given dtype
+static VALUE embedding_to_blob(int argc, VALUE *argv, VALUE self) {
+  VALUE opts;
+  rb_scan_args(argc, argv, ":", &opts);
+
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  uint8_t dtype = NIL_P(opts) ? ptr->dtype : dtype_from_opts(opts);
+  if (dtype != ptr->dtype) {
+    return embedding_convert_blob(ptr, dtype);
+  }
+
+  VALUE blob = rb_str_new(NULL, (long)((size_t)ptr->dim * dtype_size(ptr->dtype)));
+  if (ptr->dtype == EMBEDDING_DTYPE_F32) {
+    rag_floats_to_le_bytes(RSTRING_PTR(blob), ptr->values, ptr->dim);
+  } else {
+    halves_to_le_bytes(RSTRING_PTR(blob), EMBEDDING_HALVES(ptr), ptr->dim);
+  }
+
+  return blob;
+}
+
+// Allocator used only by dup and clone: the copy starts empty and initialize_copy fills it.
+// Embedding.new and Embedding.allocate stay undefined, embeddings come from the class methods
+static VALUE embedding_allocate(VALUE klass) {
+  return TypedData_Wrap_Struct(klass, &rag_embedding_type, NULL);
+}
+
+// Instance method: embedding.initialize_copy(original), called by dup and clone
+// Gives the copy its own struct holding the same dtype and values
+static VALUE embedding_initialize_copy(VALUE self, VALUE orig) {
+  if (self == orig) return self;
+
+  // Object#initialize_copy checks that the copy is not frozen and has the same class
+  rb_call_super(1, &orig);
+
+  embedding_t *src;
+  TypedData_Get_Struct(orig, embedding_t, &rag_embedding_type, src);
+
+  embedding_t *copy = rag_embedding_alloc(src->dim, src->dtype);
+  memcpy(copy->values, src->values, (size_t)src->dim * dtype_size(src->dtype));
+  copy->norm = src->norm;
+  copy->normalized = src->normalized;
+
+  xfree(DATA_PTR(self));
+  DATA_PTR(self) = copy;
+  return self;
+}
+
+// Instance method: embedding._dump(level), used by Marshal.dump
+// Returns the dtype in one byte followed by the to_blob bytes
+static VALUE embedding_dump(VALUE self, VALUE level) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  VALUE blob = embedding_to_blob(0, NULL, self);
+  VALUE dump = rb_str_buf_new(1 + RSTRING_LEN(blob));
+  char dtype = (char)ptr->dtype;
+
+  rb_str_cat(dump, &dtype, 1);
+  rb_str_cat(dump, RSTRING_PTR(blob), RSTRING_LEN(blob));
+  return dump;
+}
+
+// Class method: RagEmbeddings::Embedding._load(string), used by Marshal.load
+// Rebuilds an embedding from the output of _dump
+static VALUE embedding_load(VALUE klass, VALUE rb_dump) {
+  StringValue(rb_dump);
+
+  long len = RSTRING_LEN(rb_dump);
+  if (len < 1 || (uint8_t)RSTRING_PTR(rb_dump)[0] > EMBEDDING_DTYPE_BF16) {
+    rb_raise(rb_eArgError, "Corrupted embedding dump");
+  }
+
+  uint8_t dtype = (uint8_t)RSTRING_PTR(rb_dump)[0];
+  return embedding_wrap_blob(klass, rb_str_substr(rb_dump, 1, len - 1), dtype);
+}
+
+// Instance method: embedding.dtype
+// Returns the storage type of the values: :f32, :f16 or :bf16
+static VALUE embedding_dtype(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+  return dtype_to_sym(ptr->dtype);
+}
+
 // Instance method: embedding.dim
 // Returns the dimension of the embedding
 static VALUE embedding_dim(VALUE self) {
   embedding_t *ptr;
   // Get the C struct from the Ruby object
-  TypedData_Get_Struct(self, embedding_t, &embedding_type, ptr);
-  return INT2NUM(ptr->dim);
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+  return UINT2NUM(ptr->dim);
 }
 
 // Instance method: embedding.to_a
 // Converts the embedding back to a Ruby array
 static VALUE embedding_to_a(VALUE self) {
   embedding_t *ptr;
-  TypedData_Get_Struct(self, embedding_t, &embedding_type, ptr);
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
   // Create a new Ruby array with pre-allocated capacity
   VALUE arr = rb_ary_new_capa(ptr->dim);
 
   // Copy each float value to the Ruby array
   // Using rb_ary_store for better performance than rb_ary_push
-  for (uint16_t i = 0; i < ptr->dim; ++i) {
-    rb_ary_store(arr, i, DBL2NUM(ptr->values[i]));
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    rb_ary_store(arr, i, DBL2NUM(embedding_get(ptr, i)));
   }
 
   return arr;
 }
 
-// Instance method: embedding.cosine_similarity(other_embedding)
-// Calculate cosine similarity between two embeddings using optimized algorithm
-static VALUE embedding_cosine_similarity(VALUE self, VALUE other) {
-  embedding_t *a, *b;
-  // Get C structs for both embeddings
-  TypedData_Get_Struct(self, embedding_t, &embedding_type, a);
-  TypedData_Get_Struct(other, embedding_t, &embedding_type, b);
+// Wrap a new embedding in an object of the same class as `like`
+static VALUE embedding_wrap_like(VALUE like, embedding_t *ptr) {
+  return TypedData_Wrap_Struct(rb_obj_class(like), &rag_embedding_type, ptr);
+}
+
+// Resolve the arguments of [] and slice against the dimension, with the rules of Array#[]:
+// an index (negative counts from the end), a start and a length, or a range.
+// Returns 0 for a single index (in *beg), 1 for a span (*beg, *len), -1 when out of range
+static int embedding_span(const embedding_t *ptr, int argc, VALUE *argv, long *beg, long *len) {
+  VALUE rb_first, rb_length;
+  rb_scan_args(argc, argv, "11", &rb_first, &rb_length);
+
+  long dim = (long)ptr->dim;
+
+  if (!NIL_P(rb_length)) {
+    *beg = NUM2LONG(rb_first);
+    *len = NUM2LONG(rb_length);
+    if (*beg < 0) *beg += dim;
+    if (*beg < 0 || *beg > dim || *len < 0) return -1;
+    if (*len > dim - *beg) *len = dim - *beg;
+    return 1;
+  }
+
+  if (!RB_INTEGER_TYPE_P(rb_first)) {
+    VALUE is_range = rb_range_beg_len(rb_first, beg, len, dim, 0);
+    if (is_range == Qnil) return -1;
+    if (is_range == Qtrue) return 1;
+  }
+
+  *beg = NUM2LONG(rb_first);
+  if (*beg < 0) *beg += dim;
+  return (*beg < 0 || *beg >= dim) ? -1 : 0;
+}
+
+// Instance method: embedding[index], embedding[start, length], embedding[range]
+// Returns a Float for an index, an Array of Floats otherwise, and nil when out of range
+// (the same rules as Array#[]), reading only the requested values
+static VALUE embedding_aref(int argc, VALUE *argv, VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
-  // Ensure dimensions match
-  if (a->dim != b->dim) {
-    rb_raise(rb_eArgError, "Dimension mismatch: %d vs %d", a->dim, b->dim);
+  long beg, len;
+  int span = embedding_span(ptr, argc, argv, &beg, &len);
+  if (span < 0) return Qnil;
+  if (span == 0) return DBL2NUM(embedding_get(ptr, (size_t)beg));
+
+  VALUE arr = rb_ary_new_capa(len);
+  for (long i = 0; i < len; ++i) {
+    rb_ary_store(arr, i, DBL2NUM(embedding_get(ptr, (size_t)(beg + i))));
+  }
+  return arr;
+}
+
+// Instance method: embedding.slice(range) or embedding.slice(start, length)
+// Returns a new Embedding of the same dtype holding the selected values,
+// or nil when the start is out of range. Raises ArgumentError if the selection is empty
+static VALUE embedding_slice(int argc, VALUE *argv, VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  long beg, len;
+  int span = embedding_span(ptr, argc, argv, &beg, &len);
+  if (span < 0) return Qnil;
+  if (span == 0) {
+    rb_raise(rb_eTypeError, "slice expects a range or a start and a length");
+  }
+  if (len == 0) {
+    rb_raise(rb_eArgError, "Cannot create embedding from an empty slice");
+  }
+
+  size_t value_size = dtype_size(ptr->dtype);
+  embedding_t *result = rag_embedding_alloc((uint32_t)len, ptr->dtype);
+  memcpy(result->values, (const char *)ptr->values + (size_t)beg * value_size, (size_t)len * value_size);
+
+  return embedding_wrap_like(self, result);
+}
+
+// Size of the enumerator returned by each without a block
+static VALUE embedding_enum_size(VALUE self, VALUE args, VALUE eobj) {
+  return embedding_dim(self);
+}
+
+// Instance method: embedding.each { |value| ... }
+// Yields every value as a Float, straight from the C buffer (Enumerable is built on it).
+// Returns an Enumerator without a block
+static VALUE embedding_each(VALUE self) {
+  RETURN_SIZED_ENUMERATOR(self, 0, 0, embedding_enum_size);
+
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    rb_yield(DBL2NUM(embedding_get(ptr, i)));
+  }
+  return self;
+}
+
+// Values shown by inspect at each end of a longer embedding
+#define EMBEDDING_INSPECT_EDGE 3
+
+// Instance method: embedding.inspect
+// Returns e.g. #<RagEmbeddings::Embedding dim=1536 dtype=f32 [0.0123, -0.0456, 0.789, ..., 0.0012, 0.3, -0.07]>
+// showing only the first and last values of long embeddings
+static VALUE embedding_inspect(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  VALUE str = rb_sprintf("#<%"PRIsVALUE" dim=%u dtype=%"PRIsVALUE" [",
+                         rb_class_name(rb_obj_class(self)), ptr->dim, rb_sym2str(dtype_to_sym(ptr->dtype)));
+
+  int truncated = ptr->dim > 2 * EMBEDDING_INSPECT_EDGE;
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    if (truncated && i == EMBEDDING_INSPECT_EDGE) {
+      rb_str_cat_cstr(str, ", ...");
+      i = ptr->dim - EMBEDDING_INSPECT_EDGE;
+    }
+    rb_str_catf(str, i == 0 ? "%.6g" : ", %.6g", (double)embedding_get(ptr, i));
+  }
+
+  rb_str_cat_cstr(str, "]>");
+  return str;
+}
+
+// Fetch the C structs of two embeddings, ensuring their dimensions match
+static void embedding_pair(VALUE self, VALUE other, embedding_t **a, embedding_t **b) {
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, *a);
+  TypedData_Get_Struct(other, embedding_t, &rag_embedding_type, *b);
+
+  if ((*a)->dim != (*b)->dim) {
+    rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", (*a)->dim, (*b)->dim);
+  }
+}
+
+// Convert a metric name (:cosine, "euclidean", ...) to its C enum
+rag_metric_t rag_metric_from_value(VALUE rb_metric) {
+  ID id = rb_to_id(rb_metric);
+
+  if (id == rb_intern("cosine")) return RAG_METRIC_COSINE;
+  if (id == rb_intern("dot") || id == rb_intern("dot_product")) return RAG_METRIC_DOT;
+  if (id == rb_intern("euclidean")) return RAG_METRIC_EUCLIDEAN;
+  if (id == rb_intern("squared_euclidean")) return RAG_METRIC_SQUARED_EUCLIDEAN;
+  if (id == rb_intern("manhattan")) return RAG_METRIC_MANHATTAN;
+  if (id == rb_intern("chebyshev")) return RAG_METRIC_CHEBYSHEV;
+  if (id == rb_intern("angular")) return RAG_METRIC_ANGULAR;
+
+  rb_raise(rb_eArgError, "Unknown metric: %s", rb_id2name(id));
+}
+
+// Name of a metric as a Symbol, the inverse of rag_metric_from_value
+VALUE rag_metric_to_sym(rag_metric_t metric) {
+  switch (metric) {
+    case RAG_METRIC_DOT:               return ID2SYM(rb_intern("dot"));
+    case RAG_METRIC_EUCLIDEAN:         return ID2SYM(rb_intern("euclidean"));
+    case RAG_METRIC_SQUARED_EUCLIDEAN: return ID2SYM(rb_intern("squared_euclidean"));
+    case RAG_METRIC_MANHATTAN:         return ID2SYM(rb_intern("manhattan"));
+    case RAG_METRIC_CHEBYSHEV:         return ID2SYM(rb_intern("chebyshev"));
+    case RAG_METRIC_ANGULAR:           return ID2SYM(rb_intern("angular"));
+    default:                           return ID2SYM(rb_intern("cosine"));
   }
+}
+
+// Read the optional metric: keyword, defaulting to cosine
+rag_metric_t rag_metric_from_opts(VALUE opts) {
+  if (NIL_P(opts)) return RAG_METRIC_COSINE;
 
-  // Use double precision for intermediate calculations to reduce accumulation errors
-  double dot = 0.0, norm_a = 0.0, norm_b = 0.0;
+  ID kw = rb_intern("metric");
+  VALUE rb_metric;
+  rb_get_kwargs(opts, &kw, 0, 1, &rb_metric);
 
-  // Calculate dot product and vector magnitudes in a single loop
-  // This is more cache-friendly than separate loops
-  const float *va = a->values;
-  const float *vb = b->values;
+  return rb_metric == Qundef ? RAG_METRIC_COSINE : rag_metric_from_value(rb_metric);
+}
+
+// Largest difference from 1 of the norm of an embedding still considered unit length.
+// normalize rounds the scaled values to the dtype, so 16-bit embeddings are further off
+static double embedding_unit_tolerance(uint8_t dtype) {
+  switch (dtype) {
+    case EMBEDDING_DTYPE_F16:  return 1e-3;
+    case EMBEDDING_DTYPE_BF16: return 1e-2;
+    default:                   return 1e-6;
+  }
+}
 
-  for (uint16_t i = 0; i < a->dim; ++i) {
-    float ai = va[i];
-    float bi = vb[i];
+// L2 norm of an embedding, computed on first use and cached along with the normalized flag.
+// A frozen embedding may be read from several Ractors at once, so it is never written:
+// freeze caches the norm beforehand. *unit receives the normalized flag
+static double embedding_norm(VALUE self, embedding_t *ptr, int *unit) {
+  if (ptr->norm >= 0.0) {
+    *unit = ptr->normalized;
+    return ptr->norm;
+  }
+
+  float *scratch = NULL;
+  double norm = sqrt(rag_sum_squares(rag_embedding_floats(ptr, &scratch), ptr->dim));
+  xfree(scratch);
 
-    dot += (double)ai * bi;          // Dot product
-    norm_a += (double)ai * ai;       // Square of magnitude for vector a
-    norm_b += (double)bi * bi;       // Square of magnitude for vector b
+  *unit = fabs(norm - 1.0) <= embedding_unit_tolerance(ptr->dtype);
+  if (!OBJ_FROZEN(self)) {
+    ptr->norm = norm;
+    ptr->normalized = (uint8_t)*unit;
   }
+  return norm;
+}
+
+// Cosine similarity of two embeddings with their cached norms, so only the dot product is computed.
+// When both are unit length it is used as it is
+static double embedding_cosine(VALUE self, embedding_t *a, const float *va, VALUE other, embedding_t *b, const float *vb) {
+  int unit_a, unit_b;
+  double norm_a = embedding_norm(self, a, &unit_a);
+  double norm_b = embedding_norm(other, b, &unit_b);
 
-  // Check for zero vectors to avoid division by zero
-  if (norm_a == 0.0 || norm_b == 0.0) {
-    return DBL2NUM(0.0);  // Return 0 similarity for zero vectors
+  if (unit_a && unit_b) return rag_cosine_with_norms(va, vb, 1.0, 1.0, a->dim);
+  return rag_cosine_with_norms(va, vb, norm_a, norm_b, a->dim);
+}
+
+// Compute a metric between two embeddings of matching dimension
+// f16/bf16 values are decoded to float first, and accumulated in double by the kernels
+static VALUE embedding_compare(VALUE self, VALUE other, rag_metric_t metric) {
+  embedding_t *a, *b;
+  embedding_pair(self, other, &a, &b);
+
+  float *scratch_a = NULL, *scratch_b = NULL;
+  const float *va = rag_embedding_floats(a, &scratch_a);
+  const float *vb = rag_embedding_floats(b, &scratch_b);
+
+  double result;
+  if (metric == RAG_METRIC_COSINE || metric == RAG_METRIC_ANGULAR) {
+    result = embedding_cosine(self, a, va, other, b, vb);
+    if (metric == RAG_METRIC_ANGULAR) result = rag_angular_from_cosine(result);
+  } else {
+    result = rag_metric_compute(metric, va, vb, a->dim);
   }
 
-  // Apply cosine similarity formula: dot(a,b)/(|a|*|b|)
-  // Using sqrt for better numerical stability
-  double magnitude_product = sqrt(norm_a * norm_b);
-  double similarity = dot / magnitude_product;
+  xfree(scratch_a);
+  xfree(scratch_b);
+  return DBL2NUM(result);
+}
+
+// Instance method: embedding.cosine_similarity(other_embedding)
+// Calculate cosine similarity between two embeddings using optimized algorithm
+// Zero vectors have 0 similarity with anything; the result is clamped to [-1, 1].
+// Embeddings holding NaN or Infinity (see finite?) give NaN
+static VALUE embedding_cosine_similarity(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_COSINE);
+}
+
+// Instance method: embedding.dot_product(other_embedding)
+static VALUE embedding_dot_product(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_DOT);
+}
+
+// Instance method: embedding.euclidean_distance(other_embedding)
+// L2 distance: sqrt(sum((a - b)^2))
+static VALUE embedding_euclidean_distance(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_EUCLIDEAN);
+}
+
+// Instance method: embedding.squared_euclidean_distance(other_embedding)
+// Same ordering as euclidean_distance without the square root
+static VALUE embedding_squared_euclidean_distance(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_SQUARED_EUCLIDEAN);
+}
+
+// Instance method: embedding.manhattan_distance(other_embedding)
+// L1 distance: sum(|a - b|)
+static VALUE embedding_manhattan_distance(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_MANHATTAN);
+}
+
+// Instance method: embedding.chebyshev_distance(other_embedding)
+// L-infinity distance: max(|a - b|)
+static VALUE embedding_chebyshev_distance(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_CHEBYSHEV);
+}
+
+// Instance method: embedding.angular_distance(other_embedding)
+// Angle between the two vectors divided by pi: 0 for same direction, 1 for opposite
+static VALUE embedding_angular_distance(VALUE self, VALUE other) {
+  return embedding_compare(self, other, RAG_METRIC_ANGULAR);
+}
+
+// Instance method: embedding.similarity(other_embedding, metric: :cosine)
+// Dispatch to one of the metrics above by name:
+// :cosine, :dot, :euclidean, :squared_euclidean, :manhattan, :chebyshev, :angular
+// Every metric is finite for finite embeddings; with NaN or Infinity the result is not
+static VALUE embedding_similarity(int argc, VALUE *argv, VALUE self) {
+  VALUE other, opts;
+  rb_scan_args(argc, argv, "1:", &other, &opts);
 
-  // Clamp result to [-1, 1] to handle floating point precision errors
-  if (similarity > 1.0) similarity = 1.0;
-  if (similarity < -1.0) similarity = -1.0;
+  return embedding_compare(self, other, rag_metric_from_opts(opts));
+}
+
+// Instance method: embedding.finite?
+// True when no value is NaN or Infinity. from_array rejects them unless allow_nan: true,
+// but blobs (from_blob, Database rows) are taken as they are
+static VALUE embedding_finite_p(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
-  return DBL2NUM(similarity);
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    if (!isfinite(embedding_get(ptr, i))) return Qfalse;
+  }
+  return Qtrue;
 }
 
 // Instance method: embedding.magnitude
 // Calculate the magnitude (L2 norm) of the embedding vector
+// Cached after the first call
 static VALUE embedding_magnitude(VALUE self) {
   embedding_t *ptr;
-  TypedData_Get_Struct(self, embedding_t, &embedding_type, ptr);
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
-  double sum_squares = 0.0;
-  const float *values = ptr->values;
+  int unit;
+  return DBL2NUM(embedding_norm(self, ptr, &unit));
+}
 
-  for (uint16_t i = 0; i < ptr->dim; ++i) {
-    float val = values[i];
-    sum_squares += (double)val * val;
-  }
+// Instance method: embedding.normalized?
+// True when the magnitude is 1 within the precision of the dtype (1e-6 for f32, 1e-3 for f16,
+// 1e-2 for bf16), e.g. after normalize. Cosine similarity between two normalized embeddings
+// is computed as a plain dot product
+static VALUE embedding_normalized_p(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
-  return DBL2NUM(sqrt(sum_squares));
+  int unit;
+  embedding_norm(self, ptr, &unit);
+  return unit ? Qtrue : Qfalse;
 }
 
-// Instance method: embedding.normalize!
-// Normalize the embedding vector in-place (destructive operation)
-static VALUE embedding_normalize_bang(VALUE self) {
+// Instance method: embedding.freeze
+// Caches the norm up front, so that a frozen embedding (e.g. shared with Ractor.make_shareable)
+// never writes to itself when compared
+static VALUE embedding_freeze(VALUE self) {
   embedding_t *ptr;
-  TypedData_Get_Struct(self, embedding_t, &embedding_type, ptr);
-
-  // Calculate magnitude
-  double sum_squares = 0.0;
-  float *values = ptr->values;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
-  for (uint16_t i = 0; i < ptr->dim; ++i) {
-    float val = values[i];
-    sum_squares += (double)val * val;
-  }
+  int unit;
+  if (!OBJ_FROZEN(self)) embedding_norm(self, ptr, &unit);
+  return rb_call_super(0, NULL);
+}
 
-  double magnitude = sqrt(sum_squares);
+// Scale an embedding to unit length in place, raising on the zero vector
+static void embedding_normalize_in_place(embedding_t *ptr) {
+  // Calculate magnitude
+  float *scratch = NULL;
+  const float *values = rag_embedding_floats(ptr, &scratch);
+  double magnitude = sqrt(rag_sum_squares(values, ptr->dim));
 
   // Avoid division by zero
   if (magnitude == 0.0) {
+    xfree(scratch);
     rb_raise(rb_eZeroDivError, "Cannot normalize zero vector");
   }
 
   // Normalize each component
   float inv_magnitude = (float)(1.0 / magnitude);
-  for (uint16_t i = 0; i < ptr->dim; ++i) {
-    values[i] *= inv_magnitude;
+  if (ptr->dtype == EMBEDDING_DTYPE_F32) {
+    rag_scale(ptr->values, ptr->dim, inv_magnitude);
+  } else {
+    // 16-bit values are scaled in float and converted back
+    for (uint32_t i = 0; i < ptr->dim; ++i) {
+      embedding_set(ptr, i, values[i] * inv_magnitude);
+    }
+    xfree(scratch);
   }
 
+  // The cached norm is stale: it is computed again on next use
+  ptr->norm = -1.0;
+}
+
+// Instance method: embedding.normalize!
+// Normalize the embedding vector in-place (destructive operation).
+// Raises FrozenError on a frozen embedding
+static VALUE embedding_normalize_bang(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+  rb_check_frozen(self);
+
+  embedding_normalize_in_place(ptr);
+
   return self;  // Return self for method chaining
 }
 
-// Ruby extension initialization function
-// This function is called when the extension is loaded
-void Init_embedding(void) {
-  // Define module and class
-  VALUE mRag = rb_define_module("RagEmbeddings");
-  VALUE cEmbedding = rb_define_class_under(mRag, "Embedding", rb_cObject);
+// Instance method: embedding.normalize
+// Returns a new unit-length embedding, leaving this one untouched
+static VALUE embedding_normalize(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
 
-  // IMPORTANT: Undefine the default allocator to prevent the warning
-  // This is necessary when using TypedData_Wrap_Struct
-  rb_undef_alloc_func(cEmbedding);
+  embedding_t *copy = rag_embedding_alloc(ptr->dim, ptr->dtype);
+  memcpy(copy->values, ptr->values, (size_t)ptr->dim * dtype_size(ptr->dtype));
 
-  // Register class methods
-  rb_define_singleton_method(cEmbedding, "from_array", embedding_from_array, 1);
+  // Wrap first: if the copy is the zero vector, the GC frees it after the raise
+  VALUE obj = embedding_wrap_like(self, copy);
+  embedding_normalize_in_place(copy);
 
-  // Register instance methods
-  rb_define_method(cEmbedding, "dim", embedding_dim, 0);
-  rb_define_method(cEmbedding, "to_a", embedding_to_a, 0);
-  rb_define_method(cEmbedding, "cosine_similarity", embedding_cosine_similarity, 1);
-  rb_define_method(cEmbedding, "magnitude", embedding_magnitude, 0);
-  rb_define_method(cEmbedding, "normalize!", embedding_normalize_bang, 0);
+  return obj;
+}
+
+// Instance method: embedding.truncate(256) or embedding.truncate(256, normalize: false)
+// Returns a new embedding of the same dtype with the first `dim` values (Matryoshka models
+// are trained so that a prefix is still a good embedding). The prefix is scaled back
+// to unit length unless normalize: false; a zero prefix raises ZeroDivisionError
+static VALUE embedding_truncate(int argc, VALUE *argv, VALUE self) {
+  VALUE rb_dim, opts;
+  rb_scan_args(argc, argv, "1:", &rb_dim, &opts);
+
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  int normalize = 1;
+  if (!NIL_P(opts)) {
+    ID kw = rb_intern("normalize");
+    VALUE rb_normalize;
+    rb_get_kwargs(opts, &kw, 0, 1, &rb_normalize);
+    if (rb_normalize != Qundef) normalize = RTEST(rb_normalize);
+  }
+
+  long dim = NUM2LONG(rb_dim);
+  if (dim < 1 || dim > (long)ptr->dim) {
+    rb_raise(rb_eArgError, "Cannot truncate embedding of dimension %u to %ld", ptr->dim, dim);
+  }
+
+  embedding_t *result = rag_embedding_alloc((uint32_t)dim, ptr->dtype);
+  memcpy(result->values, ptr->values, (size_t)dim * dtype_size(ptr->dtype));
+
+  // Wrap first: if the prefix is the zero vector, the GC frees it after the raise
+  VALUE obj = embedding_wrap_like(self, result);
+  if (normalize) embedding_normalize_in_place(result);
+
+  return obj;
+}
+
+// Element-wise a + sign * b for two embeddings of matching dimension
+// The result keeps the storage type of the receiver
+static VALUE embedding_combine(VALUE self, VALUE other, float sign) {
+  embedding_t *a, *b;
+  embedding_pair(self, other, &a, &b);
+
+  float *scratch_a = NULL, *scratch_b = NULL;
+  const float *va = rag_embedding_floats(a, &scratch_a);
+  const float *vb = rag_embedding_floats(b, &scratch_b);
+
+  embedding_t *result = rag_embedding_alloc(a->dim, a->dtype);
+  for (uint32_t i = 0; i < a->dim; ++i) {
+    embedding_set(result, i, va[i] + sign * vb[i]);
+  }
+
+  xfree(scratch_a);
+  xfree(scratch_b);
+
+  return embedding_wrap_like(self, result);
+}
+
+// Instance method: embedding + other_embedding
+static VALUE embedding_add(VALUE self, VALUE other) {
+  return embedding_combine(self, other, 1.0f);
+}
+
+// Instance method: embedding - other_embedding
+static VALUE embedding_subtract(VALUE self, VALUE other) {
+  return embedding_combine(self, other, -1.0f);
+}
+
+// Instance method: embedding * scalar
+// Returns a new embedding with every value multiplied by a number
+static VALUE embedding_multiply(VALUE self, VALUE rb_scalar) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  double scalar = NUM2DBL(rb_scalar);
+
+  float *scratch = NULL;
+  const float *values = rag_embedding_floats(ptr, &scratch);
+
+  embedding_t *result = rag_embedding_alloc(ptr->dim, ptr->dtype);
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    embedding_set(result, i, (float)(values[i] * scalar));
+  }
+  xfree(scratch);
+
+  return embedding_wrap_like(self, result);
+}
+
+// Dimension of a row given as a Ruby array, a packed blob or an Embedding
+static long row_dimension(VALUE row, uint8_t blob_dtype) {
+  if (RB_TYPE_P(row, T_ARRAY)) {
+    return RARRAY_LEN(row);
+  }
+
+  if (RB_TYPE_P(row, T_STRING)) {
+    return blob_dimension(row, blob_dtype);
+  }
+
+  embedding_t *emb;
+  TypedData_Get_Struct(row, embedding_t, &rag_embedding_type, emb);
+  return emb->dim;
+}
+
+// Weighted average of a list of embeddings (or arrays); equal weights when rb_weights is nil.
+// Sums are accumulated in double and the result is a float32 embedding
+static VALUE embedding_average(VALUE klass, VALUE rb_list, VALUE rb_weights) {
+  Check_Type(rb_list, T_ARRAY);
+
+  long count = RARRAY_LEN(rb_list);
+  if (count == 0) {
+    rb_raise(rb_eArgError, "Cannot average an empty list");
+  }
+
+  if (!NIL_P(rb_weights)) {
+    Check_Type(rb_weights, T_ARRAY);
+    if (RARRAY_LEN(rb_weights) != count) {
+      rb_raise(rb_eArgError, "Expected %ld weights, got %ld", count, RARRAY_LEN(rb_weights));
+    }
+  }
+
+  // The first item decides the dimension
+  long dim = row_dimension(rb_ary_entry(rb_list, 0), EMBEDDING_DTYPE_F32);
+  rag_check_dimension(dim);
+
+  // ALLOCV keeps the buffers reachable by the GC, so a bad item that raises doesn't leak them
+  VALUE sums_buffer, row_buffer;
+  double *sums = ALLOCV_N(double, sums_buffer, (size_t)dim);
+  float *row = ALLOCV_N(float, row_buffer, (size_t)dim);
+  memset(sums, 0, (size_t)dim * sizeof(double));
+
+  double total_weight = 0.0;
+  for (long i = 0; i < count; ++i) {
+    double weight = NIL_P(rb_weights) ? 1.0 : NUM2DBL(rb_ary_entry(rb_weights, i));
+    rag_row_to_floats(rb_ary_entry(rb_list, i), (uint32_t)dim, EMBEDDING_DTYPE_F32, row);
+
+    for (long d = 0; d < dim; ++d) {
+      sums[d] += weight * row[d];
+    }
+    total_weight += weight;
+  }
+
+  if (total_weight == 0.0) {
+    ALLOCV_END(sums_buffer);
+    ALLOCV_END(row_buffer);
+    rb_raise(rb_eArgError, "Weights sum to zero");
+  }
+
+  embedding_t *result = rag_embedding_alloc((uint32_t)dim, EMBEDDING_DTYPE_F32);
+  for (long d = 0; d < dim; ++d) {
+    result->values[d] = (float)(sums[d] / total_weight);
+  }
+
+  ALLOCV_END(sums_buffer);
+  ALLOCV_END(row_buffer);
+
+  return TypedData_Wrap_Struct(klass, &rag_embedding_type, result);
+}
+
+// Class method: RagEmbeddings::Embedding.mean([embedding, ...])
+// Returns the element-wise average of the embeddings (arrays are accepted too)
+static VALUE embedding_mean(VALUE klass, VALUE rb_list) {
+  return embedding_average(klass, rb_list, Qnil);
+}
+
+// Class method: RagEmbeddings::Embedding.weighted_mean([embedding, ...], [weight, ...])
+// Returns sum(weight * embedding) / sum(weight)
+static VALUE embedding_weighted_mean(VALUE klass, VALUE rb_list, VALUE rb_weights) {
+  return embedding_average(klass, rb_list, rb_weights);
+}
+
+// Instance method: embedding == other, aliased as eql?
+// True when other is an Embedding of the same dtype holding the same values
+// (compared as floats: 0.0 equals -0.0 and NaN never matches)
+static VALUE embedding_equal(VALUE self, VALUE other) {
+  if (self == other) return Qtrue;
+  if (!rb_typeddata_is_kind_of(other, &rag_embedding_type)) return Qfalse;
+
+  embedding_t *a, *b;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, a);
+  TypedData_Get_Struct(other, embedding_t, &rag_embedding_type, b);
+
+  if (a->dtype != b->dtype || a->dim != b->dim) return Qfalse;
+
+  for (uint32_t i = 0; i < a->dim; ++i) {
+    if (embedding_get(a, i) != embedding_get(b, i)) return Qfalse;
+  }
+  return Qtrue;
+}
+
+// Instance method: embedding.hash
+// Built from the dtype and the values, so that equal embeddings work as Hash keys
+static VALUE embedding_hash(VALUE self) {
+  embedding_t *ptr;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, ptr);
+
+  st_index_t h = rb_hash_start(ptr->dim);
+  h = rb_hash_uint32(h, ptr->dtype);
+  for (uint32_t i = 0; i < ptr->dim; ++i) {
+    float value = embedding_get(ptr, i);
+    if (value == 0.0f) value = 0.0f;  // -0.0 == 0.0, so both must hash alike
+
+    uint32_t bits;
+    memcpy(&bits, &value, sizeof(bits));
+    h = rb_hash_uint32(h, bits);
+  }
+
+  return ST2FIX(rb_hash_end(h));
+}
+
+// Instance method: embedding.approx_equal?(other, tolerance = 1e-6)
+// True when both embeddings have the same dimension and no value differs by more than tolerance.
+// Unlike ==, the dtypes may differ (e.g. an embedding and its f16 copy)
+static VALUE embedding_approx_equal(int argc, VALUE *argv, VALUE self) {
+  VALUE other, rb_tolerance;
+  rb_scan_args(argc, argv, "11", &other, &rb_tolerance);
+
+  double tolerance = NIL_P(rb_tolerance) ? 1e-6 : NUM2DBL(rb_tolerance);
+  if (!(tolerance >= 0.0)) {
+    rb_raise(rb_eArgError, "tolerance must be non-negative");
+  }
+
+  embedding_t *a, *b;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, a);
+  TypedData_Get_Struct(other, embedding_t, &rag_embedding_type, b);
+
+  if (a->dim != b->dim) return Qfalse;
+
+  for (uint32_t i = 0; i < a->dim; ++i) {
+    if (!(fabs((double)embedding_get(a, i) - (double)embedding_get(b, i)) <= tolerance)) return Qfalse;
+  }
+  return Qtrue;
+}
+
+// Contiguous storage for N embeddings of the same dimension
+// Rows are stored one after another in a single float buffer (row-major),
+// so a scan over the whole matrix walks memory linearly
+typedef struct {
+  uint32_t dim;       // Dimension of every row
+  size_t count;       // Number of rows currently stored
+  size_t capacity;    // Number of rows the buffer can hold before growing
+  uint8_t blob_dtype; // Storage type of the rows given as packed blobs
+  float *values;      // count * dim floats
+  uint64_t *signs;    // Sign bits of the rows for the binary prefilter, built on first use
+  size_t signs_count; // Number of rows whose sign bits are up to date
+  size_t signs_capacity; // Number of rows the sign buffer can hold
+  int busy;           // Searches currently reading the rows without the GVL
+} embedding_matrix_t;
+
+// Callback for freeing the matrix and its row buffer
+static void embedding_matrix_free(void *ptr) {
+  if (ptr) {
+    embedding_matrix_t *m = (embedding_matrix_t *)ptr;
+    xfree(m->values);
+    xfree(m->signs);
+    xfree(m);
+  }
+}
+
+// Callback to report memory usage of the matrix to Ruby's GC
+static size_t embedding_matrix_memsize(const void *ptr) {
+  const embedding_matrix_t *m = (const embedding_matrix_t *)ptr;
+  if (!m) return 0;
+
+  size_t words = rag_binary_words(m->dim);
+  return sizeof(embedding_matrix_t) +
+         m->capacity * (size_t)m->dim * sizeof(float) +
+         m->signs_capacity * words * sizeof(uint64_t);
+}
+
+static const rb_data_type_t embedding_matrix_type = {
+  "RagEmbeddings/EmbeddingMatrix",
+  {0, embedding_matrix_free, embedding_matrix_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+// Make room for at least `needed` rows, doubling the capacity to amortize growth
+static void embedding_matrix_reserve(embedding_matrix_t *m, size_t needed) {
+  if (needed <= m->capacity) return;
+
+  size_t new_capacity = m->capacity ? m->capacity : 16;
+  while (new_capacity < needed) new_capacity *= 2;
+
+  m->values = xrealloc2(m->values, new_capacity, (size_t)m->dim * sizeof(float));
+  m->capacity = new_capacity;
+}
+
+// Copy a row given as a Ruby array, a packed blob or an Embedding into dim floats,
+// raising if its dimension differs. dst is only written once the row is known to be valid
+void rag_row_to_floats(VALUE row, uint32_t dim, uint8_t blob_dtype, float *dst) {
+  if (RB_TYPE_P(row, T_STRING)) {
+    long row_len = blob_dimension(row, blob_dtype);
+    if (row_len != dim) {
+      rb_raise(rb_eArgError, "Dimension mismatch: %u vs %ld", dim, row_len);
+    }
+
+    floats_from_blob(dst, RSTRING_PTR(row), dim, blob_dtype);
+  } else if (RB_TYPE_P(row, T_ARRAY)) {
+    long row_len = RARRAY_LEN(row);
+    if (row_len != dim) {
+      rb_raise(rb_eArgError, "Dimension mismatch: %u vs %ld", dim, row_len);
+    }
+
+    const VALUE *array_ptr = RARRAY_CONST_PTR(row);
+    for (uint32_t i = 0; i < dim; ++i) {
+      if (!RB_FLOAT_TYPE_P(array_ptr[i]) && !RB_INTEGER_TYPE_P(array_ptr[i])) {
+        rb_raise(rb_eTypeError, "Array element at index %u is not numeric", i);
+      }
+    }
+
+    for (uint32_t i = 0; i < dim; ++i) {
+      dst[i] = (float)NUM2DBL(array_ptr[i]);
+    }
+  } else {
+    embedding_t *emb;
+    TypedData_Get_Struct(row, embedding_t, &rag_embedding_type, emb);
+    if (emb->dim != dim) {
+      rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", dim, emb->dim);
+    }
+
+    if (emb->dtype == EMBEDDING_DTYPE_F32) {
+      memcpy(dst, emb->values, (size_t)dim * sizeof(float));
+    } else {
+      for (uint32_t i = 0; i < dim; ++i) {
+        dst[i] = embedding_get(emb, i);
+      }
+    }
+  }
+}
+
+// Append one row to the matrix, taking values from a Ruby array, a packed blob or an Embedding
+// A bad row raises before count is incremented, so the matrix is left untouched
+static void embedding_matrix_append(embedding_matrix_t *m, VALUE row) {
+  embedding_matrix_reserve(m, m->count + 1);
+  rag_row_to_floats(row, m->dim, m->blob_dtype, m->values + m->count * (size_t)m->dim);
+  m->count++;
+}
+
+// Class method: RagEmbeddings::EmbeddingMatrix.from_arrays([[1.0, 2.0, ...], ...])
+// Creates a matrix from a list of Ruby arrays (or Embedding objects) of equal dimension.
+// Also registered as from_blobs: rows given as packed strings are copied directly,
+// decoding them according to dtype: (f32 by default). Rows are always held as float32
+static VALUE embedding_matrix_from_arrays(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_rows, opts;
+  rb_scan_args(argc, argv, "1:", &rb_rows, &opts);
+
+  Check_Type(rb_rows, T_ARRAY);
+  uint8_t blob_dtype = dtype_from_opts(opts);
+
+  long row_count = RARRAY_LEN(rb_rows);
+  if (row_count == 0) {
+    rb_raise(rb_eArgError, "Cannot create matrix from empty list");
+  }
+
+  // The first row decides the dimension of the whole matrix
+  long dim = row_dimension(rb_ary_entry(rb_rows, 0), blob_dtype);
+  rag_check_dimension(dim);
+
+  embedding_matrix_t *m = ZALLOC_N(embedding_matrix_t, 1);
+  m->dim = (uint32_t)dim;
+  m->blob_dtype = blob_dtype;
+
+  // Wrap first: if a later row raises, the GC frees what we allocated so far
+  VALUE obj = TypedData_Wrap_Struct(klass, &embedding_matrix_type, m);
+
+  embedding_matrix_reserve(m, (size_t)row_count);
+  for (long r = 0; r < row_count; ++r) {
+    embedding_matrix_append(m, rb_ary_entry(rb_rows, r));
+  }
+
+  return obj;
+}
+
+// Class method: RagEmbeddings::EmbeddingMatrix.from_blob(blob, dim, dtype: :f32)
+// Creates a matrix from one packed little-endian string holding every row one after another
+// (the layout of to_blob), copying the bytes straight into the row buffer.
+// Values are not checked: rows holding NaN score NaN and rank after every other row in top_k
+static VALUE embedding_matrix_from_blob(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_blob, rb_dim, opts;
+  rb_scan_args(argc, argv, "2:", &rb_blob, &rb_dim, &opts);
+
+  StringValue(rb_blob);
+  uint8_t blob_dtype = dtype_from_opts(opts);
+
+  long dim = NUM2LONG(rb_dim);
+  rag_check_dimension(dim);
+
+  long values = blob_dimension(rb_blob, blob_dtype);
+  if (values == 0 || values % dim != 0) {
+    rb_raise(rb_eArgError, "Blob of %ld values does not hold rows of dimension %ld", values, dim);
+  }
+
+  embedding_matrix_t *m = ZALLOC_N(embedding_matrix_t, 1);
+  m->dim = (uint32_t)dim;
+  m->blob_dtype = blob_dtype;
+  VALUE obj = TypedData_Wrap_Struct(klass, &embedding_matrix_type, m);
+
+  embedding_matrix_reserve(m, (size_t)(values / dim));
+  floats_from_blob(m->values, RSTRING_PTR(rb_blob), (size_t)values, blob_dtype);
+  m->count = (size_t)(values / dim);
+
+  RB_GC_GUARD(rb_blob);
+  return obj;
+}
+
+// Instance method: matrix.to_blob
+// Returns every row as little-endian float32, one after another (see from_blob)
+static VALUE embedding_matrix_to_blob(VALUE self) {
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+
+  size_t values = m->count * (size_t)m->dim;
+  VALUE blob = rb_str_new(NULL, (long)(values * sizeof(float)));
+  rag_floats_to_le_bytes(RSTRING_PTR(blob), m->values, values);
+
+  return blob;
+}
+
+// Rows of an EmbeddingMatrix, for the indexes trained on it in the other source files
+const float *rag_matrix_rows(VALUE matrix, uint32_t *dim, size_t *count, int **busy) {
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(matrix, embedding_matrix_t, &embedding_matrix_type, m);
+
+  *dim = m->dim;
+  *count = m->count;
+  if (busy) *busy = OBJ_FROZEN(matrix) ? NULL : &m->busy;
+  return m->values;
+}
+
+// New EmbeddingMatrix of count rows, for the classes of the other source files that build one
+VALUE rag_matrix_new(uint32_t dim, size_t count, float **values) {
+  embedding_matrix_t *m = ZALLOC_N(embedding_matrix_t, 1);
+  m->dim = dim;
+  VALUE obj = TypedData_Wrap_Struct(rag_cEmbeddingMatrix, &embedding_matrix_type, m);
+
+  embedding_matrix_reserve(m, count);
+  m->count = count;
+  *values = m->values;
+  return obj;
+}
+
+// Instance method: matrix.push(array_blob_or_embedding), aliased as <<
+// Appends a row to the matrix and returns self.
+// Raises while another thread is searching the matrix, since growing it moves the rows
+static VALUE embedding_matrix_push(VALUE self, VALUE row) {
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+  rb_check_frozen(self);
+
+  if (m->busy) {
+    rb_raise(rb_eRuntimeError, "can't modify EmbeddingMatrix during a search");
+  }
+  embedding_matrix_append(m, row);
+  return self;
+}
+
+// Instance method: matrix.dim
+// Returns the dimension of the rows
+static VALUE embedding_matrix_dim(VALUE self) {
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+  return UINT2NUM(m->dim);
+}
+
+// Instance method: matrix.size
+// Returns the number of rows stored in the matrix
+static VALUE embedding_matrix_size(VALUE self) {
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+  return SIZET2NUM(m->count);
+}
+
+// Pack the sign bits of the rows appended since the last prefiltered search
+static void embedding_matrix_update_signs(embedding_matrix_t *m) {
+  if (m->signs_count == m->count) return;
+
+  size_t words = rag_binary_words(m->dim);
+  if (m->signs_capacity < m->capacity) {
+    m->signs = xrealloc2(m->signs, m->capacity, words * sizeof(uint64_t));
+    m->signs_capacity = m->capacity;
+  }
+
+  for (size_t r = m->signs_count; r < m->count; ++r) {
+    rag_binary_pack(m->values + r * (size_t)m->dim, m->dim, m->signs + r * words);
+  }
+  m->signs_count = m->count;
+}
+
+// Score of one row against the query for the given metric
+// Distances are negated so that the heap always keeps the highest scores
+static double embedding_matrix_score(const embedding_matrix_t *m, size_t r, const float *query_values, double query_norm_sq, rag_metric_t metric) {
+  const float *row = m->values + r * (size_t)m->dim;
+
+  if (metric == RAG_METRIC_COSINE) {
+    return rag_cosine_with_norm(row, query_values, query_norm_sq, m->dim);
+  }
+
+  double score = rag_metric_compute(metric, row, query_values, m->dim);
+  return rag_metric_is_distance(metric) ? -score : score;
+}
+
+// A top_k search over the matrix, run without the GVL.
+// Every worker fills its own heaps, merged into the first ones at the end
+typedef struct {
+  const embedding_matrix_t *m;
+  const float *query;
+  double query_norm_sq;
+  rag_metric_t metric;
+  const uint64_t *query_signs; // Sign bits of the query, with the binary prefilter
+  uint32_t workers;
+  rag_topk_t *heaps;          // Best k rows, one heap per worker
+  rag_topk_t *candidates;     // Binary first pass (-Hamming distance), one heap per worker, or NULL
+} matrix_search_t;
+
+// Score rows [begin, end) with the metric
+static void matrix_search_rows(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  matrix_search_t *search = (matrix_search_t *)ctx;
+
+  for (size_t r = begin; r < end; ++r) {
+    double score = embedding_matrix_score(search->m, r, search->query, search->query_norm_sq, search->metric);
+    rag_topk_push(&search->heaps[worker], score, r);
+  }
+}
+
+// First pass of a prefiltered search: rows [begin, end) by Hamming distance between sign bits
+static void matrix_search_signs(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  matrix_search_t *search = (matrix_search_t *)ctx;
+  size_t words = rag_binary_words(search->m->dim);
+
+  for (size_t r = begin; r < end; ++r) {
+    uint64_t distance = rag_hamming(search->m->signs + r * words, search->query_signs, words);
+    rag_topk_push(&search->candidates[worker], -(double)distance, r);
+  }
+}
+
+static void matrix_search_run(void *ctx, volatile int *interrupted) {
+  matrix_search_t *search = (matrix_search_t *)ctx;
+  const embedding_matrix_t *m = search->m;
+  rag_topk_t *topk = &search->heaps[0];
+
+  for (uint32_t w = 0; w < search->workers; ++w) {
+    rag_topk_clear(&search->heaps[w]);
+    if (search->candidates) rag_topk_clear(&search->candidates[w]);
+  }
+
+  if (search->candidates) {
+    rag_parallel_for(m->count, search->workers, matrix_search_signs, search, interrupted);
+    for (uint32_t w = 1; w < search->workers; ++w) {
+      rag_topk_merge(&search->candidates[0], &search->candidates[w]);
+    }
+
+    // Rescore the survivors with the full float metric
+    const rag_topk_t *candidates = &search->candidates[0];
+    for (size_t c = 0; c < candidates->size; ++c) {
+      size_t r = candidates->entries[c].index;
+      rag_topk_push(topk, embedding_matrix_score(m, r, search->query, search->query_norm_sq, search->metric), r);
+    }
+  } else {
+    rag_parallel_for(m->count, search->workers, matrix_search_rows, search, interrupted);
+    for (uint32_t w = 1; w < search->workers; ++w) {
+      rag_topk_merge(topk, &search->heaps[w]);
+    }
+  }
+}
+
+// Instance method: matrix.top_k(query_embedding, k, metric: :cosine, prefilter: nil, candidates: k * 10)
+// Returns the k rows most similar to the query as [[index, score], ...],
+// ordered from the most to the least similar.
+// With a distance metric the score is the distance, in ascending order.
+// With prefilter: :binary only the `candidates` rows closest in Hamming distance
+// between sign bits are scored with the metric, which is much cheaper on large matrices
+static VALUE embedding_matrix_top_k(int argc, VALUE *argv, VALUE self) {
+  VALUE query, rb_k, opts;
+  rb_scan_args(argc, argv, "2:", &query, &rb_k, &opts);
+
+  // metric:, prefilter: and candidates: keywords
+  VALUE kwargs[3] = {Qundef, Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[3] = {rb_intern("metric"), rb_intern("prefilter"), rb_intern("candidates")};
+    rb_get_kwargs(opts, kw_ids, 0, 3, kwargs);
+  }
+
+  rag_metric_t metric = kwargs[0] == Qundef ? RAG_METRIC_COSINE : rag_metric_from_value(kwargs[0]);
+  int is_distance = rag_metric_is_distance(metric);
+
+  int prefilter = 0;
+  if (kwargs[1] != Qundef && !NIL_P(kwargs[1])) {
+    if (rb_to_id(kwargs[1]) != rb_intern("binary")) {
+      rb_raise(rb_eArgError, "Unknown prefilter: %s", rb_id2name(rb_to_id(kwargs[1])));
+    }
+    prefilter = 1;
+  }
+
+  embedding_matrix_t *m;
+  embedding_t *q;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+  TypedData_Get_Struct(query, embedding_t, &rag_embedding_type, q);
+
+  if (q->dim != m->dim) {
+    rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", m->dim, q->dim);
+  }
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+
+  size_t k = (size_t)k_arg < m->count ? (size_t)k_arg : m->count;
+  if (k == 0) {
+    return rb_ary_new();
+  }
+
+  // Candidates kept by the binary first pass, never fewer than k
+  size_t candidate_count = k * 10;
+  if (prefilter && kwargs[2] != Qundef && !NIL_P(kwargs[2])) {
+    long candidates_arg = NUM2LONG(kwargs[2]);
+    if (candidates_arg < 0) {
+      rb_raise(rb_eArgError, "candidates must be non-negative");
+    }
+    candidate_count = (size_t)candidates_arg;
+  }
+  if (candidate_count < k) candidate_count = k;
+  if (candidate_count > m->count) candidate_count = m->count;
+
+  // Everything the search reads is prepared here, while holding the GVL.
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  uint32_t workers = rag_workers_for(m->count);
+  int use_prefilter = prefilter && candidate_count < m->count;
+  size_t per_worker = k + (use_prefilter ? candidate_count : 0);
+
+  VALUE query_buffer, heaps_buffer, entries_buffer, signs_buffer;
+  float *query_values = ALLOCV_N(float, query_buffer, m->dim);
+  rag_topk_t *heaps = ALLOCV_N(rag_topk_t, heaps_buffer, 2 * (size_t)workers);
+  rag_topk_entry_t *entries = ALLOCV_N(rag_topk_entry_t, entries_buffer, per_worker * workers);
+  uint64_t *query_signs = ALLOCV_N(uint64_t, signs_buffer, rag_binary_words(m->dim));
+
+  rag_row_to_floats(query, m->dim, m->blob_dtype, query_values);
+
+  matrix_search_t search = {m, query_values, 0.0, metric, NULL, workers, heaps, NULL};
+  if (metric == RAG_METRIC_COSINE) {
+    search.query_norm_sq = rag_sum_squares(query_values, m->dim);
+  }
+
+  for (uint32_t w = 0; w < workers; ++w) {
+    rag_topk_init_with(&heaps[w], k, entries + w * per_worker);
+  }
+
+  if (use_prefilter) {
+    embedding_matrix_update_signs(m);
+    rag_binary_pack(query_values, m->dim, query_signs);
+    search.query_signs = query_signs;
+
+    search.candidates = heaps + workers;
+    for (uint32_t w = 0; w < workers; ++w) {
+      rag_topk_init_with(&search.candidates[w], candidate_count, entries + w * per_worker + k);
+    }
+  }
+
+  rag_without_gvl(m->count * (size_t)m->dim, matrix_search_run, &search, OBJ_FROZEN(self) ? NULL : &m->busy);
+
+  rag_topk_sort(&heaps[0]);
+  VALUE result = rag_topk_to_ary(&heaps[0], is_distance);
+
+  ALLOCV_END(query_buffer);
+  ALLOCV_END(heaps_buffer);
+  ALLOCV_END(entries_buffer);
+  ALLOCV_END(signs_buffer);
+
+  RB_GC_GUARD(self);
+  return result;
+}
+
+// Maximal marginal relevance selection, run without the GVL.
+// Rows are picked one at a time, each maximizing
+// lambda * cos(query, row) - (1 - lambda) * max cos(row, picked rows),
+// which trades relevance to the query for diversity among the results.
+// The selection starts from the row most similar to the query
+typedef struct {
+  const embedding_matrix_t *m;
+  const float *query;
+  double lambda;
+  size_t k;
+  double *relevance;    // Cosine of each row with the query
+  double *redundancy;   // Highest cosine of each row with the rows picked so far
+  double *norms;        // Norm of each row
+  size_t *picked;       // k rows in the order they were picked
+} matrix_mmr_t;
+
+static void matrix_mmr_run(void *ctx, volatile int *interrupted) {
+  matrix_mmr_t *mmr = (matrix_mmr_t *)ctx;
+  const embedding_matrix_t *m = mmr->m;
+  double query_norm = sqrt(rag_sum_squares(mmr->query, m->dim));
+
+  for (size_t r = 0; r < m->count; ++r) {
+    const float *row = m->values + r * m->dim;
+    mmr->norms[r] = sqrt(rag_sum_squares(row, m->dim));
+    mmr->relevance[r] = rag_cosine_with_norms(mmr->query, row, query_norm, mmr->norms[r], m->dim);
+    mmr->redundancy[r] = -INFINITY;
+  }
+
+  for (size_t i = 0; i < mmr->k && !*interrupted; ++i) {
+    // Picked rows are marked with a redundancy of +infinity; equal scores go to the lowest index
+    size_t best = m->count;
+    double best_score = -INFINITY;
+    for (size_t r = 0; r < m->count; ++r) {
+      if (mmr->redundancy[r] == INFINITY) continue;
+
+      // The first pick is always the most relevant row, whatever lambda (with lambda 0
+      // every row would otherwise score 0)
+      double score = i == 0 ? mmr->relevance[r]
+                            : mmr->lambda * mmr->relevance[r] - (1.0 - mmr->lambda) * mmr->redundancy[r];
+      if (best == m->count || score > best_score) {
+        best = r;
+        best_score = score;
+      }
+    }
+
+    mmr->picked[i] = best;
+    mmr->redundancy[best] = INFINITY;
+
+    const float *chosen = m->values + best * m->dim;
+    for (size_t r = 0; r < m->count; ++r) {
+      if (mmr->redundancy[r] == INFINITY) continue;
+
+      double similarity = rag_cosine_with_norms(m->values + r * m->dim, chosen, mmr->norms[r], mmr->norms[best], m->dim);
+      if (similarity > mmr->redundancy[r]) mmr->redundancy[r] = similarity;
+    }
+  }
+}
+
+// Instance method: matrix.mmr(query_embedding, k, lambda: 0.5)
+// Picks k rows by maximal marginal relevance: each one is the most similar to the query once
+// its similarity to the rows already picked is discounted. lambda: 1.0 is a plain cosine top-k,
+// lower values favour diverse rows. Returns [[index, cosine similarity to the query], ...]
+// in the order the rows were picked. Meant for a shortlist of candidates (see Database#mmr_search):
+// the cost grows with k * rows
+static VALUE embedding_matrix_mmr(int argc, VALUE *argv, VALUE self) {
+  VALUE query, rb_k, opts;
+  rb_scan_args(argc, argv, "2:", &query, &rb_k, &opts);
+
+  double lambda = 0.5;
+  if (!NIL_P(opts)) {
+    ID kw = rb_intern("lambda");
+    VALUE rb_lambda;
+    rb_get_kwargs(opts, &kw, 0, 1, &rb_lambda);
+    if (rb_lambda != Qundef) lambda = NUM2DBL(rb_lambda);
+  }
+  if (!(lambda >= 0.0 && lambda <= 1.0)) {
+    rb_raise(rb_eArgError, "lambda must be between 0 and 1");
+  }
+
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  VALUE query_buffer, scores_buffer, picked_buffer;
+  float *query_values = ALLOCV_N(float, query_buffer, m->dim);
+  rag_row_to_floats(query, m->dim, m->blob_dtype, query_values);
+
+  size_t k = (size_t)k_arg < m->count ? (size_t)k_arg : m->count;
+  if (k == 0) {
+    ALLOCV_END(query_buffer);
+    return rb_ary_new();
+  }
+
+  double *scores = ALLOCV_N(double, scores_buffer, 3 * m->count);
+  size_t *picked = ALLOCV_N(size_t, picked_buffer, k);
+
+  matrix_mmr_t mmr = {m, query_values, lambda, k, scores, scores + m->count, scores + 2 * m->count, picked};
+  rag_without_gvl((k + 1) * m->count * (size_t)m->dim, matrix_mmr_run, &mmr, OBJ_FROZEN(self) ? NULL : &m->busy);
+
+  VALUE result = rb_ary_new_capa((long)k);
+  for (size_t i = 0; i < k; ++i) {
+    rb_ary_store(result, (long)i, rb_assoc_new(SIZET2NUM(picked[i]), DBL2NUM(mmr.relevance[picked[i]])));
+  }
+
+  ALLOCV_END(query_buffer);
+  ALLOCV_END(scores_buffer);
+  ALLOCV_END(picked_buffer);
+
+  RB_GC_GUARD(self);
+  return result;
+}
+
+// Rows per block of the all-pairs comparison in near_duplicates:
+// a block of rows is compared with every later block while both stay in cache
+#define MATRIX_DUPLICATE_BLOCK 64
+
+// Near-duplicate detection over every pair of rows, run without the GVL.
+// Pairs whose cosine reaches the threshold are joined in a union-find forest, one per worker,
+// merged at the end: the resulting clusters don't depend on the number of threads
+typedef struct {
+  const embedding_matrix_t *m;
+  double threshold;
+  uint32_t workers;
+  size_t blocks;        // Number of blocks of MATRIX_DUPLICATE_BLOCK rows
+  double *norms;        // Norm of each row
+  size_t *parents;      // count parents per worker
+} matrix_duplicates_t;
+
+// Root of a row in a union-find forest, halving the path on the way
+static size_t matrix_duplicates_find(size_t *parents, size_t r) {
+  while (parents[r] != r) {
+    parents[r] = parents[parents[r]];
+    r = parents[r];
+  }
+  return r;
+}
+
+// Join the clusters of two rows under the lowest root
+static void matrix_duplicates_union(size_t *parents, size_t a, size_t b) {
+  a = matrix_duplicates_find(parents, a);
+  b = matrix_duplicates_find(parents, b);
+  if (a < b) {
+    parents[b] = a;
+  } else if (b < a) {
+    parents[a] = b;
+  }
+}
+
+static void matrix_duplicates_norms(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  matrix_duplicates_t *dup = (matrix_duplicates_t *)ctx;
+  const embedding_matrix_t *m = dup->m;
+
+  for (size_t r = begin; r < end; ++r) {
+    dup->norms[r] = sqrt(rag_sum_squares(m->values + r * m->dim, m->dim));
+  }
+}
+
+// Compare the rows of one block with themselves and with every later block
+static void matrix_duplicates_block(matrix_duplicates_t *dup, size_t block, size_t *parents) {
+  const embedding_matrix_t *m = dup->m;
+  size_t begin = block * MATRIX_DUPLICATE_BLOCK;
+  size_t end = begin + MATRIX_DUPLICATE_BLOCK < m->count ? begin + MATRIX_DUPLICATE_BLOCK : m->count;
+
+  for (size_t other = begin; other < m->count; other += MATRIX_DUPLICATE_BLOCK) {
+    size_t other_end = other + MATRIX_DUPLICATE_BLOCK < m->count ? other + MATRIX_DUPLICATE_BLOCK : m->count;
+
+    for (size_t i = begin; i < end; ++i) {
+      if (dup->norms[i] == 0.0) continue;
+      const float *row = m->values + i * m->dim;
+
+      for (size_t j = other == begin ? i + 1 : other; j < other_end; ++j) {
+        if (dup->norms[j] == 0.0) continue;
+
+        double similarity = rag_dot(row, m->values + j * m->dim, m->dim) / (dup->norms[i] * dup->norms[j]);
+        if (similarity >= dup->threshold) matrix_duplicates_union(parents, i, j);
+      }
+    }
+  }
+}
+
+// Item t takes block t and block (blocks - 1 - t): the first blocks have the most later blocks
+// to compare with, so pairing them with the last ones evens out the work of contiguous ranges
+static void matrix_duplicates_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  matrix_duplicates_t *dup = (matrix_duplicates_t *)ctx;
+  size_t *parents = dup->parents + worker * dup->m->count;
+
+  for (size_t t = begin; t < end; ++t) {
+    matrix_duplicates_block(dup, t, parents);
+    if (dup->blocks - 1 - t != t) matrix_duplicates_block(dup, dup->blocks - 1 - t, parents);
+  }
+}
+
+static void matrix_duplicates_run(void *ctx, volatile int *interrupted) {
+  matrix_duplicates_t *dup = (matrix_duplicates_t *)ctx;
+  size_t count = dup->m->count;
+
+  for (size_t i = 0; i < count * dup->workers; ++i) {
+    dup->parents[i] = i % count;
+  }
+
+  rag_parallel_for(count, dup->workers, matrix_duplicates_norms, dup, interrupted);
+  rag_parallel_for((dup->blocks + 1) / 2, dup->workers, matrix_duplicates_range, dup, interrupted);
+
+  // Fold the forests of the other workers into the first one
+  for (uint32_t w = 1; w < dup->workers; ++w) {
+    size_t *parents = dup->parents + w * count;
+    for (size_t r = 0; r < count; ++r) {
+      if (parents[r] != r) matrix_duplicates_union(dup->parents, r, parents[r]);
+    }
+  }
+}
+
+// Instance method: matrix.near_duplicates(0.95)
+// Groups the rows whose cosine similarity with another row of the group reaches the threshold
+// (directly or through other rows: clusters are connected components).
+// Returns [[index, index, ...], ...], every cluster sorted and holding at least two rows,
+// ordered by their first index. Rows of norm zero are never duplicates.
+// Every pair is compared, block by block: the cost grows with rows * rows
+static VALUE embedding_matrix_near_duplicates(VALUE self, VALUE rb_threshold) {
+  double threshold = NUM2DBL(rb_threshold);
+  if (!(threshold >= -1.0 && threshold <= 1.0)) {
+    rb_raise(rb_eArgError, "threshold must be between -1 and 1");
+  }
+
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+
+  size_t blocks = (m->count + MATRIX_DUPLICATE_BLOCK - 1) / MATRIX_DUPLICATE_BLOCK;
+  uint32_t workers = rag_workers_for(m->count);
+  if (workers > (blocks + 1) / 2) workers = (uint32_t)((blocks + 1) / 2);
+  if (workers < 1) workers = 1;
+
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  VALUE norms_buffer, parents_buffer;
+  double *norms = ALLOCV_N(double, norms_buffer, m->count);
+  size_t *parents = ALLOCV_N(size_t, parents_buffer, m->count * (size_t)workers);
+
+  matrix_duplicates_t dup = {m, threshold, workers, blocks, norms, parents};
+  rag_without_gvl(m->count * m->count / 2 * (size_t)m->dim, matrix_duplicates_run, &dup, OBJ_FROZEN(self) ? NULL : &m->busy);
+
+  // Flag the roots (the lowest row of each cluster) that have other rows
+  VALUE flags_buffer;
+  uint8_t *has_members = ALLOCV_N(uint8_t, flags_buffer, m->count);
+  memset(has_members, 0, m->count);
+  for (size_t r = 0; r < m->count; ++r) {
+    size_t root = matrix_duplicates_find(parents, r);
+    if (root != r) has_members[root] = 1;
+  }
+
+  // Rows are visited in order: a cluster is created when its root is reached, so clusters
+  // come ordered by their first index, and each one is filled in ascending order
+  VALUE clusters = rb_hash_new();
+  VALUE result = rb_ary_new();
+  for (size_t r = 0; r < m->count; ++r) {
+    size_t root = matrix_duplicates_find(parents, r);
+    if (root == r) {
+      if (!has_members[r]) continue;
+
+      VALUE cluster = rb_ary_new_from_args(1, SIZET2NUM(r));
+      rb_hash_aset(clusters, SIZET2NUM(r), cluster);
+      rb_ary_push(result, cluster);
+    } else {
+      rb_ary_push(rb_hash_lookup(clusters, SIZET2NUM(root)), SIZET2NUM(r));
+    }
+  }
+
+  ALLOCV_END(norms_buffer);
+  ALLOCV_END(parents_buffer);
+  ALLOCV_END(flags_buffer);
+
+  RB_GC_GUARD(self);
+  return result;
+}
+
+// Instance method: matrix.freeze
+// Packs the sign bits of the binary prefilter up front, so that a frozen matrix
+// (e.g. shared with Ractor.make_shareable) never writes to itself during a search
+static VALUE embedding_matrix_freeze(VALUE self) {
+  embedding_matrix_t *m;
+  TypedData_Get_Struct(self, embedding_matrix_t, &embedding_matrix_type, m);
+
+  if (!OBJ_FROZEN(self)) embedding_matrix_update_signs(m);
+  return rb_call_super(0, NULL);
+}
+
+// Process-wide settings (max_dim, threads) can only change from the main Ractor
+void rag_check_main_ractor(const char *setting) {
+  if (!rb_ractor_main_p()) {
+    rb_raise(rb_path2class("Ractor::IsolationError"), "%s can only be changed from the main Ractor", setting);
+  }
+}
+
+// Read an optional positive integer keyword (Qundef or nil give the fallback)
+uint32_t rag_uint_option(VALUE value, uint32_t fallback, const char *name) {
+  if (value == Qundef || NIL_P(value)) return fallback;
+
+  long number = NUM2LONG(value);
+  if (number <= 0 || (unsigned long)number > UINT32_MAX) {
+    rb_raise(rb_eArgError, "%s must be a positive integer", name);
+  }
+  return (uint32_t)number;
+}
+
+// Class method: RagEmbeddings::Embedding.simd_backend
+// Returns the distance kernel in use, e.g. :avx2 (or :scalar when no SIMD is available)
+static VALUE embedding_simd_backend(VALUE klass) {
+  return ID2SYM(rb_intern(rag_simd_backend()));
+}
+
+// Class method: RagEmbeddings::Embedding.max_dim
+// Returns the largest dimension accepted when building embeddings
+static VALUE embedding_get_max_dim(VALUE klass) {
+  return UINT2NUM(embedding_max_dim);
+}
+
+// Class method: RagEmbeddings::Embedding.max_dim = 4_000_000
+// Changes the max-dimension guard (it applies to EmbeddingMatrix too)
+static VALUE embedding_set_max_dim(VALUE klass, VALUE rb_max) {
+  rag_check_main_ractor("max_dim");
+  long max = NUM2LONG(rb_max);
+
+  if (max <= 0 || (unsigned long)max > UINT32_MAX) {
+    rb_raise(rb_eArgError, "max_dim must be between 1 and %u", UINT32_MAX);
+  }
+
+  embedding_max_dim = (uint32_t)max;
+  return rb_max;
+}
+
+// Ruby extension initialization function
+// This function is called when the extension is loaded
+void Init_embedding(void) {
+  // Methods may be called from any Ractor: embeddings, matrices and indexes hold no Ruby objects,
+  // and frozen ones are never written to
+  rb_ext_ractor_safe(true);
+
+  // Pick the fastest distance kernels supported by this CPU
+  rag_simd_init();
+
+  // Define module and class
+  VALUE mRag = rb_define_module("RagEmbeddings");
+  VALUE cEmbedding = rb_define_class_under(mRag, "Embedding", rb_cObject);
+  rb_include_module(cEmbedding, rb_mEnumerable);  // map, min, max, sum... through each
+  rag_cEmbedding = cEmbedding;
+
+  // IMPORTANT: Replace the default allocator to prevent the warning
+  // This is necessary when using TypedData_Wrap_Struct.
+  // The allocator only serves dup and clone: new and allocate are removed
+  rb_define_alloc_func(cEmbedding, embedding_allocate);
+  rb_undef_method(rb_singleton_class(cEmbedding), "new");
+  rb_undef_method(rb_singleton_class(cEmbedding), "allocate");
+
+  // Register class methods
+  rb_define_singleton_method(cEmbedding, "from_array", embedding_from_array, -1);
+  rb_define_singleton_method(cEmbedding, "from_blob", embedding_from_blob, -1);
+  rb_define_singleton_method(cEmbedding, "from_float32_blob", embedding_from_float32_blob, -1);
+  rb_define_singleton_method(cEmbedding, "simd_backend", embedding_simd_backend, 0);
+  rb_define_singleton_method(cEmbedding, "max_dim", embedding_get_max_dim, 0);
+  rb_define_singleton_method(cEmbedding, "max_dim=", embedding_set_max_dim, 1);
+  rb_define_singleton_method(cEmbedding, "mean", embedding_mean, 1);
+  rb_define_singleton_method(cEmbedding, "weighted_mean", embedding_weighted_mean, 2);
+  rb_define_singleton_method(cEmbedding, "_load", embedding_load, 1);
+
+  // Register instance methods
+  rb_define_method(cEmbedding, "dim", embedding_dim, 0);
+  rb_define_method(cEmbedding, "to_a", embedding_to_a, 0);
+  rb_define_alias(cEmbedding, "size", "dim");
+  rb_define_method(cEmbedding, "[]", embedding_aref, -1);
+  rb_define_method(cEmbedding, "slice", embedding_slice, -1);
+  rb_define_method(cEmbedding, "each", embedding_each, 0);
+  rb_define_method(cEmbedding, "inspect", embedding_inspect, 0);
+  rb_define_method(cEmbedding, "to_blob", embedding_to_blob, -1);
+  rb_define_method(cEmbedding, "dtype", embedding_dtype, 0);
+  rb_define_method(cEmbedding, "cosine_similarity", embedding_cosine_similarity, 1);
+  rb_define_method(cEmbedding, "dot_product", embedding_dot_product, 1);
+  rb_define_method(cEmbedding, "euclidean_distance", embedding_euclidean_distance, 1);
+  rb_define_method(cEmbedding, "squared_euclidean_distance", embedding_squared_euclidean_distance, 1);
+  rb_define_method(cEmbedding, "manhattan_distance", embedding_manhattan_distance, 1);
+  rb_define_method(cEmbedding, "chebyshev_distance", embedding_chebyshev_distance, 1);
+  rb_define_method(cEmbedding, "angular_distance", embedding_angular_distance, 1);
+  rb_define_method(cEmbedding, "similarity", embedding_similarity, -1);
+  rb_define_method(cEmbedding, "finite?", embedding_finite_p, 0);
+  rb_define_method(cEmbedding, "magnitude", embedding_magnitude, 0);
+  rb_define_method(cEmbedding, "normalized?", embedding_normalized_p, 0);
+  rb_define_method(cEmbedding, "freeze", embedding_freeze, 0);
+  rb_define_method(cEmbedding, "normalize!", embedding_normalize_bang, 0);
+  rb_define_method(cEmbedding, "normalize", embedding_normalize, 0);
+  rb_define_method(cEmbedding, "truncate", embedding_truncate, -1);
+  rb_define_method(cEmbedding, "+", embedding_add, 1);
+  rb_define_method(cEmbedding, "-", embedding_subtract, 1);
+  rb_define_method(cEmbedding, "*", embedding_multiply, 1);
+  rb_define_alias(cEmbedding, "dot", "dot_product");
+  rb_define_method(cEmbedding, "initialize_copy", embedding_initialize_copy, 1);
+  rb_define_method(cEmbedding, "_dump", embedding_dump, 1);
+  rb_define_method(cEmbedding, "==", embedding_equal, 1);
+  rb_define_alias(cEmbedding, "eql?", "==");
+  rb_define_method(cEmbedding, "hash", embedding_hash, 0);
+  rb_define_method(cEmbedding, "approx_equal?", embedding_approx_equal, -1);
+
+  // Contiguous matrix of embeddings for batch search
+  VALUE cMatrix = rb_define_class_under(mRag, "EmbeddingMatrix", rb_cObject);
+  rb_undef_alloc_func(cMatrix);
+  rag_cEmbeddingMatrix = cMatrix;
+
+  rb_define_singleton_method(cMatrix, "from_arrays", embedding_matrix_from_arrays, -1);
+  rb_define_singleton_method(cMatrix, "from_blobs", embedding_matrix_from_arrays, -1);
+  rb_define_singleton_method(cMatrix, "from_blob", embedding_matrix_from_blob, -1);
+
+  rb_define_method(cMatrix, "push", embedding_matrix_push, 1);
+  rb_define_alias(cMatrix, "<<", "push");
+  rb_define_method(cMatrix, "dim", embedding_matrix_dim, 0);
+  rb_define_method(cMatrix, "size", embedding_matrix_size, 0);
+  rb_define_method(cMatrix, "to_blob", embedding_matrix_to_blob, 0);
+  rb_define_method(cMatrix, "top_k", embedding_matrix_top_k, -1);
+  rb_define_method(cMatrix, "mmr", embedding_matrix_mmr, -1);
+  rb_define_method(cMatrix, "near_duplicates", embedding_matrix_near_duplicates, 1);
+  rb_define_method(cMatrix, "freeze", embedding_matrix_freeze, 0);
+
+  // Classes defined in the other source files
+  rag_init_quantized(mRag);
+  rag_init_binary(mRag);
+  rag_init_pq(mRag);
+  rag_init_hnsw(mRag);
+  rag_init_ivf(mRag);
+  rag_init_clustering(mRag);
+  rag_init_parallel(mRag);
+  rag_init_projection(mRag);
+  rag_init_lsh(mRag);
 }
\ No newline at end of file
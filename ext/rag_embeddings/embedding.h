@@ -0,0 +1,117 @@
+#ifndef RAG_EMBEDDINGS_EMBEDDING_H
+#define RAG_EMBEDDINGS_EMBEDDING_H
+
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For integer types like uint32_t
+#include "half.h"     // float16 and bfloat16 conversions
+#include "metrics.h"  // rag_metric_t
+
+// Storage type of the values of an embedding
+typedef enum {
+  EMBEDDING_DTYPE_F32 = 0,   // 32-bit float
+  EMBEDDING_DTYPE_F16 = 1,   // IEEE half precision
+  EMBEDDING_DTYPE_BF16 = 2   // bfloat16
+} embedding_dtype_t;
+
+// Main data structure for storing embeddings
+// Flexible array member (values[]) allows variable length arrays
+typedef struct {
+  uint32_t dim;       // Dimension of the embedding vector
+  uint8_t dtype;      // embedding_dtype_t of the stored values
+  uint8_t normalized; // Unit length within the precision of dtype, valid once norm is cached
+  double norm;        // L2 norm, cached on first use; negative until then and after normalize!
+  float values[];     // Flexible array member to store the actual values
+                      // (for f16/bf16 the same buffer holds dim 16-bit codes)
+} embedding_t;
+
+// View of the 16-bit codes of a f16/bf16 embedding
+#define EMBEDDING_HALVES(emb) ((uint16_t *)(emb)->values)
+
+// Bytes used by one value of the given type
+static inline size_t dtype_size(uint8_t dtype) {
+  return dtype == EMBEDDING_DTYPE_F32 ? sizeof(float) : sizeof(uint16_t);
+}
+
+// Read value i of an embedding as a float, whatever its storage type
+static inline float embedding_get(const embedding_t *emb, size_t i) {
+  switch (emb->dtype) {
+    case EMBEDDING_DTYPE_F16:  return rag_f16_to_f32(EMBEDDING_HALVES(emb)[i]);
+    case EMBEDDING_DTYPE_BF16: return rag_bf16_to_f32(EMBEDDING_HALVES(emb)[i]);
+    default:                   return emb->values[i];
+  }
+}
+
+// Store value i of an embedding, converting it to the storage type
+static inline void embedding_set(embedding_t *emb, size_t i, float value) {
+  switch (emb->dtype) {
+    case EMBEDDING_DTYPE_F16:  EMBEDDING_HALVES(emb)[i] = rag_f32_to_f16(value); break;
+    case EMBEDDING_DTYPE_BF16: EMBEDDING_HALVES(emb)[i] = rag_f32_to_bf16(value); break;
+    default:                   emb->values[i] = value; break;
+  }
+}
+
+// Type information of RagEmbeddings::Embedding, to unwrap embeddings from other source files
+extern const rb_data_type_t rag_embedding_type;
+
+// Values of an embedding as float32, ready for the distance kernels.
+// f32 embeddings are returned as they are; f16/bf16 ones are decoded into
+// *scratch, which the caller releases with xfree (it stays NULL when unused)
+const float *rag_embedding_floats(const embedding_t *emb, float **scratch);
+
+// Allocate an embedding of the given dimension and storage type (values left uninitialized)
+embedding_t *rag_embedding_alloc(uint32_t dim, uint8_t dtype);
+
+// Raise if a requested dimension is empty or above the configured maximum
+void rag_check_dimension(long dim);
+
+// Copy n float32 values between floats and a little-endian byte buffer
+void rag_floats_from_le_bytes(float *dst, const char *src, size_t n);
+void rag_floats_to_le_bytes(char *dst, const float *src, size_t n);
+
+// Read the optional metric: keyword of a method, defaulting to cosine
+rag_metric_t rag_metric_from_opts(VALUE opts);
+
+// Convert a metric name (:cosine, "euclidean", ...) to its C enum
+rag_metric_t rag_metric_from_value(VALUE rb_metric);
+
+// Name of a metric as a Symbol
+VALUE rag_metric_to_sym(rag_metric_t metric);
+
+// Read an optional positive integer keyword (Qundef or nil give the fallback)
+uint32_t rag_uint_option(VALUE value, uint32_t fallback, const char *name);
+
+// Copy a row given as a Ruby array, a packed blob (of blob_dtype) or an Embedding into dim floats.
+// Raises ArgumentError on a dimension mismatch and TypeError on non-numeric values
+void rag_row_to_floats(VALUE row, uint32_t dim, uint8_t blob_dtype, float *dst);
+
+// Rows of a RagEmbeddings::EmbeddingMatrix: count * dim floats, row-major.
+// The pointer is only valid until the matrix grows.
+// busy, when not NULL, receives the counter to pass to rag_without_gvl while the rows are read
+const float *rag_matrix_rows(VALUE matrix, uint32_t *dim, size_t *count, int **busy);
+
+// New RagEmbeddings::EmbeddingMatrix of count rows of dim floats.
+// *values receives the row buffer, left uninitialized for the caller to fill
+VALUE rag_matrix_new(uint32_t dim, size_t count, float **values);
+
+// Raise Ractor::IsolationError unless called from the main Ractor (for process-wide settings)
+void rag_check_main_ractor(const char *setting);
+
+// RagEmbeddings::Embedding and RagEmbeddings::EmbeddingMatrix, set by Init_embedding
+extern VALUE rag_cEmbedding;
+extern VALUE rag_cEmbeddingMatrix;
+
+// Registration of the classes defined in the other source files
+void rag_init_quantized(VALUE mRag);
+void rag_init_binary(VALUE mRag);
+void rag_init_pq(VALUE mRag);
+void rag_init_hnsw(VALUE mRag);
+void rag_init_ivf(VALUE mRag);
+void rag_init_clustering(VALUE mRag);
+void rag_init_parallel(VALUE mRag);
+void rag_init_projection(VALUE mRag);
+void rag_init_lsh(VALUE mRag);
+
+// Embedding#binarize, shared with Embedding#quantize(:binary)
+VALUE rag_embedding_binarize(VALUE self);
+
+#endif
@@ -1,2 +1,6 @@
 require "mkmf"
-create_makefile("rag_embeddings/embedding")
\ No newline at end of file
+
+# Native threads for parallel scans (RagEmbeddings.threads); without them the work runs on one thread
+have_header("pthread.h")
+
+create_makefile("rag_embeddings/embedding")
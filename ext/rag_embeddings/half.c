@@ -0,0 +1,101 @@
+#include <string.h>   // For memcpy
+#include "half.h"
+
+uint16_t rag_f32_to_f16(float value) {
+  uint32_t bits;
+  memcpy(&bits, &value, sizeof(bits));
+
+  uint32_t sign = (bits >> 16) & 0x8000;
+  uint32_t exponent = (bits >> 23) & 0xff;
+  uint32_t mantissa = bits & 0x7fffff;
+
+  // Infinity stays infinity, NaN stays a (quiet) NaN
+  if (exponent == 0xff) {
+    return (uint16_t)(sign | 0x7c00 | (mantissa ? 0x200 | (mantissa >> 13) : 0));
+  }
+
+  int32_t half_exponent = (int32_t)exponent - 127 + 15;
+
+  // Too large for half precision: overflow to infinity
+  if (half_exponent >= 0x1f) {
+    return (uint16_t)(sign | 0x7c00);
+  }
+
+  // Too small for a normal half: produce a subnormal (or signed zero)
+  if (half_exponent <= 0) {
+    if (half_exponent < -10) {
+      return (uint16_t)sign;
+    }
+
+    mantissa |= 0x800000;  // Make the implicit leading bit explicit
+    uint32_t shift = (uint32_t)(14 - half_exponent);
+    uint32_t half_mantissa = mantissa >> shift;
+    uint32_t remainder = mantissa & ((1u << shift) - 1);
+    uint32_t halfway = 1u << (shift - 1);
+
+    if (remainder > halfway || (remainder == halfway && (half_mantissa & 1))) {
+      half_mantissa++;
+    }
+    return (uint16_t)(sign | half_mantissa);
+  }
+
+  uint32_t half = sign | ((uint32_t)half_exponent << 10) | (mantissa >> 13);
+  uint32_t remainder = mantissa & 0x1fff;
+
+  // Round to nearest even; a carry into the exponent is the correct result
+  if (remainder > 0x1000 || (remainder == 0x1000 && (half & 1))) {
+    half++;
+  }
+  return (uint16_t)half;
+}
+
+float rag_f16_to_f32(uint16_t half) {
+  uint32_t sign = (uint32_t)(half & 0x8000) << 16;
+  uint32_t exponent = (half >> 10) & 0x1f;
+  uint32_t mantissa = half & 0x3ff;
+  uint32_t bits;
+
+  if (exponent == 0) {
+    if (mantissa == 0) {
+      bits = sign;  // Signed zero
+    } else {
+      // Subnormal half: shift until the leading bit becomes implicit
+      exponent = 127 - 15 + 1;
+      while (!(mantissa & 0x400)) {
+        mantissa <<= 1;
+        exponent--;
+      }
+      mantissa &= 0x3ff;
+      bits = sign | (exponent << 23) | (mantissa << 13);
+    }
+  } else if (exponent == 0x1f) {
+    bits = sign | 0x7f800000 | (mantissa << 13);  // Infinity or NaN
+  } else {
+    bits = sign | ((exponent + 127 - 15) << 23) | (mantissa << 13);
+  }
+
+  float value;
+  memcpy(&value, &bits, sizeof(value));
+  return value;
+}
+
+uint16_t rag_f32_to_bf16(float value) {
+  uint32_t bits;
+  memcpy(&bits, &value, sizeof(bits));
+
+  // Keep NaN a NaN: rounding could otherwise turn it into infinity
+  if ((bits & 0x7fffffff) > 0x7f800000) {
+    return (uint16_t)((bits >> 16) | 0x40);
+  }
+
+  // Round to nearest even on the 16 bits being dropped
+  uint32_t rounding = 0x7fff + ((bits >> 16) & 1);
+  return (uint16_t)((bits + rounding) >> 16);
+}
+
+float rag_bf16_to_f32(uint16_t half) {
+  uint32_t bits = (uint32_t)half << 16;
+  float value;
+  memcpy(&value, &bits, sizeof(value));
+  return value;
+}
@@ -0,0 +1,17 @@
+#ifndef RAG_EMBEDDINGS_HALF_H
+#define RAG_EMBEDDINGS_HALF_H
+
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For uint16_t
+
+// Conversions between float32 and the 16-bit storage types
+// IEEE 754 half precision (f16): 1 sign, 5 exponent, 10 mantissa bits
+// bfloat16 (bf16): the upper half of a float32, 1 sign, 8 exponent, 7 mantissa bits
+// Both round to nearest even and keep infinities and NaN
+
+uint16_t rag_f32_to_f16(float value);
+float rag_f16_to_f32(uint16_t half);
+uint16_t rag_f32_to_bf16(float value);
+float rag_bf16_to_f32(uint16_t half);
+
+#endif
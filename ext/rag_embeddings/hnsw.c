@@ -0,0 +1,862 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For uint32_t, int64_t
+#include <stdlib.h>   // For qsort
+#include <math.h>     // For sqrt, log
+#include <string.h>   // For memcpy, memset
+#include "embedding.h"
+#include "simd.h"
+#include "topk.h"
+#include "kmeans.h"
+#include "serialize.h"
+#include "parallel.h"
+
+// Hierarchical Navigable Small World graph (Malkov & Yashunin)
+// Every node lives on level 0 and, with exponentially decreasing probability, on the levels above.
+// Each level is a proximity graph with at most m links per node (2 * m on level 0).
+// A search descends greedily from the entry point on the top level, then runs a best-first
+// search with a dynamic list of ef candidates on level 0.
+// Deleted nodes are only marked: they keep routing searches but never show up in results
+typedef struct {
+  uint32_t dim;              // Dimension of the indexed vectors
+  uint32_t m;                // Max links per node on the upper levels
+  uint32_t m0;               // Max links per node on level 0 (2 * m)
+  uint32_t ef_construction;  // Candidate list size while inserting
+  uint32_t ef_search;        // Default candidate list size while searching
+  uint8_t metric;            // rag_metric_t: cosine, euclidean or squared_euclidean
+  size_t count;              // Nodes in the graph, deleted ones included
+  size_t capacity;           // Nodes the buffers can hold before growing
+  size_t deleted_count;      // Nodes marked as deleted
+  float *vectors;            // count * dim floats (unit length for cosine)
+  int64_t *ids;              // Id of each node (the Database row id)
+  uint8_t *deleted;          // Non-zero for deleted nodes
+  uint8_t *levels;           // Top level of each node
+  uint32_t **links;          // Link lists of each node, see hnsw_links
+  uint32_t entry;            // Entry point: a node on the top level
+  int max_level;             // Top level of the graph, -1 while empty
+  rag_rng_t rng;             // Draws the level of new nodes
+  int busy;                  // Searches and inserts currently walking the graph without the GVL
+} hnsw_t;
+
+// Magic and version of the serialized index
+#define HNSW_MAGIC "RGHN"
+#define HNSW_VERSION 1
+
+// Levels are drawn from an exponential distribution; this cap is never reached in practice
+#define HNSW_MAX_LEVEL 16
+
+static VALUE cHNSWIndex;
+
+// Words of the link lists of a node with the given top level.
+// Each list is a count followed by its slots: m0 on level 0, m on the levels above
+static size_t hnsw_links_words(const hnsw_t *h, uint32_t level) {
+  return (size_t)(h->m0 + 1) + (size_t)level * (h->m + 1);
+}
+
+// Link list of a node on a level: [0] is the number of links, then the neighbours
+static uint32_t *hnsw_links(const hnsw_t *h, uint32_t node, uint32_t level) {
+  uint32_t *base = h->links[node];
+  return level == 0 ? base : base + (h->m0 + 1) + (size_t)(level - 1) * (h->m + 1);
+}
+
+static void hnsw_free(void *ptr) {
+  if (ptr) {
+    hnsw_t *h = (hnsw_t *)ptr;
+    for (size_t i = 0; i < h->count; ++i) {
+      xfree(h->links[i]);
+    }
+    xfree(h->links);
+    xfree(h->vectors);
+    xfree(h->ids);
+    xfree(h->deleted);
+    xfree(h->levels);
+    xfree(h);
+  }
+}
+
+static size_t hnsw_memsize(const void *ptr) {
+  const hnsw_t *h = (const hnsw_t *)ptr;
+  if (!h) return 0;
+
+  size_t size = sizeof(hnsw_t) +
+                h->capacity * ((size_t)h->dim * sizeof(float) + sizeof(int64_t) + 2 + sizeof(uint32_t *));
+  for (size_t i = 0; i < h->count; ++i) {
+    size += hnsw_links_words(h, h->levels[i]) * sizeof(uint32_t);
+  }
+  return size;
+}
+
+static const rb_data_type_t hnsw_type = {
+  "RagEmbeddings/HNSWIndex",
+  {0, hnsw_free, hnsw_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+// The graph needs a true distance: cosine is searched as 1 - cos on unit vectors
+static void hnsw_check_metric(rag_metric_t metric) {
+  if (metric != RAG_METRIC_COSINE && metric != RAG_METRIC_EUCLIDEAN && metric != RAG_METRIC_SQUARED_EUCLIDEAN) {
+    rb_raise(rb_eArgError, "HNSWIndex supports only the :cosine, :euclidean and :squared_euclidean metrics");
+  }
+}
+
+static hnsw_t *hnsw_alloc(uint32_t dim, uint32_t m, uint32_t ef_construction, uint32_t ef_search, rag_metric_t metric) {
+  hnsw_t *h = ZALLOC_N(hnsw_t, 1);
+  h->dim = dim;
+  h->m = m;
+  h->m0 = 2 * m;
+  h->ef_construction = ef_construction;
+  h->ef_search = ef_search;
+  h->metric = (uint8_t)metric;
+  h->max_level = -1;
+  return h;
+}
+
+// Make room for at least `needed` nodes, doubling the capacity to amortize growth
+static void hnsw_reserve(hnsw_t *h, size_t needed) {
+  if (needed <= h->capacity) return;
+
+  size_t new_capacity = h->capacity ? h->capacity : 16;
+  while (new_capacity < needed) new_capacity *= 2;
+
+  h->vectors = xrealloc2(h->vectors, new_capacity, (size_t)h->dim * sizeof(float));
+  h->ids = xrealloc2(h->ids, new_capacity, sizeof(int64_t));
+  h->deleted = xrealloc2(h->deleted, new_capacity, 1);
+  h->levels = xrealloc2(h->levels, new_capacity, 1);
+  h->links = xrealloc2(h->links, new_capacity, sizeof(uint32_t *));
+  h->capacity = new_capacity;
+}
+
+static const float *hnsw_vector(const hnsw_t *h, uint32_t node) {
+  return h->vectors + (size_t)node * h->dim;
+}
+
+// Distance used inside the graph: 1 - cos for cosine (vectors are unit length), squared L2 otherwise
+static double hnsw_distance(const hnsw_t *h, const float *a, const float *b) {
+  if (h->metric == RAG_METRIC_COSINE) {
+    return 1.0 - rag_dot(a, b, h->dim);
+  }
+  return rag_squared_l2(a, b, h->dim);
+}
+
+// Vector as stored in the graph: unit length for cosine
+static void hnsw_prepare(const hnsw_t *h, const float *src, float *dst) {
+  memcpy(dst, src, (size_t)h->dim * sizeof(float));
+
+  if (h->metric == RAG_METRIC_COSINE) {
+    double norm = sqrt(rag_sum_squares(dst, h->dim));
+    if (norm > 0.0) rag_scale(dst, h->dim, (float)(1.0 / norm));
+  }
+}
+
+// Score reported to Ruby for an internal distance
+static double hnsw_score(const hnsw_t *h, double distance) {
+  switch (h->metric) {
+    case RAG_METRIC_COSINE:    return 1.0 - distance;
+    case RAG_METRIC_EUCLIDEAN: return sqrt(distance);
+    default:                   return distance;
+  }
+}
+
+// Binary heap of (distance, node), ordered as a min-heap or a max-heap on distance.
+// Its storage is allocated by the caller with room for every push (ALLOCV),
+// so that graph walks can run without the GVL
+typedef struct {
+  double distance;
+  uint32_t node;
+} hnsw_candidate_t;
+
+typedef struct {
+  hnsw_candidate_t *items;
+  size_t size;
+  int max_heap;       // Non-zero: the farthest candidate is on top
+} hnsw_heap_t;
+
+static void hnsw_heap_init(hnsw_heap_t *heap, hnsw_candidate_t *items, int max_heap) {
+  heap->items = items;
+  heap->size = 0;
+  heap->max_heap = max_heap;
+}
+
+// Non-zero when a must be above b
+static int hnsw_heap_before(const hnsw_heap_t *heap, const hnsw_candidate_t *a, const hnsw_candidate_t *b) {
+  return heap->max_heap ? a->distance > b->distance : a->distance < b->distance;
+}
+
+static void hnsw_heap_push(hnsw_heap_t *heap, double distance, uint32_t node) {
+  size_t i = heap->size++;
+  heap->items[i].distance = distance;
+  heap->items[i].node = node;
+
+  while (i > 0) {
+    size_t parent = (i - 1) / 2;
+    if (!hnsw_heap_before(heap, &heap->items[i], &heap->items[parent])) break;
+
+    hnsw_candidate_t tmp = heap->items[i];
+    heap->items[i] = heap->items[parent];
+    heap->items[parent] = tmp;
+    i = parent;
+  }
+}
+
+static hnsw_candidate_t hnsw_heap_pop(hnsw_heap_t *heap) {
+  hnsw_candidate_t top = heap->items[0];
+  heap->items[0] = heap->items[--heap->size];
+
+  size_t i = 0;
+  for (;;) {
+    size_t left = 2 * i + 1, right = left + 1, best = i;
+    if (left < heap->size && hnsw_heap_before(heap, &heap->items[left], &heap->items[best])) best = left;
+    if (right < heap->size && hnsw_heap_before(heap, &heap->items[right], &heap->items[best])) best = right;
+    if (best == i) break;
+
+    hnsw_candidate_t tmp = heap->items[i];
+    heap->items[i] = heap->items[best];
+    heap->items[best] = tmp;
+    i = best;
+  }
+
+  return top;
+}
+
+// Nodes already reached by a search, one bit per node (storage allocated by the caller)
+typedef struct {
+  uint64_t *bits;
+  size_t words;
+} hnsw_visited_t;
+
+// Words of the visited bits of a graph of count nodes
+static size_t hnsw_visited_words(size_t count) {
+  return count ? (count + 63) / 64 : 1;
+}
+
+static void hnsw_visited_clear(hnsw_visited_t *visited) {
+  memset(visited->bits, 0, visited->words * sizeof(uint64_t));
+}
+
+// Mark a node, returning non-zero if it was already marked
+static int hnsw_visit(hnsw_visited_t *visited, uint32_t node) {
+  uint64_t mask = 1ULL << (node % 64);
+  int seen = (visited->bits[node / 64] & mask) != 0;
+  visited->bits[node / 64] |= mask;
+  return seen;
+}
+
+// Greedy walk on one level: move to the closest neighbour until none is closer
+static uint32_t hnsw_greedy(const hnsw_t *h, const float *query, uint32_t node, uint32_t level) {
+  double best = hnsw_distance(h, query, hnsw_vector(h, node));
+
+  int changed = 1;
+  while (changed) {
+    changed = 0;
+    const uint32_t *links = hnsw_links(h, node, level);
+
+    for (uint32_t i = 1; i <= links[0]; ++i) {
+      double distance = hnsw_distance(h, query, hnsw_vector(h, links[i]));
+      if (distance < best) {
+        best = distance;
+        node = links[i];
+        changed = 1;
+      }
+    }
+  }
+
+  return node;
+}
+
+// Best-first search on one level from an entry node, keeping the ef closest nodes in
+// `results` (a max-heap, room for ef + 1). With skip_deleted, deleted nodes are walked through
+// but not returned. `candidates` is a min-heap with room for every node, each being pushed once at most
+static void hnsw_search_level(const hnsw_t *h, const float *query, uint32_t entry, uint32_t ef, uint32_t level,
+                              int skip_deleted, hnsw_visited_t *visited, hnsw_heap_t *candidates,
+                              hnsw_heap_t *results) {
+  hnsw_visited_clear(visited);
+  candidates->size = 0;
+  results->size = 0;
+
+  double entry_distance = hnsw_distance(h, query, hnsw_vector(h, entry));
+  hnsw_visit(visited, entry);
+  hnsw_heap_push(candidates, entry_distance, entry);
+  if (!skip_deleted || !h->deleted[entry]) {
+    hnsw_heap_push(results, entry_distance, entry);
+  }
+
+  while (candidates->size > 0) {
+    hnsw_candidate_t closest = hnsw_heap_pop(candidates);
+
+    // Every remaining candidate is farther than the worst result: the search has converged
+    if (results->size >= ef && closest.distance > results->items[0].distance) break;
+
+    const uint32_t *links = hnsw_links(h, closest.node, level);
+    for (uint32_t i = 1; i <= links[0]; ++i) {
+      uint32_t neighbour = links[i];
+      if (hnsw_visit(visited, neighbour)) continue;
+
+      double distance = hnsw_distance(h, query, hnsw_vector(h, neighbour));
+      if (results->size < ef || distance < results->items[0].distance) {
+        hnsw_heap_push(candidates, distance, neighbour);
+
+        if (!skip_deleted || !h->deleted[neighbour]) {
+          hnsw_heap_push(results, distance, neighbour);
+          if (results->size > ef) hnsw_heap_pop(results);
+        }
+      }
+    }
+  }
+}
+
+static int hnsw_candidate_compare(const void *a, const void *b) {
+  double da = ((const hnsw_candidate_t *)a)->distance;
+  double db = ((const hnsw_candidate_t *)b)->distance;
+  return (da > db) - (da < db);
+}
+
+// Neighbour selection heuristic: walking the candidates from the closest, keep one only if it is
+// closer to the base than to every neighbour kept so far. This spreads the links in all
+// directions instead of clustering them, which keeps the graph navigable.
+// Sorts `candidates` in place and writes at most max_links nodes to `selected`
+static uint32_t hnsw_select_neighbours(const hnsw_t *h, hnsw_candidate_t *candidates, size_t n,
+                                       uint32_t max_links, uint32_t *selected) {
+  qsort(candidates, n, sizeof(hnsw_candidate_t), hnsw_candidate_compare);
+
+  uint32_t kept = 0;
+  for (size_t i = 0; i < n && kept < max_links; ++i) {
+    const float *candidate = hnsw_vector(h, candidates[i].node);
+    int good = 1;
+
+    for (uint32_t j = 0; j < kept; ++j) {
+      if (hnsw_distance(h, candidate, hnsw_vector(h, selected[j])) < candidates[i].distance) {
+        good = 0;
+        break;
+      }
+    }
+
+    if (good) selected[kept++] = candidates[i].node;
+  }
+
+  return kept;
+}
+
+// Add a back link from `node` to `neighbour` on a level, pruning the neighbour's links
+// with the selection heuristic when they overflow
+static void hnsw_link_back(hnsw_t *h, uint32_t neighbour, uint32_t node, uint32_t level) {
+  uint32_t *links = hnsw_links(h, neighbour, level);
+  uint32_t max_links = level == 0 ? h->m0 : h->m;
+
+  if (links[0] < max_links) {
+    links[++links[0]] = node;
+    return;
+  }
+
+  // Full: choose again among the current links plus the new node
+  const float *base = hnsw_vector(h, neighbour);
+  hnsw_candidate_t *candidates = ALLOC_N(hnsw_candidate_t, max_links + 1);
+  for (uint32_t i = 0; i < links[0]; ++i) {
+    candidates[i].node = links[i + 1];
+    candidates[i].distance = hnsw_distance(h, base, hnsw_vector(h, links[i + 1]));
+  }
+  candidates[max_links].node = node;
+  candidates[max_links].distance = hnsw_distance(h, base, hnsw_vector(h, node));
+
+  links[0] = hnsw_select_neighbours(h, candidates, max_links + 1, max_links, links + 1);
+  xfree(candidates);
+}
+
+// Draw the top level of a new node: floor(-ln(U) / ln(m))
+static uint32_t hnsw_random_level(const hnsw_t *h, rag_rng_t *rng) {
+  double u = 1.0 - rag_rng_uniform(rng);   // In (0, 1]
+  double level = -log(u) / log((double)h->m);
+  return level >= HNSW_MAX_LEVEL ? HNSW_MAX_LEVEL : (uint32_t)level;
+}
+
+// Buffers of a graph walk: the visited bits, the candidates (room for every node)
+// and the results (room for ef + 1), allocated with ALLOCV by hnsw_walk_alloc
+typedef struct {
+  hnsw_visited_t visited;
+  hnsw_heap_t candidates;
+  hnsw_heap_t results;
+} hnsw_walk_t;
+
+static void hnsw_walk_alloc(hnsw_walk_t *walk, size_t count, uint32_t ef, VALUE *visited_buffer, VALUE *heaps_buffer) {
+  walk->visited.words = hnsw_visited_words(count);
+  walk->visited.bits = ALLOCV_N(uint64_t, *visited_buffer, walk->visited.words);
+
+  hnsw_candidate_t *items = ALLOCV_N(hnsw_candidate_t, *heaps_buffer, count + 1 + (size_t)ef + 1);
+  hnsw_heap_init(&walk->candidates, items, 0);
+  hnsw_heap_init(&walk->results, items + count + 1, 1);
+}
+
+// The search part of an insert, run without the GVL: the neighbours of the new node on each
+// of its levels. It only reads the graph, so that searches may run at the same time; the new node
+// is linked afterwards by hnsw_insert, holding the GVL
+typedef struct {
+  const hnsw_t *h;
+  const float *vector;        // Prepared vector of the new node
+  uint32_t level;             // Top level drawn for the new node
+  hnsw_walk_t walk;
+  uint32_t *neighbours;       // m0 slots per level, from level 0 up
+  uint32_t *neighbour_counts; // Neighbours selected on each level
+} hnsw_insert_t;
+
+static void hnsw_insert_run(void *ctx, volatile int *interrupted) {
+  hnsw_insert_t *insert = (hnsw_insert_t *)ctx;
+  const hnsw_t *h = insert->h;
+
+  // Descend greedily through the levels above the new node
+  uint32_t entry = h->entry;
+  for (int lc = h->max_level; lc > (int)insert->level; --lc) {
+    entry = hnsw_greedy(h, insert->vector, entry, (uint32_t)lc);
+  }
+
+  hnsw_heap_t *results = &insert->walk.results;
+  int top = (int)insert->level < h->max_level ? (int)insert->level : h->max_level;
+  for (int lc = top; lc >= 0 && !*interrupted; --lc) {
+    hnsw_search_level(h, insert->vector, entry, h->ef_construction, (uint32_t)lc, 0,
+                      &insert->walk.visited, &insert->walk.candidates, results);
+
+    // The closest node found is the entry point of the next level
+    uint32_t closest = results->items[0].node;
+    double closest_distance = results->items[0].distance;
+    for (size_t i = 1; i < results->size; ++i) {
+      if (results->items[i].distance < closest_distance) {
+        closest_distance = results->items[i].distance;
+        closest = results->items[i].node;
+      }
+    }
+
+    uint32_t max_links = lc == 0 ? h->m0 : h->m;
+    insert->neighbour_counts[lc] = hnsw_select_neighbours(h, results->items, results->size, max_links,
+                                                          insert->neighbours + (size_t)lc * h->m0);
+    entry = closest;
+  }
+}
+
+// Insert a prepared vector as a new node. Its neighbours are searched without the GVL
+// (busy, when not NULL, keeps other threads from modifying the graph meanwhile),
+// then the node and its links are added holding it. Searching every level before linking any
+// gives the same graph as linking level by level: each level only reads its own link lists
+static void hnsw_insert(hnsw_t *h, const float *vector, int64_t id, int *busy) {
+  rag_rng_t rng = h->rng;
+  uint32_t level = hnsw_random_level(h, &rng);
+  size_t count = h->count;
+
+  VALUE visited_buffer, heaps_buffer, neighbours_buffer;
+  hnsw_insert_t insert = {h, vector, level};
+  hnsw_walk_alloc(&insert.walk, count, h->ef_construction, &visited_buffer, &heaps_buffer);
+  insert.neighbours = ALLOCV_N(uint32_t, neighbours_buffer, ((size_t)level + 1) * (h->m0 + 1));
+  insert.neighbour_counts = insert.neighbours + ((size_t)level + 1) * h->m0;
+
+  if (h->max_level >= 0) {
+    size_t reach = count < h->ef_construction ? count : h->ef_construction;
+    rag_without_gvl(reach * h->m0 * (size_t)h->dim * (level + 1), hnsw_insert_run, &insert, busy);
+
+    // Searches started by other threads meanwhile may still be walking the graph: let them finish
+    while (h->busy) rb_thread_schedule();
+  }
+
+  hnsw_reserve(h, count + 1);
+  uint32_t node = (uint32_t)count;
+
+  memcpy(h->vectors + (size_t)node * h->dim, vector, (size_t)h->dim * sizeof(float));
+  h->ids[node] = id;
+  h->deleted[node] = 0;
+  h->levels[node] = (uint8_t)level;
+  h->links[node] = ZALLOC_N(uint32_t, hnsw_links_words(h, level));
+  h->count++;
+  h->rng = rng;
+
+  if (h->max_level < 0) {
+    h->entry = node;
+    h->max_level = (int)level;
+  } else {
+    int top = (int)level < h->max_level ? (int)level : h->max_level;
+    for (int lc = top; lc >= 0; --lc) {
+      uint32_t *links = hnsw_links(h, node, (uint32_t)lc);
+      links[0] = insert.neighbour_counts[lc];
+      memcpy(links + 1, insert.neighbours + (size_t)lc * h->m0, links[0] * sizeof(uint32_t));
+
+      for (uint32_t i = 1; i <= links[0]; ++i) {
+        hnsw_link_back(h, links[i], node, (uint32_t)lc);
+      }
+    }
+
+    if ((int)level > h->max_level) {
+      h->entry = node;
+      h->max_level = (int)level;
+    }
+  }
+
+  ALLOCV_END(visited_buffer);
+  ALLOCV_END(heaps_buffer);
+  ALLOCV_END(neighbours_buffer);
+}
+
+// A search of the ef closest live nodes to a prepared query, run without the GVL.
+// Fills a top-k heap with -distance
+typedef struct {
+  const hnsw_t *h;
+  const float *query;
+  uint32_t ef;
+  hnsw_walk_t walk;
+  rag_topk_t *topk;
+} hnsw_search_t;
+
+static void hnsw_search_run(void *ctx, volatile int *interrupted) {
+  hnsw_search_t *search = (hnsw_search_t *)ctx;
+  const hnsw_t *h = search->h;
+  (void)interrupted;
+
+  rag_topk_clear(search->topk);
+
+  uint32_t entry = h->entry;
+  for (int lc = h->max_level; lc > 0; --lc) {
+    entry = hnsw_greedy(h, search->query, entry, (uint32_t)lc);
+  }
+
+  hnsw_heap_t *results = &search->walk.results;
+  hnsw_search_level(h, search->query, entry, search->ef, 0, 1, &search->walk.visited, &search->walk.candidates,
+                    results);
+
+  for (size_t i = 0; i < results->size; ++i) {
+    rag_topk_push(search->topk, -results->items[i].distance, results->items[i].node);
+  }
+}
+
+// Class method: RagEmbeddings::HNSWIndex.create(dim, m: 16, ef_construction: 200, ef_search: 50, metric: :cosine, seed: 0)
+// Creates an empty graph for vectors of the given dimension.
+// Higher m and ef_construction give a better graph at the cost of memory and insertion time
+static VALUE hnsw_create(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_dim, opts;
+  rb_scan_args(argc, argv, "1:", &rb_dim, &opts);
+
+  VALUE kwargs[5] = {Qundef, Qundef, Qundef, Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[5] = {rb_intern("m"), rb_intern("ef_construction"), rb_intern("ef_search"),
+                    rb_intern("metric"), rb_intern("seed")};
+    rb_get_kwargs(opts, kw_ids, 0, 5, kwargs);
+  }
+
+  long dim = NUM2LONG(rb_dim);
+  rag_check_dimension(dim);
+
+  uint32_t m = rag_uint_option(kwargs[0], 16, "m");
+  uint32_t ef_construction = rag_uint_option(kwargs[1], 200, "ef_construction");
+  uint32_t ef_search = rag_uint_option(kwargs[2], 50, "ef_search");
+  rag_metric_t metric = kwargs[3] == Qundef ? RAG_METRIC_COSINE : rag_metric_from_value(kwargs[3]);
+  uint64_t seed = (kwargs[4] == Qundef || NIL_P(kwargs[4])) ? 0 : NUM2ULL(kwargs[4]);
+
+  hnsw_check_metric(metric);
+  if (m < 2 || m > UINT16_MAX) {
+    rb_raise(rb_eArgError, "m must be between 2 and %u", UINT16_MAX);
+  }
+
+  hnsw_t *h = hnsw_alloc((uint32_t)dim, m, ef_construction, ef_search, metric);
+  rag_rng_seed(&h->rng, seed);
+
+  return TypedData_Wrap_Struct(klass, &hnsw_type, h);
+}
+
+// Raise if the index is frozen, or if a search or an insert running without the GVL is walking the graph
+static void hnsw_check_modifiable(VALUE self, const hnsw_t *h) {
+  rb_check_frozen(self);
+  if (h->busy) {
+    rb_raise(rb_eRuntimeError, "can't modify HNSWIndex during a search");
+  }
+}
+
+// Instance method: index.add(id, array_blob_or_embedding)
+// Inserts a vector into the graph under the given id. Returns self.
+// The search for its neighbours runs without the GVL
+static VALUE hnsw_add(VALUE self, VALUE rb_id, VALUE row) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  hnsw_check_modifiable(self, h);
+
+  int64_t id = NUM2LL(rb_id);
+  if (h->count >= UINT32_MAX) {
+    rb_raise(rb_eArgError, "HNSWIndex is full");
+  }
+
+  // ALLOCV keeps the buffer reachable by the GC, so a bad row that raises doesn't leak it
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)h->dim);
+  float *prepared = values + h->dim;
+
+  rag_row_to_floats(row, h->dim, EMBEDDING_DTYPE_F32, values);
+  hnsw_prepare(h, values, prepared);
+  hnsw_insert(h, prepared, id, &h->busy);
+
+  ALLOCV_END(buffer);
+  return self;
+}
+
+// Instance method: index.delete(id)
+// Marks the nodes with the given id as deleted; they stay in the graph to route searches.
+// Returns true if a node was found. Lookup by id is a linear scan over the nodes
+static VALUE hnsw_delete(VALUE self, VALUE rb_id) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  hnsw_check_modifiable(self, h);
+
+  int64_t id = NUM2LL(rb_id);
+  int found = 0;
+
+  for (size_t i = 0; i < h->count; ++i) {
+    if (h->ids[i] == id && !h->deleted[i]) {
+      h->deleted[i] = 1;
+      h->deleted_count++;
+      found = 1;
+    }
+  }
+
+  return found ? Qtrue : Qfalse;
+}
+
+// Instance method: index.top_k(query_embedding, k, ef: ef_search)
+// Returns [[id, score], ...] for the k nearest live nodes, best first.
+// The score follows the metric: cosine similarity, or the (squared) euclidean distance.
+// A larger ef explores more of the graph for a better recall
+static VALUE hnsw_top_k(int argc, VALUE *argv, VALUE self) {
+  VALUE query, rb_k, opts;
+  rb_scan_args(argc, argv, "2:", &query, &rb_k, &opts);
+
+  VALUE rb_ef = Qundef;
+  if (!NIL_P(opts)) {
+    ID kw = rb_intern("ef");
+    rb_get_kwargs(opts, &kw, 0, 1, &rb_ef);
+  }
+
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+  uint32_t ef = rag_uint_option(rb_ef, h->ef_search, "ef");
+
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)h->dim);
+  float *prepared = values + h->dim;
+  rag_row_to_floats(query, h->dim, EMBEDDING_DTYPE_F32, values);
+
+  size_t live = h->count - h->deleted_count;
+  size_t k = (size_t)k_arg < live ? (size_t)k_arg : live;
+  if (k == 0) {
+    ALLOCV_END(buffer);
+    return rb_ary_new();
+  }
+  if (ef < k) ef = (uint32_t)k;
+
+  hnsw_prepare(h, values, prepared);
+
+  // The walk runs without the GVL; ALLOCV keeps its buffers reachable by the GC if an interrupt raises
+  VALUE visited_buffer, heaps_buffer, entries_buffer;
+  rag_topk_t topk;
+  rag_topk_init_with(&topk, k, ALLOCV_N(rag_topk_entry_t, entries_buffer, k));
+  hnsw_search_t search = {h, prepared, ef};
+  search.topk = &topk;
+  hnsw_walk_alloc(&search.walk, h->count, ef, &visited_buffer, &heaps_buffer);
+
+  rag_without_gvl((size_t)ef * h->m0 * h->dim, hnsw_search_run, &search, OBJ_FROZEN(self) ? NULL : &h->busy);
+  rag_topk_sort(&topk);
+
+  // Convert the negated internal distances to the reported score
+  for (size_t i = 0; i < topk.size; ++i) {
+    topk.entries[i].score = hnsw_score(h, -topk.entries[i].score);
+  }
+
+  VALUE result = rag_topk_to_id_ary(&topk, h->ids, 0);
+  ALLOCV_END(entries_buffer);
+  ALLOCV_END(visited_buffer);
+  ALLOCV_END(heaps_buffer);
+  ALLOCV_END(buffer);
+
+  return result;
+}
+
+// Instance method: index.size
+// Returns the number of live (not deleted) nodes
+static VALUE hnsw_size(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  return SIZET2NUM(h->count - h->deleted_count);
+}
+
+// Instance method: index.dim
+static VALUE hnsw_dim(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  return UINT2NUM(h->dim);
+}
+
+// Instance method: index.m
+static VALUE hnsw_m(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  return UINT2NUM(h->m);
+}
+
+// Instance method: index.ef_construction
+static VALUE hnsw_ef_construction(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  return UINT2NUM(h->ef_construction);
+}
+
+// Instance method: index.ef_search
+static VALUE hnsw_ef_search(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  return UINT2NUM(h->ef_search);
+}
+
+// Instance method: index.ef_search = 100
+// Changes the default candidate list size of top_k
+static VALUE hnsw_set_ef_search(VALUE self, VALUE rb_ef) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  rb_check_frozen(self);
+  h->ef_search = rag_uint_option(rb_ef, h->ef_search, "ef_search");
+  return rb_ef;
+}
+
+// Instance method: index.metric
+static VALUE hnsw_metric(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+  return rag_metric_to_sym((rag_metric_t)h->metric);
+}
+
+// Instance method: index.to_blob
+// Serializes the parameters, the nodes and their links (little-endian), see HNSWIndex.from_blob
+static VALUE hnsw_to_blob(VALUE self) {
+  hnsw_t *h;
+  TypedData_Get_Struct(self, hnsw_t, &hnsw_type, h);
+
+  VALUE blob = rb_str_buf_new(0);
+  rag_write_bytes(blob, HNSW_MAGIC, 4);
+  rag_write_u32(blob, HNSW_VERSION);
+  rag_write_u32(blob, h->dim);
+  rag_write_u32(blob, h->m);
+  rag_write_u32(blob, h->ef_construction);
+  rag_write_u32(blob, h->ef_search);
+  rag_write_u32(blob, h->metric);
+  rag_write_u64(blob, h->rng.state);
+  rag_write_u64(blob, h->count);
+  rag_write_u32(blob, h->entry);
+  rag_write_u32(blob, (uint32_t)(h->max_level + 1));
+
+  rag_write_floats(blob, h->vectors, h->count * (size_t)h->dim);
+  rag_write_i64s(blob, h->ids, h->count);
+  rag_write_bytes(blob, h->deleted, h->count);
+  rag_write_bytes(blob, h->levels, h->count);
+
+  for (size_t i = 0; i < h->count; ++i) {
+    for (uint32_t level = 0; level <= h->levels[i]; ++level) {
+      const uint32_t *links = hnsw_links(h, (uint32_t)i, level);
+      for (uint32_t j = 0; j <= links[0]; ++j) {
+        rag_write_u32(blob, links[j]);
+      }
+    }
+  }
+
+  return blob;
+}
+
+// Class method: RagEmbeddings::HNSWIndex.from_blob(string)
+// Restores a graph serialized with to_blob
+static VALUE hnsw_from_blob(VALUE klass, VALUE rb_blob) {
+  rag_reader_t reader;
+  rag_reader_init(&reader, rb_blob);
+  rag_read_header(&reader, HNSW_MAGIC, HNSW_VERSION);
+
+  uint32_t dim = rag_read_u32(&reader);
+  uint32_t m = rag_read_u32(&reader);
+  uint32_t ef_construction = rag_read_u32(&reader);
+  uint32_t ef_search = rag_read_u32(&reader);
+  uint32_t metric = rag_read_u32(&reader);
+  uint64_t rng_state = rag_read_u64(&reader);
+  uint64_t count = rag_read_u64(&reader);
+  uint32_t entry = rag_read_u32(&reader);
+  uint32_t top = rag_read_u32(&reader);
+
+  rag_check_dimension((long)dim);
+  if (m < 2 || m > UINT16_MAX || ef_construction == 0 || ef_search == 0 || metric > RAG_METRIC_ANGULAR ||
+      count >= UINT32_MAX || top > HNSW_MAX_LEVEL + 1 || (count == 0) != (top == 0) || (count > 0 && entry >= count)) {
+    rb_raise(rb_eArgError, "Corrupted HNSWIndex header");
+  }
+  hnsw_check_metric((rag_metric_t)metric);
+  if (count > reader.left / ((size_t)dim * sizeof(float))) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+
+  hnsw_t *h = hnsw_alloc(dim, m, ef_construction, ef_search, (rag_metric_t)metric);
+  VALUE obj = TypedData_Wrap_Struct(klass, &hnsw_type, h);
+  h->rng.state = rng_state;
+  h->entry = entry;
+  h->max_level = (int)top - 1;
+
+  size_t n = (size_t)count;
+  hnsw_reserve(h, n);
+  rag_read_floats(&reader, h->vectors, n * dim);
+  rag_read_i64s(&reader, h->ids, n);
+  rag_read_bytes(&reader, h->deleted, n);
+  rag_read_bytes(&reader, h->levels, n);
+
+  for (size_t i = 0; i < n; ++i) {
+    if (h->levels[i] > h->max_level) {
+      rb_raise(rb_eArgError, "Corrupted HNSWIndex levels");
+    }
+    if (h->deleted[i]) h->deleted_count++;
+  }
+
+  // Searches start from the entry point on the top level: it must have every level
+  if (n > 0 && h->levels[entry] != h->max_level) {
+    rb_raise(rb_eArgError, "Corrupted HNSWIndex entry point");
+  }
+
+  for (size_t i = 0; i < n; ++i) {
+    // count is only advanced once the lists exist, so the free callback stays consistent
+    h->links[i] = ZALLOC_N(uint32_t, hnsw_links_words(h, h->levels[i]));
+    h->count = i + 1;
+
+    for (uint32_t level = 0; level <= h->levels[i]; ++level) {
+      uint32_t *links = hnsw_links(h, (uint32_t)i, level);
+      uint32_t max_links = level == 0 ? h->m0 : h->m;
+
+      links[0] = rag_read_u32(&reader);
+      if (links[0] > max_links) {
+        rb_raise(rb_eArgError, "Corrupted HNSWIndex links");
+      }
+      for (uint32_t j = 1; j <= links[0]; ++j) {
+        links[j] = rag_read_u32(&reader);
+        if (links[j] >= n || h->levels[links[j]] < level) {
+          rb_raise(rb_eArgError, "Corrupted HNSWIndex links");
+        }
+      }
+    }
+  }
+  rag_reader_finish(&reader);
+
+  return obj;
+}
+
+void rag_init_hnsw(VALUE mRag) {
+  cHNSWIndex = rb_define_class_under(mRag, "HNSWIndex", rb_cObject);
+  rb_undef_alloc_func(cHNSWIndex);
+
+  rb_define_singleton_method(cHNSWIndex, "create", hnsw_create, -1);
+  rb_define_singleton_method(cHNSWIndex, "from_blob", hnsw_from_blob, 1);
+
+  rb_define_method(cHNSWIndex, "add", hnsw_add, 2);
+  rb_define_method(cHNSWIndex, "delete", hnsw_delete, 1);
+  rb_define_method(cHNSWIndex, "top_k", hnsw_top_k, -1);
+  rb_define_method(cHNSWIndex, "size", hnsw_size, 0);
+  rb_define_method(cHNSWIndex, "dim", hnsw_dim, 0);
+  rb_define_method(cHNSWIndex, "m", hnsw_m, 0);
+  rb_define_method(cHNSWIndex, "ef_construction", hnsw_ef_construction, 0);
+  rb_define_method(cHNSWIndex, "ef_search", hnsw_ef_search, 0);
+  rb_define_method(cHNSWIndex, "ef_search=", hnsw_set_ef_search, 1);
+  rb_define_method(cHNSWIndex, "metric", hnsw_metric, 0);
+  rb_define_method(cHNSWIndex, "to_blob", hnsw_to_blob, 0);
+}
@@ -0,0 +1,503 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For uint32_t, int64_t
+#include <math.h>     // For sqrt
+#include <string.h>   // For memcpy
+#include "embedding.h"
+#include "simd.h"
+#include "topk.h"
+#include "kmeans.h"
+#include "serialize.h"
+#include "parallel.h"
+
+// Inverted file index with flat (uncompressed) storage
+// Rows are clustered around nlist k-means centroids and each centroid owns a posting list
+// holding the ids and the full vectors of its rows. A query only scans the nprobe lists
+// whose centroids are closest to it, with the exact metric
+typedef struct {
+  size_t count;       // Rows in the list
+  size_t capacity;    // Rows the list can hold before growing
+  float *vectors;     // count * dim floats
+  int64_t *ids;       // Id of each row (the Database row id)
+} ivf_list_t;
+
+typedef struct {
+  uint32_t dim;       // Dimension of the indexed vectors
+  uint32_t nlist;     // Number of centroids / posting lists
+  uint32_t nprobe;    // Default number of lists scanned per query
+  uint8_t metric;     // rag_metric_t used to score the rows
+  float *centroids;   // nlist * dim floats
+  ivf_list_t *lists;  // nlist posting lists
+  size_t count;       // Rows in all the lists
+  int busy;           // Searches currently reading the lists without the GVL
+} ivf_t;
+
+// Magic and version of the serialized index
+#define IVF_MAGIC "RGIV"
+#define IVF_VERSION 1
+
+static VALUE cIVFIndex;
+
+static void ivf_free(void *ptr) {
+  if (ptr) {
+    ivf_t *ivf = (ivf_t *)ptr;
+    for (uint32_t l = 0; l < ivf->nlist; ++l) {
+      xfree(ivf->lists[l].vectors);
+      xfree(ivf->lists[l].ids);
+    }
+    xfree(ivf->lists);
+    xfree(ivf->centroids);
+    xfree(ivf);
+  }
+}
+
+static size_t ivf_memsize(const void *ptr) {
+  const ivf_t *ivf = (const ivf_t *)ptr;
+  if (!ivf) return 0;
+
+  size_t size = sizeof(ivf_t) + (size_t)ivf->nlist * ((size_t)ivf->dim * sizeof(float) + sizeof(ivf_list_t));
+  for (uint32_t l = 0; l < ivf->nlist; ++l) {
+    size += ivf->lists[l].capacity * ((size_t)ivf->dim * sizeof(float) + sizeof(int64_t));
+  }
+  return size;
+}
+
+static const rb_data_type_t ivf_type = {
+  "RagEmbeddings/IVFIndex",
+  {0, ivf_free, ivf_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+// Centroids are compared by direction for the angle-based metrics, by L2 distance otherwise
+static int ivf_spherical(const ivf_t *ivf) {
+  return ivf->metric == RAG_METRIC_COSINE || ivf->metric == RAG_METRIC_DOT || ivf->metric == RAG_METRIC_ANGULAR;
+}
+
+static ivf_t *ivf_alloc(uint32_t dim, uint32_t nlist, uint32_t nprobe, rag_metric_t metric) {
+  ivf_t *ivf = ZALLOC_N(ivf_t, 1);
+  ivf->dim = dim;
+  ivf->nlist = nlist;
+  ivf->nprobe = nprobe;
+  ivf->metric = (uint8_t)metric;
+  ivf->centroids = ALLOC_N(float, (size_t)nlist * dim);
+  ivf->lists = ZALLOC_N(ivf_list_t, nlist);
+  return ivf;
+}
+
+// Make room for at least `needed` rows in a list, doubling the capacity to amortize growth
+static void ivf_list_reserve(const ivf_t *ivf, ivf_list_t *list, size_t needed) {
+  if (needed <= list->capacity) return;
+
+  size_t new_capacity = list->capacity ? list->capacity : 16;
+  while (new_capacity < needed) new_capacity *= 2;
+
+  list->vectors = xrealloc2(list->vectors, new_capacity, (size_t)ivf->dim * sizeof(float));
+  list->ids = xrealloc2(list->ids, new_capacity, sizeof(int64_t));
+  list->capacity = new_capacity;
+}
+
+// Append a row to the list of its closest centroid
+static void ivf_insert(ivf_t *ivf, const float *vector, int64_t id) {
+  uint32_t l = rag_kmeans_nearest(vector, ivf->centroids, ivf->nlist, ivf->dim, ivf_spherical(ivf));
+  ivf_list_t *list = &ivf->lists[l];
+
+  ivf_list_reserve(ivf, list, list->count + 1);
+  memcpy(list->vectors + list->count * ivf->dim, vector, (size_t)ivf->dim * sizeof(float));
+  list->ids[list->count] = id;
+  list->count++;
+  ivf->count++;
+}
+
+// Score of a row for the heap: distances are negated so that higher is always better
+static double ivf_score(const ivf_t *ivf, const float *row, const float *query, double query_norm_sq) {
+  if (ivf->metric == RAG_METRIC_COSINE) {
+    return rag_cosine_with_norm(row, query, query_norm_sq, ivf->dim);
+  }
+
+  double score = rag_metric_compute((rag_metric_t)ivf->metric, row, query, ivf->dim);
+  return rag_metric_is_distance((rag_metric_t)ivf->metric) ? -score : score;
+}
+
+// A scan of the probed lists, run without the GVL.
+// Every worker fills its own heap over a range of scan positions, merged into the first one at the end
+typedef struct {
+  const ivf_t *ivf;
+  const float *query;
+  double query_norm_sq;
+  const ivf_list_t **lists;   // Probed lists, in scan order
+  const size_t *offsets;      // Scan position of the first row of each probed list
+  size_t scanned;             // Rows in all the probed lists
+  uint32_t workers;
+  rag_topk_t *heaps;          // One heap per worker
+} ivf_search_t;
+
+static void ivf_search_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  ivf_search_t *search = (ivf_search_t *)ctx;
+  const ivf_t *ivf = search->ivf;
+
+  // Probed list holding the first position of the range
+  size_t p = 0;
+  while (search->offsets[p + 1] <= begin) p++;
+
+  for (size_t position = begin; position < end; ++position) {
+    while (search->offsets[p + 1] <= position) p++;
+
+    const float *row = search->lists[p]->vectors + (position - search->offsets[p]) * ivf->dim;
+    rag_topk_push(&search->heaps[worker], ivf_score(ivf, row, search->query, search->query_norm_sq), position);
+  }
+}
+
+static void ivf_search_run(void *ctx, volatile int *interrupted) {
+  ivf_search_t *search = (ivf_search_t *)ctx;
+
+  for (uint32_t w = 0; w < search->workers; ++w) {
+    rag_topk_clear(&search->heaps[w]);
+  }
+
+  rag_parallel_for(search->scanned, search->workers, ivf_search_range, search, interrupted);
+  for (uint32_t w = 1; w < search->workers; ++w) {
+    rag_topk_merge(&search->heaps[0], &search->heaps[w]);
+  }
+}
+
+// Raise if the index is frozen, or if a search running without the GVL is reading the lists
+static void ivf_check_modifiable(VALUE self, const ivf_t *ivf) {
+  rb_check_frozen(self);
+  if (ivf->busy) {
+    rb_raise(rb_eRuntimeError, "can't modify IVFIndex during a search");
+  }
+}
+
+// Class method: RagEmbeddings::IVFIndex.train(matrix, nlist: 100, nprobe: 8, iterations: 25, seed: 0, metric: :cosine)
+// Clusters the rows of an EmbeddingMatrix into nlist centroids and returns an empty index.
+// The matrix needs at least nlist rows
+static VALUE ivf_train(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_matrix, opts;
+  rb_scan_args(argc, argv, "1:", &rb_matrix, &opts);
+
+  VALUE kwargs[5] = {Qundef, Qundef, Qundef, Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[5] = {rb_intern("nlist"), rb_intern("nprobe"), rb_intern("iterations"), rb_intern("seed"), rb_intern("metric")};
+    rb_get_kwargs(opts, kw_ids, 0, 5, kwargs);
+  }
+
+  uint32_t nlist = rag_uint_option(kwargs[0], 100, "nlist");
+  uint32_t nprobe = rag_uint_option(kwargs[1], 8, "nprobe");
+  int iterations = (int)rag_uint_option(kwargs[2], 25, "iterations");
+  uint64_t seed = (kwargs[3] == Qundef || NIL_P(kwargs[3])) ? 0 : NUM2ULL(kwargs[3]);
+  rag_metric_t metric = kwargs[4] == Qundef ? RAG_METRIC_COSINE : rag_metric_from_value(kwargs[4]);
+
+  uint32_t dim;
+  size_t n;
+  int *busy;
+  const float *rows = rag_matrix_rows(rb_matrix, &dim, &n, &busy);
+
+  if (n < nlist) {
+    rb_raise(rb_eArgError, "Training needs at least nlist (%u) rows, got %zu", nlist, n);
+  }
+
+  ivf_t *ivf = ivf_alloc(dim, nlist, nprobe, metric);
+  VALUE obj = TypedData_Wrap_Struct(klass, &ivf_type, ivf);
+
+  rag_rng_t rng;
+  rag_rng_seed(&rng, seed);
+  rag_kmeans(rows, n, dim, nlist, iterations, ivf_spherical(ivf), &rng, ivf->centroids, NULL, busy);
+
+  RB_GC_GUARD(rb_matrix);
+  return obj;
+}
+
+// Instance method: index.add(id, array_blob_or_embedding)
+// Appends a row to the posting list of its closest centroid. Returns self
+static VALUE ivf_add(VALUE self, VALUE rb_id, VALUE row) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+
+  int64_t id = NUM2LL(rb_id);
+  ivf_check_modifiable(self, ivf);
+
+  // ALLOCV keeps the buffer reachable by the GC, so a bad row that raises doesn't leak it
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, ivf->dim);
+
+  rag_row_to_floats(row, ivf->dim, EMBEDDING_DTYPE_F32, values);
+  ivf_insert(ivf, values, id);
+
+  ALLOCV_END(buffer);
+  return self;
+}
+
+// Instance method: index.delete(id)
+// Removes the rows with the given id from their posting list. Returns true if one was found
+static VALUE ivf_delete(VALUE self, VALUE rb_id) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+
+  int64_t id = NUM2LL(rb_id);
+  int found = 0;
+  ivf_check_modifiable(self, ivf);
+
+  for (uint32_t l = 0; l < ivf->nlist; ++l) {
+    ivf_list_t *list = &ivf->lists[l];
+
+    for (size_t i = 0; i < list->count;) {
+      if (list->ids[i] != id) {
+        ++i;
+        continue;
+      }
+
+      // Move the last row into the hole: the order inside a list doesn't matter
+      size_t last = list->count - 1;
+      list->ids[i] = list->ids[last];
+      memmove(list->vectors + i * ivf->dim, list->vectors + last * ivf->dim, (size_t)ivf->dim * sizeof(float));
+      list->count--;
+      ivf->count--;
+      found = 1;
+    }
+  }
+
+  return found ? Qtrue : Qfalse;
+}
+
+// Instance method: index.top_k(query_embedding, k, nprobe: index.nprobe)
+// Returns [[id, score], ...] for the k best rows of the nprobe closest lists, best first.
+// Scores are exact for the metric (distances in ascending order)
+static VALUE ivf_top_k(int argc, VALUE *argv, VALUE self) {
+  VALUE query, rb_k, opts;
+  rb_scan_args(argc, argv, "2:", &query, &rb_k, &opts);
+
+  VALUE rb_nprobe = Qundef;
+  if (!NIL_P(opts)) {
+    ID kw = rb_intern("nprobe");
+    rb_get_kwargs(opts, &kw, 0, 1, &rb_nprobe);
+  }
+
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+  uint32_t nprobe = rag_uint_option(rb_nprobe, ivf->nprobe, "nprobe");
+  if (nprobe > ivf->nlist) nprobe = ivf->nlist;
+
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, ivf->dim);
+  rag_row_to_floats(query, ivf->dim, EMBEDDING_DTYPE_F32, values);
+
+  size_t k = (size_t)k_arg < ivf->count ? (size_t)k_arg : ivf->count;
+  if (k == 0) {
+    ALLOCV_END(buffer);
+    return rb_ary_new();
+  }
+
+  // Closest centroids first (the negated distance keeps the heap ordered by proximity).
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises during the scan
+  int spherical = ivf_spherical(ivf);
+  double query_norm_sq = rag_sum_squares(values, ivf->dim);
+  double query_norm = sqrt(query_norm_sq);
+
+  VALUE probes_buffer, lists_buffer;
+  rag_topk_t probes;
+  rag_topk_init_with(&probes, nprobe, ALLOCV_N(rag_topk_entry_t, probes_buffer, nprobe));
+  for (uint32_t l = 0; l < ivf->nlist; ++l) {
+    const float *centroid = ivf->centroids + (size_t)l * ivf->dim;
+    double closeness = spherical
+      ? (query_norm > 0.0 ? rag_dot(values, centroid, ivf->dim) / query_norm : 0.0)
+      : -rag_squared_l2(values, centroid, ivf->dim);
+    rag_topk_push(&probes, closeness, l);
+  }
+
+  // Rows are numbered in scan order across the probed lists: the heaps index into scanned_ids
+  const ivf_list_t **lists = ALLOCV_N(const ivf_list_t *, lists_buffer, probes.size);
+
+  size_t scanned = 0;
+  for (size_t p = 0; p < probes.size; ++p) {
+    lists[p] = &ivf->lists[probes.entries[p].index];
+    scanned += lists[p]->count;
+  }
+
+  VALUE offsets_buffer, ids_buffer, heaps_buffer, entries_buffer;
+  size_t *offsets = ALLOCV_N(size_t, offsets_buffer, probes.size + 1);
+  int64_t *scanned_ids = ALLOCV_N(int64_t, ids_buffer, scanned ? scanned : 1);
+
+  size_t position = 0;
+  for (size_t p = 0; p < probes.size; ++p) {
+    offsets[p] = position;
+    memcpy(scanned_ids + position, lists[p]->ids, lists[p]->count * sizeof(int64_t));
+    position += lists[p]->count;
+  }
+  offsets[probes.size] = position;
+
+  uint32_t workers = rag_workers_for(scanned);
+  rag_topk_t *heaps = ALLOCV_N(rag_topk_t, heaps_buffer, workers);
+  rag_topk_entry_t *entries = ALLOCV_N(rag_topk_entry_t, entries_buffer, k * workers);
+  for (uint32_t w = 0; w < workers; ++w) {
+    rag_topk_init_with(&heaps[w], k, entries + w * k);
+  }
+
+  ivf_search_t search = {ivf, values, query_norm_sq, lists, offsets, scanned, workers, heaps};
+  rag_without_gvl(scanned * (size_t)ivf->dim, ivf_search_run, &search, OBJ_FROZEN(self) ? NULL : &ivf->busy);
+
+  rag_topk_sort(&heaps[0]);
+  VALUE result = rag_topk_to_id_ary(&heaps[0], scanned_ids, rag_metric_is_distance((rag_metric_t)ivf->metric));
+
+  ALLOCV_END(probes_buffer);
+  ALLOCV_END(lists_buffer);
+  ALLOCV_END(offsets_buffer);
+  ALLOCV_END(ids_buffer);
+  ALLOCV_END(heaps_buffer);
+  ALLOCV_END(entries_buffer);
+  ALLOCV_END(buffer);
+
+  RB_GC_GUARD(self);
+  return result;
+}
+
+// Instance method: index.size
+// Returns the number of rows in all the posting lists
+static VALUE ivf_size(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+  return SIZET2NUM(ivf->count);
+}
+
+// Instance method: index.dim
+static VALUE ivf_dim(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+  return UINT2NUM(ivf->dim);
+}
+
+// Instance method: index.nlist
+static VALUE ivf_nlist(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+  return UINT2NUM(ivf->nlist);
+}
+
+// Instance method: index.nprobe
+static VALUE ivf_nprobe(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+  return UINT2NUM(ivf->nprobe);
+}
+
+// Instance method: index.nprobe = 16
+// Changes the default number of lists scanned by top_k
+static VALUE ivf_set_nprobe(VALUE self, VALUE rb_nprobe) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+  rb_check_frozen(self);
+  ivf->nprobe = rag_uint_option(rb_nprobe, ivf->nprobe, "nprobe");
+  return rb_nprobe;
+}
+
+// Instance method: index.metric
+static VALUE ivf_metric(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+  return rag_metric_to_sym((rag_metric_t)ivf->metric);
+}
+
+// Instance method: index.list_sizes
+// Returns the number of rows in each posting list, to check the balance of the clusters
+static VALUE ivf_list_sizes(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+
+  VALUE sizes = rb_ary_new_capa(ivf->nlist);
+  for (uint32_t l = 0; l < ivf->nlist; ++l) {
+    rb_ary_store(sizes, l, SIZET2NUM(ivf->lists[l].count));
+  }
+  return sizes;
+}
+
+// Instance method: index.to_blob
+// Serializes the centroids and the posting lists (little-endian), see IVFIndex.from_blob
+static VALUE ivf_to_blob(VALUE self) {
+  ivf_t *ivf;
+  TypedData_Get_Struct(self, ivf_t, &ivf_type, ivf);
+
+  VALUE blob = rb_str_buf_new(0);
+  rag_write_bytes(blob, IVF_MAGIC, 4);
+  rag_write_u32(blob, IVF_VERSION);
+  rag_write_u32(blob, ivf->dim);
+  rag_write_u32(blob, ivf->nlist);
+  rag_write_u32(blob, ivf->nprobe);
+  rag_write_u32(blob, ivf->metric);
+  rag_write_floats(blob, ivf->centroids, (size_t)ivf->nlist * ivf->dim);
+
+  for (uint32_t l = 0; l < ivf->nlist; ++l) {
+    const ivf_list_t *list = &ivf->lists[l];
+    rag_write_u64(blob, list->count);
+    rag_write_floats(blob, list->vectors, list->count * ivf->dim);
+    rag_write_i64s(blob, list->ids, list->count);
+  }
+
+  return blob;
+}
+
+// Class method: RagEmbeddings::IVFIndex.from_blob(string)
+// Restores an index serialized with to_blob
+static VALUE ivf_from_blob(VALUE klass, VALUE rb_blob) {
+  rag_reader_t reader;
+  rag_reader_init(&reader, rb_blob);
+  rag_read_header(&reader, IVF_MAGIC, IVF_VERSION);
+
+  uint32_t dim = rag_read_u32(&reader);
+  uint32_t nlist = rag_read_u32(&reader);
+  uint32_t nprobe = rag_read_u32(&reader);
+  uint32_t metric = rag_read_u32(&reader);
+
+  rag_check_dimension((long)dim);
+  if (nlist == 0 || nprobe == 0 || metric > RAG_METRIC_ANGULAR) {
+    rb_raise(rb_eArgError, "Corrupted IVFIndex header");
+  }
+  if (nlist > reader.left / ((size_t)dim * sizeof(float))) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+
+  ivf_t *ivf = ivf_alloc(dim, nlist, nprobe, (rag_metric_t)metric);
+  VALUE obj = TypedData_Wrap_Struct(klass, &ivf_type, ivf);
+
+  rag_read_floats(&reader, ivf->centroids, (size_t)nlist * dim);
+
+  for (uint32_t l = 0; l < nlist; ++l) {
+    ivf_list_t *list = &ivf->lists[l];
+    uint64_t count = rag_read_u64(&reader);
+    if (count > reader.left / ((size_t)dim * sizeof(float))) {
+      rb_raise(rb_eArgError, "Truncated index data");
+    }
+
+    ivf_list_reserve(ivf, list, (size_t)count);
+    rag_read_floats(&reader, list->vectors, (size_t)count * dim);
+    rag_read_i64s(&reader, list->ids, (size_t)count);
+    list->count = (size_t)count;
+    ivf->count += list->count;
+  }
+  rag_reader_finish(&reader);
+
+  return obj;
+}
+
+void rag_init_ivf(VALUE mRag) {
+  cIVFIndex = rb_define_class_under(mRag, "IVFIndex", rb_cObject);
+  rb_undef_alloc_func(cIVFIndex);
+
+  rb_define_singleton_method(cIVFIndex, "train", ivf_train, -1);
+  rb_define_singleton_method(cIVFIndex, "from_blob", ivf_from_blob, 1);
+
+  rb_define_method(cIVFIndex, "add", ivf_add, 2);
+  rb_define_method(cIVFIndex, "delete", ivf_delete, 1);
+  rb_define_method(cIVFIndex, "top_k", ivf_top_k, -1);
+  rb_define_method(cIVFIndex, "size", ivf_size, 0);
+  rb_define_method(cIVFIndex, "dim", ivf_dim, 0);
+  rb_define_method(cIVFIndex, "nlist", ivf_nlist, 0);
+  rb_define_method(cIVFIndex, "nprobe", ivf_nprobe, 0);
+  rb_define_method(cIVFIndex, "nprobe=", ivf_set_nprobe, 1);
+  rb_define_method(cIVFIndex, "metric", ivf_metric, 0);
+  rb_define_method(cIVFIndex, "list_sizes", ivf_list_sizes, 0);
+  rb_define_method(cIVFIndex, "to_blob", ivf_to_blob, 0);
+}
@@ -0,0 +1,271 @@
+#include <ruby.h>     // Ruby API (ALLOCV)
+#include <string.h>   // For memcpy, memset
+#include <math.h>     // For sqrt, log, cos
+#include "simd.h"
+#include "kmeans.h"
+#include "parallel.h"
+
+void rag_rng_seed(rag_rng_t *rng, uint64_t seed) {
+  rng->state = seed;
+}
+
+uint64_t rag_rng_next(rag_rng_t *rng) {
+  uint64_t z = (rng->state += 0x9e3779b97f4a7c15ULL);
+  z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9ULL;
+  z = (z ^ (z >> 27)) * 0x94d049bb133111ebULL;
+  return z ^ (z >> 31);
+}
+
+double rag_rng_uniform(rag_rng_t *rng) {
+  // 53 random bits, the precision of a double
+  return (double)(rag_rng_next(rng) >> 11) * (1.0 / 9007199254740992.0);
+}
+
+#ifndef M_PI
+#define M_PI 3.14159265358979323846
+#endif
+
+double rag_rng_gaussian(rag_rng_t *rng) {
+  // Box-Muller transform; u1 is in (0, 1] so that the log is finite
+  double u1 = 1.0 - rag_rng_uniform(rng);
+  double u2 = rag_rng_uniform(rng);
+  return sqrt(-2.0 * log(u1)) * cos(2.0 * M_PI * u2);
+}
+
+// Dissimilarity between a row and a centroid: squared L2, or 1 - cosine when spherical
+// (spherical centroids have unit length, so only the row norm is needed)
+static double kmeans_distance(const float *row, double row_norm, const float *centroid, uint32_t dim, int spherical) {
+  if (!spherical) {
+    return rag_squared_l2(row, centroid, dim);
+  }
+
+  if (row_norm == 0.0) return 1.0;
+  return 1.0 - rag_dot(row, centroid, dim) / row_norm;
+}
+
+static double row_norm(const float *row, uint32_t dim, int spherical) {
+  return spherical ? sqrt(rag_sum_squares(row, dim)) : 0.0;
+}
+
+// Scale a centroid to unit length (left as is when it is the zero vector)
+static void normalize_centroid(float *centroid, uint32_t dim) {
+  double norm = sqrt(rag_sum_squares(centroid, dim));
+  if (norm > 0.0) rag_scale(centroid, dim, (float)(1.0 / norm));
+}
+
+// Copy a row into a centroid slot, scaled to unit length when spherical
+static void set_centroid(float *centroid, const float *row, uint32_t dim, int spherical) {
+  memcpy(centroid, row, (size_t)dim * sizeof(float));
+  if (spherical) normalize_centroid(centroid, dim);
+}
+
+uint32_t rag_kmeans_nearest(const float *row, const float *centroids, uint32_t k, uint32_t dim, int spherical) {
+  double norm = row_norm(row, dim, spherical);
+  uint32_t best = 0;
+  double best_distance = INFINITY;
+
+  for (uint32_t c = 0; c < k; ++c) {
+    double distance = kmeans_distance(row, norm, centroids + (size_t)c * dim, dim, spherical);
+    if (distance < best_distance) {
+      best_distance = distance;
+      best = c;
+    }
+  }
+  return best;
+}
+
+// One k-means run, computed without the GVL.
+// The loops over the rows are split across workers: every row is handled independently,
+// and the sums of the update step are done on one thread, so the result never depends on the thread count
+typedef struct {
+  const float *rows;
+  size_t n;
+  uint32_t dim;
+  uint32_t k;
+  int iterations;
+  int spherical;
+  rag_rng_t start;      // Generator state at the start, to replay a run cut short by an interrupt
+  rag_rng_t rng;
+  float *centroids;
+  uint32_t *assignments;
+  uint32_t workers;
+  double *norms;        // Row norms (spherical only)
+  double *closest;      // k-means++: distance from each row to its closest centroid
+  const float *added;   // k-means++: centroid just picked
+  uint32_t *assigned;   // Current cluster of each row
+  double *sums;         // k * dim sums of the update step
+  size_t *counts;       // Rows in each cluster
+  size_t changed[RAG_MAX_THREADS]; // Assignments changed by each worker in the current pass
+  int ran;              // Iterations run
+} kmeans_t;
+
+static void kmeans_norms_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  kmeans_t *km = (kmeans_t *)ctx;
+  for (size_t i = begin; i < end; ++i) {
+    km->norms[i] = row_norm(km->rows + i * km->dim, km->dim, km->spherical);
+  }
+}
+
+// k-means++: bring the closest distance of each row up to date with the centroid just added
+static void kmeans_closest_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  kmeans_t *km = (kmeans_t *)ctx;
+  int first = km->added == km->centroids;
+
+  for (size_t i = begin; i < end; ++i) {
+    double distance = kmeans_distance(km->rows + i * km->dim, km->norms[i], km->added, km->dim, km->spherical);
+    if (first || distance < km->closest[i]) km->closest[i] = distance;
+  }
+}
+
+static void kmeans_assign_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  kmeans_t *km = (kmeans_t *)ctx;
+
+  for (size_t i = begin; i < end; ++i) {
+    uint32_t nearest = rag_kmeans_nearest(km->rows + i * km->dim, km->centroids, km->k, km->dim, km->spherical);
+    if (nearest != km->assigned[i]) {
+      km->assigned[i] = nearest;
+      km->changed[worker]++;
+    }
+  }
+}
+
+static void kmeans_final_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  kmeans_t *km = (kmeans_t *)ctx;
+
+  for (size_t i = begin; i < end; ++i) {
+    km->assignments[i] = rag_kmeans_nearest(km->rows + i * km->dim, km->centroids, km->k, km->dim, km->spherical);
+  }
+}
+
+// k-means++ seeding: each new centroid is a row drawn with probability
+// proportional to its distance from the closest centroid picked so far
+static void kmeans_plus_plus(kmeans_t *km, volatile int *interrupted) {
+  const float *rows = km->rows;
+  size_t n = km->n;
+  uint32_t dim = km->dim;
+
+  size_t first = (size_t)(rag_rng_uniform(&km->rng) * (double)n);
+  set_centroid(km->centroids, rows + first * dim, dim, km->spherical);
+  km->added = km->centroids;
+  rag_parallel_for(n, km->workers, kmeans_closest_range, km, interrupted);
+
+  for (uint32_t c = 1; c < km->k && !*interrupted; ++c) {
+    double total = 0.0;
+    for (size_t i = 0; i < n; ++i) total += km->closest[i];
+
+    // All rows already coincide with a centroid: fall back to a uniform draw
+    size_t pick = n - 1;
+    if (total > 0.0) {
+      double target = rag_rng_uniform(&km->rng) * total;
+      for (size_t i = 0; i < n; ++i) {
+        target -= km->closest[i];
+        if (target < 0.0) {
+          pick = i;
+          break;
+        }
+      }
+    } else {
+      pick = (size_t)(rag_rng_uniform(&km->rng) * (double)n);
+    }
+
+    float *centroid = km->centroids + (size_t)c * dim;
+    set_centroid(centroid, rows + pick * dim, dim, km->spherical);
+    km->added = centroid;
+    rag_parallel_for(n, km->workers, kmeans_closest_range, km, interrupted);
+  }
+}
+
+static void kmeans_run(void *ctx, volatile int *interrupted) {
+  kmeans_t *km = (kmeans_t *)ctx;
+  const float *rows = km->rows;
+  size_t n = km->n;
+  uint32_t dim = km->dim;
+  uint32_t k = km->k;
+
+  km->rng = km->start;
+  km->ran = 0;
+
+  rag_parallel_for(n, km->workers, kmeans_norms_range, km, interrupted);
+  kmeans_plus_plus(km, interrupted);
+  if (*interrupted) return;
+
+  // Assignments start out of range so that the first pass always counts as a change
+  for (size_t i = 0; i < n; ++i) km->assigned[i] = k;
+
+  while (km->ran < km->iterations) {
+    km->ran++;
+
+    // Assignment step
+    memset(km->changed, 0, sizeof(km->changed));
+    rag_parallel_for(n, km->workers, kmeans_assign_range, km, interrupted);
+    if (*interrupted) return;
+
+    size_t changed = 0;
+    for (uint32_t w = 0; w < km->workers; ++w) changed += km->changed[w];
+    if (changed == 0) break;
+
+    // Update step: each centroid moves to the mean of its rows
+    memset(km->sums, 0, (size_t)k * dim * sizeof(double));
+    memset(km->counts, 0, (size_t)k * sizeof(size_t));
+    for (size_t i = 0; i < n; ++i) {
+      const float *row = rows + i * dim;
+      double *sum = km->sums + (size_t)km->assigned[i] * dim;
+      for (uint32_t d = 0; d < dim; ++d) sum[d] += row[d];
+      km->counts[km->assigned[i]]++;
+    }
+
+    for (uint32_t c = 0; c < k; ++c) {
+      float *centroid = km->centroids + (size_t)c * dim;
+
+      // An empty cluster is restarted on a random row
+      if (km->counts[c] == 0) {
+        size_t pick = (size_t)(rag_rng_uniform(&km->rng) * (double)n);
+        set_centroid(centroid, rows + pick * dim, dim, km->spherical);
+        continue;
+      }
+
+      const double *sum = km->sums + (size_t)c * dim;
+      for (uint32_t d = 0; d < dim; ++d) {
+        centroid[d] = (float)(sum[d] / (double)km->counts[c]);
+      }
+      if (km->spherical) normalize_centroid(centroid, dim);
+    }
+  }
+
+  // Final assignment against the last centroids
+  if (km->assignments) {
+    rag_parallel_for(n, km->workers, kmeans_final_range, km, interrupted);
+  }
+}
+
+int rag_kmeans(const float *rows, size_t n, uint32_t dim, uint32_t k, int iterations,
+               int spherical, rag_rng_t *rng, float *centroids, uint32_t *assignments, int *busy) {
+  kmeans_t km = {0};
+  km.rows = rows;
+  km.n = n;
+  km.dim = dim;
+  km.k = k;
+  km.iterations = iterations;
+  km.spherical = spherical;
+  km.start = *rng;
+  km.centroids = centroids;
+  km.assignments = assignments;
+  km.workers = rag_workers_for(n);
+
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  VALUE doubles_buffer, assigned_buffer, counts_buffer;
+  double *doubles = ALLOCV_N(double, doubles_buffer, 2 * n + (size_t)k * dim);
+  km.norms = doubles;
+  km.closest = doubles + n;
+  km.sums = doubles + 2 * n;
+  km.assigned = ALLOCV_N(uint32_t, assigned_buffer, n);
+  km.counts = ALLOCV_N(size_t, counts_buffer, k);
+
+  rag_without_gvl(n * (size_t)k * dim, kmeans_run, &km, busy);
+  *rng = km.rng;
+
+  ALLOCV_END(doubles_buffer);
+  ALLOCV_END(assigned_buffer);
+  ALLOCV_END(counts_buffer);
+  return km.ran;
+}
@@ -0,0 +1,35 @@
+#ifndef RAG_EMBEDDINGS_KMEANS_H
+#define RAG_EMBEDDINGS_KMEANS_H
+
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For uint32_t, uint64_t
+
+// Small seeded PRNG (splitmix64), so that training is reproducible across platforms
+typedef struct {
+  uint64_t state;
+} rag_rng_t;
+
+void rag_rng_seed(rag_rng_t *rng, uint64_t seed);
+uint64_t rag_rng_next(rag_rng_t *rng);
+
+// Uniform double in [0, 1)
+double rag_rng_uniform(rag_rng_t *rng);
+
+// Standard normal double (random projections and hyperplanes)
+double rag_rng_gaussian(rag_rng_t *rng);
+
+// Lloyd's k-means over n rows of dim floats (row-major), seeded with k-means++.
+// With spherical set rows are compared by cosine and the centroids are kept at unit length,
+// otherwise by squared L2 distance. Requires 0 < k <= n.
+// Writes k * dim floats to centroids and, when not NULL, the cluster of each row to assignments.
+// Returns the number of iterations run (it stops early once no assignment changes).
+// Must be called holding the GVL: the work itself runs without it, split across
+// RagEmbeddings.threads workers (see parallel.h). busy is the counter of the object
+// owning rows, or NULL when the rows are a private copy
+int rag_kmeans(const float *rows, size_t n, uint32_t dim, uint32_t k, int iterations,
+               int spherical, rag_rng_t *rng, float *centroids, uint32_t *assignments, int *busy);
+
+// Index of the centroid closest to a row, with the same comparison as rag_kmeans
+uint32_t rag_kmeans_nearest(const float *row, const float *centroids, uint32_t k, uint32_t dim, int spherical);
+
+#endif
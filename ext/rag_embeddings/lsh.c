@@ -0,0 +1,588 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For uint32_t, uint64_t, int64_t
+#include <math.h>     // For sqrt, fabs
+#include <string.h>   // For memcpy, memset
+#include "embedding.h"
+#include "simd.h"
+#include "topk.h"
+#include "kmeans.h"
+#include "serialize.h"
+#include "parallel.h"
+
+// Locality-sensitive hashing with random hyperplanes (SimHash), for cosine similarity
+// Each of the `tables` hash tables draws `bits` Gaussian hyperplanes; the bucket of a vector
+// is the word of the sides it falls on. Two vectors at angle theta agree on a bit with
+// probability 1 - theta / pi, so similar vectors tend to share buckets.
+// A query collects the rows sharing its bucket in any table (and, with probes, the buckets
+// that differ from it on its least certain bits), then rescores them with the exact cosine.
+// Rows are added incrementally; deleted rows are only marked
+typedef struct {
+  uint32_t dim;         // Dimension of the indexed vectors
+  uint32_t tables;      // Number of hash tables
+  uint32_t bits;        // Hyperplanes per table, at most 32
+  uint32_t probes;      // Default number of extra buckets looked up per table
+  float *planes;        // tables * bits * dim floats
+  size_t count;         // Rows stored, deleted ones included
+  size_t capacity;      // Rows the buffers can hold before growing
+  size_t deleted_count; // Rows marked as deleted
+  float *vectors;       // count * dim floats, scaled to unit length
+  int64_t *ids;         // Id of each row (the Database row id)
+  uint8_t *deleted;     // Non-zero for deleted rows
+  uint32_t *codes;      // count * tables: bucket of each row in each table
+  size_t *next;         // count * tables: next row in the same slot of a table, LSH_NONE at the end
+  size_t *heads;        // tables * slots: first row of each slot, LSH_NONE when empty
+  size_t slots;         // Slots per table (a power of two, at least count)
+  int busy;             // Searches currently reading the rows without the GVL
+} lsh_t;
+
+// Magic and version of the serialized index
+#define LSH_MAGIC "RGLS"
+#define LSH_VERSION 1
+
+#define LSH_NONE SIZE_MAX
+#define LSH_MIN_SLOTS 64
+
+static VALUE cLSHIndex;
+
+static void lsh_free(void *ptr) {
+  if (ptr) {
+    lsh_t *lsh = (lsh_t *)ptr;
+    xfree(lsh->planes);
+    xfree(lsh->vectors);
+    xfree(lsh->ids);
+    xfree(lsh->deleted);
+    xfree(lsh->codes);
+    xfree(lsh->next);
+    xfree(lsh->heads);
+    xfree(lsh);
+  }
+}
+
+static size_t lsh_memsize(const void *ptr) {
+  const lsh_t *lsh = (const lsh_t *)ptr;
+  if (!lsh) return 0;
+
+  return sizeof(lsh_t) +
+         (size_t)lsh->tables * lsh->bits * lsh->dim * sizeof(float) +
+         lsh->capacity * ((size_t)lsh->dim * sizeof(float) + sizeof(int64_t) + 1 +
+                          (size_t)lsh->tables * (sizeof(uint32_t) + sizeof(size_t))) +
+         (size_t)lsh->tables * lsh->slots * sizeof(size_t);
+}
+
+static const rb_data_type_t lsh_type = {
+  "RagEmbeddings/LSHIndex",
+  {0, lsh_free, lsh_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+// Allocate an empty index with its hyperplanes (left uninitialized)
+static lsh_t *lsh_alloc(uint32_t dim, uint32_t tables, uint32_t bits, uint32_t probes) {
+  lsh_t *lsh = ZALLOC_N(lsh_t, 1);
+  lsh->dim = dim;
+  lsh->tables = tables;
+  lsh->bits = bits;
+  lsh->probes = probes;
+  lsh->planes = ALLOC_N(float, (size_t)tables * bits * dim);
+  return lsh;
+}
+
+static void lsh_check_options(uint32_t tables, uint32_t bits, uint32_t probes) {
+  if (bits > 32) {
+    rb_raise(rb_eArgError, "bits must be at most 32");
+  }
+  if (tables > 1024) {
+    rb_raise(rb_eArgError, "tables must be at most 1024");
+  }
+  if (probes > bits) {
+    rb_raise(rb_eArgError, "probes must be at most bits (%u)", bits);
+  }
+}
+
+// Slot of a bucket in the chained hash table of a table
+static size_t lsh_slot(uint32_t code, size_t slots) {
+  return (size_t)(((uint64_t)code * 0x9e3779b97f4a7c15ULL) >> 32) & (slots - 1);
+}
+
+// Link row r into the slot of its bucket in every table
+static void lsh_link(lsh_t *lsh, size_t r) {
+  for (uint32_t t = 0; t < lsh->tables; ++t) {
+    size_t *head = lsh->heads + (size_t)t * lsh->slots + lsh_slot(lsh->codes[r * lsh->tables + t], lsh->slots);
+    lsh->next[r * lsh->tables + t] = *head;
+    *head = r;
+  }
+}
+
+// Make room for at least `needed` rows, doubling the capacity to amortize growth.
+// The slots grow with the rows, so that chains stay short; every row is linked again then
+static void lsh_reserve(lsh_t *lsh, size_t needed) {
+  if (needed > lsh->capacity) {
+    size_t new_capacity = lsh->capacity ? lsh->capacity : 16;
+    while (new_capacity < needed) new_capacity *= 2;
+
+    lsh->vectors = xrealloc2(lsh->vectors, new_capacity, (size_t)lsh->dim * sizeof(float));
+    lsh->ids = xrealloc2(lsh->ids, new_capacity, sizeof(int64_t));
+    lsh->deleted = xrealloc2(lsh->deleted, new_capacity, sizeof(uint8_t));
+    lsh->codes = xrealloc2(lsh->codes, new_capacity, (size_t)lsh->tables * sizeof(uint32_t));
+    lsh->next = xrealloc2(lsh->next, new_capacity, (size_t)lsh->tables * sizeof(size_t));
+    lsh->capacity = new_capacity;
+  }
+
+  if (lsh->heads && needed <= lsh->slots) return;
+
+  size_t slots = lsh->slots ? lsh->slots : LSH_MIN_SLOTS;
+  while (slots < needed) slots *= 2;
+
+  xfree(lsh->heads);
+  lsh->heads = ALLOC_N(size_t, (size_t)lsh->tables * slots);
+  lsh->slots = slots;
+  for (size_t i = 0; i < (size_t)lsh->tables * slots; ++i) lsh->heads[i] = LSH_NONE;
+  for (size_t r = 0; r < lsh->count; ++r) lsh_link(lsh, r);
+}
+
+// Scale a vector to unit length (left as is when it is the zero vector)
+static void lsh_prepare(const lsh_t *lsh, const float *src, float *dst) {
+  memcpy(dst, src, (size_t)lsh->dim * sizeof(float));
+
+  double norm = sqrt(rag_sum_squares(dst, lsh->dim));
+  if (norm > 0.0) rag_scale(dst, lsh->dim, (float)(1.0 / norm));
+}
+
+// Bucket of a vector in table t. margins, when not NULL, receives the distance
+// to each hyperplane (bits values): the smallest ones are the least certain bits
+static uint32_t lsh_code(const lsh_t *lsh, const float *vector, uint32_t t, double *margins) {
+  const float *planes = lsh->planes + (size_t)t * lsh->bits * lsh->dim;
+  uint32_t code = 0;
+
+  for (uint32_t b = 0; b < lsh->bits; ++b) {
+    double side = rag_dot(planes + (size_t)b * lsh->dim, vector, lsh->dim);
+    if (side >= 0.0) code |= (uint32_t)1 << b;
+    if (margins) margins[b] = fabs(side);
+  }
+  return code;
+}
+
+// Buckets looked up for a query in table t: its own, then the ones obtained by flipping
+// each of its `probes` least certain bits. Writes 1 + probes codes. margins is a scratch buffer of bits values
+static void lsh_probe_codes(const lsh_t *lsh, const float *query, uint32_t t, uint32_t probes,
+                            double *margins, uint32_t *codes) {
+  uint32_t code = lsh_code(lsh, query, t, margins);
+  codes[0] = code;
+
+  for (uint32_t p = 0; p < probes; ++p) {
+    // Selection of the next smallest margin; bits already flipped are set to infinity
+    uint32_t weakest = 0;
+    for (uint32_t b = 1; b < lsh->bits; ++b) {
+      if (margins[b] < margins[weakest]) weakest = b;
+    }
+    margins[weakest] = INFINITY;
+    codes[1 + p] = code ^ ((uint32_t)1 << weakest);
+  }
+}
+
+// Hashing of a new row into every table, run without the GVL.
+// Only reads the hyperplanes, which never change once the index is created
+typedef struct {
+  const lsh_t *lsh;
+  const float *vector;  // Prepared row
+  uint32_t *codes;      // Bucket in each table
+} lsh_hash_t;
+
+static void lsh_hash_run(void *ctx, volatile int *interrupted) {
+  lsh_hash_t *hash = (lsh_hash_t *)ctx;
+  for (uint32_t t = 0; t < hash->lsh->tables && !*interrupted; ++t) {
+    hash->codes[t] = lsh_code(hash->lsh, hash->vector, t, NULL);
+  }
+}
+
+// Raise if the index is frozen, or if a search running without the GVL is reading the rows
+static void lsh_check_modifiable(VALUE self, const lsh_t *lsh) {
+  rb_check_frozen(self);
+  if (lsh->busy) {
+    rb_raise(rb_eRuntimeError, "can't modify LSHIndex during a search");
+  }
+}
+
+// Class method: RagEmbeddings::LSHIndex.create(dim, tables: 8, bits: 16, probes: 0, seed: 0)
+// Creates an empty index for vectors of the given dimension, drawing tables * bits hyperplanes.
+// More bits make buckets smaller (faster, lower recall); more tables and probes raise the recall
+static VALUE lsh_create(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_dim, opts;
+  rb_scan_args(argc, argv, "1:", &rb_dim, &opts);
+
+  VALUE kwargs[4] = {Qundef, Qundef, Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[4] = {rb_intern("tables"), rb_intern("bits"), rb_intern("probes"), rb_intern("seed")};
+    rb_get_kwargs(opts, kw_ids, 0, 4, kwargs);
+  }
+
+  long dim = NUM2LONG(rb_dim);
+  rag_check_dimension(dim);
+
+  uint32_t tables = rag_uint_option(kwargs[0], 8, "tables");
+  uint32_t bits = rag_uint_option(kwargs[1], 16, "bits");
+  uint32_t probes = (kwargs[2] == Qundef || NIL_P(kwargs[2])) ? 0 : NUM2UINT(kwargs[2]);
+  uint64_t seed = (kwargs[3] == Qundef || NIL_P(kwargs[3])) ? 0 : NUM2ULL(kwargs[3]);
+  lsh_check_options(tables, bits, probes);
+
+  lsh_t *lsh = lsh_alloc((uint32_t)dim, tables, bits, probes);
+  VALUE obj = TypedData_Wrap_Struct(klass, &lsh_type, lsh);
+
+  rag_rng_t rng;
+  rag_rng_seed(&rng, seed);
+  for (size_t i = 0; i < (size_t)tables * bits * lsh->dim; ++i) {
+    lsh->planes[i] = (float)rag_rng_gaussian(&rng);
+  }
+
+  return obj;
+}
+
+// Instance method: index.add(id, array_blob_or_embedding)
+// Hashes a vector into every table (without the GVL) and stores it under the given id. Returns self
+static VALUE lsh_add(VALUE self, VALUE rb_id, VALUE row) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  lsh_check_modifiable(self, lsh);
+
+  int64_t id = NUM2LL(rb_id);
+
+  // ALLOCV keeps the buffers reachable by the GC, so a bad row or an interrupt that raises doesn't leak them
+  VALUE buffer, codes_buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)lsh->dim);
+  float *prepared = values + lsh->dim;
+  uint32_t *codes = ALLOCV_N(uint32_t, codes_buffer, lsh->tables);
+  rag_row_to_floats(row, lsh->dim, EMBEDDING_DTYPE_F32, values);
+  lsh_prepare(lsh, values, prepared);
+
+  lsh_hash_t hash = {lsh, prepared, codes};
+  rag_without_gvl((size_t)lsh->tables * lsh->bits * lsh->dim, lsh_hash_run, &hash, NULL);
+
+  // Searches started by other threads meanwhile may still be reading the rows: let them finish
+  while (lsh->busy) rb_thread_schedule();
+
+  lsh_reserve(lsh, lsh->count + 1);
+  size_t r = lsh->count;
+  memcpy(lsh->vectors + r * lsh->dim, prepared, (size_t)lsh->dim * sizeof(float));
+  memcpy(lsh->codes + r * lsh->tables, codes, (size_t)lsh->tables * sizeof(uint32_t));
+  lsh->ids[r] = id;
+  lsh->deleted[r] = 0;
+  lsh_link(lsh, r);
+  lsh->count++;
+
+  ALLOCV_END(buffer);
+  ALLOCV_END(codes_buffer);
+  return self;
+}
+
+// Instance method: index.delete(id)
+// Marks the rows with the given id as deleted. Returns true if a row was found.
+// Lookup by id is a linear scan over the rows
+static VALUE lsh_delete(VALUE self, VALUE rb_id) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  lsh_check_modifiable(self, lsh);
+
+  int64_t id = NUM2LL(rb_id);
+  int found = 0;
+
+  for (size_t i = 0; i < lsh->count; ++i) {
+    if (lsh->ids[i] == id && !lsh->deleted[i]) {
+      lsh->deleted[i] = 1;
+      lsh->deleted_count++;
+      found = 1;
+    }
+  }
+
+  return found ? Qtrue : Qfalse;
+}
+
+// Read the optional probes: keyword, defaulting to the index setting
+static uint32_t lsh_probes_option(const lsh_t *lsh, VALUE opts) {
+  if (NIL_P(opts)) return lsh->probes;
+
+  ID kw = rb_intern("probes");
+  VALUE rb_probes;
+  rb_get_kwargs(opts, &kw, 0, 1, &rb_probes);
+  if (rb_probes == Qundef || NIL_P(rb_probes)) return lsh->probes;
+
+  uint32_t probes = NUM2UINT(rb_probes);
+  if (probes > lsh->bits) {
+    rb_raise(rb_eArgError, "probes must be at most bits (%u)", lsh->bits);
+  }
+  return probes;
+}
+
+// A lookup of the rows sharing a bucket with a prepared query, run without the GVL.
+// A row found in several tables is scored once
+typedef struct {
+  const lsh_t *lsh;
+  const float *query;
+  uint32_t probes;
+  uint64_t *seen;       // One bit per row
+  size_t words;
+  double *margins;      // Scratch buffer of bits values
+  uint32_t *codes;      // Buckets looked up in a table
+  rag_topk_t *topk;
+} lsh_search_t;
+
+static void lsh_search_run(void *ctx, volatile int *interrupted) {
+  lsh_search_t *search = (lsh_search_t *)ctx;
+  const lsh_t *lsh = search->lsh;
+
+  memset(search->seen, 0, search->words * sizeof(uint64_t));
+  rag_topk_clear(search->topk);
+
+  for (uint32_t t = 0; t < lsh->tables && !*interrupted; ++t) {
+    lsh_probe_codes(lsh, search->query, t, search->probes, search->margins, search->codes);
+
+    for (uint32_t p = 0; p <= search->probes; ++p) {
+      uint32_t code = search->codes[p];
+      size_t r = lsh->heads[(size_t)t * lsh->slots + lsh_slot(code, lsh->slots)];
+
+      for (; r != LSH_NONE; r = lsh->next[r * lsh->tables + t]) {
+        // Other buckets may share the slot
+        if (lsh->codes[r * lsh->tables + t] != code) continue;
+        if (lsh->deleted[r] || (search->seen[r / 64] >> (r % 64)) & 1) continue;
+
+        search->seen[r / 64] |= (uint64_t)1 << (r % 64);
+        rag_topk_push(search->topk, rag_dot(search->query, lsh->vectors + r * lsh->dim, lsh->dim), r);
+      }
+    }
+  }
+}
+
+// Instance method: index.top_k(query_embedding, k, probes: index.probes)
+// Returns [[id, cosine similarity], ...] for the k most similar rows among the candidates
+// sharing a bucket with the query, best first. Fewer than k results are returned
+// when the buckets hold fewer live rows
+static VALUE lsh_top_k(int argc, VALUE *argv, VALUE self) {
+  VALUE query, rb_k, opts;
+  rb_scan_args(argc, argv, "2:", &query, &rb_k, &opts);
+
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+  uint32_t probes = lsh_probes_option(lsh, opts);
+
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)lsh->dim);
+  float *prepared = values + lsh->dim;
+  rag_row_to_floats(query, lsh->dim, EMBEDDING_DTYPE_F32, values);
+
+  size_t live = lsh->count - lsh->deleted_count;
+  size_t k = (size_t)k_arg < live ? (size_t)k_arg : live;
+  if (k == 0) {
+    ALLOCV_END(buffer);
+    return rb_ary_new();
+  }
+
+  lsh_prepare(lsh, values, prepared);
+
+  // The lookup runs without the GVL. ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  VALUE seen_buffer, margins_buffer, codes_buffer, entries_buffer;
+  size_t words = (lsh->count + 63) / 64;
+  rag_topk_t topk;
+  rag_topk_init_with(&topk, k, ALLOCV_N(rag_topk_entry_t, entries_buffer, k));
+  lsh_search_t search = {lsh, prepared, probes};
+  search.seen = ALLOCV_N(uint64_t, seen_buffer, words ? words : 1);
+  search.words = words;
+  search.margins = ALLOCV_N(double, margins_buffer, lsh->bits);
+  search.codes = ALLOCV_N(uint32_t, codes_buffer, (size_t)probes + 1);
+  search.topk = &topk;
+
+  // Hashing the query costs tables * bits dot products, and each of the rows found one more
+  size_t work = (size_t)lsh->tables * (lsh->bits + (probes + 1) * (lsh->count / ((size_t)1 << lsh->bits) + 1)) * lsh->dim;
+  rag_without_gvl(work, lsh_search_run, &search, OBJ_FROZEN(self) ? NULL : &lsh->busy);
+
+  rag_topk_sort(&topk);
+  VALUE result = rag_topk_to_id_ary(&topk, lsh->ids, 0);
+
+  ALLOCV_END(entries_buffer);
+  ALLOCV_END(seen_buffer);
+  ALLOCV_END(margins_buffer);
+  ALLOCV_END(codes_buffer);
+  ALLOCV_END(buffer);
+
+  return result;
+}
+
+// Instance method: index.buckets(query_embedding, probes: index.probes)
+// Returns the [table, bucket] pairs a query looks up: its own bucket in every table,
+// then the probed ones. Lets buckets be stored elsewhere (e.g. the Database SQLite table)
+static VALUE lsh_buckets(int argc, VALUE *argv, VALUE self) {
+  VALUE query, opts;
+  rb_scan_args(argc, argv, "1:", &query, &opts);
+
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  uint32_t probes = lsh_probes_option(lsh, opts);
+
+  VALUE buffer, margins_buffer, codes_buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)lsh->dim);
+  float *prepared = values + lsh->dim;
+  rag_row_to_floats(query, lsh->dim, EMBEDDING_DTYPE_F32, values);
+  lsh_prepare(lsh, values, prepared);
+
+  double *margins = ALLOCV_N(double, margins_buffer, lsh->bits);
+  uint32_t *codes = ALLOCV_N(uint32_t, codes_buffer, (size_t)probes + 1);
+
+  VALUE result = rb_ary_new_capa((long)lsh->tables * (probes + 1));
+  for (uint32_t t = 0; t < lsh->tables; ++t) {
+    lsh_probe_codes(lsh, prepared, t, probes, margins, codes);
+    for (uint32_t p = 0; p <= probes; ++p) {
+      rb_ary_push(result, rb_assoc_new(UINT2NUM(t), UINT2NUM(codes[p])));
+    }
+  }
+
+  ALLOCV_END(buffer);
+  ALLOCV_END(margins_buffer);
+  ALLOCV_END(codes_buffer);
+  return result;
+}
+
+// Instance method: index.size
+// Returns the number of live (not deleted) rows
+static VALUE lsh_size(VALUE self) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  return SIZET2NUM(lsh->count - lsh->deleted_count);
+}
+
+// Instance method: index.dim
+static VALUE lsh_dim(VALUE self) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  return UINT2NUM(lsh->dim);
+}
+
+// Instance method: index.tables
+static VALUE lsh_tables(VALUE self) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  return UINT2NUM(lsh->tables);
+}
+
+// Instance method: index.bits
+// Returns the number of hyperplanes of each table
+static VALUE lsh_bits(VALUE self) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  return UINT2NUM(lsh->bits);
+}
+
+// Instance method: index.probes
+// Returns the default number of extra buckets looked up per table
+static VALUE lsh_probes(VALUE self) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  return UINT2NUM(lsh->probes);
+}
+
+// Instance method: index.probes = 2
+static VALUE lsh_set_probes(VALUE self, VALUE rb_probes) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+  rb_check_frozen(self);
+
+  uint32_t probes = NUM2UINT(rb_probes);
+  lsh_check_options(lsh->tables, lsh->bits, probes);
+  lsh->probes = probes;
+  return rb_probes;
+}
+
+// Instance method: index.to_blob
+// Serializes the hyperplanes, the rows and their buckets (little-endian), see LSHIndex.from_blob
+static VALUE lsh_to_blob(VALUE self) {
+  lsh_t *lsh;
+  TypedData_Get_Struct(self, lsh_t, &lsh_type, lsh);
+
+  VALUE blob = rb_str_buf_new(0);
+  rag_write_bytes(blob, LSH_MAGIC, 4);
+  rag_write_u32(blob, LSH_VERSION);
+  rag_write_u32(blob, lsh->dim);
+  rag_write_u32(blob, lsh->tables);
+  rag_write_u32(blob, lsh->bits);
+  rag_write_u32(blob, lsh->probes);
+  rag_write_u64(blob, lsh->count);
+  rag_write_floats(blob, lsh->planes, (size_t)lsh->tables * lsh->bits * lsh->dim);
+  rag_write_floats(blob, lsh->vectors, lsh->count * lsh->dim);
+  rag_write_i64s(blob, lsh->ids, lsh->count);
+  rag_write_bytes(blob, lsh->deleted, lsh->count);
+  for (size_t i = 0; i < lsh->count * lsh->tables; ++i) {
+    rag_write_u32(blob, lsh->codes[i]);
+  }
+
+  return blob;
+}
+
+// Class method: RagEmbeddings::LSHIndex.from_blob(string)
+// Restores an index serialized with to_blob
+static VALUE lsh_from_blob(VALUE klass, VALUE rb_blob) {
+  rag_reader_t reader;
+  rag_reader_init(&reader, rb_blob);
+  rag_read_header(&reader, LSH_MAGIC, LSH_VERSION);
+
+  uint32_t dim = rag_read_u32(&reader);
+  uint32_t tables = rag_read_u32(&reader);
+  uint32_t bits = rag_read_u32(&reader);
+  uint32_t probes = rag_read_u32(&reader);
+  uint64_t count = rag_read_u64(&reader);
+
+  rag_check_dimension((long)dim);
+  if (tables == 0 || tables > 1024 || bits == 0 || bits > 32 || probes > bits) {
+    rb_raise(rb_eArgError, "Corrupted LSHIndex header");
+  }
+  size_t row_bytes = (size_t)dim * sizeof(float) + sizeof(int64_t) + 1 + (size_t)tables * sizeof(uint32_t);
+  if (count > reader.left / row_bytes) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+
+  lsh_t *lsh = lsh_alloc(dim, tables, bits, probes);
+  VALUE obj = TypedData_Wrap_Struct(klass, &lsh_type, lsh);
+
+  rag_read_floats(&reader, lsh->planes, (size_t)tables * bits * dim);
+
+  lsh_reserve(lsh, (size_t)count);
+  rag_read_floats(&reader, lsh->vectors, (size_t)count * dim);
+  rag_read_i64s(&reader, lsh->ids, (size_t)count);
+  rag_read_bytes(&reader, lsh->deleted, (size_t)count);
+  for (size_t i = 0; i < (size_t)count * tables; ++i) {
+    lsh->codes[i] = rag_read_u32(&reader);
+    if (bits < 32 && lsh->codes[i] >> bits) {
+      rb_raise(rb_eArgError, "Corrupted LSHIndex buckets");
+    }
+  }
+  rag_reader_finish(&reader);
+
+  for (size_t r = 0; r < (size_t)count; ++r) {
+    if (lsh->deleted[r]) lsh->deleted_count++;
+    lsh_link(lsh, r);
+    lsh->count++;
+  }
+
+  return obj;
+}
+
+void rag_init_lsh(VALUE mRag) {
+  cLSHIndex = rb_define_class_under(mRag, "LSHIndex", rb_cObject);
+  rb_undef_alloc_func(cLSHIndex);
+
+  rb_define_singleton_method(cLSHIndex, "create", lsh_create, -1);
+  rb_define_singleton_method(cLSHIndex, "from_blob", lsh_from_blob, 1);
+
+  rb_define_method(cLSHIndex, "add", lsh_add, 2);
+  rb_define_method(cLSHIndex, "delete", lsh_delete, 1);
+  rb_define_method(cLSHIndex, "top_k", lsh_top_k, -1);
+  rb_define_method(cLSHIndex, "buckets", lsh_buckets, -1);
+  rb_define_method(cLSHIndex, "size", lsh_size, 0);
+  rb_define_method(cLSHIndex, "dim", lsh_dim, 0);
+  rb_define_method(cLSHIndex, "tables", lsh_tables, 0);
+  rb_define_method(cLSHIndex, "bits", lsh_bits, 0);
+  rb_define_method(cLSHIndex, "probes", lsh_probes, 0);
+  rb_define_method(cLSHIndex, "probes=", lsh_set_probes, 1);
+  rb_define_method(cLSHIndex, "to_blob", lsh_to_blob, 0);
+}
@@ -0,0 +1,110 @@
+#include <math.h>     // For sqrt, fabs, acos, isfinite
+#include "metrics.h"
+#include "simd.h"
+
+#ifndef M_PI
+#define M_PI 3.14159265358979323846
+#endif
+
+double rag_cosine(const float *a, const float *b, size_t n) {
+  double dot, norm_a, norm_b;
+  rag_dot_norms(a, b, n, &dot, &norm_a, &norm_b);
+
+  // NaN or Infinity in either vector always makes the dot product NaN or infinite:
+  // report NaN, even against a zero vector
+  if (!isfinite(dot)) {
+    return NAN;
+  }
+
+  // Zero vectors have no direction: report 0 similarity
+  if (norm_a == 0.0 || norm_b == 0.0) {
+    return 0.0;
+  }
+
+  double similarity = dot / sqrt(norm_a * norm_b);
+
+  // Clamp result to [-1, 1] to handle floating point precision errors
+  if (similarity > 1.0) similarity = 1.0;
+  if (similarity < -1.0) similarity = -1.0;
+
+  return similarity;
+}
+
+double rag_cosine_with_norm(const float *row, const float *query, double query_norm_sq, size_t n) {
+  double dot, norm_row, unused;
+  rag_dot_norms(row, query, n, &dot, &norm_row, &unused);
+
+  if (!isfinite(dot)) {
+    return NAN;
+  }
+  if (norm_row == 0.0 || query_norm_sq == 0.0) {
+    return 0.0;
+  }
+
+  double similarity = dot / sqrt(norm_row * query_norm_sq);
+  if (similarity > 1.0) similarity = 1.0;
+  if (similarity < -1.0) similarity = -1.0;
+
+  return similarity;
+}
+
+double rag_cosine_with_norms(const float *a, const float *b, double norm_a, double norm_b, size_t n) {
+  double dot = rag_dot(a, b, n);
+
+  if (!isfinite(dot)) {
+    return NAN;
+  }
+
+  if (norm_a == 0.0 || norm_b == 0.0) {
+    return 0.0;
+  }
+
+  double similarity = dot / (norm_a * norm_b);
+  if (similarity > 1.0) similarity = 1.0;
+  if (similarity < -1.0) similarity = -1.0;
+
+  return similarity;
+}
+
+double rag_manhattan(const float *a, const float *b, size_t n) {
+  double sum = 0.0;
+  for (size_t i = 0; i < n; ++i) {
+    sum += fabs((double)a[i] - b[i]);
+  }
+  return sum;
+}
+
+double rag_chebyshev(const float *a, const float *b, size_t n) {
+  double max = 0.0;
+  for (size_t i = 0; i < n; ++i) {
+    double diff = fabs((double)a[i] - b[i]);
+    if (isnan(diff)) return NAN;  // NaN never compares greater: it would be skipped
+    if (diff > max) max = diff;
+  }
+  return max;
+}
+
+double rag_angular_from_cosine(double cosine) {
+  return acos(cosine) / M_PI;
+}
+
+double rag_angular(const float *a, const float *b, size_t n) {
+  return rag_angular_from_cosine(rag_cosine(a, b, n));
+}
+
+double rag_metric_compute(rag_metric_t metric, const float *a, const float *b, size_t n) {
+  switch (metric) {
+    case RAG_METRIC_COSINE:            return rag_cosine(a, b, n);
+    case RAG_METRIC_DOT:               return rag_dot(a, b, n);
+    case RAG_METRIC_EUCLIDEAN:         return sqrt(rag_squared_l2(a, b, n));
+    case RAG_METRIC_SQUARED_EUCLIDEAN: return rag_squared_l2(a, b, n);
+    case RAG_METRIC_MANHATTAN:         return rag_manhattan(a, b, n);
+    case RAG_METRIC_CHEBYSHEV:         return rag_chebyshev(a, b, n);
+    case RAG_METRIC_ANGULAR:           return rag_angular(a, b, n);
+  }
+  return 0.0;
+}
+
+int rag_metric_is_distance(rag_metric_t metric) {
+  return metric != RAG_METRIC_COSINE && metric != RAG_METRIC_DOT;
+}
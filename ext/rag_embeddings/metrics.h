@@ -0,0 +1,43 @@
+#ifndef RAG_EMBEDDINGS_METRICS_H
+#define RAG_EMBEDDINGS_METRICS_H
+
+#include <stddef.h>   // For size_t
+
+// Comparison metrics supported by Embedding#similarity, EmbeddingMatrix#top_k and Database
+typedef enum {
+  RAG_METRIC_COSINE,             // Cosine similarity, higher is closer
+  RAG_METRIC_DOT,                // Dot product, higher is closer
+  RAG_METRIC_EUCLIDEAN,          // L2 distance, lower is closer
+  RAG_METRIC_SQUARED_EUCLIDEAN,  // Squared L2 distance, lower is closer
+  RAG_METRIC_MANHATTAN,          // L1 distance, lower is closer
+  RAG_METRIC_CHEBYSHEV,          // L-infinity distance, lower is closer
+  RAG_METRIC_ANGULAR             // Angle between the vectors divided by pi, in [0, 1], lower is closer
+} rag_metric_t;
+
+// Compute the metric between two vectors of n floats
+// Finite inputs always give a finite result (cosine in [-1, 1], 0 against a zero vector),
+// since sums are accumulated in double. A NaN or Infinity in either vector gives NaN for
+// cosine and angular, and NaN or Infinity for the other metrics
+double rag_metric_compute(rag_metric_t metric, const float *a, const float *b, size_t n);
+
+// Non-zero when lower values mean closer vectors (distances), zero for similarities
+int rag_metric_is_distance(rag_metric_t metric);
+
+// Individual kernels, also used directly where the metric is known in advance
+double rag_cosine(const float *a, const float *b, size_t n);
+double rag_manhattan(const float *a, const float *b, size_t n);
+double rag_chebyshev(const float *a, const float *b, size_t n);
+double rag_angular(const float *a, const float *b, size_t n);
+
+// Angular distance for a cosine similarity: acos(cosine) / pi
+double rag_angular_from_cosine(double cosine);
+
+// Cosine similarity between two vectors whose norms are already known (0 when one of them is zero).
+// Only a dot product is computed: for two unit vectors pass norms of 1
+double rag_cosine_with_norms(const float *a, const float *b, double norm_a, double norm_b, size_t n);
+
+// Cosine similarity between a row and a query whose squared norm is already known
+// Saves recomputing the query norm for every row of a scan
+double rag_cosine_with_norm(const float *row, const float *query, double query_norm_sq, size_t n);
+
+#endif
@@ -0,0 +1,168 @@
+#include <ruby.h>         // Ruby API
+#include <ruby/thread.h>  // For rb_thread_call_without_gvl
+#include "embedding.h"
+#include "parallel.h"
+
+#ifdef HAVE_PTHREAD_H
+#include <pthread.h>
+#endif
+
+// Below this many float operations the GVL is kept: releasing it costs more than the work
+#define RAG_NOGVL_MIN_WORK (1 << 16)
+
+// Fewest items given to a worker, so that small loops don't pay for extra threads
+#define RAG_MIN_ITEMS_PER_WORKER 1024
+
+// Items processed between two checks of the interrupt flag
+#define RAG_CHUNK 4096
+
+uint32_t rag_threads = 1;
+
+uint32_t rag_workers_for(size_t n) {
+  size_t workers = n / RAG_MIN_ITEMS_PER_WORKER;
+  if (workers > rag_threads) workers = rag_threads;
+  return workers > 0 ? (uint32_t)workers : 1;
+}
+
+// One parallel loop, shared by its workers
+typedef struct {
+  size_t n;
+  uint32_t workers;
+  rag_range_fn fn;
+  void *ctx;
+  volatile int *interrupted;
+} rag_loop_t;
+
+typedef struct {
+  rag_loop_t *loop;
+  uint32_t worker;
+} rag_worker_t;
+
+// Process the range of one worker chunk by chunk, stopping when interrupted
+static void run_range(rag_loop_t *loop, uint32_t worker) {
+  size_t begin = loop->n * worker / loop->workers;
+  size_t end = loop->n * (worker + 1) / loop->workers;
+
+  for (size_t chunk = begin; chunk < end && !*loop->interrupted; chunk += RAG_CHUNK) {
+    size_t chunk_end = end - chunk > RAG_CHUNK ? chunk + RAG_CHUNK : end;
+    loop->fn(loop->ctx, chunk, chunk_end, worker);
+  }
+}
+
+#ifdef HAVE_PTHREAD_H
+static void *run_worker(void *arg) {
+  rag_worker_t *worker = (rag_worker_t *)arg;
+  run_range(worker->loop, worker->worker);
+  return NULL;
+}
+#endif
+
+void rag_parallel_for(size_t n, uint32_t workers, rag_range_fn fn, void *ctx, volatile int *interrupted) {
+  if (workers < 1) workers = 1;
+  if (workers > RAG_MAX_THREADS) workers = RAG_MAX_THREADS;
+
+  rag_loop_t loop = {n, workers, fn, ctx, interrupted};
+
+#ifdef HAVE_PTHREAD_H
+  pthread_t threads[RAG_MAX_THREADS];
+  rag_worker_t args[RAG_MAX_THREADS];
+  int started[RAG_MAX_THREADS] = {0};
+
+  for (uint32_t w = 1; w < workers; ++w) {
+    args[w].loop = &loop;
+    args[w].worker = w;
+    started[w] = pthread_create(&threads[w], NULL, run_worker, &args[w]) == 0;
+  }
+
+  run_range(&loop, 0);
+
+  // Ranges whose thread couldn't be created are run here instead
+  for (uint32_t w = 1; w < workers; ++w) {
+    if (started[w]) {
+      pthread_join(threads[w], NULL);
+    } else {
+      run_range(&loop, w);
+    }
+  }
+#else
+  for (uint32_t w = 0; w < workers; ++w) {
+    run_range(&loop, w);
+  }
+#endif
+}
+
+// A computation handed to rb_thread_call_without_gvl
+typedef struct {
+  rag_nogvl_fn fn;
+  void *ctx;
+  volatile int interrupted;
+} rag_call_t;
+
+static void *call_without_gvl(void *arg) {
+  rag_call_t *call = (rag_call_t *)arg;
+  call->fn(call->ctx, &call->interrupted);
+  return NULL;
+}
+
+// Unblocking function: Ruby calls it to stop the computation (Thread#raise, Ctrl-C, exit)
+static void interrupt_call(void *arg) {
+  ((rag_call_t *)arg)->interrupted = 1;
+}
+
+static VALUE check_ints(VALUE unused) {
+  rb_thread_check_ints();
+  return Qnil;
+}
+
+void rag_without_gvl(size_t work, rag_nogvl_fn fn, void *ctx, int *busy) {
+  rag_call_t call = {fn, ctx, 0};
+
+  if (work < RAG_NOGVL_MIN_WORK) {
+    fn(ctx, &call.interrupted);
+    return;
+  }
+
+  if (busy) (*busy)++;
+  for (;;) {
+    call.interrupted = 0;
+    rb_thread_call_without_gvl(call_without_gvl, &call, interrupt_call, &call);
+    if (!call.interrupted) break;
+
+    // Let Ruby handle the interrupt (this raises for Thread#raise and Ctrl-C),
+    // then start over if it didn't. Other threads may run meanwhile: the object stays busy,
+    // so that the restarted computation sees the same rows its buffers were sized for
+    int state = 0;
+    rb_protect(check_ints, Qnil, &state);
+    if (state) {
+      if (busy) (*busy)--;
+      rb_jump_tag(state);
+    }
+  }
+  if (busy) (*busy)--;
+}
+
+// Module method: RagEmbeddings.threads
+// Returns the number of native threads used by batch computations
+static VALUE rag_get_threads(VALUE self) {
+  return UINT2NUM(rag_threads);
+}
+
+// Module method: RagEmbeddings.threads = 4
+// Splits matrix scans, index searches and k-means across this many native threads.
+// Results are the same whatever the number of threads
+static VALUE rag_set_threads(VALUE self, VALUE rb_threads) {
+  rag_check_main_ractor("threads");
+  long threads = NUM2LONG(rb_threads);
+
+  if (threads < 1 || threads > RAG_MAX_THREADS) {
+    rb_raise(rb_eArgError, "threads must be between 1 and %d", RAG_MAX_THREADS);
+  }
+
+  rag_threads = (uint32_t)threads;
+  return rb_threads;
+}
+
+void rag_init_parallel(VALUE mRag) {
+  rb_define_singleton_method(mRag, "threads", rag_get_threads, 0);
+  rb_define_singleton_method(mRag, "threads=", rag_set_threads, 1);
+}
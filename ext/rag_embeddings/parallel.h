@@ -0,0 +1,47 @@
+#ifndef RAG_EMBEDDINGS_PARALLEL_H
+#define RAG_EMBEDDINGS_PARALLEL_H
+
+#include <ruby.h>     // Ruby API
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For uint32_t
+
+// Batch computations (matrix scans, index searches, k-means) run without the GVL,
+// so that other Ruby threads keep going, and can be split across native threads.
+// Work is cut into contiguous ranges, one per worker, and every worker fills its own
+// partial result; callers merge them in worker order so the output never depends
+// on the number of threads.
+// Code running without the GVL must not touch Ruby objects or allocate with xmalloc:
+// buffers are allocated beforehand (ALLOCV, so that an interrupt that raises doesn't leak them)
+
+// Upper bound for RagEmbeddings.threads
+#define RAG_MAX_THREADS 64
+
+// Native threads used by batch computations (RagEmbeddings.threads), 1 by default
+extern uint32_t rag_threads;
+
+// Body of one range [begin, end) of a parallel loop, run by the given worker (0 <= worker < workers)
+typedef void (*rag_range_fn)(void *ctx, size_t begin, size_t end, uint32_t worker);
+
+// Body of a computation run without the GVL. It returns early once *interrupted is set
+// (rag_parallel_for checks it between chunks), and must be restartable:
+// after an interrupt that doesn't raise, it is run again from the start
+typedef void (*rag_nogvl_fn)(void *ctx, volatile int *interrupted);
+
+// Number of workers for a loop over n items: rag_threads at most,
+// and never so many that a worker gets only a handful of items
+uint32_t rag_workers_for(size_t n);
+
+// Run fn over [0, n) split into `workers` contiguous ranges, one native thread per range
+// (the calling thread takes the first). Falls back to running the ranges one after another
+// when threads are unavailable, which gives the same partial results
+void rag_parallel_for(size_t n, uint32_t workers, rag_range_fn fn, void *ctx, volatile int *interrupted);
+
+// Run fn without the GVL when `work` (an estimate of the float operations) is large enough
+// to be worth it, otherwise directly. Pending interrupts are handled afterwards, which may raise.
+// busy, when not NULL, is the counter of the object being read: it stays incremented while
+// the GVL is released and while an interrupt is handled before a restart,
+// so that methods modifying the object can refuse to run.
+// Frozen objects pass NULL: they can't change, and may be searched from several Ractors at once
+void rag_without_gvl(size_t work, rag_nogvl_fn fn, void *ctx, int *busy);
+
+#endif
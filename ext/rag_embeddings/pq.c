@@ -0,0 +1,481 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For uint8_t, int64_t
+#include <math.h>     // For sqrt
+#include <string.h>   // For memcpy
+#include "embedding.h"
+#include "simd.h"
+#include "topk.h"
+#include "kmeans.h"
+#include "serialize.h"
+#include "parallel.h"
+
+// Product quantization index
+// Vectors are split into m sub-vectors of dsub = dim / m values. Each subspace has its own
+// codebook of ksub centroids trained with k-means, and a row is stored as the m bytes
+// naming the closest centroid in every subspace.
+// Queries are answered with asymmetric distance computation (ADC): the query is kept in float,
+// a table of query/centroid scores is built once per subspace, and each row costs m lookups
+typedef struct {
+  uint32_t dim;       // Dimension of the indexed vectors
+  uint32_t m;         // Number of subspaces (bytes per row)
+  uint32_t ksub;      // Centroids per subspace, at most 256
+  uint32_t dsub;      // Values per subspace (dim / m)
+  uint8_t metric;     // rag_metric_t: cosine, dot, euclidean or squared_euclidean
+  float *centroids;   // m * ksub * dsub floats, subspace by subspace
+  size_t count;       // Rows currently stored
+  size_t capacity;    // Rows the buffers can hold before growing
+  uint8_t *codes;     // count * m codes
+  int64_t *ids;       // Id of each row (the Database row id)
+  int busy;           // Searches currently reading the codes without the GVL
+} pq_index_t;
+
+// Magic and version of the serialized index
+#define PQ_MAGIC "RGPQ"
+#define PQ_VERSION 1
+
+static VALUE cPQIndex;
+
+static void pq_free(void *ptr) {
+  if (ptr) {
+    pq_index_t *pq = (pq_index_t *)ptr;
+    xfree(pq->centroids);
+    xfree(pq->codes);
+    xfree(pq->ids);
+    xfree(pq);
+  }
+}
+
+static size_t pq_memsize(const void *ptr) {
+  const pq_index_t *pq = (const pq_index_t *)ptr;
+  if (!pq) return 0;
+
+  return sizeof(pq_index_t) +
+         (size_t)pq->m * pq->ksub * pq->dsub * sizeof(float) +
+         pq->capacity * ((size_t)pq->m + sizeof(int64_t));
+}
+
+static const rb_data_type_t pq_type = {
+  "RagEmbeddings/PQIndex",
+  {0, pq_free, pq_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+// Only metrics that decompose into a sum over subspaces have an ADC table
+static void pq_check_metric(rag_metric_t metric) {
+  if (metric != RAG_METRIC_COSINE && metric != RAG_METRIC_DOT &&
+      metric != RAG_METRIC_EUCLIDEAN && metric != RAG_METRIC_SQUARED_EUCLIDEAN) {
+    rb_raise(rb_eArgError, "PQIndex supports only the :cosine, :dot, :euclidean and :squared_euclidean metrics");
+  }
+}
+
+// Allocate an empty index with its codebooks (left uninitialized)
+static pq_index_t *pq_alloc(uint32_t dim, uint32_t m, uint32_t ksub, rag_metric_t metric) {
+  pq_index_t *pq = ZALLOC_N(pq_index_t, 1);
+  pq->dim = dim;
+  pq->m = m;
+  pq->ksub = ksub;
+  pq->dsub = dim / m;
+  pq->metric = (uint8_t)metric;
+  pq->centroids = ALLOC_N(float, (size_t)m * ksub * pq->dsub);
+  return pq;
+}
+
+// Make room for at least `needed` rows, doubling the capacity to amortize growth
+static void pq_reserve(pq_index_t *pq, size_t needed) {
+  if (needed <= pq->capacity) return;
+
+  size_t new_capacity = pq->capacity ? pq->capacity : 16;
+  while (new_capacity < needed) new_capacity *= 2;
+
+  pq->codes = xrealloc2(pq->codes, new_capacity, pq->m);
+  pq->ids = xrealloc2(pq->ids, new_capacity, sizeof(int64_t));
+  pq->capacity = new_capacity;
+}
+
+// Vector as seen by the codebooks: cosine indexes work on unit vectors,
+// so that the inner product of the reconstructions approximates the cosine
+static void pq_prepare(const pq_index_t *pq, const float *src, float *dst) {
+  memcpy(dst, src, (size_t)pq->dim * sizeof(float));
+
+  if (pq->metric == RAG_METRIC_COSINE) {
+    double norm = sqrt(rag_sum_squares(dst, pq->dim));
+    if (norm > 0.0) rag_scale(dst, pq->dim, (float)(1.0 / norm));
+  }
+}
+
+// Training rows regrouped by subspace, run without the GVL: every row is prepared
+// into the scratch row of its worker, then subspace j of row i is copied to
+// sub_rows + (j * n + i) * dsub, so that each codebook trains on a contiguous block
+typedef struct {
+  const pq_index_t *pq;
+  const float *rows;    // Rows of the training matrix
+  size_t n;
+  uint32_t workers;
+  float *scratch;       // One prepared row per worker
+  float *sub_rows;      // n * dim floats, subspace-major
+} pq_gather_t;
+
+static void pq_gather_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  pq_gather_t *gather = (pq_gather_t *)ctx;
+  const pq_index_t *pq = gather->pq;
+  float *prepared = gather->scratch + (size_t)worker * pq->dim;
+
+  for (size_t i = begin; i < end; ++i) {
+    pq_prepare(pq, gather->rows + i * pq->dim, prepared);
+    for (uint32_t j = 0; j < pq->m; ++j) {
+      memcpy(gather->sub_rows + ((size_t)j * gather->n + i) * pq->dsub, prepared + (size_t)j * pq->dsub,
+             pq->dsub * sizeof(float));
+    }
+  }
+}
+
+static void pq_gather_run(void *ctx, volatile int *interrupted) {
+  pq_gather_t *gather = (pq_gather_t *)ctx;
+  rag_parallel_for(gather->n, gather->workers, pq_gather_range, gather, interrupted);
+}
+
+// Train the m codebooks on n rows regrouped by pq_gather_run.
+// rag_kmeans runs each of them without the GVL
+static void pq_train_codebooks(pq_index_t *pq, const float *sub_rows, size_t n, int iterations, rag_rng_t *rng) {
+  for (uint32_t j = 0; j < pq->m; ++j) {
+    float *codebook = pq->centroids + (size_t)j * pq->ksub * pq->dsub;
+    rag_kmeans(sub_rows + (size_t)j * n * pq->dsub, n, pq->dsub, pq->ksub, iterations, 0, rng, codebook, NULL, NULL);
+  }
+}
+
+// Encode a prepared vector into m codes
+static void pq_encode(const pq_index_t *pq, const float *vector, uint8_t *codes) {
+  for (uint32_t j = 0; j < pq->m; ++j) {
+    const float *codebook = pq->centroids + (size_t)j * pq->ksub * pq->dsub;
+    codes[j] = (uint8_t)rag_kmeans_nearest(vector + (size_t)j * pq->dsub, codebook, pq->ksub, pq->dsub, 0);
+  }
+}
+
+// ADC tables: score of each subspace of the query against each centroid (m * ksub values).
+// Inner products for cosine/dot, squared distances for the euclidean metrics
+static void pq_tables(const pq_index_t *pq, const float *query, double *tables) {
+  int inner_product = pq->metric == RAG_METRIC_COSINE || pq->metric == RAG_METRIC_DOT;
+
+  for (uint32_t j = 0; j < pq->m; ++j) {
+    const float *sub_query = query + (size_t)j * pq->dsub;
+    const float *codebook = pq->centroids + (size_t)j * pq->ksub * pq->dsub;
+
+    for (uint32_t c = 0; c < pq->ksub; ++c) {
+      const float *centroid = codebook + (size_t)c * pq->dsub;
+      tables[(size_t)j * pq->ksub + c] = inner_product
+        ? rag_dot(sub_query, centroid, pq->dsub)
+        : rag_squared_l2(sub_query, centroid, pq->dsub);
+    }
+  }
+}
+
+// A scan of every row, run without the GVL: each row sums one table entry per subspace.
+// Every worker fills its own heap, merged into the first one at the end. Distances are negated for the heap
+typedef struct {
+  const pq_index_t *pq;
+  const float *query;
+  double *tables;       // ADC tables, m * ksub values
+  uint32_t workers;
+  rag_topk_t *heaps;    // One heap per worker
+} pq_search_t;
+
+static void pq_search_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  pq_search_t *search = (pq_search_t *)ctx;
+  const pq_index_t *pq = search->pq;
+  int is_distance = rag_metric_is_distance((rag_metric_t)pq->metric);
+
+  for (size_t r = begin; r < end; ++r) {
+    const uint8_t *codes = pq->codes + r * pq->m;
+    double score = 0.0;
+
+    for (uint32_t j = 0; j < pq->m; ++j) {
+      score += search->tables[(size_t)j * pq->ksub + codes[j]];
+    }
+
+    rag_topk_push(&search->heaps[worker], is_distance ? -score : score, r);
+  }
+}
+
+static void pq_search_run(void *ctx, volatile int *interrupted) {
+  pq_search_t *search = (pq_search_t *)ctx;
+
+  for (uint32_t w = 0; w < search->workers; ++w) {
+    rag_topk_clear(&search->heaps[w]);
+  }
+
+  pq_tables(search->pq, search->query, search->tables);
+  rag_parallel_for(search->pq->count, search->workers, pq_search_range, search, interrupted);
+  for (uint32_t w = 1; w < search->workers; ++w) {
+    rag_topk_merge(&search->heaps[0], &search->heaps[w]);
+  }
+}
+
+// Class method: RagEmbeddings::PQIndex.train(matrix, m: 8, ksub: 256, iterations: 25, seed: 0, metric: :cosine)
+// Trains the codebooks on the rows of an EmbeddingMatrix and returns an empty index.
+// dim must be a multiple of m, and the matrix needs at least ksub rows
+static VALUE pq_train(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_matrix, opts;
+  rb_scan_args(argc, argv, "1:", &rb_matrix, &opts);
+
+  VALUE kwargs[5] = {Qundef, Qundef, Qundef, Qundef, Qundef};
+  if (!NIL_P(opts)) {
+    ID kw_ids[5] = {rb_intern("m"), rb_intern("ksub"), rb_intern("iterations"), rb_intern("seed"), rb_intern("metric")};
+    rb_get_kwargs(opts, kw_ids, 0, 5, kwargs);
+  }
+
+  uint32_t m = rag_uint_option(kwargs[0], 8, "m");
+  uint32_t ksub = rag_uint_option(kwargs[1], 256, "ksub");
+  int iterations = (int)rag_uint_option(kwargs[2], 25, "iterations");
+  uint64_t seed = (kwargs[3] == Qundef || NIL_P(kwargs[3])) ? 0 : NUM2ULL(kwargs[3]);
+  rag_metric_t metric = kwargs[4] == Qundef ? RAG_METRIC_COSINE : rag_metric_from_value(kwargs[4]);
+  pq_check_metric(metric);
+
+  uint32_t dim;
+  size_t n;
+  int *busy;
+  const float *rows = rag_matrix_rows(rb_matrix, &dim, &n, &busy);
+
+  if (dim % m != 0) {
+    rb_raise(rb_eArgError, "Dimension %u is not a multiple of m (%u)", dim, m);
+  }
+  if (ksub > 256) {
+    rb_raise(rb_eArgError, "ksub must be at most 256");
+  }
+  if (n < ksub) {
+    rb_raise(rb_eArgError, "Training needs at least ksub (%u) rows, got %zu", ksub, n);
+  }
+
+  pq_index_t *pq = pq_alloc(dim, m, ksub, metric);
+  VALUE obj = TypedData_Wrap_Struct(klass, &pq_type, pq);
+
+  // The rows are copied without the GVL (the matrix is kept busy meanwhile), then training
+  // works on that private copy, so the matrix is free to change during k-means.
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  uint32_t workers = rag_workers_for(n);
+  VALUE buffer, scratch_buffer;
+  float *sub_rows = ALLOCV_N(float, buffer, n * dim);
+  float *scratch = ALLOCV_N(float, scratch_buffer, (size_t)workers * dim);
+
+  pq_gather_t gather = {pq, rows, n, workers, scratch, sub_rows};
+  rag_without_gvl(n * (size_t)dim, pq_gather_run, &gather, busy);
+  ALLOCV_END(scratch_buffer);
+
+  rag_rng_t rng;
+  rag_rng_seed(&rng, seed);
+  pq_train_codebooks(pq, sub_rows, n, iterations, &rng);
+  ALLOCV_END(buffer);
+
+  return obj;
+}
+
+// Instance method: index.add(id, array_blob_or_embedding)
+// Encodes a row into m bytes and stores it under the given id. Returns self
+static VALUE pq_add(VALUE self, VALUE rb_id, VALUE row) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+
+  int64_t id = NUM2LL(rb_id);
+  rb_check_frozen(self);
+  if (pq->busy) {
+    rb_raise(rb_eRuntimeError, "can't modify PQIndex during a search");
+  }
+
+  // ALLOCV keeps the buffer reachable by the GC, so a bad row that raises doesn't leak it
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)pq->dim);
+  float *prepared = values + pq->dim;
+
+  rag_row_to_floats(row, pq->dim, EMBEDDING_DTYPE_F32, values);
+  pq_prepare(pq, values, prepared);
+
+  pq_reserve(pq, pq->count + 1);
+  pq_encode(pq, prepared, pq->codes + pq->count * pq->m);
+  pq->ids[pq->count] = id;
+  pq->count++;
+
+  ALLOCV_END(buffer);
+  return self;
+}
+
+// Instance method: index.top_k(query_embedding, k)
+// Returns [[id, score], ...] for the k best rows, best first.
+// Scores are approximate: the query against the reconstructed rows
+static VALUE pq_top_k(VALUE self, VALUE query, VALUE rb_k) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)pq->dim);
+  float *prepared = values + pq->dim;
+  rag_row_to_floats(query, pq->dim, EMBEDDING_DTYPE_F32, values);
+
+  size_t k = (size_t)k_arg < pq->count ? (size_t)k_arg : pq->count;
+  if (k == 0) {
+    ALLOCV_END(buffer);
+    return rb_ary_new();
+  }
+
+  pq_prepare(pq, values, prepared);
+
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises during the scan
+  uint32_t workers = rag_workers_for(pq->count);
+  VALUE tables_buffer, heaps_buffer, entries_buffer;
+  double *tables = ALLOCV_N(double, tables_buffer, (size_t)pq->m * pq->ksub);
+  rag_topk_t *heaps = ALLOCV_N(rag_topk_t, heaps_buffer, workers);
+  rag_topk_entry_t *entries = ALLOCV_N(rag_topk_entry_t, entries_buffer, k * workers);
+  for (uint32_t w = 0; w < workers; ++w) {
+    rag_topk_init_with(&heaps[w], k, entries + w * k);
+  }
+
+  pq_search_t search = {pq, prepared, tables, workers, heaps};
+  rag_without_gvl(pq->count * (size_t)pq->m, pq_search_run, &search, OBJ_FROZEN(self) ? NULL : &pq->busy);
+
+  rag_topk_t topk = heaps[0];
+  rag_topk_sort(&topk);
+
+  int is_distance = rag_metric_is_distance((rag_metric_t)pq->metric);
+  if (pq->metric == RAG_METRIC_EUCLIDEAN) {
+    // The tables sum squared distances: take the root of the negated sums
+    for (size_t i = 0; i < topk.size; ++i) {
+      topk.entries[i].score = -sqrt(-topk.entries[i].score);
+    }
+  }
+
+  VALUE result = rag_topk_to_id_ary(&topk, pq->ids, is_distance);
+  ALLOCV_END(tables_buffer);
+  ALLOCV_END(heaps_buffer);
+  ALLOCV_END(entries_buffer);
+  ALLOCV_END(buffer);
+
+  RB_GC_GUARD(self);
+  return result;
+}
+
+// Instance method: index.size
+// Returns the number of rows stored in the index
+static VALUE pq_size(VALUE self) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+  return SIZET2NUM(pq->count);
+}
+
+// Instance method: index.dim
+static VALUE pq_dim(VALUE self) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+  return UINT2NUM(pq->dim);
+}
+
+// Instance method: index.m
+// Returns the number of subspaces, which is also the number of bytes per row
+static VALUE pq_m(VALUE self) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+  return UINT2NUM(pq->m);
+}
+
+// Instance method: index.ksub
+// Returns the number of centroids of each codebook
+static VALUE pq_ksub(VALUE self) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+  return UINT2NUM(pq->ksub);
+}
+
+// Instance method: index.metric
+static VALUE pq_metric(VALUE self) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+  return rag_metric_to_sym((rag_metric_t)pq->metric);
+}
+
+// Instance method: index.to_blob
+// Serializes the codebooks, the codes and the ids (little-endian), see PQIndex.from_blob
+static VALUE pq_to_blob(VALUE self) {
+  pq_index_t *pq;
+  TypedData_Get_Struct(self, pq_index_t, &pq_type, pq);
+
+  VALUE blob = rb_str_buf_new(0);
+  rag_write_bytes(blob, PQ_MAGIC, 4);
+  rag_write_u32(blob, PQ_VERSION);
+  rag_write_u32(blob, pq->dim);
+  rag_write_u32(blob, pq->m);
+  rag_write_u32(blob, pq->ksub);
+  rag_write_u32(blob, pq->metric);
+  rag_write_u64(blob, pq->count);
+  rag_write_floats(blob, pq->centroids, (size_t)pq->m * pq->ksub * pq->dsub);
+  rag_write_bytes(blob, pq->codes, pq->count * pq->m);
+  rag_write_i64s(blob, pq->ids, pq->count);
+
+  return blob;
+}
+
+// Class method: RagEmbeddings::PQIndex.from_blob(string)
+// Restores an index serialized with to_blob
+static VALUE pq_from_blob(VALUE klass, VALUE rb_blob) {
+  rag_reader_t reader;
+  rag_reader_init(&reader, rb_blob);
+  rag_read_header(&reader, PQ_MAGIC, PQ_VERSION);
+
+  uint32_t dim = rag_read_u32(&reader);
+  uint32_t m = rag_read_u32(&reader);
+  uint32_t ksub = rag_read_u32(&reader);
+  uint32_t metric = rag_read_u32(&reader);
+  uint64_t count = rag_read_u64(&reader);
+
+  rag_check_dimension((long)dim);
+  if (m == 0 || dim % m != 0 || ksub == 0 || ksub > 256 || metric > RAG_METRIC_ANGULAR) {
+    rb_raise(rb_eArgError, "Corrupted PQIndex header");
+  }
+  pq_check_metric((rag_metric_t)metric);
+
+  // The codebooks (ksub centroids of dsub floats in each of the m subspaces) must be in the blob
+  // before they are allocated, and the codes after them
+  size_t centroid_bytes = (size_t)ksub * dim * sizeof(float);
+  if (centroid_bytes > reader.left || count > (reader.left - centroid_bytes) / m) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+
+  pq_index_t *pq = pq_alloc(dim, m, ksub, (rag_metric_t)metric);
+  VALUE obj = TypedData_Wrap_Struct(klass, &pq_type, pq);
+
+  rag_read_floats(&reader, pq->centroids, (size_t)m * ksub * pq->dsub);
+
+  pq_reserve(pq, (size_t)count);
+  rag_read_bytes(&reader, pq->codes, (size_t)count * m);
+  rag_read_i64s(&reader, pq->ids, (size_t)count);
+  pq->count = (size_t)count;
+  rag_reader_finish(&reader);
+
+  // Codes must name existing centroids
+  for (size_t i = 0; i < pq->count * m; ++i) {
+    if (pq->codes[i] >= ksub) {
+      rb_raise(rb_eArgError, "Corrupted PQIndex codes");
+    }
+  }
+
+  return obj;
+}
+
+void rag_init_pq(VALUE mRag) {
+  cPQIndex = rb_define_class_under(mRag, "PQIndex", rb_cObject);
+  rb_undef_alloc_func(cPQIndex);
+
+  rb_define_singleton_method(cPQIndex, "train", pq_train, -1);
+  rb_define_singleton_method(cPQIndex, "from_blob", pq_from_blob, 1);
+
+  rb_define_method(cPQIndex, "add", pq_add, 2);
+  rb_define_method(cPQIndex, "top_k", pq_top_k, 2);
+  rb_define_method(cPQIndex, "size", pq_size, 0);
+  rb_define_method(cPQIndex, "dim", pq_dim, 0);
+  rb_define_method(cPQIndex, "m", pq_m, 0);
+  rb_define_method(cPQIndex, "ksub", pq_ksub, 0);
+  rb_define_method(cPQIndex, "metric", pq_metric, 0);
+  rb_define_method(cPQIndex, "to_blob", pq_to_blob, 0);
+}
@@ -0,0 +1,525 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For uint32_t, uint64_t
+#include <math.h>     // For sqrt, fabs
+#include <string.h>   // For memcpy, memset
+#include "embedding.h"
+#include "simd.h"
+#include "kmeans.h"
+#include "serialize.h"
+#include "parallel.h"
+
+// Linear projection to a lower dimension: y = W (x - mean)
+// W holds dim rows of input_dim values. PCA fits W to the principal components of a sample
+// (the eigenvectors of its covariance, by power iteration); the random projection draws W
+// from a seeded Gaussian, which roughly preserves distances without any training
+typedef struct {
+  uint32_t input_dim;   // Dimension of the embeddings projected
+  uint32_t dim;         // Dimension of the projected embeddings
+  uint8_t kind;         // projection_kind_t
+  float *mean;          // input_dim values subtracted first (zeros for a random projection)
+  float *components;    // dim * input_dim floats, one row per output value
+  float *variances;     // PCA: variance of the sample along each component (zeros otherwise)
+} projection_t;
+
+typedef enum {
+  PROJECTION_PCA = 0,
+  PROJECTION_RANDOM = 1
+} projection_kind_t;
+
+// Magic and version of the serialized projection
+#define PROJECTION_MAGIC "RGPJ"
+#define PROJECTION_VERSION 1
+
+// Power iteration stops once a component moves less than this between two iterations
+#define PCA_TOLERANCE 1e-10
+
+static VALUE cProjection;
+
+static void projection_free(void *ptr) {
+  if (ptr) {
+    projection_t *proj = (projection_t *)ptr;
+    xfree(proj->mean);
+    xfree(proj->components);
+    xfree(proj->variances);
+    xfree(proj);
+  }
+}
+
+static size_t projection_memsize(const void *ptr) {
+  const projection_t *proj = (const projection_t *)ptr;
+  if (!proj) return 0;
+
+  return sizeof(projection_t) +
+         ((size_t)proj->dim * proj->input_dim + proj->input_dim + proj->dim) * sizeof(float);
+}
+
+static const rb_data_type_t projection_type = {
+  "RagEmbeddings/Projection",
+  {0, projection_free, projection_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+// Allocate a projection with zeroed mean, components and variances
+static projection_t *projection_alloc(uint32_t input_dim, uint32_t dim, projection_kind_t kind) {
+  projection_t *proj = ZALLOC_N(projection_t, 1);
+  proj->input_dim = input_dim;
+  proj->dim = dim;
+  proj->kind = (uint8_t)kind;
+  proj->mean = ZALLOC_N(float, input_dim);
+  proj->components = ZALLOC_N(float, (size_t)dim * input_dim);
+  proj->variances = ZALLOC_N(float, dim);
+  return proj;
+}
+
+// Raise unless the output dimension is between 1 and the input dimension
+static void projection_check_dim(uint32_t input_dim, uint32_t dim) {
+  if (dim > input_dim) {
+    rb_raise(rb_eArgError, "Cannot project dimension %u to %u", input_dim, dim);
+  }
+}
+
+// Project one row of input_dim floats. centered is a scratch buffer of input_dim floats
+static void projection_apply_row(const projection_t *proj, const float *row, float *centered, float *dst) {
+  for (uint32_t j = 0; j < proj->input_dim; ++j) {
+    centered[j] = row[j] - proj->mean[j];
+  }
+  for (uint32_t c = 0; c < proj->dim; ++c) {
+    dst[c] = (float)rag_dot(proj->components + (size_t)c * proj->input_dim, centered, proj->input_dim);
+  }
+}
+
+// PCA fit, computed without the GVL.
+// The covariance matrix is built first (each worker owns a range of its rows),
+// then every component is found by power iteration on it, made orthogonal to the
+// components already found (deflation). Each value is computed by a single worker
+// in a fixed order, so the result never depends on the thread count
+typedef struct {
+  const float *rows;
+  size_t n;
+  uint32_t input_dim;
+  uint32_t dim;
+  int iterations;
+  rag_rng_t start;          // Generator state at the start, to replay a fit cut short by an interrupt
+  rag_rng_t rng;
+  uint32_t workers;
+  volatile int *interrupted;
+  float *mean;              // input_dim values
+  double *covariance;       // input_dim * input_dim values
+  double *vectors;          // dim * input_dim components found so far
+  double *values;           // Eigenvalue of each component
+  double *v;                // Current estimate of the component
+  double *w;                // Covariance times v
+} pca_t;
+
+// Rows of the covariance are filled by blocks, so that the block stays in cache
+// while the samples are streamed through
+#define PCA_BLOCK 16
+
+static void pca_covariance_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  pca_t *pca = (pca_t *)ctx;
+  uint32_t d = pca->input_dim;
+
+  for (size_t block = begin; block < end && !*pca->interrupted; block += PCA_BLOCK) {
+    size_t block_end = end - block > PCA_BLOCK ? block + PCA_BLOCK : end;
+
+    for (size_t s = 0; s < pca->n; ++s) {
+      const float *row = pca->rows + s * d;
+
+      for (size_t i = block; i < block_end; ++i) {
+        double centered_i = (double)row[i] - pca->mean[i];
+        double *cov = pca->covariance + i * d;
+
+        // Upper triangle only, mirrored afterwards
+        for (size_t j = i; j < d; ++j) {
+          cov[j] += centered_i * ((double)row[j] - pca->mean[j]);
+        }
+      }
+    }
+  }
+}
+
+static void pca_multiply_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  pca_t *pca = (pca_t *)ctx;
+  uint32_t d = pca->input_dim;
+
+  for (size_t i = begin; i < end; ++i) {
+    const double *cov = pca->covariance + i * d;
+    double sum = 0.0;
+    for (uint32_t j = 0; j < d; ++j) sum += cov[j] * pca->v[j];
+    pca->w[i] = sum;
+  }
+}
+
+// Remove from vector the parts along the first `found` components, then scale it
+// to unit length. Returns the length it had before scaling
+static double pca_orthonormalize(const pca_t *pca, double *vector, uint32_t found) {
+  uint32_t d = pca->input_dim;
+
+  for (uint32_t c = 0; c < found; ++c) {
+    const double *component = pca->vectors + (size_t)c * d;
+    double along = 0.0;
+    for (uint32_t j = 0; j < d; ++j) along += vector[j] * component[j];
+    for (uint32_t j = 0; j < d; ++j) vector[j] -= along * component[j];
+  }
+
+  double norm = 0.0;
+  for (uint32_t j = 0; j < d; ++j) norm += vector[j] * vector[j];
+  norm = sqrt(norm);
+  if (norm > 0.0) {
+    for (uint32_t j = 0; j < d; ++j) vector[j] /= norm;
+  }
+  return norm;
+}
+
+static void pca_run(void *ctx, volatile int *interrupted) {
+  pca_t *pca = (pca_t *)ctx;
+  uint32_t d = pca->input_dim;
+
+  pca->rng = pca->start;
+  pca->interrupted = interrupted;
+
+  memset(pca->covariance, 0, (size_t)d * d * sizeof(double));
+  rag_parallel_for(d, pca->workers, pca_covariance_range, pca, interrupted);
+  if (*interrupted) return;
+
+  for (uint32_t i = 0; i < d; ++i) {
+    for (uint32_t j = i; j < d; ++j) {
+      double value = pca->covariance[(size_t)i * d + j] / (double)(pca->n - 1);
+      pca->covariance[(size_t)i * d + j] = value;
+      pca->covariance[(size_t)j * d + i] = value;
+    }
+  }
+
+  for (uint32_t c = 0; c < pca->dim && !*interrupted; ++c) {
+    // Random start, orthogonal to the components already found
+    for (uint32_t j = 0; j < d; ++j) pca->v[j] = rag_rng_gaussian(&pca->rng);
+    pca_orthonormalize(pca, pca->v, c);
+
+    double eigenvalue = 0.0;
+    for (int iteration = 0; iteration < pca->iterations; ++iteration) {
+      rag_parallel_for(d, pca->workers, pca_multiply_range, pca, interrupted);
+      if (*interrupted) return;
+
+      // The rest of the variance lies along the components already found: keep the start
+      eigenvalue = pca_orthonormalize(pca, pca->w, c);
+      if (eigenvalue == 0.0) break;
+
+      double agreement = 0.0;
+      for (uint32_t j = 0; j < d; ++j) agreement += pca->v[j] * pca->w[j];
+      memcpy(pca->v, pca->w, (size_t)d * sizeof(double));
+      if (1.0 - fabs(agreement) < PCA_TOLERANCE) break;
+    }
+
+    // Eigenvectors have no sign: make the largest value positive, for stable output
+    uint32_t largest = 0;
+    for (uint32_t j = 1; j < d; ++j) {
+      if (fabs(pca->v[j]) > fabs(pca->v[largest])) largest = j;
+    }
+    double sign = pca->v[largest] < 0.0 ? -1.0 : 1.0;
+
+    double *component = pca->vectors + (size_t)c * d;
+    for (uint32_t j = 0; j < d; ++j) component[j] = sign * pca->v[j];
+    pca->values[c] = eigenvalue;
+  }
+}
+
+// Read the dim: and seed: keywords shared by pca and random
+static void projection_options(VALUE opts, uint32_t *dim, int *iterations, uint64_t *seed) {
+  VALUE kwargs[3] = {Qundef, Qundef, Qundef};
+  ID kw_ids[3] = {rb_intern("dim"), rb_intern("seed"), rb_intern("iterations")};
+  rb_get_kwargs(NIL_P(opts) ? rb_hash_new() : opts, kw_ids, 1, iterations ? 2 : 1, kwargs);
+
+  *dim = rag_uint_option(kwargs[0], 0, "dim");
+  *seed = (kwargs[1] == Qundef || NIL_P(kwargs[1])) ? 0 : NUM2ULL(kwargs[1]);
+  if (iterations) *iterations = (int)rag_uint_option(kwargs[2], 100, "iterations");
+}
+
+// Class method: RagEmbeddings::Projection.pca(embeddings, dim:, iterations: 100, seed: 0)
+// Fits a PCA on a list of embeddings (Embedding objects, arrays or blobs) or an EmbeddingMatrix:
+// the projection keeps the dim directions along which the sample varies the most,
+// ordered from the largest variance. Needs at least 2 embeddings.
+// iterations bounds the power iterations per component; seed picks their starting vectors
+static VALUE projection_pca(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_embeddings, opts;
+  rb_scan_args(argc, argv, "1:", &rb_embeddings, &opts);
+
+  uint32_t dim;
+  int iterations;
+  uint64_t seed;
+  projection_options(opts, &dim, &iterations, &seed);
+
+  // Lists are copied into a contiguous matrix first
+  VALUE matrix = rb_embeddings;
+  if (RB_TYPE_P(rb_embeddings, T_ARRAY)) {
+    matrix = rb_funcall(rag_cEmbeddingMatrix, rb_intern("from_arrays"), 1, rb_embeddings);
+  }
+
+  uint32_t input_dim;
+  size_t n;
+  int *busy;
+  const float *rows = rag_matrix_rows(matrix, &input_dim, &n, &busy);
+
+  projection_check_dim(input_dim, dim);
+  if (n < 2) {
+    rb_raise(rb_eArgError, "PCA needs at least 2 embeddings, got %zu", n);
+  }
+
+  projection_t *proj = projection_alloc(input_dim, dim, PROJECTION_PCA);
+  VALUE obj = TypedData_Wrap_Struct(klass, &projection_type, proj);
+
+  // The mean is computed under the GVL: it is cheap next to the covariance
+  VALUE sums_buffer;
+  double *sums = ALLOCV_N(double, sums_buffer, input_dim);
+  memset(sums, 0, (size_t)input_dim * sizeof(double));
+  for (size_t s = 0; s < n; ++s) {
+    for (uint32_t j = 0; j < input_dim; ++j) sums[j] += rows[s * input_dim + j];
+  }
+  for (uint32_t j = 0; j < input_dim; ++j) proj->mean[j] = (float)(sums[j] / (double)n);
+  ALLOCV_END(sums_buffer);
+
+  pca_t pca = {0};
+  pca.rows = rows;
+  pca.n = n;
+  pca.input_dim = input_dim;
+  pca.dim = dim;
+  pca.iterations = iterations;
+  rag_rng_seed(&pca.start, seed);
+  pca.mean = proj->mean;
+
+  // Every row of the covariance costs input_dim operations per pass over it:
+  // workers are sized on the whole matrix rather than on its rows
+  pca.workers = rag_workers_for((size_t)input_dim * input_dim);
+
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  VALUE covariance_buffer, vectors_buffer;
+  pca.covariance = ALLOCV_N(double, covariance_buffer, (size_t)input_dim * input_dim);
+  double *vectors = ALLOCV_N(double, vectors_buffer, ((size_t)dim + 2) * input_dim + dim);
+  pca.vectors = vectors;
+  pca.v = vectors + (size_t)dim * input_dim;
+  pca.w = pca.v + input_dim;
+  pca.values = pca.w + input_dim;
+
+  rag_without_gvl(n * input_dim * (size_t)input_dim, pca_run, &pca, busy);
+
+  for (size_t i = 0; i < (size_t)dim * input_dim; ++i) {
+    proj->components[i] = (float)pca.vectors[i];
+  }
+  for (uint32_t c = 0; c < dim; ++c) {
+    proj->variances[c] = (float)pca.values[c];
+  }
+
+  ALLOCV_END(covariance_buffer);
+  ALLOCV_END(vectors_buffer);
+
+  // Keep the matrix alive until the rows are no longer read
+  RB_GC_GUARD(matrix);
+  return obj;
+}
+
+// Class method: RagEmbeddings::Projection.random(input_dim, dim:, seed: 0)
+// Gaussian random projection from input_dim to dim values: every weight is drawn
+// from N(0, 1 / dim), so that lengths and distances are preserved on average.
+// Needs no sample; the same seed always gives the same projection
+static VALUE projection_random(int argc, VALUE *argv, VALUE klass) {
+  VALUE rb_input_dim, opts;
+  rb_scan_args(argc, argv, "1:", &rb_input_dim, &opts);
+
+  uint32_t dim;
+  uint64_t seed;
+  projection_options(opts, &dim, NULL, &seed);
+
+  long input_dim = NUM2LONG(rb_input_dim);
+  rag_check_dimension(input_dim);
+  projection_check_dim((uint32_t)input_dim, dim);
+
+  projection_t *proj = projection_alloc((uint32_t)input_dim, dim, PROJECTION_RANDOM);
+  VALUE obj = TypedData_Wrap_Struct(klass, &projection_type, proj);
+
+  rag_rng_t rng;
+  rag_rng_seed(&rng, seed);
+  double scale = 1.0 / sqrt((double)dim);
+  for (size_t i = 0; i < (size_t)dim * proj->input_dim; ++i) {
+    proj->components[i] = (float)(rag_rng_gaussian(&rng) * scale);
+  }
+
+  return obj;
+}
+
+// A projection of every row of a matrix, run without the GVL
+typedef struct {
+  const projection_t *proj;
+  const float *rows;
+  float *output;
+  float *scratch;       // input_dim floats per worker
+} projection_matrix_t;
+
+static void projection_matrix_range(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  projection_matrix_t *job = (projection_matrix_t *)ctx;
+  const projection_t *proj = job->proj;
+  float *centered = job->scratch + (size_t)worker * proj->input_dim;
+
+  for (size_t r = begin; r < end; ++r) {
+    projection_apply_row(proj, job->rows + r * proj->input_dim, centered, job->output + r * proj->dim);
+  }
+}
+
+typedef struct {
+  projection_matrix_t job;
+  size_t n;
+  uint32_t workers;
+} projection_apply_t;
+
+static void projection_apply_run(void *ctx, volatile int *interrupted) {
+  projection_apply_t *apply = (projection_apply_t *)ctx;
+  rag_parallel_for(apply->n, apply->workers, projection_matrix_range, &apply->job, interrupted);
+}
+
+// Instance method: projection.apply(embedding) or projection.apply(matrix)
+// Projects an Embedding (or a float array or blob) to a new float32 Embedding of dim values,
+// or every row of an EmbeddingMatrix to a new EmbeddingMatrix
+static VALUE projection_apply(VALUE self, VALUE input) {
+  projection_t *proj;
+  TypedData_Get_Struct(self, projection_t, &projection_type, proj);
+
+  if (rb_obj_is_kind_of(input, rag_cEmbeddingMatrix)) {
+    uint32_t input_dim;
+    size_t n;
+    int *busy;
+    const float *rows = rag_matrix_rows(input, &input_dim, &n, &busy);
+    if (input_dim != proj->input_dim) {
+      rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", proj->input_dim, input_dim);
+    }
+
+    float *output;
+    VALUE result = rag_matrix_new(proj->dim, n, &output);
+
+    projection_apply_t apply;
+    apply.n = n;
+    apply.workers = rag_workers_for(n);
+
+    // ALLOCV keeps the buffer reachable by the GC if an interrupt raises
+    VALUE scratch_buffer;
+    float *scratch = ALLOCV_N(float, scratch_buffer, (size_t)apply.workers * proj->input_dim);
+    apply.job = (projection_matrix_t){proj, rows, output, scratch};
+    rag_without_gvl(n * proj->dim * (size_t)proj->input_dim, projection_apply_run, &apply, busy);
+    ALLOCV_END(scratch_buffer);
+
+    RB_GC_GUARD(input);
+    RB_GC_GUARD(self);
+    return result;
+  }
+
+  VALUE buffer;
+  float *values = ALLOCV_N(float, buffer, 2 * (size_t)proj->input_dim);
+  rag_row_to_floats(input, proj->input_dim, EMBEDDING_DTYPE_F32, values);
+
+  embedding_t *emb = rag_embedding_alloc(proj->dim, EMBEDDING_DTYPE_F32);
+  projection_apply_row(proj, values, values + proj->input_dim, emb->values);
+  ALLOCV_END(buffer);
+
+  return TypedData_Wrap_Struct(rag_cEmbedding, &rag_embedding_type, emb);
+}
+
+// Instance method: projection.input_dim
+// Returns the dimension of the embeddings it projects
+static VALUE projection_input_dim(VALUE self) {
+  projection_t *proj;
+  TypedData_Get_Struct(self, projection_t, &projection_type, proj);
+  return UINT2NUM(proj->input_dim);
+}
+
+// Instance method: projection.dim
+// Returns the dimension of the projected embeddings
+static VALUE projection_dim(VALUE self) {
+  projection_t *proj;
+  TypedData_Get_Struct(self, projection_t, &projection_type, proj);
+  return UINT2NUM(proj->dim);
+}
+
+// Instance method: projection.kind
+// Returns :pca or :random
+static VALUE projection_kind(VALUE self) {
+  projection_t *proj;
+  TypedData_Get_Struct(self, projection_t, &projection_type, proj);
+  return ID2SYM(rb_intern(proj->kind == PROJECTION_PCA ? "pca" : "random"));
+}
+
+// Instance method: projection.explained_variance
+// PCA: the variance of the sample along each component, largest first. nil for a random projection
+static VALUE projection_explained_variance(VALUE self) {
+  projection_t *proj;
+  TypedData_Get_Struct(self, projection_t, &projection_type, proj);
+  if (proj->kind != PROJECTION_PCA) return Qnil;
+
+  VALUE arr = rb_ary_new_capa(proj->dim);
+  for (uint32_t c = 0; c < proj->dim; ++c) {
+    rb_ary_store(arr, c, DBL2NUM(proj->variances[c]));
+  }
+  return arr;
+}
+
+// Instance method: projection.to_blob
+// Serializes the mean, the components and the variances (little-endian), see Projection.from_blob
+static VALUE projection_to_blob(VALUE self) {
+  projection_t *proj;
+  TypedData_Get_Struct(self, projection_t, &projection_type, proj);
+
+  VALUE blob = rb_str_buf_new(0);
+  rag_write_bytes(blob, PROJECTION_MAGIC, 4);
+  rag_write_u32(blob, PROJECTION_VERSION);
+  rag_write_u32(blob, proj->input_dim);
+  rag_write_u32(blob, proj->dim);
+  rag_write_u32(blob, proj->kind);
+  rag_write_floats(blob, proj->mean, proj->input_dim);
+  rag_write_floats(blob, proj->components, (size_t)proj->dim * proj->input_dim);
+  rag_write_floats(blob, proj->variances, proj->dim);
+
+  return blob;
+}
+
+// Class method: RagEmbeddings::Projection.from_blob(string)
+// Restores a projection serialized with to_blob
+static VALUE projection_from_blob(VALUE klass, VALUE rb_blob) {
+  rag_reader_t reader;
+  rag_reader_init(&reader, rb_blob);
+  rag_read_header(&reader, PROJECTION_MAGIC, PROJECTION_VERSION);
+
+  uint32_t input_dim = rag_read_u32(&reader);
+  uint32_t dim = rag_read_u32(&reader);
+  uint32_t kind = rag_read_u32(&reader);
+
+  rag_check_dimension((long)input_dim);
+  if (dim == 0 || dim > input_dim || kind > PROJECTION_RANDOM) {
+    rb_raise(rb_eArgError, "Corrupted Projection header");
+  }
+  if ((size_t)dim * input_dim > reader.left / sizeof(float)) {
+    rb_raise(rb_eArgError, "Truncated projection data");
+  }
+
+  projection_t *proj = projection_alloc(input_dim, dim, (projection_kind_t)kind);
+  VALUE obj = TypedData_Wrap_Struct(klass, &projection_type, proj);
+
+  rag_read_floats(&reader, proj->mean, input_dim);
+  rag_read_floats(&reader, proj->components, (size_t)dim * input_dim);
+  rag_read_floats(&reader, proj->variances, dim);
+  rag_reader_finish(&reader);
+
+  return obj;
+}
+
+void rag_init_projection(VALUE mRag) {
+  cProjection = rb_define_class_under(mRag, "Projection", rb_cObject);
+  rb_undef_alloc_func(cProjection);
+
+  rb_define_singleton_method(cProjection, "pca", projection_pca, -1);
+  rb_define_singleton_method(cProjection, "random", projection_random, -1);
+  rb_define_singleton_method(cProjection, "from_blob", projection_from_blob, 1);
+
+  rb_define_method(cProjection, "apply", projection_apply, 1);
+  rb_define_method(cProjection, "input_dim", projection_input_dim, 0);
+  rb_define_method(cProjection, "dim", projection_dim, 0);
+  rb_define_method(cProjection, "kind", projection_kind, 0);
+  rb_define_method(cProjection, "explained_variance", projection_explained_variance, 0);
+  rb_define_method(cProjection, "to_blob", projection_to_blob, 0);
+}
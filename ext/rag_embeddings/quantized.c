@@ -0,0 +1,489 @@
+#include <ruby.h>     // Ruby API
+#include <stdint.h>   // For int8_t, int64_t
+#include <math.h>     // For sqrt, lrintf
+#include <string.h>   // For memcpy
+#include "embedding.h"
+#include "parallel.h"
+#include "simd.h"
+#include "topk.h"
+
+// Int8 scalar quantization of an embedding
+// Each value is approximated as offset + scale * code, with code in [-127, 127].
+// offset and scale are calibrated per vector from its minimum and maximum,
+// so the whole range of the vector is covered with 255 levels
+typedef struct {
+  uint32_t dim;          // Dimension of the embedding vector
+  float scale;           // Distance between two consecutive codes
+  float offset;          // Value represented by code 0 (midpoint of the range)
+  int64_t code_sum;      // Sum of the codes, used to compute dot products and norms
+  int64_t code_sq_sum;   // Sum of the squared codes
+  int8_t codes[];        // One code per dimension
+} quantized_embedding_t;
+
+// Bytes of the header of a quantized blob: scale and offset as little-endian float32
+#define QUANTIZED_BLOB_HEADER (2 * sizeof(float))
+
+static VALUE cQuantizedEmbedding;
+
+static void quantized_free(void *ptr) {
+  if (ptr) {
+    xfree(ptr);
+  }
+}
+
+static size_t quantized_memsize(const void *ptr) {
+  const quantized_embedding_t *q = (const quantized_embedding_t *)ptr;
+  return q ? sizeof(quantized_embedding_t) + q->dim : 0;
+}
+
+static const rb_data_type_t quantized_type = {
+  "RagEmbeddings/QuantizedEmbedding",
+  {0, quantized_free, quantized_memsize,},
+  0, 0,
+  RUBY_TYPED_FREE_IMMEDIATELY | RUBY_TYPED_FROZEN_SHAREABLE
+};
+
+static quantized_embedding_t *quantized_alloc(uint32_t dim) {
+  quantized_embedding_t *q = xmalloc(sizeof(quantized_embedding_t) + dim);
+  q->dim = dim;
+  return q;
+}
+
+// Recompute the code sums after the codes have been filled
+static void quantized_update_sums(quantized_embedding_t *q) {
+  int64_t sum = 0, sq_sum = 0;
+  for (uint32_t i = 0; i < q->dim; ++i) {
+    sum += q->codes[i];
+    sq_sum += (int64_t)q->codes[i] * q->codes[i];
+  }
+  q->code_sum = sum;
+  q->code_sq_sum = sq_sum;
+}
+
+// Squared norm of the dequantized vector, from the stored sums:
+// sum((o + s*c)^2) = n*o^2 + 2*o*s*sum(c) + s^2*sum(c^2)
+static double quantized_norm_sq(const quantized_embedding_t *q) {
+  double o = q->offset, s = q->scale;
+  return q->dim * o * o + 2.0 * o * s * (double)q->code_sum + s * s * (double)q->code_sq_sum;
+}
+
+// Dot product of the codes of two quantized vectors, in integer arithmetic
+static int64_t int8_dot(const int8_t *a, const int8_t *b, size_t n) {
+  int64_t sum = 0;
+  for (size_t i = 0; i < n; ++i) {
+    sum += (int32_t)a[i] * b[i];
+  }
+  return sum;
+}
+
+// Approximate dot product between two quantized vectors:
+// sum((oa + sa*ca) * (ob + sb*cb)) = n*oa*ob + oa*sb*sum(cb) + ob*sa*sum(ca) + sa*sb*sum(ca*cb)
+static double quantized_dot(const quantized_embedding_t *a, const quantized_embedding_t *b) {
+  double oa = a->offset, sa = a->scale, ob = b->offset, sb = b->scale;
+  double code_dot = (double)int8_dot(a->codes, b->codes, a->dim);
+
+  return a->dim * oa * ob + oa * sb * (double)b->code_sum + ob * sa * (double)a->code_sum + sa * sb * code_dot;
+}
+
+// Approximate dot product between a float query and quantized codes:
+// sum(f * (o + s*c)) = o*sum(f) + s*sum(f*c)
+// The code sums of the row are accumulated in the same pass
+static double float_quantized_dot(const float *query, double query_sum, const int8_t *codes,
+                                  float scale, float offset, size_t n,
+                                  int64_t *code_sum, int64_t *code_sq_sum) {
+  double mixed = 0.0;
+  int64_t sum = 0, sq_sum = 0;
+
+  for (size_t i = 0; i < n; ++i) {
+    int32_t c = codes[i];
+    mixed += (double)query[i] * c;
+    sum += c;
+    sq_sum += c * c;
+  }
+
+  *code_sum = sum;
+  *code_sq_sum = sq_sum;
+  return offset * query_sum + scale * mixed;
+}
+
+// Cosine from a dot product and the two squared norms, clamped to [-1, 1]
+static double cosine_from_dot(double dot, double norm_a_sq, double norm_b_sq) {
+  if (norm_a_sq <= 0.0 || norm_b_sq <= 0.0) {
+    return 0.0;
+  }
+
+  double similarity = dot / sqrt(norm_a_sq * norm_b_sq);
+  if (similarity > 1.0) similarity = 1.0;
+  if (similarity < -1.0) similarity = -1.0;
+
+  return similarity;
+}
+
+// Only cosine and dot product have a quantized kernel
+static rag_metric_t quantized_metric_from_opts(VALUE opts) {
+  rag_metric_t metric = rag_metric_from_opts(opts);
+
+  if (metric != RAG_METRIC_COSINE && metric != RAG_METRIC_DOT) {
+    rb_raise(rb_eArgError, "Quantized embeddings support only the :cosine and :dot metrics");
+  }
+
+  return metric;
+}
+
+// Instance method: embedding.quantize(:int8)
+// Returns a QuantizedEmbedding with one int8 code per dimension
+// and a scale/offset calibrated on the range of this vector.
+// quantize(:binary) returns a BinaryEmbedding instead (see binary.c)
+static VALUE embedding_quantize(int argc, VALUE *argv, VALUE self) {
+  VALUE rb_kind;
+  rb_scan_args(argc, argv, "01", &rb_kind);
+
+  if (!NIL_P(rb_kind)) {
+    ID kind = rb_to_id(rb_kind);
+    if (kind == rb_intern("binary")) {
+      return rag_embedding_binarize(self);
+    }
+    if (kind != rb_intern("int8")) {
+      rb_raise(rb_eArgError, "Unknown quantization: %s", rb_id2name(kind));
+    }
+  }
+
+  embedding_t *emb;
+  TypedData_Get_Struct(self, embedding_t, &rag_embedding_type, emb);
+
+  float *scratch = NULL;
+  const float *values = rag_embedding_floats(emb, &scratch);
+
+  // Calibrate the range on the vector itself
+  float min = values[0], max = values[0];
+  for (uint32_t i = 1; i < emb->dim; ++i) {
+    if (values[i] < min) min = values[i];
+    if (values[i] > max) max = values[i];
+  }
+
+  quantized_embedding_t *q = quantized_alloc(emb->dim);
+  q->offset = (float)(((double)min + max) / 2.0);
+  q->scale = (float)(((double)max - min) / 254.0);
+
+  for (uint32_t i = 0; i < emb->dim; ++i) {
+    long code = q->scale > 0.0f ? lrintf((values[i] - q->offset) / q->scale) : 0;
+    if (code > 127) code = 127;
+    if (code < -127) code = -127;
+    q->codes[i] = (int8_t)code;
+  }
+
+  quantized_update_sums(q);
+  xfree(scratch);
+
+  return TypedData_Wrap_Struct(cQuantizedEmbedding, &quantized_type, q);
+}
+
+// Class method: RagEmbeddings::QuantizedEmbedding.from_blob(string)
+// Restores a quantized embedding from the layout produced by to_blob
+static VALUE quantized_from_blob(VALUE klass, VALUE rb_blob) {
+  StringValue(rb_blob);
+
+  long byte_len = RSTRING_LEN(rb_blob);
+  if (byte_len <= (long)QUANTIZED_BLOB_HEADER) {
+    rb_raise(rb_eArgError, "Blob too short for a quantized embedding: %ld bytes", byte_len);
+  }
+
+  long dim = byte_len - (long)QUANTIZED_BLOB_HEADER;
+  rag_check_dimension(dim);
+
+  const char *src = RSTRING_PTR(rb_blob);
+  float header[2];
+  rag_floats_from_le_bytes(header, src, 2);
+
+  quantized_embedding_t *q = quantized_alloc((uint32_t)dim);
+  q->scale = header[0];
+  q->offset = header[1];
+  memcpy(q->codes, src + QUANTIZED_BLOB_HEADER, (size_t)dim);
+  quantized_update_sums(q);
+
+  return TypedData_Wrap_Struct(klass, &quantized_type, q);
+}
+
+// Instance method: quantized.to_blob
+// Packs scale and offset (little-endian float32) followed by the int8 codes,
+// 8 + dim bytes instead of 4 * dim for a float32 blob
+static VALUE quantized_to_blob(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+
+  VALUE blob = rb_str_new(NULL, (long)(QUANTIZED_BLOB_HEADER + q->dim));
+  char *dst = RSTRING_PTR(blob);
+  float header[2] = { q->scale, q->offset };
+
+  rag_floats_to_le_bytes(dst, header, 2);
+  memcpy(dst + QUANTIZED_BLOB_HEADER, q->codes, q->dim);
+
+  return blob;
+}
+
+// Instance method: quantized.dim
+static VALUE quantized_dim(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+  return UINT2NUM(q->dim);
+}
+
+// Instance method: quantized.scale
+static VALUE quantized_scale(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+  return DBL2NUM(q->scale);
+}
+
+// Instance method: quantized.offset
+static VALUE quantized_offset(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+  return DBL2NUM(q->offset);
+}
+
+// Instance method: quantized.codes
+// Returns the int8 codes as a Ruby array of integers
+static VALUE quantized_codes(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+
+  VALUE arr = rb_ary_new_capa(q->dim);
+  for (uint32_t i = 0; i < q->dim; ++i) {
+    rb_ary_store(arr, i, INT2FIX(q->codes[i]));
+  }
+  return arr;
+}
+
+// Instance method: quantized.dequantize
+// Returns the approximated float32 Embedding
+static VALUE quantized_dequantize(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+
+  embedding_t *emb = rag_embedding_alloc(q->dim, EMBEDDING_DTYPE_F32);
+  for (uint32_t i = 0; i < q->dim; ++i) {
+    emb->values[i] = q->offset + q->scale * q->codes[i];
+  }
+
+  return TypedData_Wrap_Struct(rag_cEmbedding, &rag_embedding_type, emb);
+}
+
+// Instance method: quantized.to_a
+// Returns the approximated values as a Ruby array
+static VALUE quantized_to_a(VALUE self) {
+  quantized_embedding_t *q;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, q);
+
+  VALUE arr = rb_ary_new_capa(q->dim);
+  for (uint32_t i = 0; i < q->dim; ++i) {
+    rb_ary_store(arr, i, DBL2NUM(q->offset + q->scale * q->codes[i]));
+  }
+  return arr;
+}
+
+// Approximate dot product and squared norms between a quantized vector and
+// either another QuantizedEmbedding or a float Embedding
+static void quantized_compare(VALUE self, VALUE other, double *dot, double *norm_a_sq, double *norm_b_sq) {
+  quantized_embedding_t *a;
+  TypedData_Get_Struct(self, quantized_embedding_t, &quantized_type, a);
+
+  if (rb_typeddata_is_kind_of(other, &quantized_type)) {
+    quantized_embedding_t *b = RTYPEDDATA_DATA(other);
+    if (a->dim != b->dim) {
+      rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", a->dim, b->dim);
+    }
+
+    *dot = quantized_dot(a, b);
+    *norm_a_sq = quantized_norm_sq(a);
+    *norm_b_sq = quantized_norm_sq(b);
+    return;
+  }
+
+  embedding_t *b;
+  TypedData_Get_Struct(other, embedding_t, &rag_embedding_type, b);
+  if (a->dim != b->dim) {
+    rb_raise(rb_eArgError, "Dimension mismatch: %u vs %u", a->dim, b->dim);
+  }
+
+  float *scratch = NULL;
+  const float *values = rag_embedding_floats(b, &scratch);
+
+  double query_sum = 0.0;
+  for (uint32_t i = 0; i < b->dim; ++i) {
+    query_sum += values[i];
+  }
+
+  int64_t code_sum, code_sq_sum;
+  *dot = float_quantized_dot(values, query_sum, a->codes, a->scale, a->offset, a->dim, &code_sum, &code_sq_sum);
+  *norm_a_sq = quantized_norm_sq(a);
+  *norm_b_sq = rag_sum_squares(values, b->dim);
+
+  xfree(scratch);
+}
+
+// Instance method: quantized.cosine_similarity(other)
+// Approximate cosine similarity with another QuantizedEmbedding or a float Embedding
+static VALUE quantized_cosine_similarity(VALUE self, VALUE other) {
+  double dot, norm_a_sq, norm_b_sq;
+  quantized_compare(self, other, &dot, &norm_a_sq, &norm_b_sq);
+  return DBL2NUM(cosine_from_dot(dot, norm_a_sq, norm_b_sq));
+}
+
+// Instance method: quantized.dot_product(other)
+// Approximate dot product with another QuantizedEmbedding or a float Embedding
+static VALUE quantized_dot_product(VALUE self, VALUE other) {
+  double dot, norm_a_sq, norm_b_sq;
+  quantized_compare(self, other, &dot, &norm_a_sq, &norm_b_sq);
+  return DBL2NUM(dot);
+}
+
+// A top_k scan over quantized rows, run without the GVL.
+// The rows are copied out of their blobs beforehand, so that other threads may
+// change the Ruby strings meanwhile. Every worker fills its own heap, merged into the first one at the end
+typedef struct {
+  const float *query;
+  double query_sum;
+  double query_norm_sq;
+  rag_metric_t metric;
+  uint32_t dim;
+  size_t count;
+  const float *headers;   // Scale and offset of each row
+  const int8_t *codes;    // dim codes per row
+  uint32_t workers;
+  rag_topk_t *heaps;      // Best k rows, one heap per worker
+} quantized_search_t;
+
+// Score rows [begin, end)
+static void quantized_search_rows(void *ctx, size_t begin, size_t end, uint32_t worker) {
+  quantized_search_t *search = (quantized_search_t *)ctx;
+
+  for (size_t r = begin; r < end; ++r) {
+    double s = search->headers[2 * r], o = search->headers[2 * r + 1];
+
+    int64_t code_sum, code_sq_sum;
+    double dot = float_quantized_dot(search->query, search->query_sum, search->codes + r * search->dim,
+                                     (float)s, (float)o, search->dim, &code_sum, &code_sq_sum);
+
+    double score = dot;
+    if (search->metric == RAG_METRIC_COSINE) {
+      double row_norm_sq = search->dim * o * o + 2.0 * o * s * (double)code_sum + s * s * (double)code_sq_sum;
+      score = cosine_from_dot(dot, row_norm_sq, search->query_norm_sq);
+    }
+
+    rag_topk_push(&search->heaps[worker], score, r);
+  }
+}
+
+static void quantized_search_run(void *ctx, volatile int *interrupted) {
+  quantized_search_t *search = (quantized_search_t *)ctx;
+
+  for (uint32_t w = 0; w < search->workers; ++w) {
+    rag_topk_clear(&search->heaps[w]);
+  }
+
+  rag_parallel_for(search->count, search->workers, quantized_search_rows, search, interrupted);
+  for (uint32_t w = 1; w < search->workers; ++w) {
+    rag_topk_merge(&search->heaps[0], &search->heaps[w]);
+  }
+}
+
+// Class method: RagEmbeddings::QuantizedEmbedding.top_k(query_embedding, blobs, k, metric: :cosine)
+// Scans a list of quantized blobs (as produced by to_blob) against a float query
+// without building a Ruby object per row. Returns [[index, score], ...], best first
+static VALUE quantized_top_k(int argc, VALUE *argv, VALUE klass) {
+  VALUE query, rb_blobs, rb_k, opts;
+  rb_scan_args(argc, argv, "3:", &query, &rb_blobs, &rb_k, &opts);
+
+  rag_metric_t metric = quantized_metric_from_opts(opts);
+
+  embedding_t *q;
+  TypedData_Get_Struct(query, embedding_t, &rag_embedding_type, q);
+  Check_Type(rb_blobs, T_ARRAY);
+
+  long k_arg = NUM2LONG(rb_k);
+  if (k_arg < 0) {
+    rb_raise(rb_eArgError, "k must be non-negative");
+  }
+
+  uint32_t dim = q->dim;
+  long row_count = RARRAY_LEN(rb_blobs);
+  long expected_len = (long)(QUANTIZED_BLOB_HEADER + dim);
+
+  // Validate every row up front, so nothing can raise while the buffers are allocated
+  for (long r = 0; r < row_count; ++r) {
+    VALUE blob = RARRAY_CONST_PTR(rb_blobs)[r];
+    Check_Type(blob, T_STRING);
+    if (RSTRING_LEN(blob) != expected_len) {
+      rb_raise(rb_eArgError, "Dimension mismatch: %u vs %ld", dim, RSTRING_LEN(blob) - (long)QUANTIZED_BLOB_HEADER);
+    }
+  }
+
+  size_t count = (size_t)row_count;
+  size_t k = (size_t)k_arg < count ? (size_t)k_arg : count;
+  if (k == 0) {
+    return rb_ary_new();
+  }
+
+  // Everything the scan reads is copied here, while holding the GVL.
+  // ALLOCV keeps the buffers reachable by the GC if an interrupt raises
+  uint32_t workers = rag_workers_for(count);
+
+  VALUE query_buffer, headers_buffer, codes_buffer, heaps_buffer, entries_buffer;
+  float *query_values = ALLOCV_N(float, query_buffer, dim);
+  float *headers = ALLOCV_N(float, headers_buffer, 2 * count);
+  int8_t *codes = ALLOCV_N(int8_t, codes_buffer, count * dim);
+  rag_topk_t *heaps = ALLOCV_N(rag_topk_t, heaps_buffer, workers);
+  rag_topk_entry_t *entries = ALLOCV_N(rag_topk_entry_t, entries_buffer, k * workers);
+
+  rag_row_to_floats(query, dim, EMBEDDING_DTYPE_F32, query_values);
+  for (size_t r = 0; r < count; ++r) {
+    const char *src = RSTRING_PTR(RARRAY_CONST_PTR(rb_blobs)[r]);
+    rag_floats_from_le_bytes(headers + 2 * r, src, 2);
+    memcpy(codes + r * dim, src + QUANTIZED_BLOB_HEADER, dim);
+  }
+
+  double query_sum = 0.0;
+  for (uint32_t i = 0; i < dim; ++i) {
+    query_sum += query_values[i];
+  }
+
+  quantized_search_t search = {query_values, query_sum, rag_sum_squares(query_values, dim), metric,
+                               dim, count, headers, codes, workers, heaps};
+  for (uint32_t w = 0; w < workers; ++w) {
+    rag_topk_init_with(&heaps[w], k, entries + w * k);
+  }
+
+  rag_without_gvl(count * (size_t)dim, quantized_search_run, &search, NULL);
+
+  rag_topk_sort(&heaps[0]);
+  VALUE result = rag_topk_to_ary(&heaps[0], 0);
+
+  ALLOCV_END(query_buffer);
+  ALLOCV_END(headers_buffer);
+  ALLOCV_END(codes_buffer);
+  ALLOCV_END(heaps_buffer);
+  ALLOCV_END(entries_buffer);
+
+  return result;
+}
+
+void rag_init_quantized(VALUE mRag) {
+  cQuantizedEmbedding = rb_define_class_under(mRag, "QuantizedEmbedding", rb_cObject);
+  rb_undef_alloc_func(cQuantizedEmbedding);
+
+  rb_define_method(rag_cEmbedding, "quantize", embedding_quantize, -1);
+
+  rb_define_singleton_method(cQuantizedEmbedding, "from_blob", quantized_from_blob, 1);
+  rb_define_singleton_method(cQuantizedEmbedding, "top_k", quantized_top_k, -1);
+
+  rb_define_method(cQuantizedEmbedding, "dim", quantized_dim, 0);
+  rb_define_method(cQuantizedEmbedding, "scale", quantized_scale, 0);
+  rb_define_method(cQuantizedEmbedding, "offset", quantized_offset, 0);
+  rb_define_method(cQuantizedEmbedding, "codes", quantized_codes, 0);
+  rb_define_method(cQuantizedEmbedding, "to_a", quantized_to_a, 0);
+  rb_define_method(cQuantizedEmbedding, "to_blob", quantized_to_blob, 0);
+  rb_define_method(cQuantizedEmbedding, "dequantize", quantized_dequantize, 0);
+  rb_define_method(cQuantizedEmbedding, "cosine_similarity", quantized_cosine_similarity, 1);
+  rb_define_method(cQuantizedEmbedding, "dot_product", quantized_dot_product, 1);
+}
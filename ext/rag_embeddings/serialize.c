@@ -0,0 +1,98 @@
+#include <ruby.h>     // Ruby API
+#include <string.h>   // For memcpy, memcmp
+#include "embedding.h"
+#include "serialize.h"
+
+void rag_write_u32(VALUE str, uint32_t value) {
+  char bytes[4];
+  for (int i = 0; i < 4; ++i) bytes[i] = (char)((value >> (8 * i)) & 0xff);
+  rb_str_cat(str, bytes, 4);
+}
+
+void rag_write_u64(VALUE str, uint64_t value) {
+  char bytes[8];
+  for (int i = 0; i < 8; ++i) bytes[i] = (char)((value >> (8 * i)) & 0xff);
+  rb_str_cat(str, bytes, 8);
+}
+
+void rag_write_bytes(VALUE str, const void *src, size_t n) {
+  rb_str_cat(str, (const char *)src, (long)n);
+}
+
+void rag_write_floats(VALUE str, const float *src, size_t n) {
+  long offset = RSTRING_LEN(str);
+  rb_str_resize(str, offset + (long)(n * sizeof(float)));
+  rag_floats_to_le_bytes(RSTRING_PTR(str) + offset, src, n);
+}
+
+void rag_write_i64s(VALUE str, const int64_t *src, size_t n) {
+  for (size_t i = 0; i < n; ++i) rag_write_u64(str, (uint64_t)src[i]);
+}
+
+void rag_reader_init(rag_reader_t *reader, VALUE str) {
+  StringValue(str);
+  reader->ptr = RSTRING_PTR(str);
+  reader->left = (size_t)RSTRING_LEN(str);
+}
+
+// Claim n bytes, raising when the buffer is too short
+static const unsigned char *reader_take(rag_reader_t *reader, size_t n) {
+  if (n > reader->left) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+
+  const unsigned char *bytes = (const unsigned char *)reader->ptr;
+  reader->ptr += n;
+  reader->left -= n;
+  return bytes;
+}
+
+uint32_t rag_read_u32(rag_reader_t *reader) {
+  const unsigned char *bytes = reader_take(reader, 4);
+  uint32_t value = 0;
+  for (int i = 0; i < 4; ++i) value |= (uint32_t)bytes[i] << (8 * i);
+  return value;
+}
+
+uint64_t rag_read_u64(rag_reader_t *reader) {
+  const unsigned char *bytes = reader_take(reader, 8);
+  uint64_t value = 0;
+  for (int i = 0; i < 8; ++i) value |= (uint64_t)bytes[i] << (8 * i);
+  return value;
+}
+
+void rag_read_bytes(rag_reader_t *reader, void *dst, size_t n) {
+  memcpy(dst, reader_take(reader, n), n);
+}
+
+void rag_read_floats(rag_reader_t *reader, float *dst, size_t n) {
+  if (n > reader->left / sizeof(float)) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+  rag_floats_from_le_bytes(dst, (const char *)reader_take(reader, n * sizeof(float)), n);
+}
+
+void rag_read_i64s(rag_reader_t *reader, int64_t *dst, size_t n) {
+  if (n > reader->left / 8) {
+    rb_raise(rb_eArgError, "Truncated index data");
+  }
+  for (size_t i = 0; i < n; ++i) dst[i] = (int64_t)rag_read_u64(reader);
+}
+
+void rag_read_header(rag_reader_t *reader, const char *magic, uint32_t version) {
+  if (reader->left < 4 || memcmp(reader->ptr, magic, 4) != 0) {
+    rb_raise(rb_eArgError, "Not a %.4s index", magic);
+  }
+  reader_take(reader, 4);
+
+  uint32_t found = rag_read_u32(reader);
+  if (found != version) {
+    rb_raise(rb_eArgError, "Unsupported %.4s index version: %u", magic, found);
+  }
+}
+
+void rag_reader_finish(const rag_reader_t *reader) {
+  if (reader->left != 0) {
+    rb_raise(rb_eArgError, "Unexpected %zu trailing bytes in index data", reader->left);
+  }
+}
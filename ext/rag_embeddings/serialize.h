@@ -0,0 +1,38 @@
+#ifndef RAG_EMBEDDINGS_SERIALIZE_H
+#define RAG_EMBEDDINGS_SERIALIZE_H
+
+#include <ruby.h>     // Ruby API
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For fixed-width integer types
+
+// Little-endian binary format shared by the indexes (to_blob / from_blob, save / load).
+// Writers append to a Ruby String; readers walk a buffer and raise ArgumentError
+// when the data ends early, so a truncated file can't be read past its end
+
+void rag_write_u32(VALUE str, uint32_t value);
+void rag_write_u64(VALUE str, uint64_t value);
+void rag_write_bytes(VALUE str, const void *src, size_t n);
+void rag_write_floats(VALUE str, const float *src, size_t n);
+void rag_write_i64s(VALUE str, const int64_t *src, size_t n);
+
+typedef struct {
+  const char *ptr;    // Next byte to read
+  size_t left;        // Bytes remaining
+} rag_reader_t;
+
+// Reader over the bytes of a Ruby String (which must stay alive while reading)
+void rag_reader_init(rag_reader_t *reader, VALUE str);
+
+uint32_t rag_read_u32(rag_reader_t *reader);
+uint64_t rag_read_u64(rag_reader_t *reader);
+void rag_read_bytes(rag_reader_t *reader, void *dst, size_t n);
+void rag_read_floats(rag_reader_t *reader, float *dst, size_t n);
+void rag_read_i64s(rag_reader_t *reader, int64_t *dst, size_t n);
+
+// Check the 4-byte magic and the version at the start of a blob
+void rag_read_header(rag_reader_t *reader, const char *magic, uint32_t version);
+
+// Raise unless every byte has been consumed
+void rag_reader_finish(const rag_reader_t *reader);
+
+#endif
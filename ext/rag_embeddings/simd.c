@@ -0,0 +1,471 @@
+#include "simd.h"
+
+// SIMD kernels are only built for x86 with GCC or Clang, which provide
+// per-function target attributes and __builtin_cpu_supports for runtime dispatch
+#if (defined(__x86_64__) || defined(__i386__)) && defined(__GNUC__)
+#define RAG_SIMD_X86 1
+#include <immintrin.h>
+#endif
+
+// ---------------------------------------------------------------------------
+// Scalar fallback
+// Always available, and the reference the SIMD kernels must agree with.
+// Accumulation is done in double to reduce accumulation errors
+// ---------------------------------------------------------------------------
+
+static void dot_norms_scalar(const float *a, const float *b, size_t n,
+                             double *dot, double *norm_a, double *norm_b) {
+  double d = 0.0, na = 0.0, nb = 0.0;
+
+  for (size_t i = 0; i < n; ++i) {
+    float ai = a[i];
+    float bi = b[i];
+
+    d += (double)ai * bi;
+    na += (double)ai * ai;
+    nb += (double)bi * bi;
+  }
+
+  *dot = d;
+  *norm_a = na;
+  *norm_b = nb;
+}
+
+static double sum_squares_scalar(const float *a, size_t n) {
+  double sum = 0.0;
+  for (size_t i = 0; i < n; ++i) {
+    sum += (double)a[i] * a[i];
+  }
+  return sum;
+}
+
+static void scale_scalar(float *a, size_t n, float factor) {
+  for (size_t i = 0; i < n; ++i) {
+    a[i] *= factor;
+  }
+}
+
+static double dot_scalar(const float *a, const float *b, size_t n) {
+  double sum = 0.0;
+  for (size_t i = 0; i < n; ++i) {
+    sum += (double)a[i] * b[i];
+  }
+  return sum;
+}
+
+static double squared_l2_scalar(const float *a, const float *b, size_t n) {
+  double sum = 0.0;
+  for (size_t i = 0; i < n; ++i) {
+    double diff = (double)a[i] - b[i];
+    sum += diff * diff;
+  }
+  return sum;
+}
+
+// Portable population count (SWAR), for CPUs without a popcount instruction
+static inline uint64_t popcount64(uint64_t x) {
+  x = x - ((x >> 1) & 0x5555555555555555ULL);
+  x = (x & 0x3333333333333333ULL) + ((x >> 2) & 0x3333333333333333ULL);
+  x = (x + (x >> 4)) & 0x0f0f0f0f0f0f0f0fULL;
+  return (x * 0x0101010101010101ULL) >> 56;
+}
+
+static uint64_t hamming_scalar(const uint64_t *a, const uint64_t *b, size_t words) {
+  uint64_t distance = 0;
+  for (size_t i = 0; i < words; ++i) {
+    distance += popcount64(a[i] ^ b[i]);
+  }
+  return distance;
+}
+
+#ifdef RAG_SIMD_X86
+
+// Hardware POPCNT (SSE4.2 era CPUs onwards)
+__attribute__((target("popcnt")))
+static uint64_t hamming_popcnt(const uint64_t *a, const uint64_t *b, size_t words) {
+  uint64_t distance = 0;
+  for (size_t i = 0; i < words; ++i) {
+    distance += (uint64_t)__builtin_popcountll(a[i] ^ b[i]);
+  }
+  return distance;
+}
+
+// ---------------------------------------------------------------------------
+// SSE2 (baseline on every x86-64 CPU)
+// Floats are widened to double before accumulating to keep the same
+// precision as the scalar path
+// ---------------------------------------------------------------------------
+
+__attribute__((target("sse2")))
+static void dot_norms_sse2(const float *a, const float *b, size_t n,
+                           double *dot, double *norm_a, double *norm_b) {
+  __m128d d = _mm_setzero_pd(), na = _mm_setzero_pd(), nb = _mm_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m128 fa = _mm_loadu_ps(a + i);
+    __m128 fb = _mm_loadu_ps(b + i);
+
+    // Low and high pairs of floats, widened to double
+    __m128d a_lo = _mm_cvtps_pd(fa), a_hi = _mm_cvtps_pd(_mm_movehl_ps(fa, fa));
+    __m128d b_lo = _mm_cvtps_pd(fb), b_hi = _mm_cvtps_pd(_mm_movehl_ps(fb, fb));
+
+    d = _mm_add_pd(d, _mm_add_pd(_mm_mul_pd(a_lo, b_lo), _mm_mul_pd(a_hi, b_hi)));
+    na = _mm_add_pd(na, _mm_add_pd(_mm_mul_pd(a_lo, a_lo), _mm_mul_pd(a_hi, a_hi)));
+    nb = _mm_add_pd(nb, _mm_add_pd(_mm_mul_pd(b_lo, b_lo), _mm_mul_pd(b_hi, b_hi)));
+  }
+
+  double buf[2];
+  _mm_storeu_pd(buf, d);  double sd = buf[0] + buf[1];
+  _mm_storeu_pd(buf, na); double sna = buf[0] + buf[1];
+  _mm_storeu_pd(buf, nb); double snb = buf[0] + buf[1];
+
+  // Remaining tail
+  for (; i < n; ++i) {
+    sd += (double)a[i] * b[i];
+    sna += (double)a[i] * a[i];
+    snb += (double)b[i] * b[i];
+  }
+
+  *dot = sd;
+  *norm_a = sna;
+  *norm_b = snb;
+}
+
+__attribute__((target("sse2")))
+static double sum_squares_sse2(const float *a, size_t n) {
+  __m128d acc = _mm_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m128 fa = _mm_loadu_ps(a + i);
+    __m128d lo = _mm_cvtps_pd(fa), hi = _mm_cvtps_pd(_mm_movehl_ps(fa, fa));
+    acc = _mm_add_pd(acc, _mm_add_pd(_mm_mul_pd(lo, lo), _mm_mul_pd(hi, hi)));
+  }
+
+  double buf[2];
+  _mm_storeu_pd(buf, acc);
+  double sum = buf[0] + buf[1];
+
+  for (; i < n; ++i) {
+    sum += (double)a[i] * a[i];
+  }
+  return sum;
+}
+
+__attribute__((target("sse2")))
+static void scale_sse2(float *a, size_t n, float factor) {
+  __m128 f = _mm_set1_ps(factor);
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    _mm_storeu_ps(a + i, _mm_mul_ps(_mm_loadu_ps(a + i), f));
+  }
+  for (; i < n; ++i) {
+    a[i] *= factor;
+  }
+}
+
+__attribute__((target("sse2")))
+static double dot_sse2(const float *a, const float *b, size_t n) {
+  __m128d acc = _mm_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m128 fa = _mm_loadu_ps(a + i);
+    __m128 fb = _mm_loadu_ps(b + i);
+    __m128d a_lo = _mm_cvtps_pd(fa), a_hi = _mm_cvtps_pd(_mm_movehl_ps(fa, fa));
+    __m128d b_lo = _mm_cvtps_pd(fb), b_hi = _mm_cvtps_pd(_mm_movehl_ps(fb, fb));
+    acc = _mm_add_pd(acc, _mm_add_pd(_mm_mul_pd(a_lo, b_lo), _mm_mul_pd(a_hi, b_hi)));
+  }
+
+  double buf[2];
+  _mm_storeu_pd(buf, acc);
+  double sum = buf[0] + buf[1];
+
+  for (; i < n; ++i) {
+    sum += (double)a[i] * b[i];
+  }
+  return sum;
+}
+
+__attribute__((target("sse2")))
+static double squared_l2_sse2(const float *a, const float *b, size_t n) {
+  __m128d acc = _mm_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m128 fa = _mm_loadu_ps(a + i);
+    __m128 fb = _mm_loadu_ps(b + i);
+    __m128d lo = _mm_sub_pd(_mm_cvtps_pd(fa), _mm_cvtps_pd(fb));
+    __m128d hi = _mm_sub_pd(_mm_cvtps_pd(_mm_movehl_ps(fa, fa)), _mm_cvtps_pd(_mm_movehl_ps(fb, fb)));
+    acc = _mm_add_pd(acc, _mm_add_pd(_mm_mul_pd(lo, lo), _mm_mul_pd(hi, hi)));
+  }
+
+  double buf[2];
+  _mm_storeu_pd(buf, acc);
+  double sum = buf[0] + buf[1];
+
+  for (; i < n; ++i) {
+    double diff = (double)a[i] - b[i];
+    sum += diff * diff;
+  }
+  return sum;
+}
+
+// ---------------------------------------------------------------------------
+// AVX2 + FMA
+// ---------------------------------------------------------------------------
+
+__attribute__((target("avx2,fma")))
+static double hsum256_pd(__m256d v) {
+  __m128d lo = _mm256_castpd256_pd128(v);
+  __m128d hi = _mm256_extractf128_pd(v, 1);
+  lo = _mm_add_pd(lo, hi);
+  return _mm_cvtsd_f64(_mm_add_sd(lo, _mm_unpackhi_pd(lo, lo)));
+}
+
+__attribute__((target("avx2,fma")))
+static void dot_norms_avx2(const float *a, const float *b, size_t n,
+                           double *dot, double *norm_a, double *norm_b) {
+  __m256d d = _mm256_setzero_pd(), na = _mm256_setzero_pd(), nb = _mm256_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m256d va = _mm256_cvtps_pd(_mm_loadu_ps(a + i));
+    __m256d vb = _mm256_cvtps_pd(_mm_loadu_ps(b + i));
+
+    d = _mm256_fmadd_pd(va, vb, d);
+    na = _mm256_fmadd_pd(va, va, na);
+    nb = _mm256_fmadd_pd(vb, vb, nb);
+  }
+
+  double sd = hsum256_pd(d), sna = hsum256_pd(na), snb = hsum256_pd(nb);
+
+  for (; i < n; ++i) {
+    sd += (double)a[i] * b[i];
+    sna += (double)a[i] * a[i];
+    snb += (double)b[i] * b[i];
+  }
+
+  *dot = sd;
+  *norm_a = sna;
+  *norm_b = snb;
+}
+
+__attribute__((target("avx2,fma")))
+static double sum_squares_avx2(const float *a, size_t n) {
+  __m256d acc = _mm256_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m256d va = _mm256_cvtps_pd(_mm_loadu_ps(a + i));
+    acc = _mm256_fmadd_pd(va, va, acc);
+  }
+
+  double sum = hsum256_pd(acc);
+  for (; i < n; ++i) {
+    sum += (double)a[i] * a[i];
+  }
+  return sum;
+}
+
+__attribute__((target("avx2,fma")))
+static void scale_avx2(float *a, size_t n, float factor) {
+  __m256 f = _mm256_set1_ps(factor);
+  size_t i = 0;
+
+  for (; i + 8 <= n; i += 8) {
+    _mm256_storeu_ps(a + i, _mm256_mul_ps(_mm256_loadu_ps(a + i), f));
+  }
+  for (; i < n; ++i) {
+    a[i] *= factor;
+  }
+}
+
+__attribute__((target("avx2,fma")))
+static double dot_avx2(const float *a, const float *b, size_t n) {
+  __m256d acc = _mm256_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m256d va = _mm256_cvtps_pd(_mm_loadu_ps(a + i));
+    __m256d vb = _mm256_cvtps_pd(_mm_loadu_ps(b + i));
+    acc = _mm256_fmadd_pd(va, vb, acc);
+  }
+
+  double sum = hsum256_pd(acc);
+  for (; i < n; ++i) {
+    sum += (double)a[i] * b[i];
+  }
+  return sum;
+}
+
+__attribute__((target("avx2,fma")))
+static double squared_l2_avx2(const float *a, const float *b, size_t n) {
+  __m256d acc = _mm256_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 4 <= n; i += 4) {
+    __m256d diff = _mm256_sub_pd(_mm256_cvtps_pd(_mm_loadu_ps(a + i)), _mm256_cvtps_pd(_mm_loadu_ps(b + i)));
+    acc = _mm256_fmadd_pd(diff, diff, acc);
+  }
+
+  double sum = hsum256_pd(acc);
+  for (; i < n; ++i) {
+    double diff = (double)a[i] - b[i];
+    sum += diff * diff;
+  }
+  return sum;
+}
+
+// ---------------------------------------------------------------------------
+// AVX-512 (foundation instructions only)
+// ---------------------------------------------------------------------------
+
+__attribute__((target("avx512f")))
+static void dot_norms_avx512(const float *a, const float *b, size_t n,
+                             double *dot, double *norm_a, double *norm_b) {
+  __m512d d = _mm512_setzero_pd(), na = _mm512_setzero_pd(), nb = _mm512_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 8 <= n; i += 8) {
+    __m512d va = _mm512_cvtps_pd(_mm256_loadu_ps(a + i));
+    __m512d vb = _mm512_cvtps_pd(_mm256_loadu_ps(b + i));
+
+    d = _mm512_fmadd_pd(va, vb, d);
+    na = _mm512_fmadd_pd(va, va, na);
+    nb = _mm512_fmadd_pd(vb, vb, nb);
+  }
+
+  double sd = _mm512_reduce_add_pd(d);
+  double sna = _mm512_reduce_add_pd(na);
+  double snb = _mm512_reduce_add_pd(nb);
+
+  for (; i < n; ++i) {
+    sd += (double)a[i] * b[i];
+    sna += (double)a[i] * a[i];
+    snb += (double)b[i] * b[i];
+  }
+
+  *dot = sd;
+  *norm_a = sna;
+  *norm_b = snb;
+}
+
+__attribute__((target("avx512f")))
+static double sum_squares_avx512(const float *a, size_t n) {
+  __m512d acc = _mm512_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 8 <= n; i += 8) {
+    __m512d va = _mm512_cvtps_pd(_mm256_loadu_ps(a + i));
+    acc = _mm512_fmadd_pd(va, va, acc);
+  }
+
+  double sum = _mm512_reduce_add_pd(acc);
+  for (; i < n; ++i) {
+    sum += (double)a[i] * a[i];
+  }
+  return sum;
+}
+
+__attribute__((target("avx512f")))
+static void scale_avx512(float *a, size_t n, float factor) {
+  __m512 f = _mm512_set1_ps(factor);
+  size_t i = 0;
+
+  for (; i + 16 <= n; i += 16) {
+    _mm512_storeu_ps(a + i, _mm512_mul_ps(_mm512_loadu_ps(a + i), f));
+  }
+  for (; i < n; ++i) {
+    a[i] *= factor;
+  }
+}
+
+__attribute__((target("avx512f")))
+static double dot_avx512(const float *a, const float *b, size_t n) {
+  __m512d acc = _mm512_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 8 <= n; i += 8) {
+    __m512d va = _mm512_cvtps_pd(_mm256_loadu_ps(a + i));
+    __m512d vb = _mm512_cvtps_pd(_mm256_loadu_ps(b + i));
+    acc = _mm512_fmadd_pd(va, vb, acc);
+  }
+
+  double sum = _mm512_reduce_add_pd(acc);
+  for (; i < n; ++i) {
+    sum += (double)a[i] * b[i];
+  }
+  return sum;
+}
+
+__attribute__((target("avx512f")))
+static double squared_l2_avx512(const float *a, const float *b, size_t n) {
+  __m512d acc = _mm512_setzero_pd();
+  size_t i = 0;
+
+  for (; i + 8 <= n; i += 8) {
+    __m512d diff = _mm512_sub_pd(_mm512_cvtps_pd(_mm256_loadu_ps(a + i)), _mm512_cvtps_pd(_mm256_loadu_ps(b + i)));
+    acc = _mm512_fmadd_pd(diff, diff, acc);
+  }
+
+  double sum = _mm512_reduce_add_pd(acc);
+  for (; i < n; ++i) {
+    double diff = (double)a[i] - b[i];
+    sum += diff * diff;
+  }
+  return sum;
+}
+
+#endif // RAG_SIMD_X86
+
+// Kernels in use, scalar until rag_simd_init() runs
+rag_dot_norms_fn rag_dot_norms = dot_norms_scalar;
+rag_sum_squares_fn rag_sum_squares = sum_squares_scalar;
+rag_scale_fn rag_scale = scale_scalar;
+rag_dot_fn rag_dot = dot_scalar;
+rag_squared_l2_fn rag_squared_l2 = squared_l2_scalar;
+rag_hamming_fn rag_hamming = hamming_scalar;
+
+static const char *simd_backend = "scalar";
+
+const char *rag_simd_init(void) {
+#ifdef RAG_SIMD_X86
+  __builtin_cpu_init();
+
+  if (__builtin_cpu_supports("avx512f")) {
+    rag_dot_norms = dot_norms_avx512;
+    rag_sum_squares = sum_squares_avx512;
+    rag_scale = scale_avx512;
+    rag_dot = dot_avx512;
+    rag_squared_l2 = squared_l2_avx512;
+    simd_backend = "avx512";
+  } else if (__builtin_cpu_supports("avx2") && __builtin_cpu_supports("fma")) {
+    rag_dot_norms = dot_norms_avx2;
+    rag_sum_squares = sum_squares_avx2;
+    rag_scale = scale_avx2;
+    rag_dot = dot_avx2;
+    rag_squared_l2 = squared_l2_avx2;
+    simd_backend = "avx2";
+  } else if (__builtin_cpu_supports("sse2")) {
+    rag_dot_norms = dot_norms_sse2;
+    rag_sum_squares = sum_squares_sse2;
+    rag_scale = scale_sse2;
+    rag_dot = dot_sse2;
+    rag_squared_l2 = squared_l2_sse2;
+    simd_backend = "sse2";
+  }
+
+  if (__builtin_cpu_supports("popcnt")) {
+    rag_hamming = hamming_popcnt;
+  }
+#endif
+
+  return simd_backend;
+}
+
+const char *rag_simd_backend(void) {
+  return simd_backend;
+}
@@ -0,0 +1,43 @@
+#ifndef RAG_EMBEDDINGS_SIMD_H
+#define RAG_EMBEDDINGS_SIMD_H
+
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For uint64_t
+
+// Distance kernels shared by every part of the extension
+// They point to the fastest implementation supported by the running CPU,
+// chosen once by rag_simd_init() when the extension is loaded
+
+// Dot product of a and b plus the squared magnitude of both vectors, in one pass
+typedef void (*rag_dot_norms_fn)(const float *a, const float *b, size_t n,
+                                 double *dot, double *norm_a, double *norm_b);
+
+// Sum of the squares of the values (squared L2 norm)
+typedef double (*rag_sum_squares_fn)(const float *a, size_t n);
+
+// Multiply every value by factor, in place
+typedef void (*rag_scale_fn)(float *a, size_t n, float factor);
+
+// Plain dot product, and squared euclidean distance between a and b
+typedef double (*rag_dot_fn)(const float *a, const float *b, size_t n);
+typedef double (*rag_squared_l2_fn)(const float *a, const float *b, size_t n);
+
+// Hamming distance between two bit strings of `words` 64-bit words
+typedef uint64_t (*rag_hamming_fn)(const uint64_t *a, const uint64_t *b, size_t words);
+
+extern rag_dot_norms_fn rag_dot_norms;
+extern rag_sum_squares_fn rag_sum_squares;
+extern rag_scale_fn rag_scale;
+extern rag_dot_fn rag_dot;
+extern rag_squared_l2_fn rag_squared_l2;
+extern rag_hamming_fn rag_hamming;
+
+// Select the kernels for the current CPU and return the backend name
+// ("avx512", "avx2", "sse2" or "scalar").
+// The hamming kernel is picked separately, on the POPCNT instruction
+const char *rag_simd_init(void);
+
+// Name of the backend selected by rag_simd_init()
+const char *rag_simd_backend(void);
+
+#endif
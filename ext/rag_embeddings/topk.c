@@ -0,0 +1,127 @@
+#include <math.h>     // For isnan
+#include "topk.h"
+
+// Whether entry a ranks below entry b: a lower score, or the same score at a later index.
+// Ties always resolve to the earliest rows, whatever order they were pushed in,
+// so heaps filled by several workers and merged hold the same entries as a single scan.
+// NaN scores (rows with NaN values, e.g. from a blob) rank below every number:
+// compared as doubles they would never be evicted from a full heap
+static inline int topk_weaker(const rag_topk_entry_t *a, const rag_topk_entry_t *b) {
+  int a_nan = isnan(a->score), b_nan = isnan(b->score);
+  if (a_nan || b_nan) {
+    return a_nan && (!b_nan || a->index > b->index);
+  }
+  return a->score < b->score || (a->score == b->score && a->index > b->index);
+}
+
+// Restore the min-heap property from position i downwards
+// The root of the heap is always the worst score among the current top k
+static void topk_sift_down(rag_topk_entry_t *heap, size_t n, size_t i) {
+  for (;;) {
+    size_t smallest = i;
+    size_t left = 2 * i + 1;
+    size_t right = left + 1;
+
+    if (left < n && topk_weaker(&heap[left], &heap[smallest])) smallest = left;
+    if (right < n && topk_weaker(&heap[right], &heap[smallest])) smallest = right;
+    if (smallest == i) return;
+
+    rag_topk_entry_t tmp = heap[i];
+    heap[i] = heap[smallest];
+    heap[smallest] = tmp;
+    i = smallest;
+  }
+}
+
+// Restore the min-heap property from position i upwards
+static void topk_sift_up(rag_topk_entry_t *heap, size_t i) {
+  while (i > 0) {
+    size_t parent = (i - 1) / 2;
+    if (!topk_weaker(&heap[i], &heap[parent])) return;
+
+    rag_topk_entry_t tmp = heap[i];
+    heap[i] = heap[parent];
+    heap[parent] = tmp;
+    i = parent;
+  }
+}
+
+void rag_topk_init(rag_topk_t *topk, size_t k) {
+  topk->entries = ALLOC_N(rag_topk_entry_t, k);
+  topk->size = 0;
+  topk->capacity = k;
+}
+
+void rag_topk_init_with(rag_topk_t *topk, size_t k, rag_topk_entry_t *entries) {
+  topk->entries = entries;
+  topk->size = 0;
+  topk->capacity = k;
+}
+
+void rag_topk_free(rag_topk_t *topk) {
+  xfree(topk->entries);
+  topk->entries = NULL;
+}
+
+void rag_topk_clear(rag_topk_t *topk) {
+  topk->size = 0;
+}
+
+void rag_topk_push(rag_topk_t *topk, double score, size_t index) {
+  rag_topk_entry_t *heap = topk->entries;
+  rag_topk_entry_t entry = {score, index};
+
+  if (topk->size < topk->capacity) {
+    heap[topk->size] = entry;
+    topk_sift_up(heap, topk->size);
+    topk->size++;
+  } else if (topk_weaker(&heap[0], &entry)) {
+    heap[0] = entry;
+    topk_sift_down(heap, topk->size, 0);
+  }
+}
+
+void rag_topk_merge(rag_topk_t *topk, const rag_topk_t *other) {
+  for (size_t i = 0; i < other->size; ++i) {
+    rag_topk_push(topk, other->entries[i].score, other->entries[i].index);
+  }
+}
+
+double rag_topk_worst(const rag_topk_t *topk) {
+  return topk->entries[0].score;
+}
+
+void rag_topk_sort(rag_topk_t *topk) {
+  rag_topk_entry_t *heap = topk->entries;
+
+  // Heap sort: repeatedly move the weakest entry to the end of the shrinking heap,
+  // which leaves the array ordered from the strongest to the weakest
+  for (size_t n = topk->size; n > 1; --n) {
+    rag_topk_entry_t weakest = heap[0];
+    heap[0] = heap[n - 1];
+    heap[n - 1] = weakest;
+    topk_sift_down(heap, n - 1, 0);
+  }
+}
+
+VALUE rag_topk_to_ary(const rag_topk_t *topk, int negate) {
+  VALUE result = rb_ary_new_capa((long)topk->size);
+
+  for (size_t i = 0; i < topk->size; ++i) {
+    double score = negate ? -topk->entries[i].score : topk->entries[i].score;
+    rb_ary_store(result, (long)i, rb_assoc_new(SIZET2NUM(topk->entries[i].index), DBL2NUM(score)));
+  }
+
+  return result;
+}
+
+VALUE rag_topk_to_id_ary(const rag_topk_t *topk, const int64_t *ids, int negate) {
+  VALUE result = rb_ary_new_capa((long)topk->size);
+
+  for (size_t i = 0; i < topk->size; ++i) {
+    double score = negate ? -topk->entries[i].score : topk->entries[i].score;
+    rb_ary_store(result, (long)i, rb_assoc_new(LL2NUM(ids[topk->entries[i].index]), DBL2NUM(score)));
+  }
+
+  return result;
+}
@@ -0,0 +1,55 @@
+#ifndef RAG_EMBEDDINGS_TOPK_H
+#define RAG_EMBEDDINGS_TOPK_H
+
+#include <ruby.h>     // Ruby API
+#include <stddef.h>   // For size_t
+#include <stdint.h>   // For int64_t
+
+// Bounded min-heap keeping the k highest scores seen so far,
+// with the weakest of them at the root so it can be replaced in O(log k).
+// Shared by every search in the extension (matrix scan, quantized scan, indexes)
+typedef struct {
+  double score;
+  size_t index;
+} rag_topk_entry_t;
+
+typedef struct {
+  rag_topk_entry_t *entries;
+  size_t size;        // Entries currently in the heap
+  size_t capacity;    // k
+} rag_topk_t;
+
+// Allocate room for k entries (k must be > 0); release with rag_topk_free
+void rag_topk_init(rag_topk_t *topk, size_t k);
+void rag_topk_free(rag_topk_t *topk);
+
+// Same as rag_topk_init on k entries owned by the caller (e.g. an ALLOCV buffer),
+// for heaps filled without the GVL. Not to be released with rag_topk_free
+void rag_topk_init_with(rag_topk_t *topk, size_t k, rag_topk_entry_t *entries);
+
+// Empty the heap, keeping its storage
+void rag_topk_clear(rag_topk_t *topk);
+
+// Offer a candidate: kept if the heap is not full or it beats the current worst.
+// Equal scores are ranked by index, the lowest first; NaN ranks below any other score
+void rag_topk_push(rag_topk_t *topk, double score, size_t index);
+
+// Offer every entry of another heap, e.g. the partial result of a worker thread
+void rag_topk_merge(rag_topk_t *topk, const rag_topk_t *other);
+
+// Lowest score currently kept (only meaningful when the heap is full)
+double rag_topk_worst(const rag_topk_t *topk);
+
+// Sort the entries from the highest to the lowest score, in place.
+// The heap must not be pushed to afterwards
+void rag_topk_sort(rag_topk_t *topk);
+
+// Build [[index, score], ...] from the sorted entries.
+// With negate the scores are flipped back, for searches that negate distances
+VALUE rag_topk_to_ary(const rag_topk_t *topk, int negate);
+
+// Same as rag_topk_to_ary, reporting ids[index] instead of the index itself
+// (for indexes that map their slots to Database row ids)
+VALUE rag_topk_to_id_ary(const rag_topk_t *topk, const int64_t *ids, int negate);
+
+#endif
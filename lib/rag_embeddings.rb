@@ -4,5 +4,7 @@ require_relative "rag_embeddings/database"
 
 # Loads the compiled C extension
 require "rag_embeddings/embedding"
+require_relative "rag_embeddings/index_file"
+require_relative "rag_embeddings/numo"
 
 require "faraday"
@@ -1,8 +1,46 @@
+require "digest"
 require "sqlite3"
 
 module RagEmbeddings
   class Database
-    def initialize(path = "embeddings.db")
+    # Metric used by top_k_similar: :cosine, :dot, :euclidean, :squared_euclidean,
+    # :manhattan, :chebyshev or :angular
+    attr_reader :metric
+
+    # Storage type of the blobs: :f32, or :f16 / :bf16 to store half the bytes per row.
+    # It is not recorded in the file, so reopen a database with the dtype it was written with
+    attr_reader :dtype
+
+    # Quantization of the blobs: nil for full precision, or :int8 to store 1 byte per value
+    # (4x smaller than f32). Like dtype, it is not recorded in the file
+    attr_reader :quantization
+
+    # Dimension the embeddings are truncated to on insert and search (Embedding#truncate,
+    # re-normalized to unit length), or nil to keep them whole. Matryoshka models lose little
+    # quality from a prefix, so a smaller dimension trades some accuracy for speed and space.
+    # Like dtype, it is not recorded in the file: use truncate_embeddings! to shrink existing rows
+    attr_reader :dimensions
+
+    # Projection (PCA or random, see fit_projection) applied on insert and search, after the
+    # truncation to dimensions, or nil. Unlike dtype it is persisted: project_embeddings! saves it
+    # next to the database file as "<path>.projection", which is loaded again when the database is opened
+    attr_reader :projection
+
+    def initialize(path = "embeddings.db", metric: :cosine, dtype: :f32, quantization: nil, dimensions: nil,
+                   projection: nil)
+      @metric = metric.to_sym
+      @dimensions = dimensions
+      @dtype = dtype.to_sym
+      @quantization = quantization&.to_sym
+      if @quantization && @dtype != :f32
+        raise ArgumentError, "quantization: #{@quantization} can't be combined with dtype: #{@dtype}"
+      end
+      if @quantization && !%i[cosine dot].include?(@metric)
+        raise ArgumentError, "quantization: #{@quantization} supports only the :cosine and :dot metrics, not :#{@metric}"
+      end
+
+      @path = path
+      @projection = projection || saved_projection
       @db = SQLite3::Database.new(path)
       @db.execute <<~SQL
         CREATE TABLE IF NOT EXISTS embeddings (
@@ -11,29 +49,564 @@ module RagEmbeddings
           embedding BLOB NOT NULL
         );
       SQL
+      @db.execute("CREATE TABLE IF NOT EXISTS settings (name TEXT PRIMARY KEY, value TEXT NOT NULL)")
+      write_unit_rows(true) if setting("unit_rows").nil? && raw_rows_empty?
+      @unit_rows = setting("unit_rows") == "1"
+      @lsh_planes = saved_lsh_planes
     end
 
-    def insert(text, embedding)
-      blob = embedding.pack("f*")
-      @db.execute("INSERT INTO embeddings (content, embedding) VALUES (?, ?)", [text, blob])
+    # With the :cosine and :angular metrics embeddings are scaled to unit length on insert (zero vectors are
+    # kept as they are), which doesn't change their similarities. True when every stored row is unit length:
+    # cosine searches then rank the rows by dot product with the normalized query, skipping the norms.
+    # Rows inserted before normalization was introduced, or with another metric, turn it off;
+    # normalize_embeddings! rescales them and turns it back on. Recorded in a `settings` table
+    def unit_rows?
+      @unit_rows
+    end
+
+    # Embeddings are stored as little-endian blobs in the database dtype, the layout of Embedding#to_blob.
+    # The embedding can be a float array or an Embedding object
+    # When LSH buckets are kept in the database (build_lsh_index(persist: true)) the row is also hashed into them.
+    # With on_duplicate: the most similar stored row is looked up first, and when its cosine similarity
+    # reaches `threshold` the text is not inserted: :skip drops it, :merge appends it to the content
+    # of that row (unless already there), keeping the stored embedding.
+    # Returns the id of the row holding the text
+    def insert(text, embedding, on_duplicate: nil, threshold: 0.95)
+      unless on_duplicate.nil? || %i[skip merge].include?(on_duplicate.to_sym)
+        raise ArgumentError, "Unknown on_duplicate: #{on_duplicate}"
+      end
+
+      blob = encode(embedding)
+      @db.transaction do
+        duplicate = nearest_duplicate(embedding, threshold) if on_duplicate
+        if duplicate
+          merge_content(duplicate, text) if on_duplicate.to_sym == :merge
+          duplicate
+        else
+          @db.execute("INSERT INTO embeddings (content, embedding) VALUES (?, ?)", [text, blob])
+          write_unit_rows(false) if unit_rows? && !normalize_rows?
+          id = @db.last_insert_row_id
+          write_lsh_buckets([[id, blob]]) if @lsh_planes
+          id
+        end
+      end
     end
 
     def all
-      @db.execute("SELECT id, content, embedding FROM embeddings").map do |id, content, blob|
-        [id, content, blob.unpack("f*")]
+      raw_rows.map do |id, content, blob|
+        [id, content, decode(blob).to_a]
       end
     end
 
     # "Raw" search: returns the N texts most similar to the query
-    def top_k_similar(query_text, k: 5)
-      query_embedding = RagEmbeddings.embed(query_text)
-      query_obj = RagEmbeddings::Embedding.from_array(query_embedding)
+    # Rows are loaded into a contiguous EmbeddingMatrix and ranked in C.
+    # The score is the configured metric: with a distance metric lower means more similar.
+    # The query can be a text, a float array or an Embedding.
+    # With prefilter: :binary a first pass keeps the `candidates` rows (k * 10 by default) closest
+    # in Hamming distance between sign bits, and only those are rescored with the full float metric.
+    # With index: (a PQIndex, HNSWIndex, IVFIndex or LSHIndex built from this database) the index is searched
+    # instead of the table; index: :lsh uses the LSH buckets kept in the database (see build_lsh_index)
+    def top_k_similar(query_text, k: 5, prefilter: nil, candidates: nil, index: nil)
+      if prefilter && quantization
+        raise ArgumentError, "prefilter: #{prefilter} is not available with quantization: #{quantization}"
+      end
+
+      query_obj = query_embedding(query_text)
+      return search_lsh_buckets(query_obj, k) if index == :lsh
+      return search_index(index, query_obj, k) if index
+
+      if quantization
+        rows = raw_rows
+        ranked = rows.empty? ? [] : rank(query_obj, rows.map { |_, _, blob| blob }, k)
+      else
+        rows, matrix = stored_matrix
+        ranked = matrix ? matrix.top_k(query_obj, k, metric: search_metric, prefilter:, candidates:) : []
+      end
 
-      all.map do |id, content, emb|
-        emb_obj = RagEmbeddings::Embedding.from_array(emb)
-        similarity = emb_obj.cosine_similarity(query_obj)
+      ranked.map do |index, similarity|
+        id, content, _ = rows[index]
         [id, content, similarity]
-      end.sort_by { |_,_,sim| -sim }.first(k)
+      end
+    end
+
+    # Diverse search by maximal marginal relevance: the fetch_k rows closest to the query (ranked with
+    # the database metric) are shortlisted, then k of them are picked one at a time, each balancing
+    # similarity to the query against similarity to the rows already picked (EmbeddingMatrix#mmr).
+    # lambda: 1.0 gives the plain top k, lower values spread the results over near-duplicates.
+    # Returns [[id, content, cosine similarity to the query], ...] in the order the rows were picked
+    def mmr_search(query_text, k: 5, fetch_k: 20, lambda: 0.5)
+      query_obj = query_embedding(query_text)
+      rows = raw_rows
+      return [] if rows.empty?
+
+      blobs = rows.map { |_, _, blob| blob }
+      shortlist = rank(query_obj, blobs, [fetch_k, k].max).map { |index, _| rows[index] }
+
+      matrix_of(shortlist).mmr(query_obj, k, lambda:).map do |index, similarity|
+        id, content, _ = shortlist[index]
+        [id, content, similarity]
+      end
+    end
+
+    # Clusters of stored rows whose embeddings are near-duplicates: every row of a cluster has
+    # a cosine similarity of at least `threshold` with another row of it (EmbeddingMatrix#near_duplicates,
+    # which compares every pair in C). Returns [[[id, content], ...], ...], each cluster ordered by id
+    def near_duplicates(threshold: 0.95)
+      rows = raw_rows
+      return [] if rows.size < 2
+
+      matrix_of(rows).near_duplicates(threshold).map do |cluster|
+        cluster.map { |index| rows[index].first(2) }
+      end
+    end
+
+    # Average recall@k of int8 quantized search against the exact search, over the given queries
+    # (texts, float arrays or Embedding objects). 1.0 means quantization returns the same rows.
+    # Run it on a full precision database to validate quantization before switching to it
+    def quantization_recall(queries, k: 10)
+      raise ArgumentError, "the database already stores quantized embeddings" if quantization
+
+      blobs = raw_rows.map { |_, _, blob| blob }
+      return 1.0 if blobs.empty? || queries.empty?
+
+      matrix = RagEmbeddings::EmbeddingMatrix.from_blobs(blobs, dtype:)
+      quantized = blobs.map { |blob| RagEmbeddings::Embedding.from_blob(blob, dtype:).quantize(:int8).to_blob }
+
+      recalls = queries.map do |query|
+        query_obj = query_embedding(query)
+        exact = matrix.top_k(query_obj, k, metric:).map(&:first)
+        approximate = RagEmbeddings::QuantizedEmbedding.top_k(query_obj, quantized, k, metric:).map(&:first)
+        recall(exact, approximate)
+      end
+
+      recalls.sum / recalls.size
+    end
+
+    # Train a product quantization index on the stored embeddings and add every row to it.
+    # The dimension must be a multiple of m, and the table needs at least ksub rows.
+    # Persist it with PQIndex#save and pass it to top_k_similar(index:)
+    def build_pq_index(m: 8, ksub: 256, iterations: 25, seed: 0)
+      rows = raw_rows
+      raise ArgumentError, "the database is empty" if rows.empty?
+
+      index = RagEmbeddings::PQIndex.train(matrix_of(rows), m:, ksub:, iterations:, seed:, metric:)
+      rows.each { |id, _, blob| index.add(id, embedding_of(blob)) }
+      index
+    end
+
+    # Build an HNSW graph over the stored embeddings, inserting every row under its id.
+    # The database metric must be :cosine, :euclidean or :squared_euclidean.
+    # Keep it up to date with HNSWIndex#add / #delete, persist it with HNSWIndex#save
+    # and pass it to top_k_similar(index:)
+    def build_hnsw_index(m: 16, ef_construction: 200, ef_search: 50, seed: 0)
+      rows = raw_rows
+      raise ArgumentError, "the database is empty" if rows.empty?
+
+      index = RagEmbeddings::HNSWIndex.create(embedding_of(rows.first[2]).dim,
+                                              m:, ef_construction:, ef_search:, metric:, seed:)
+      rows.each { |id, _, blob| index.add(id, embedding_of(blob)) }
+      index
+    end
+
+    # Random-hyperplane LSH index over the stored rows, for the :cosine metric.
+    # Returns an in-memory LSHIndex holding every row: keep it up to date with LSHIndex#add / #delete,
+    # persist it with LSHIndex#save and pass it to top_k_similar(index:).
+    # With persist: true the buckets are also kept in the database (an `lsh_buckets` table, replaced
+    # on every call): inserted rows are hashed into them, and top_k_similar(index: :lsh) looks up
+    # the candidates in SQLite and rescores them, without loading the index in memory
+    def build_lsh_index(tables: 8, bits: 16, probes: 0, seed: 0, persist: false)
+      raise ArgumentError, "LSH indexes need the :cosine metric, not :#{metric}" unless metric == :cosine
+
+      rows = raw_rows
+      raise ArgumentError, "the database is empty" if rows.empty?
+
+      index = RagEmbeddings::LSHIndex.create(embedding_of(rows.first[2]).dim, tables:, bits:, probes:, seed:)
+      persist_lsh_planes(index) if persist
+      rows.each { |id, _, blob| index.add(id, embedding_of(blob)) }
+      index
+    end
+
+    # IVF index over the stored rows, persisted next to the database file as "<path>.ivf"
+    # so that a restart doesn't require training again. The file is reused when it was built
+    # from exactly the current rows (a digest of their ids and embeddings is kept in the
+    # settings table) with the same nlist and metric; otherwise (or with rebuild: true, or when
+    # the file can't be read) the index is trained again and the file rewritten.
+    # nlist is capped to the number of rows
+    def ivf_index(nlist: 100, nprobe: 8, iterations: 25, seed: 0, rebuild: false)
+      rows = raw_rows
+      raise ArgumentError, "the database is empty" if rows.empty?
+
+      nlist = [nlist, rows.size].min
+      path = ivf_path
+      digest = rows_digest(rows)
+
+      if !rebuild && path && File.exist?(path)
+        index = load_ivf_index(path)
+        if index && index.size == rows.size && index.nlist == nlist && index.metric == metric &&
+           setting("ivf_rows") == digest
+          index.nprobe = nprobe
+          return index
+        end
+      end
+
+      index = RagEmbeddings::IVFIndex.train(matrix_of(rows), nlist:, nprobe:, iterations:, seed:, metric:)
+      rows.each { |id, _, blob| index.add(id, embedding_of(blob)) }
+      if path
+        index.save(path)
+        write_setting("ivf_rows", digest)
+      end
+      index
+    end
+
+    # Cluster every stored embedding with RagEmbeddings.kmeans.
+    # Returns {centroids: [Embedding, ...], assignments: {row id => cluster}}.
+    # With write: true the cluster of each row is also saved in a `cluster` column,
+    # added to the table the first time
+    def cluster(k:, iterations: 25, seed: 0, metric: :cosine, write: false)
+      rows = raw_rows
+      return { centroids: [], assignments: {} } if rows.empty?
+
+      result = RagEmbeddings.kmeans(matrix_of(rows), k:, iterations:, seed:, metric:)
+      assignments = rows.map(&:first).zip(result[:assignments]).to_h
+      write_clusters(assignments) if write
+
+      { centroids: result[:centroids], assignments: }
+    end
+
+    # Truncate every stored embedding to its first `dimensions` values, re-normalized,
+    # rewriting the rows in place in one transaction, and use that dimension from now on.
+    # Rows shorter than `dimensions` raise ArgumentError and leave the table untouched.
+    # The persisted IVF index and the LSH buckets no longer match the rows and are removed
+    def truncate_embeddings!(dimensions)
+      raise ArgumentError, "embeddings are projected: truncation would not match new rows" if projection
+
+      rows = raw_rows
+      blobs = rows.map { |id, _, blob| [id, encode(embedding_of(blob), dimensions:)] }
+      rewrite_embeddings(blobs)
+      @dimensions = dimensions
+    end
+
+    # Scale every stored embedding to unit length in place, in one transaction, so that
+    # cosine searches take the dot product path (see unit_rows?). Needs the :cosine or :angular metric.
+    # The persisted IVF index and the LSH buckets are removed like by the other rewrites
+    def normalize_embeddings!
+      raise ArgumentError, "normalizing would change :#{metric} results" unless normalize_rows?
+
+      rows = raw_rows
+      blobs = rows.map { |id, _, blob| [id, encode(embedding_of(blob), dimensions: nil, projection: nil)] }
+      rewrite_embeddings(blobs)
+    end
+
+    # Fit a projection to `dim` dimensions on a random sample of at most `sample` stored embeddings:
+    # method: :pca keeps the directions of largest variance, :random draws a seeded Gaussian projection
+    # (which needs no sample). Apply it to the collection with project_embeddings!
+    def fit_projection(dim:, method: :pca, sample: 10_000, seed: 0, iterations: 100)
+      rows = raw_rows
+      raise ArgumentError, "the database is empty" if rows.empty?
+
+      case method.to_sym
+      when :pca
+        sampled = rows.size > sample ? rows.sample(sample, random: Random.new(seed)) : rows
+        RagEmbeddings::Projection.pca(matrix_of(sampled), dim:, iterations:, seed:)
+      when :random
+        RagEmbeddings::Projection.random(embedding_of(rows.first[2]).dim, dim:, seed:)
+      else
+        raise ArgumentError, "Unknown projection method: #{method}"
+      end
+    end
+
+    # Project every stored embedding in place, in one transaction, and project new rows and queries
+    # the same way from now on. The projection is saved as "<path>.projection";
+    # the persisted IVF index and the LSH buckets no longer match the rows and are removed
+    def project_embeddings!(projection)
+      raise ArgumentError, "embeddings are already projected" if self.projection
+
+      rows = raw_rows
+      blobs = rows.map { |id, _, blob| [id, encode(embedding_of(blob), dimensions: nil, projection:)] }
+      rewrite_embeddings(blobs)
+
+      path = projection_path
+      projection.save(path) if path
+      @projection = projection
+    end
+
+    private
+
+    # Replace the blob of each row ([[id, blob], ...]) in one transaction.
+    # Indexes built on the previous rows are stale: the persisted IVF index and the LSH buckets are removed
+    def rewrite_embeddings(blobs)
+      @db.transaction do
+        blobs.each do |id, blob|
+          @db.execute("UPDATE embeddings SET embedding = ? WHERE id = ?", [blob, id])
+        end
+      end
+
+      path = ivf_path
+      File.delete(path) if path && File.exist?(path)
+      drop_lsh_buckets
+      write_unit_rows(normalize_rows?)
+    end
+
+    def write_unit_rows(value)
+      write_setting("unit_rows", value ? "1" : "0")
+      @unit_rows = value
+    end
+
+    def setting(name)
+      @db.get_first_value("SELECT value FROM settings WHERE name = ?", [name])
+    end
+
+    def write_setting(name, value)
+      @db.execute("INSERT OR REPLACE INTO settings (name, value) VALUES (?, ?)", [name, value])
+    end
+
+    def raw_rows_empty?
+      @db.get_first_value("SELECT COUNT(*) FROM embeddings").zero?
+    end
+
+    # Metrics whose results don't depend on the length of the embeddings, which are then normalized on insert
+    def normalize_rows?
+      %i[cosine angular].include?(metric)
+    end
+
+    # Cosine searches over unit length f32 rows rank them by dot product with the normalized query:
+    # the same scores, without computing a norm per row. 16-bit and int8 rows are only unit length
+    # to their precision and keep the cosine
+    def dot_product_search?
+      metric == :cosine && dtype == :f32 && quantization.nil? && unit_rows?
+    end
+
+    # Hyperplanes of the LSH buckets kept in the database (an empty LSHIndex), nil when there are none
+    def saved_lsh_planes
+      table = @db.get_first_value("SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'lsh_planes'")
+      blob = @db.get_first_value("SELECT planes FROM lsh_planes") if table
+      RagEmbeddings::LSHIndex.from_blob(blob) if blob
+    end
+
+    # Store the hyperplanes of an index (without its rows) and hash every stored row into new buckets
+    def persist_lsh_planes(index)
+      planes = RagEmbeddings::LSHIndex.from_blob(index.to_blob)
+
+      @db.transaction do
+        drop_lsh_buckets
+        @db.execute("CREATE TABLE lsh_planes (planes BLOB NOT NULL)")
+        @db.execute("CREATE TABLE lsh_buckets (tbl INTEGER NOT NULL, bucket INTEGER NOT NULL, row_id INTEGER NOT NULL)")
+        @db.execute("CREATE INDEX lsh_buckets_lookup ON lsh_buckets (tbl, bucket)")
+        @db.execute("INSERT INTO lsh_planes (planes) VALUES (?)", [planes.to_blob])
+
+        @lsh_planes = planes
+        write_lsh_buckets(raw_rows.map { |id, _, blob| [id, blob] })
+      end
+    end
+
+    def drop_lsh_buckets
+      @db.execute("DROP TABLE IF EXISTS lsh_planes")
+      @db.execute("DROP TABLE IF EXISTS lsh_buckets")
+      @lsh_planes = nil
+    end
+
+    # Add the buckets of stored rows ([[id, blob], ...]) to the lsh_buckets table
+    def write_lsh_buckets(rows)
+      rows.each do |id, blob|
+        @lsh_planes.buckets(embedding_of(blob), probes: 0).each do |table, bucket|
+          @db.execute("INSERT INTO lsh_buckets (tbl, bucket, row_id) VALUES (?, ?, ?)", [table, bucket, id])
+        end
+      end
+    end
+
+    # Search through the LSH buckets kept in the database: the rows sharing a bucket
+    # with the query are loaded and ranked with the database metric
+    def search_lsh_buckets(query_obj, k)
+      raise ArgumentError, "no LSH buckets in the database: call build_lsh_index(persist: true)" unless @lsh_planes
+
+      pairs = @lsh_planes.buckets(query_obj)
+      conditions = (["(tbl = ? AND bucket = ?)"] * pairs.size).join(" OR ")
+      rows = @db.execute(<<~SQL, pairs.flatten)
+        SELECT id, content, embedding FROM embeddings
+        WHERE id IN (SELECT row_id FROM lsh_buckets WHERE #{conditions})
+      SQL
+      return [] if rows.empty?
+
+      blobs = rows.map { |_, _, blob| blob }
+      rank(query_obj, blobs, k).map do |index, similarity|
+        id, content, _ = rows[index]
+        [id, content, similarity]
+      end
+    end
+
+    # Id of the stored row most similar to an embedding when their cosine similarity reaches the threshold
+    def nearest_duplicate(embedding, threshold)
+      rows = raw_rows
+      return if rows.empty?
+
+      index, similarity = matrix_of(rows).top_k(to_embedding(embedding), 1).first
+      rows[index].first if similarity >= threshold
+    end
+
+    # Append a text to the content of a row, unless the row already contains it
+    def merge_content(id, text)
+      content = @db.get_first_value("SELECT content FROM embeddings WHERE id = ?", [id])
+      return if content.include?(text)
+
+      @db.execute("UPDATE embeddings SET content = ? WHERE id = ?", ["#{content}\n#{text}", id])
+    end
+
+    # File of the persisted projection (nil for in-memory databases)
+    def projection_path
+      "#{@path}.projection" unless @path.to_s.empty? || @path == ":memory:"
+    end
+
+    # Projection saved by project_embeddings!, if any
+    def saved_projection
+      path = projection_path
+      RagEmbeddings::Projection.load(path) if path && File.exist?(path)
+    end
+
+    # Store the cluster of each row, adding the column when missing
+    def write_clusters(assignments)
+      columns = @db.execute("PRAGMA table_info(embeddings)").map { |column| column[1] }
+      @db.execute("ALTER TABLE embeddings ADD COLUMN cluster INTEGER") unless columns.include?("cluster")
+
+      @db.transaction do
+        assignments.each do |id, cluster|
+          @db.execute("UPDATE embeddings SET cluster = ? WHERE id = ?", [cluster, id])
+        end
+      end
+    end
+
+    # Saved IVF index, or nil when the file is truncated or corrupted
+    def load_ivf_index(path)
+      RagEmbeddings::IVFIndex.load(path)
+    rescue ArgumentError
+      nil
+    end
+
+    # Digest of the ids and embeddings of the rows, telling whether an index still matches them
+    def rows_digest(rows)
+      digest = Digest::SHA256.new
+      rows.each { |id, _, blob| digest << [id, blob.bytesize].pack("q<Q<") << blob }
+      digest.hexdigest
+    end
+
+    # File of the persisted IVF index (nil for in-memory databases)
+    def ivf_path
+      "#{@path}.ivf" unless @path.to_s.empty? || @path == ":memory:"
+    end
+
+    # Results of an index ([[id, score], ...]) with the content of each row.
+    # Ids no longer in the table are skipped
+    def search_index(index, query_obj, k)
+      results = index.top_k(query_obj, k)
+      return [] if results.empty?
+
+      contents = contents_by_id(results.map(&:first))
+      results.filter_map do |id, score|
+        [id, contents[id], score] if contents.key?(id)
+      end
+    end
+
+    def contents_by_id(ids)
+      placeholders = (["?"] * ids.size).join(", ")
+      @db.execute("SELECT id, content FROM embeddings WHERE id IN (#{placeholders})", ids).to_h
+    end
+
+    # All the rows in one EmbeddingMatrix, in the order of the rows
+    def matrix_of(rows)
+      if quantization
+        RagEmbeddings::EmbeddingMatrix.from_arrays(rows.map { |_, _, blob| embedding_of(blob) })
+      else
+        RagEmbeddings::EmbeddingMatrix.from_blobs(rows.map { |_, _, blob| blob }, dtype:)
+      end
+    end
+
+    # Float Embedding of a stored blob, dequantizing int8 rows
+    def embedding_of(blob)
+      quantization ? decode(blob).dequantize : decode(blob)
+    end
+
+    # Fraction of the exact result ids also found by an approximate search
+    def recall(exact, approximate)
+      return 1.0 if exact.empty?
+
+      (exact & approximate).size.fdiv(exact.size)
+    end
+
+    # Query as an Embedding, embedding it first when it is a text,
+    # and truncated and projected like the stored rows
+    def query_embedding(query)
+      embedding = case query
+                  when RagEmbeddings::Embedding then query
+                  when String then RagEmbeddings::Embedding.from_array(RagEmbeddings.embed(query))
+                  else RagEmbeddings::Embedding.from_array(query.to_a)
+                  end
+      embedding = embedding.truncate(dimensions) if dimensions
+      embedding = projection.apply(embedding) if projection
+      dot_product_search? ? unit(embedding) : embedding
+    end
+
+    # Rank the stored blobs against the query: [[index, score], ...], best first
+    def rank(query_obj, blobs, k, prefilter: nil, candidates: nil)
+      if quantization
+        RagEmbeddings::QuantizedEmbedding.top_k(query_obj, blobs, k, metric:)
+      else
+        # Blobs are copied straight into the matrix, skipping intermediate Ruby arrays
+        matrix = RagEmbeddings::EmbeddingMatrix.from_blobs(blobs, dtype:)
+        matrix.top_k(query_obj, k, metric: search_metric, prefilter:, candidates:)
+      end
+    end
+
+    # Metric the rows are ranked with: :dot when the cosine fast path applies (see dot_product_search?)
+    def search_metric
+      dot_product_search? ? :dot : metric
+    end
+
+    # The stored rows ([[id, content], ...]) and an EmbeddingMatrix of their embeddings (nil when empty),
+    # kept between searches: a query then neither copies every blob again nor packs the sign bits of
+    # every row for the binary prefilter, which the matrix does once and keeps.
+    # Built again after a write from this connection (total_changes) or another one (PRAGMA data_version)
+    def stored_matrix
+      version = [@db.total_changes, @db.get_first_value("PRAGMA data_version")]
+      unless @stored_matrix && @stored_matrix[0] == version
+        rows = raw_rows
+        matrix = RagEmbeddings::EmbeddingMatrix.from_blobs(rows.map { |_, _, blob| blob }, dtype:) unless rows.empty?
+        @stored_matrix = [version, rows.map { |id, content, _| [id, content] }, matrix]
+      end
+      @stored_matrix.drop(1)
+    end
+
+    # Blob stored for an embedding, according to dtype, dimensions, projection and quantization
+    def encode(embedding, dimensions: self.dimensions, projection: self.projection)
+      embedding = to_embedding(embedding, dimensions:, projection:)
+      quantization ? embedding.quantize(quantization).to_blob : embedding.to_blob
+    end
+
+    # Object decoded from a stored blob (an Embedding or a QuantizedEmbedding)
+    def decode(blob)
+      if quantization
+        RagEmbeddings::QuantizedEmbedding.from_blob(blob)
+      else
+        RagEmbeddings::Embedding.from_blob(blob, dtype:)
+      end
+    end
+
+    # Embedding in the database dtype, truncated to dimensions, projected and then normalized
+    # (see normalize_rows?), converting arrays and embeddings of another dtype
+    def to_embedding(embedding, dimensions: self.dimensions, projection: self.projection)
+      embedding = RagEmbeddings::Embedding.from_array(embedding.to_a) unless embedding.is_a?(RagEmbeddings::Embedding)
+      embedding = embedding.truncate(dimensions) if dimensions
+      embedding = projection.apply(embedding) if projection
+      embedding = unit(embedding) if normalize_rows?
+      embedding.dtype == dtype ? embedding : RagEmbeddings::Embedding.from_array(embedding.to_a, dtype:)
+    end
+
+    # Embedding scaled to unit length; zero vectors, which have no direction, are kept
+    def unit(embedding)
+      embedding.normalized? || embedding.magnitude.zero? ? embedding : embedding.normalize
+    end
+
+    # Rows with the embedding still in its packed binary form
+    def raw_rows
+      @db.execute("SELECT id, content, embedding FROM embeddings")
     end
   end
 end
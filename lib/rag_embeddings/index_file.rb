@@ -0,0 +1,27 @@
+module RagEmbeddings
+  # save / load for the indexes and projections implemented in C, on top of their to_blob / from_blob
+  module IndexFile
+    def self.included(base)
+      base.extend(ClassMethods)
+    end
+
+    # Write the serialized object to path
+    def save(path)
+      File.binwrite(path, to_blob)
+      path
+    end
+
+    module ClassMethods
+      # Read an object written by save
+      def load(path)
+        from_blob(File.binread(path))
+      end
+    end
+  end
+
+  PQIndex.include(IndexFile)
+  HNSWIndex.include(IndexFile)
+  IVFIndex.include(IndexFile)
+  LSHIndex.include(IndexFile)
+  Projection.include(IndexFile)
+end
@@ -0,0 +1,68 @@
+begin
+  require "numo/narray"
+rescue LoadError
+  # numo-narray is optional: the Numo conversions below are only defined when it is installed
+end
+
+if defined?(Numo::NArray)
+  module RagEmbeddings
+    # Conversions between embeddings and Numo::SFloat / Numo::DFloat. Values move as packed binary strings
+    # between the C buffers (to_blob / from_blob on one side, NArray#to_binary / .from_binary on the other),
+    # never through Ruby arrays. DFloat values are cast to float32 by Numo
+    module NumoConversion
+      LITTLE_ENDIAN = [1].pack("S") == [1].pack("v")
+
+      module_function
+
+      # Little-endian float32 bytes of a Numo::SFloat or Numo::DFloat of ndim dimensions
+      def float32_blob(narray, ndim)
+        unless narray.is_a?(Numo::SFloat) || narray.is_a?(Numo::DFloat)
+          raise TypeError, "expected a Numo::SFloat or Numo::DFloat, got #{narray.class}"
+        end
+        if narray.ndim != ndim
+          raise ArgumentError, "expected #{ndim} dimension(s), got shape #{narray.shape.inspect}"
+        end
+
+        sfloat = Numo::SFloat.cast(narray)
+        (LITTLE_ENDIAN ? sfloat : sfloat.swap_byte).to_binary
+      end
+
+      # Numo array of the given type and shape holding little-endian float32 bytes
+      def narray(blob, shape, type)
+        unless [Numo::SFloat, Numo::DFloat].include?(type)
+          raise ArgumentError, "expected Numo::SFloat or Numo::DFloat, got #{type}"
+        end
+
+        sfloat = Numo::SFloat.from_binary(blob, shape)
+        sfloat = sfloat.swap_byte unless LITTLE_ENDIAN
+        type == Numo::SFloat ? sfloat : type.cast(sfloat)
+      end
+    end
+
+    class Embedding
+      # Embedding of the values of a 1-dimensional Numo::SFloat or Numo::DFloat.
+      # Like from_array, NaN, Infinity and values out of range for the dtype raise ArgumentError
+      # unless allow_nan: true
+      def self.from_numo(narray, dtype: :f32, allow_nan: false)
+        from_float32_blob(NumoConversion.float32_blob(narray, 1), dtype:, allow_nan:)
+      end
+
+      # Values as a Numo::SFloat (or Numo::DFloat) of shape [dim]; 16-bit embeddings are decoded in C
+      def to_numo(type = Numo::SFloat)
+        NumoConversion.narray(to_blob(dtype: :f32), [dim], type)
+      end
+    end
+
+    class EmbeddingMatrix
+      # Matrix of the rows of a 2-dimensional Numo::SFloat or Numo::DFloat (shape [rows, dim])
+      def self.from_numo(narray)
+        from_blob(NumoConversion.float32_blob(narray, 2), narray.shape[1])
+      end
+
+      # Rows as a Numo::SFloat (or Numo::DFloat) of shape [size, dim]
+      def to_numo(type = Numo::SFloat)
+        NumoConversion.narray(to_blob, [size, dim], type)
+      end
+    end
+  end
+end
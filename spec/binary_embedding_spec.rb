@@ -0,0 +1,56 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe RagEmbeddings::BinaryEmbedding do
+  let(:values) { Array.new(130) { rand - 0.5 } }
+  let(:other_values) { Array.new(130) { rand - 0.5 } }
+  let(:binary) { RagEmbeddings::Embedding.from_array(values).quantize(:binary) }
+  let(:other_binary) { RagEmbeddings::Embedding.from_array(other_values).binarize }
+
+  it "keeps one sign bit per dimension" do
+    expect(binary.dim).to eq 130
+    expect(binary.to_a).to eq(values.map { |v| v > 0 ? 1 : 0 })
+  end
+
+  it "counts the dimensions whose sign differs" do
+    expected = values.zip(other_values).count { |a, b| (a > 0) != (b > 0) }
+    expect(binary.hamming_distance(other_binary)).to eq expected
+    expect(binary.hamming_distance(binary)).to eq 0
+  end
+
+  it "raises on dimension mismatch" do
+    short = RagEmbeddings::Embedding.from_array([1.0, -1.0]).binarize
+    expect { binary.hamming_distance(short) }.to raise_error(ArgumentError)
+  end
+
+  it "round-trips through blobs packed in 64-bit words" do
+    blob = binary.to_blob
+    expect(blob.bytesize).to eq 4 + 3 * 8
+    expect(described_class.from_blob(blob).to_a).to eq binary.to_a
+  end
+
+  describe "binary prefilter" do
+    let(:rows) { Array.new(200) { Array.new(64) { rand - 0.5 } } }
+    let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(rows) }
+    let(:query) { RagEmbeddings::Embedding.from_array(rows[42].map { |v| v + (rand - 0.5) * 0.05 }) }
+
+    it "rescores the candidates with the float metric" do
+      result = matrix.top_k(query, 5, prefilter: :binary, candidates: 50)
+      expect(result.size).to eq 5
+      expect(result.first.first).to eq 42
+
+      result.each do |index, score|
+        expected = RagEmbeddings::Embedding.from_array(rows[index]).cosine_similarity(query)
+        expect(score).to be_within(1e-6).of(expected)
+      end
+    end
+
+    it "matches the exact search when every row is a candidate" do
+      expect(matrix.top_k(query, 5, prefilter: :binary, candidates: 200)).to eq matrix.top_k(query, 5)
+    end
+
+    it "rejects unknown prefilters" do
+      expect { matrix.top_k(query, 5, prefilter: :lsh) }.to raise_error(ArgumentError)
+    end
+  end
+end
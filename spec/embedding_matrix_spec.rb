@@ -0,0 +1,139 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe RagEmbeddings::EmbeddingMatrix do
+  let(:rows) do
+    [
+      [1.0, 0.0, 0.0],
+      [0.0, 1.0, 0.0],
+      [0.7, 0.7, 0.0],
+      [-1.0, 0.0, 0.0]
+    ]
+  end
+  let(:matrix) { described_class.from_arrays(rows) }
+  let(:query) { RagEmbeddings::Embedding.from_array([1.0, 0.1, 0.0]) }
+
+  it "stores rows contiguously with a shared dimension" do
+    expect(matrix.size).to eq 4
+    expect(matrix.dim).to eq 3
+  end
+
+  it "round-trips every row through one blob" do
+    blob = matrix.to_blob
+    expect(blob).to eq rows.flatten.pack("e*")
+
+    copy = described_class.from_blob(blob, 3)
+    expect([copy.size, copy.dim]).to eq [4, 3]
+    expect(copy.top_k(query, 4)).to eq matrix.top_k(query, 4)
+    expect { described_class.from_blob(blob, 5) }.to raise_error(ArgumentError, /dimension 5/)
+    expect { described_class.from_blob(blob, -4) }.to raise_error(ArgumentError, /must be positive/)
+  end
+
+  it "ranks rows with NaN scores after every other row" do
+    # The NaN row comes first, so it fills the heap before the finite rows are offered
+    blob = [[Float::NAN, 0.0, 0.0], *rows].flatten.pack("e*")
+    with_nan = described_class.from_blob(blob, 3)
+
+    expect(with_nan.top_k(query, 4).map(&:first)).to eq(matrix.top_k(query, 4).map { |index, _| index + 1 })
+    expect(with_nan.top_k(query, 5).last.first).to eq 0
+    expect(with_nan.top_k(query, 5, metric: :euclidean).last.first).to eq 0
+  end
+
+  it "returns the k most similar rows ordered by score" do
+    result = matrix.top_k(query, 2)
+    expect(result.map(&:first)).to eq [0, 2]
+    expect(result.first.last).to be_within(1e-6).of(query.cosine_similarity(RagEmbeddings::Embedding.from_array(rows[0])))
+  end
+
+  it "matches a full sort of cosine similarities" do
+    data = Array.new(200) { Array.new(16) { rand - 0.5 } }
+    q = RagEmbeddings::Embedding.from_array(Array.new(16) { rand - 0.5 })
+    expected = data.each_with_index
+                   .map { |emb, i| [i, RagEmbeddings::Embedding.from_array(emb).cosine_similarity(q)] }
+                   .sort_by { |_, sim| -sim }
+                   .first(10)
+
+    result = described_class.from_arrays(data).top_k(q, 10)
+    expect(result.map(&:first)).to eq expected.map(&:first)
+  end
+
+  it "ranks by ascending distance with a distance metric" do
+    result = matrix.top_k(query, 2, metric: :euclidean)
+    expect(result.map(&:first)).to eq [0, 2]
+    expect(result.first.last).to be < result.last.last
+  end
+
+  it "clamps k to the number of rows" do
+    expect(matrix.top_k(query, 10).size).to eq 4
+    expect(matrix.top_k(query, 0)).to eq []
+  end
+
+  it "accepts new rows with push" do
+    matrix << [0.9, 0.1, 0.0]
+    matrix.push(RagEmbeddings::Embedding.from_array([0.0, 0.0, 1.0]))
+    expect(matrix.size).to eq 6
+  end
+
+  it "builds from packed blobs" do
+    from_blobs = described_class.from_blobs(rows.map { |row| row.pack("e*") })
+    expect(from_blobs.top_k(query, 4)).to eq matrix.top_k(query, 4)
+  end
+
+  it "raises on dimension mismatch" do
+    expect { matrix << [1.0, 2.0] }.to raise_error(ArgumentError)
+    expect { matrix.top_k(RagEmbeddings::Embedding.from_array([1.0]), 1) }.to raise_error(ArgumentError)
+  end
+
+  describe "#mmr" do
+    let(:near_duplicates) { described_class.from_arrays([[1.0, 0.0, 0.0], [0.99, 0.01, 0.0], [0.0, 1.0, 0.0]]) }
+    let(:q) { RagEmbeddings::Embedding.from_array([1.0, 0.5, 0.0]) }
+
+    it "prefers a diverse row over a near-duplicate of the first pick" do
+      expect(near_duplicates.top_k(q, 2).map(&:first)).to eq [1, 0]
+
+      result = near_duplicates.mmr(q, 2)
+      expect(result.map(&:first)).to eq [1, 2]
+      expect(result.last.last).to be_within(1e-6).of(q.cosine_similarity(RagEmbeddings::Embedding.from_array([0.0, 1.0, 0.0])))
+    end
+
+    it "matches the cosine top k with lambda 1" do
+      expect(near_duplicates.mmr(q, 3, lambda: 1.0).map(&:first)).to eq near_duplicates.top_k(q, 3).map(&:first)
+    end
+
+    it "starts from the most relevant row with lambda 0" do
+      result = near_duplicates.mmr(q, 2, lambda: 0.0)
+      expect(result.map(&:first)).to eq [1, 2]
+    end
+
+    it "clamps k and validates lambda" do
+      expect(near_duplicates.mmr(q, 10).size).to eq 3
+      expect(near_duplicates.mmr(q, 0)).to eq []
+      expect { near_duplicates.mmr(q, 2, lambda: 1.5) }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe "#near_duplicates" do
+    let(:duplicates) do
+      described_class.from_arrays([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.99, 0.01, 0.0], [0.0, 0.0, 1.0], [0.0, 0.98, 0.02], [0.0, 0.0, 0.0]])
+    end
+
+    it "groups rows above the threshold" do
+      expect(duplicates.near_duplicates(0.95)).to eq [[0, 2], [1, 4]]
+      expect(duplicates.near_duplicates(0.99999)).to eq []
+    end
+
+    it "orders interleaved clusters by their first row" do
+      interleaved = described_class.from_arrays([[1.0, 0.0], [0.0, 1.0], [0.01, 1.0], [0.0, -1.0], [-0.01, -1.0], [1.0, 0.01]])
+      expect(interleaved.near_duplicates(0.99)).to eq [[0, 5], [1, 2], [3, 4]]
+    end
+
+    it "joins rows linked through another row" do
+      chain = described_class.from_arrays([[1.0, 0.0], [0.9659, 0.2588], [0.866, 0.5]])
+      expect(chain.near_duplicates(0.95)).to eq [[0, 1, 2]]
+    end
+
+    it "validates the threshold" do
+      expect { duplicates.near_duplicates(1.5) }.to raise_error(ArgumentError)
+    end
+  end
+end
@@ -0,0 +1,408 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe RagEmbeddings::Embedding do
+  def ruby_cosine(a, b)
+    dot = a.zip(b).sum { |x, y| x * y }
+    dot / (Math.sqrt(a.sum { |x| x * x }) * Math.sqrt(b.sum { |x| x * x }))
+  end
+
+  describe ".simd_backend" do
+    it "reports the kernel selected at load time" do
+      expect(%i[avx512 avx2 sse2 scalar]).to include(described_class.simd_backend)
+    end
+  end
+
+  describe "SIMD kernels" do
+    # Odd sizes exercise the scalar tail after the vector loop
+    [1, 3, 7, 17, 768, 3073].each do |size|
+      it "agree with a reference implementation for #{size} dimensions" do
+        a = Array.new(size) { rand - 0.5 }
+        b = Array.new(size) { rand - 0.5 }
+        emb_a = described_class.from_array(a)
+        emb_b = described_class.from_array(b)
+
+        fa = emb_a.to_a
+        expect(emb_a.cosine_similarity(emb_b)).to be_within(1e-6).of(ruby_cosine(fa, emb_b.to_a))
+        expect(emb_a.magnitude).to be_within(1e-6).of(Math.sqrt(fa.sum { |x| x * x }))
+        expect(emb_a.normalize!.magnitude).to be_within(1e-5).of(1.0)
+      end
+    end
+  end
+
+  describe "distance metrics" do
+    let(:a) { described_class.from_array([1.0, 2.0, 3.0]) }
+    let(:b) { described_class.from_array([4.0, 0.0, -1.0]) }
+
+    it "computes the dot product" do
+      expect(a.dot_product(b)).to be_within(1e-6).of(1.0)
+    end
+
+    it "computes euclidean and squared euclidean distances" do
+      expect(a.squared_euclidean_distance(b)).to be_within(1e-6).of(29.0)
+      expect(a.euclidean_distance(b)).to be_within(1e-6).of(Math.sqrt(29.0))
+    end
+
+    it "computes manhattan and chebyshev distances" do
+      expect(a.manhattan_distance(b)).to be_within(1e-6).of(9.0)
+      expect(a.chebyshev_distance(b)).to be_within(1e-6).of(4.0)
+    end
+
+    it "computes the angular distance in [0, 1]" do
+      x = described_class.from_array([1.0, 0.0])
+      expect(x.angular_distance(described_class.from_array([0.0, 1.0]))).to be_within(1e-6).of(0.5)
+      expect(x.angular_distance(described_class.from_array([-1.0, 0.0]))).to be_within(1e-6).of(1.0)
+    end
+
+    it "dispatches by name with similarity(metric:)" do
+      expect(a.similarity(b)).to eq a.cosine_similarity(b)
+      expect(a.similarity(b, metric: :manhattan)).to eq a.manhattan_distance(b)
+      expect(a.similarity(b, metric: "dot")).to eq a.dot_product(b)
+      expect { a.similarity(b, metric: :hamming) }.to raise_error(ArgumentError, /Unknown metric/)
+    end
+
+    it "raises on dimension mismatch" do
+      other = described_class.from_array([1.0])
+      %i[dot_product euclidean_distance manhattan_distance chebyshev_distance angular_distance].each do |m|
+        expect { a.public_send(m, other) }.to raise_error(ArgumentError, /Dimension mismatch/)
+      end
+    end
+  end
+
+  describe "dimension limits" do
+    it "accepts more than 65535 dimensions" do
+      emb = described_class.from_array(Array.new(70_000) { 0.5 })
+      expect(emb.dim).to eq 70_000
+      expect(emb.to_a.size).to eq 70_000
+      expect(emb.cosine_similarity(emb)).to be_within(1e-6).of(1.0)
+    end
+
+    it "raises ArgumentError above the configurable max_dim" do
+      original = described_class.max_dim
+      described_class.max_dim = 8
+      expect { described_class.from_array(Array.new(9) { 1.0 }) }.to raise_error(ArgumentError, /maximum 8/)
+      expect(described_class.from_array(Array.new(8) { 1.0 }).dim).to eq 8
+    ensure
+      described_class.max_dim = original
+    end
+
+    it "rejects a non-positive max_dim" do
+      expect { described_class.max_dim = 0 }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe "non-finite values" do
+    it "rejects NaN and Infinity with the index" do
+      expect { described_class.from_array([1.0, Float::NAN]) }.to raise_error(ArgumentError, /index 1 is not finite \(NaN\)/)
+      expect { described_class.from_array([-Float::INFINITY]) }.to raise_error(ArgumentError, /index 0 is not finite \(-Infinity\)/)
+    end
+
+    it "rejects values out of range for the dtype" do
+      expect { described_class.from_array([1.0, 1e300]) }.to raise_error(ArgumentError, /index 1 .* out of range for f32/)
+      expect { described_class.from_array([70_000.0], dtype: :f16) }.to raise_error(ArgumentError, /out of range for f16/)
+    end
+
+    it "keeps them with allow_nan: true" do
+      emb = described_class.from_array([1.0, Float::NAN], allow_nan: true)
+      expect(emb.to_a.last).to be_nan
+      expect(emb).not_to be_finite
+      expect(described_class.from_array([1.0, 2.0])).to be_finite
+    end
+
+    it "gives NaN similarities instead of finite numbers" do
+      nan = described_class.from_array([Float::NAN, 0.0], allow_nan: true)
+      infinite = described_class.from_array([Float::INFINITY, 1.0], allow_nan: true)
+      zero = described_class.from_array([0.0, 0.0])
+      one = described_class.from_array([1.0, 2.0])
+
+      expect(infinite.cosine_similarity(zero)).to be_nan
+      expect(nan.cosine_similarity(one)).to be_nan
+      expect(nan.chebyshev_distance(one)).to be_nan
+      expect(infinite.angular_distance(one)).to be_nan
+      expect(one.cosine_similarity(zero)).to eq 0.0
+    end
+  end
+
+  describe "cached norms" do
+    let(:emb) { described_class.from_array([3.0, 4.0]) }
+
+    it "tracks unit length through normalize!" do
+      expect(emb.magnitude).to eq 5.0
+      expect(emb).not_to be_normalized
+
+      emb.normalize!
+      expect(emb.magnitude).to be_within(1e-6).of(1.0)
+      expect(emb).to be_normalized
+      expect(emb.dup).to be_normalized
+    end
+
+    it "considers 16-bit embeddings unit length within their precision" do
+      %i[f16 bf16].each do |dtype|
+        expect(described_class.from_array(Array.new(384) { rand - 0.5 }, dtype:).normalize).to be_normalized
+      end
+    end
+
+    it "gives the same cosine through the dot product of normalized embeddings" do
+      a = described_class.from_array(Array.new(64) { rand - 0.5 })
+      b = described_class.from_array(Array.new(64) { rand - 0.5 })
+      expected = a.cosine_similarity(b)
+
+      expect(a.normalize.cosine_similarity(b.normalize)).to be_within(1e-6).of(expected)
+      expect(a.normalize.angular_distance(b)).to be_within(1e-6).of(Math.acos(expected) / Math::PI)
+    end
+
+    it "is computed before freezing" do
+      emb.freeze
+      expect(emb.magnitude).to eq 5.0
+      expect(emb.normalize).to be_normalized
+    end
+  end
+
+  describe "binary blobs" do
+    let(:values) { [1.0, -2.5, 0.125, 3.0] }
+
+    it "round-trips through to_blob and from_blob" do
+      emb = described_class.from_array(values)
+      blob = emb.to_blob
+      expect(blob.bytesize).to eq 16
+      expect(blob.encoding).to eq Encoding::BINARY
+      expect(described_class.from_blob(blob).to_a).to eq values
+    end
+
+    it 'uses the little-endian float32 layout of pack("e*")' do
+      expect(described_class.from_blob(values.pack("e*")).to_a).to eq values
+      expect(described_class.from_array(values).to_blob).to eq values.pack("e*")
+    end
+
+    it "converts to another dtype with to_blob(dtype:)" do
+      half = described_class.from_array(values, dtype: :f16)
+      expect(half.to_blob(dtype: :f32)).to eq values.pack("e*")
+      expect(described_class.from_array(values).to_blob(dtype: :f16)).to eq half.to_blob
+    end
+
+    it "validates the blob length" do
+      expect { described_class.from_blob("abc") }.to raise_error(ArgumentError, /multiple of 4/)
+      expect { described_class.from_blob("") }.to raise_error(ArgumentError, /empty/)
+    end
+  end
+
+  describe "16-bit storage types" do
+    let(:values) { [0.1, -0.25, 3.5, 1.0e-3, 0.0] }
+
+    it "defaults to f32" do
+      expect(described_class.from_array(values).dtype).to eq :f32
+    end
+
+    %i[f16 bf16].each do |dtype|
+      context dtype.to_s do
+        let(:emb) { described_class.from_array(values, dtype:) }
+        let(:reference) { described_class.from_array(values) }
+
+        it "stores 2 bytes per value" do
+          expect(emb.dtype).to eq dtype
+          expect(emb.to_blob.bytesize).to eq values.size * 2
+        end
+
+        it "keeps values close to the float32 ones" do
+          emb.to_a.zip(values).each do |actual, expected|
+            expect(actual).to be_within(expected.abs * 0.01 + 1e-6).of(expected)
+          end
+        end
+
+        it "round-trips through blobs" do
+          copy = described_class.from_blob(emb.to_blob, dtype:)
+          expect(copy.dtype).to eq dtype
+          expect(copy.to_a).to eq emb.to_a
+        end
+
+        it "compares with float32 embeddings" do
+          expect(emb.cosine_similarity(reference)).to be_within(1e-3).of(1.0)
+          expect(emb.normalize!.magnitude).to be_within(1e-2).of(1.0)
+        end
+      end
+    end
+
+    it "rejects unknown dtypes" do
+      expect { described_class.from_array(values, dtype: :f8) }.to raise_error(ArgumentError, /Unknown dtype/)
+    end
+  end
+
+  describe "vector arithmetic" do
+    let(:a) { described_class.from_array([1.0, 2.0, 3.0]) }
+    let(:b) { described_class.from_array([0.5, -1.0, 2.0]) }
+
+    it "adds and subtracts embeddings" do
+      expect((a + b).to_a).to eq [1.5, 1.0, 5.0]
+      expect((a - b).to_a).to eq [0.5, 3.0, 1.0]
+    end
+
+    it "multiplies by a scalar" do
+      expect((a * 2).to_a).to eq [2.0, 4.0, 6.0]
+      expect { a * "2" }.to raise_error(TypeError)
+    end
+
+    it "exposes dot as a shorthand for dot_product" do
+      expect(a.dot(b)).to eq a.dot_product(b)
+    end
+
+    it "normalizes without touching the receiver" do
+      unit = a.normalize
+      expect(unit.magnitude).to be_within(1e-6).of(1.0)
+      expect(a.to_a).to eq [1.0, 2.0, 3.0]
+      expect { described_class.from_array([0.0, 0.0]).normalize }.to raise_error(ZeroDivisionError)
+    end
+
+    it "keeps the storage type of the receiver" do
+      half = described_class.from_array([1.0, 2.0, 3.0], dtype: :f16)
+      expect((half + b).dtype).to eq :f16
+      expect(half.normalize.dtype).to eq :f16
+    end
+
+    it "raises on dimension mismatch" do
+      expect { a + described_class.from_array([1.0, 2.0]) }.to raise_error(ArgumentError, /Dimension mismatch/)
+    end
+
+    it "averages a list of embeddings" do
+      expect(described_class.mean([a, b]).to_a).to eq [0.75, 0.5, 2.5]
+      expect(described_class.mean([a, [3.0, 4.0, 5.0]]).to_a).to eq [2.0, 3.0, 4.0]
+      expect { described_class.mean([]) }.to raise_error(ArgumentError)
+      expect { described_class.mean([a, [1.0]]) }.to raise_error(ArgumentError)
+    end
+
+    it "computes a weighted mean" do
+      expect(described_class.weighted_mean([a, b], [3, 1]).to_a).to eq [0.875, 1.25, 2.75]
+      expect { described_class.weighted_mean([a, b], [1]) }.to raise_error(ArgumentError)
+      expect { described_class.weighted_mean([a, b], [1, -1]) }.to raise_error(ArgumentError, /zero/)
+    end
+  end
+
+  describe "copies, Marshal and equality" do
+    let(:emb) { described_class.from_array([1.0, -2.5, 3.0]) }
+
+    it "dups and clones into independent embeddings" do
+      copy = emb.dup
+      copy.normalize!
+      expect(emb.to_a).to eq [1.0, -2.5, 3.0]
+      expect(copy.magnitude).to be_within(1e-6).of(1.0)
+
+      emb.freeze
+      expect(emb.clone).to be_frozen
+      expect(emb.dup).not_to be_frozen
+      expect(emb.clone.to_a).to eq emb.to_a
+    end
+
+    it "still cannot be built with new" do
+      expect { described_class.new }.to raise_error(NoMethodError)
+    end
+
+    it "round-trips through Marshal, keeping the dtype" do
+      expect(Marshal.load(Marshal.dump(emb))).to eq emb
+
+      half = described_class.from_array([0.5, 1.5], dtype: :f16)
+      loaded = Marshal.load(Marshal.dump(half))
+      expect(loaded.dtype).to eq :f16
+      expect(loaded.to_blob).to eq half.to_blob
+    end
+
+    it "compares dtype and values" do
+      expect(emb).to eq described_class.from_array([1.0, -2.5, 3.0])
+      expect(emb).not_to eq described_class.from_array([1.0, -2.5, 3.5])
+      expect(emb).not_to eq described_class.from_array([1.0, -2.5, 3.0], dtype: :f16)
+      expect(emb).not_to eq [1.0, -2.5, 3.0]
+      expect(described_class.from_array([0.0])).to eq described_class.from_array([-0.0])
+    end
+
+    it "works as a Hash key" do
+      same = described_class.from_array([1.0, -2.5, 3.0])
+      expect(emb.eql?(same)).to be true
+      expect(emb.hash).to eq same.hash
+      expect({emb => :found}[same]).to eq :found
+      expect(described_class.from_array([0.0]).hash).to eq described_class.from_array([-0.0]).hash
+    end
+
+    it "compares approximately within a tolerance" do
+      near = described_class.from_array([1.0, -2.5, 3.0 + 1e-7])
+      expect(emb.approx_equal?(near)).to be true
+      expect(emb.approx_equal?(described_class.from_array([1.0, -2.5, 3.01]))).to be false
+      expect(emb.approx_equal?(described_class.from_array([1.0, -2.5, 3.01]), 0.1)).to be true
+      expect(emb.approx_equal?(described_class.from_array([1.0, -2.5, 3.0], dtype: :f16))).to be true
+      expect(emb.approx_equal?(described_class.from_array([1.0, -2.5]))).to be false
+      expect { emb.approx_equal?(near, -1) }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe "element access" do
+    let(:emb) { described_class.from_array([1.0, -2.5, 3.0, 4.0]) }
+
+    it "reads values by index like an Array" do
+      expect(emb[0]).to eq 1.0
+      expect(emb[-1]).to eq 4.0
+      expect(emb[4]).to be_nil
+      expect(emb[-5]).to be_nil
+      expect(emb.size).to eq 4
+    end
+
+    it "reads spans by start and length or range" do
+      expect(emb[1, 2]).to eq [-2.5, 3.0]
+      expect(emb[1..]).to eq [-2.5, 3.0, 4.0]
+      expect(emb[-2..]).to eq [3.0, 4.0]
+      expect(emb[4, 1]).to eq []
+      expect(emb[5, 1]).to be_nil
+      expect(emb[2, 10]).to eq [3.0, 4.0]
+    end
+
+    it "slices into a new embedding of the same dtype" do
+      sliced = emb.slice(1..2)
+      expect(sliced).to be_a described_class
+      expect(sliced.to_a).to eq [-2.5, 3.0]
+      expect(emb.slice(0, 2).to_a).to eq [1.0, -2.5]
+
+      half = described_class.from_array([0.5, 1.5, 2.5], dtype: :f16).slice(1..)
+      expect(half.dtype).to eq :f16
+      expect(half.to_a).to eq [1.5, 2.5]
+
+      expect(emb.slice(5..)).to be_nil
+      expect { emb.slice(4..) }.to raise_error(ArgumentError)
+      expect { emb.slice(1) }.to raise_error(TypeError)
+    end
+
+    it "is Enumerable" do
+      expect(emb.each.size).to eq 4
+      expect(emb.each.to_a).to eq emb.to_a
+      expect(emb.max).to eq 4.0
+      expect(emb.sum).to eq 5.5
+      expect(emb.map(&:abs)).to eq [1.0, 2.5, 3.0, 4.0]
+      expect(emb.each { |_| }).to equal emb
+    end
+
+    it "previews the values in inspect" do
+      expect(emb.inspect).to eq "#<RagEmbeddings::Embedding dim=4 dtype=f32 [1, -2.5, 3, 4]>"
+
+      long = described_class.from_array((1..10).map(&:to_f))
+      expect(long.inspect).to eq "#<RagEmbeddings::Embedding dim=10 dtype=f32 [1, 2, 3, ..., 8, 9, 10]>"
+    end
+  end
+
+  describe "#truncate" do
+    let(:emb) { described_class.from_array([3.0, 4.0, 12.0]) }
+
+    it "keeps a prefix scaled back to unit length" do
+      short = emb.truncate(2)
+      expect(short.dim).to eq 2
+      expect(short.to_a[0]).to be_within(1e-6).of(0.6)
+      expect(short.to_a[1]).to be_within(1e-6).of(0.8)
+      expect(emb.dim).to eq 3
+    end
+
+    it "keeps the raw prefix and the dtype on request" do
+      expect(emb.truncate(2, normalize: false).to_a).to eq [3.0, 4.0]
+      expect(described_class.from_array([3.0, 4.0, 12.0], dtype: :f16).truncate(2).dtype).to eq :f16
+    end
+
+    it "rejects dimensions out of range and zero prefixes" do
+      expect { emb.truncate(0) }.to raise_error(ArgumentError)
+      expect { emb.truncate(4) }.to raise_error(ArgumentError)
+      expect { described_class.from_array([0.0, 1.0]).truncate(1) }.to raise_error(ZeroDivisionError)
+    end
+  end
+end
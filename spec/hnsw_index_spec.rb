@@ -0,0 +1,94 @@
+require "spec_helper"
+require "rag_embeddings"
+require "tmpdir"
+
+RSpec.describe RagEmbeddings::HNSWIndex do
+  let(:rows) { Array.new(1000) { Array.new(32) { rand - 0.5 } } }
+  let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(rows) }
+  let(:index) do
+    described_class.create(32, m: 16, ef_construction: 100, seed: 1).tap do |hnsw|
+      rows.each_with_index { |row, i| hnsw.add(i, row) }
+    end
+  end
+  let(:queries) { Array.new(20) { RagEmbeddings::Embedding.from_array(Array.new(32) { rand - 0.5 }) } }
+
+  it "exposes its parameters" do
+    expect(index.size).to eq 1000
+    expect(index.dim).to eq 32
+    expect(index.m).to eq 16
+    expect(index.ef_construction).to eq 100
+    expect(index.ef_search).to eq 50
+    expect(index.metric).to eq :cosine
+  end
+
+  it "finds almost the same neighbours as the exact search" do
+    recalls = queries.map do |query|
+      exact = matrix.top_k(query, 10).map(&:first)
+      (exact & index.top_k(query, 10).map(&:first)).size / 10.0
+    end
+    expect(recalls.sum / recalls.size).to be > 0.9
+  end
+
+  it "returns exact cosine scores, best first" do
+    result = index.top_k(queries.first, 5, ef: 100)
+    result.each do |id, score|
+      expect(score).to be_within(1e-5).of(RagEmbeddings::Embedding.from_array(rows[id]).cosine_similarity(queries.first))
+    end
+    expect(result.map(&:last)).to eq result.map(&:last).sort.reverse
+  end
+
+  it "skips deleted nodes" do
+    nearest = index.top_k(queries.first, 1).first.first
+    expect(index.delete(nearest)).to be true
+    expect(index.delete(nearest)).to be false
+    expect(index.size).to eq 999
+    expect(index.top_k(queries.first, 10).map(&:first)).not_to include(nearest)
+  end
+
+  it "reports euclidean distances in ascending order" do
+    l2 = described_class.create(32, metric: :euclidean)
+    rows.first(100).each_with_index { |row, i| l2.add(i, row) }
+
+    result = l2.top_k(rows[7], 3)
+    expect(result.first).to eq [7, 0.0]
+    expect(result.map(&:last)).to eq result.map(&:last).sort
+  end
+
+  it "saves and loads the graph" do
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, "index.hnsw")
+      index.delete(3)
+      index.save(path)
+      loaded = described_class.load(path)
+
+      expect(loaded.size).to eq 999
+      expect(loaded.top_k(queries.first, 10)).to eq index.top_k(queries.first, 10)
+
+      loaded.add(5000, rows.first)
+      expect(loaded.size).to eq 1000
+    end
+  end
+
+  it "validates its arguments" do
+    expect { described_class.create(32, metric: :dot) }.to raise_error(ArgumentError)
+    expect { described_class.create(32, m: 1) }.to raise_error(ArgumentError)
+    expect { index.add(1, [1.0, 2.0]) }.to raise_error(ArgumentError)
+    expect { described_class.from_blob(index.to_blob[0...-4]) }.to raise_error(ArgumentError)
+  end
+
+  it "rejects a blob whose entry point is not on the top level" do
+    rows = Array.new(500) { Array.new(8) { rand - 0.5 } }
+    big = described_class.create(8, m: 4, seed: 1)
+    rows.each_with_index { |row, id| big.add(id, row) }
+
+    blob = big.to_blob
+    # magic, version, 5 u32 settings, rng state and count come before the entry point
+    entry_offset = 4 + 4 + 5 * 4 + 8 + 8
+    levels_offset = entry_offset + 8 + rows.size * (8 * 4 + 8 + 1)
+    levels = blob.byteslice(levels_offset, rows.size).bytes
+    low = levels.index { |level| level < levels.max }
+
+    blob[entry_offset, 4] = [low].pack("V")
+    expect { described_class.from_blob(blob) }.to raise_error(ArgumentError, /entry point/)
+  end
+end
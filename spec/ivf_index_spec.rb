@@ -0,0 +1,64 @@
+require "spec_helper"
+require "rag_embeddings"
+require "tmpdir"
+
+RSpec.describe RagEmbeddings::IVFIndex do
+  let(:rows) { Array.new(1000) { Array.new(32) { rand - 0.5 } } }
+  let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(rows) }
+  let(:index) do
+    described_class.train(matrix, nlist: 10, nprobe: 5, seed: 1).tap do |ivf|
+      rows.each_with_index { |row, i| ivf.add(i, row) }
+    end
+  end
+  let(:query) { RagEmbeddings::Embedding.from_array(Array.new(32) { rand - 0.5 }) }
+
+  it "spreads the rows over nlist posting lists" do
+    expect(index.size).to eq 1000
+    expect(index.nlist).to eq 10
+    expect(index.nprobe).to eq 5
+    expect(index.list_sizes.sum).to eq 1000
+    expect(index.list_sizes).to all(be > 0)
+  end
+
+  it "matches the exact search when every list is probed" do
+    expect(index.top_k(query, 10, nprobe: 10)).to eq matrix.top_k(query, 10)
+  end
+
+  it "keeps most of the exact neighbours when probing half of the lists" do
+    queries = Array.new(20) { RagEmbeddings::Embedding.from_array(Array.new(32) { rand - 0.5 }) }
+    recalls = queries.map do |q|
+      exact = matrix.top_k(q, 10).map(&:first)
+      (exact & index.top_k(q, 10).map(&:first)).size / 10.0
+    end
+    expect(recalls.sum / recalls.size).to be > 0.6
+  end
+
+  it "supports distance metrics" do
+    l2 = described_class.train(matrix, nlist: 4, metric: :euclidean)
+    rows.each_with_index { |row, i| l2.add(i, row) }
+    expect(l2.top_k(query, 5, nprobe: 4)).to eq matrix.top_k(query, 5, metric: :euclidean)
+  end
+
+  it "deletes rows" do
+    nearest = index.top_k(query, 1, nprobe: 10).first.first
+    expect(index.delete(nearest)).to be true
+    expect(index.size).to eq 999
+    expect(index.top_k(query, 10, nprobe: 10).map(&:first)).not_to include(nearest)
+  end
+
+  it "saves and loads the index" do
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, "index.ivf")
+      index.save(path)
+      loaded = described_class.load(path)
+
+      expect(loaded.list_sizes).to eq index.list_sizes
+      expect(loaded.top_k(query, 10)).to eq index.top_k(query, 10)
+    end
+  end
+
+  it "needs at least nlist rows to train" do
+    small = RagEmbeddings::EmbeddingMatrix.from_arrays(rows.first(5))
+    expect { described_class.train(small, nlist: 10) }.to raise_error(ArgumentError)
+  end
+end
@@ -0,0 +1,48 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe "RagEmbeddings.kmeans" do
+  # Three well separated groups along different axes
+  let(:embeddings) do
+    Array.new(90) do |i|
+      Array.new(8) { |d| (d == i % 3 ? 5.0 : 0.0) + (rand - 0.5) * 0.5 }
+    end
+  end
+
+  it "finds the groups" do
+    result = RagEmbeddings.kmeans(embeddings, k: 3, seed: 1)
+
+    expect(result[:centroids].size).to eq 3
+    expect(result[:centroids]).to all(be_a(RagEmbeddings::Embedding))
+    expect(result[:assignments].size).to eq 90
+    expect(result[:assignments].first(3).uniq.size).to eq 3
+    result[:assignments].each_with_index do |cluster, i|
+      expect(cluster).to eq result[:assignments][i % 3]
+    end
+  end
+
+  it "returns unit-length centroids with the cosine metric" do
+    result = RagEmbeddings.kmeans(embeddings, k: 3, metric: :cosine)
+    result[:centroids].each { |centroid| expect(centroid.magnitude).to be_within(1e-5).of(1.0) }
+  end
+
+  it "returns mean centroids with the euclidean metric" do
+    result = RagEmbeddings.kmeans(embeddings, k: 3, metric: :euclidean)
+    expect(result[:centroids].map(&:magnitude)).to all(be_within(0.5).of(5.0))
+  end
+
+  it "is reproducible with the same seed" do
+    matrix = RagEmbeddings::EmbeddingMatrix.from_arrays(embeddings)
+    first = RagEmbeddings.kmeans(matrix, k: 4, seed: 7)
+    second = RagEmbeddings.kmeans(matrix, k: 4, seed: 7)
+
+    expect(second[:assignments]).to eq first[:assignments]
+    expect(second[:centroids].map(&:to_a)).to eq first[:centroids].map(&:to_a)
+  end
+
+  it "validates its arguments" do
+    expect { RagEmbeddings.kmeans(embeddings) }.to raise_error(ArgumentError)
+    expect { RagEmbeddings.kmeans(embeddings, k: 100) }.to raise_error(ArgumentError)
+    expect { RagEmbeddings.kmeans(embeddings, k: 3, metric: :manhattan) }.to raise_error(ArgumentError)
+  end
+end
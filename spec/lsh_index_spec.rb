@@ -0,0 +1,73 @@
+require "spec_helper"
+require "rag_embeddings"
+require "tmpdir"
+
+RSpec.describe RagEmbeddings::LSHIndex do
+  let(:rows) { Array.new(2_000) { Array.new(32) { rand - 0.5 } } }
+  let(:index) do
+    described_class.create(32, tables: 8, bits: 10, seed: 42).tap do |lsh|
+      rows.each_with_index { |row, i| lsh.add(i, row) }
+    end
+  end
+  # Queries close to a known row
+  let(:queries) do
+    Array.new(20) { |q| [q * 50, rows[q * 50].map { |v| v + (rand - 0.5) * 0.1 }] }
+  end
+
+  it "stores every row with its settings" do
+    expect(index.size).to eq 2_000
+    expect(index.dim).to eq 32
+    expect(index.tables).to eq 8
+    expect(index.bits).to eq 10
+    expect(index.probes).to eq 0
+  end
+
+  it "finds the nearest row and scores it with the exact cosine" do
+    found = queries.count do |id, query|
+      best_id, score = index.top_k(query, 1).first
+      expected = RagEmbeddings::Embedding.from_array(query).cosine_similarity(RagEmbeddings::Embedding.from_array(rows[best_id]))
+      expect(score).to be_within(1e-5).of(expected)
+      best_id == id
+    end
+    expect(found).to be >= 18
+  end
+
+  it "looks up more buckets with probes" do
+    query = queries.first.last
+    expect(index.buckets(query).size).to eq 8
+    expect(index.buckets(query, probes: 2).size).to eq 24
+    expect(index.buckets(query, probes: 2).first(3).map(&:first)).to eq [0, 0, 0]
+    expect(index.top_k(query, 50, probes: 3).size).to be >= index.top_k(query, 50).size
+    expect { index.top_k(query, 5, probes: 11) }.to raise_error(ArgumentError)
+  end
+
+  it "skips deleted rows" do
+    id, query = queries.first
+    expect(index.delete(id)).to be true
+    expect(index.delete(id)).to be false
+    expect(index.size).to eq 1_999
+    expect(index.top_k(query, 10).map(&:first)).not_to include(id)
+  end
+
+  it "rejects invalid settings" do
+    expect { described_class.create(32, bits: 33) }.to raise_error(ArgumentError)
+    expect { described_class.create(32, bits: 4, probes: 5) }.to raise_error(ArgumentError)
+    expect { described_class.create(32, tables: 0) }.to raise_error(ArgumentError)
+    expect { described_class.create(-5) }.to raise_error(ArgumentError, /must be positive/)
+  end
+
+  it "saves and loads the index" do
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, "index.lsh")
+      index.delete(0)
+      index.save(path)
+      loaded = described_class.load(path)
+
+      expect(loaded.size).to eq index.size
+      expect(loaded.to_blob).to eq index.to_blob
+      expect(loaded.top_k(queries[1].last, 5)).to eq index.top_k(queries[1].last, 5)
+    end
+
+    expect { described_class.from_blob("RGLS") }.to raise_error(ArgumentError)
+  end
+end
@@ -0,0 +1,38 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe "Numo::NArray conversions" do
+  let(:values) { [1.0, -2.5, 0.125, 3.0] }
+  let(:rows) { [[1.0, 0.0, 0.5], [0.0, 1.0, -0.5]] }
+
+  before { skip "numo-narray is not installed" unless defined?(Numo::NArray) }
+
+  it "round-trips an embedding through SFloat and DFloat" do
+    emb = RagEmbeddings::Embedding.from_array(values)
+
+    expect(emb.to_numo).to be_a Numo::SFloat
+    expect(emb.to_numo.to_a).to eq values
+    expect(emb.to_numo(Numo::DFloat)).to be_a Numo::DFloat
+    expect(RagEmbeddings::Embedding.from_numo(Numo::DFloat.cast(values))).to eq emb
+    expect(RagEmbeddings::Embedding.from_numo(Numo::SFloat.cast(values), dtype: :f16).dtype).to eq :f16
+    expect(RagEmbeddings::Embedding.from_array(values, dtype: :bf16).to_numo.to_a).to eq values
+  end
+
+  it "round-trips a matrix" do
+    matrix = RagEmbeddings::EmbeddingMatrix.from_numo(Numo::DFloat.cast(rows))
+
+    expect([matrix.size, matrix.dim]).to eq [2, 3]
+    expect(matrix.to_numo.shape).to eq [2, 3]
+    expect(matrix.to_numo.to_a).to eq rows
+  end
+
+  it "validates the array" do
+    expect { RagEmbeddings::Embedding.from_numo(Numo::Int32[1, 2]) }.to raise_error(TypeError)
+    expect { RagEmbeddings::Embedding.from_numo(Numo::SFloat.cast(rows)) }.to raise_error(ArgumentError, /1 dimension/)
+    expect { RagEmbeddings::Embedding.from_numo(Numo::SFloat[1.0, Float::NAN]) }.to raise_error(ArgumentError, /NaN/)
+    expect(RagEmbeddings::Embedding.from_numo(Numo::SFloat[1.0, Float::NAN], allow_nan: true)).not_to be_finite
+    expect { RagEmbeddings::Embedding.from_numo(Numo::SFloat[1.0, 1.0e5], dtype: :f16) }
+      .to raise_error(ArgumentError, /index 1 \(100000\) is out of range for f16/)
+    expect { RagEmbeddings::EmbeddingMatrix.from_numo(Numo::SFloat.cast(values)) }.to raise_error(ArgumentError)
+  end
+end
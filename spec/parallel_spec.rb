@@ -0,0 +1,109 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe "RagEmbeddings.threads" do
+  # Large enough to release the GVL and to give every worker a range of rows
+  let(:data) { Array.new(8_000) { Array.new(32) { rand - 0.5 } } }
+  let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(data) }
+  let(:query) { RagEmbeddings::Embedding.from_array(Array.new(32) { rand - 0.5 }) }
+
+  after { RagEmbeddings.threads = 1 }
+
+  # Runs the block once on a single thread and once on 4, returning both results
+  def single_and_parallel
+    RagEmbeddings.threads = 1
+    single = yield
+    RagEmbeddings.threads = 4
+    [single, yield]
+  end
+
+  it "defaults to a single thread" do
+    expect(RagEmbeddings.threads).to eq 1
+  end
+
+  it "rejects counts out of range" do
+    expect { RagEmbeddings.threads = 0 }.to raise_error(ArgumentError)
+    expect { RagEmbeddings.threads = 1_000 }.to raise_error(ArgumentError)
+  end
+
+  it "gives the same matrix results whatever the number of threads" do
+    [:cosine, :dot, :euclidean].each do |metric|
+      single, parallel = single_and_parallel { matrix.top_k(query, 20, metric: metric) }
+      expect(parallel).to eq single
+    end
+
+    single, parallel = single_and_parallel { matrix.top_k(query, 20, prefilter: :binary, candidates: 200) }
+    expect(parallel).to eq single
+  end
+
+  it "gives the same quantized scan whatever the number of threads" do
+    blobs = data.map { |row| RagEmbeddings::Embedding.from_array(row).quantize(:int8).to_blob }
+
+    [:cosine, :dot].each do |metric|
+      single, parallel = single_and_parallel { RagEmbeddings::QuantizedEmbedding.top_k(query, blobs, 20, metric:) }
+      expect(parallel).to eq single
+    end
+  end
+
+  it "ranks equal scores by index" do
+    duplicates = RagEmbeddings::EmbeddingMatrix.from_arrays(Array.new(5_000) { [1.0, 2.0, 3.0] })
+    q = RagEmbeddings::Embedding.from_array([1.0, 0.0, 0.0])
+
+    single, parallel = single_and_parallel { duplicates.top_k(q, 5).map(&:first) }
+    expect(single).to eq [0, 1, 2, 3, 4]
+    expect(parallel).to eq single
+  end
+
+  it "gives the same index searches and clusters whatever the number of threads" do
+    single, parallel = single_and_parallel do
+      pq = RagEmbeddings::PQIndex.train(matrix, m: 8, ksub: 16, iterations: 5, seed: 1)
+      ivf = RagEmbeddings::IVFIndex.train(matrix, nlist: 20, iterations: 5, seed: 1)
+      data.each_with_index do |row, id|
+        pq.add(id, row)
+        ivf.add(id, row)
+      end
+      clusters = RagEmbeddings.kmeans(matrix, k: 10, iterations: 5, seed: 1)
+
+      [pq.top_k(query, 10), ivf.top_k(query, 10, nprobe: 5), clusters[:assignments]]
+    end
+
+    expect(parallel).to eq single
+  end
+
+  it "finds the same near-duplicates whatever the number of threads" do
+    copies = RagEmbeddings::EmbeddingMatrix.from_arrays(data + data.first(100).map { |row| row.map { |v| v * 1.01 } })
+
+    single, parallel = single_and_parallel { copies.near_duplicates(0.999) }
+    expect(single.size).to be >= 100
+    expect(parallel).to eq single
+  end
+
+  it "lets other Ruby threads search at the same time" do
+    RagEmbeddings.threads = 2
+    expected = matrix.top_k(query, 10)
+
+    results = Array.new(4) { Thread.new { matrix.top_k(query, 10) } }.map(&:value)
+    expect(results).to all(eq expected)
+  end
+
+  it "builds and searches HNSW and LSH indexes while other Ruby threads run" do
+    hnsw = RagEmbeddings::HNSWIndex.create(32, m: 8, ef_construction: 64, seed: 1)
+    lsh = RagEmbeddings::LSHIndex.create(32, tables: 8, bits: 8, seed: 1)
+    rows = data.first(2_000)
+    rows.each_with_index do |row, id|
+      hnsw.add(id, row)
+      lsh.add(id, row)
+    end
+
+    # The same graph as one built while searches run in other threads
+    concurrent = RagEmbeddings::HNSWIndex.create(32, m: 8, ef_construction: 64, seed: 1)
+    searches = Array.new(2) { Thread.new { 20.times.map { hnsw.top_k(query, 5) } } }
+    rows.each_with_index { |row, id| concurrent.add(id, row) }
+    expect(searches.flat_map(&:value)).to all(eq hnsw.top_k(query, 5))
+    expect(concurrent.to_blob).to eq hnsw.to_blob
+
+    expected = lsh.top_k(query, 5)
+    results = Array.new(4) { Thread.new { lsh.top_k(query, 5) } }.map(&:value)
+    expect(results).to all(eq expected)
+  end
+end
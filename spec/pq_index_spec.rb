@@ -0,0 +1,78 @@
+require "spec_helper"
+require "rag_embeddings"
+require "tmpdir"
+
+RSpec.describe RagEmbeddings::PQIndex do
+  let(:rows) { Array.new(300) { Array.new(32) { rand - 0.5 } } }
+  let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(rows) }
+  let(:index) do
+    described_class.train(matrix, m: 8, ksub: 16, seed: 42).tap do |pq|
+      rows.each_with_index { |row, i| pq.add(i, row) }
+    end
+  end
+  let(:queries) do
+    Array.new(20) { |q| RagEmbeddings::Embedding.from_array(rows[q * 7].map { |v| v + (rand - 0.5) * 0.2 }) }
+  end
+
+  # Average fraction of the exact top 10 also returned by the index
+  def recall(index, matrix, queries)
+    recalls = queries.map do |query|
+      exact = matrix.top_k(query, 10).map(&:first)
+      approximate = index.top_k(query, 10).map(&:first)
+      (exact & approximate).size / 10.0
+    end
+    recalls.sum / recalls.size
+  end
+
+  it "encodes every row into m bytes" do
+    expect(index.size).to eq 300
+    expect(index.dim).to eq 32
+    expect(index.m).to eq 8
+    expect(index.ksub).to eq 16
+    expect(index.metric).to eq :cosine
+  end
+
+  it "approaches the recall of exact cosine search" do
+    # Random rows are the worst case for PQ; about 0.6 is expected here
+    expect(recall(index, matrix, queries)).to be > 0.4
+  end
+
+  it "returns ids with approximate scores, best first" do
+    result = index.top_k(queries.first, 5)
+    expect(result.size).to eq 5
+    expect(result.first.first).to eq 0
+    expect(result.map(&:last)).to eq result.map(&:last).sort.reverse
+  end
+
+  it "is reproducible with the same seed" do
+    other = described_class.train(matrix, m: 8, ksub: 16, seed: 42)
+    expect(other.to_blob).to eq described_class.train(matrix, m: 8, ksub: 16, seed: 42).to_blob
+  end
+
+  it "saves and loads the index" do
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, "index.pq")
+      index.save(path)
+      loaded = described_class.load(path)
+
+      expect(loaded.size).to eq index.size
+      expect(loaded.top_k(queries.first, 5)).to eq index.top_k(queries.first, 5)
+    end
+  end
+
+  it "validates the training parameters" do
+    expect { described_class.train(matrix, m: 5) }.to raise_error(ArgumentError)
+    expect { described_class.train(matrix, ksub: 512) }.to raise_error(ArgumentError)
+    expect { described_class.train(matrix, ksub: 256) }.to raise_error(ArgumentError)
+    expect { described_class.train(matrix, metric: :manhattan) }.to raise_error(ArgumentError)
+  end
+
+  it "rejects corrupted blobs" do
+    expect { described_class.from_blob("nope") }.to raise_error(ArgumentError)
+    expect { described_class.from_blob(index.to_blob[0...-1]) }.to raise_error(ArgumentError)
+
+    # A header announcing 256 centroids of 2^20 floats, with no codebook data behind it
+    header = "RGPQ" + [1, 1 << 20, 1, 256, 0].pack("V5") + [0].pack("Q<")
+    expect { described_class.from_blob(header) }.to raise_error(ArgumentError, /Truncated/)
+  end
+end
@@ -0,0 +1,91 @@
+require "spec_helper"
+require "rag_embeddings"
+require "tmpdir"
+
+RSpec.describe RagEmbeddings::Projection do
+  # Rows varying mostly along the first two axes, with a little noise elsewhere
+  let(:rows) do
+    Array.new(400) do
+      row = Array.new(24) { (rand - 0.5) * 0.1 }
+      row[0] += (rand - 0.5) * 10
+      row[1] += (rand - 0.5) * 4
+      row
+    end
+  end
+  let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(rows) }
+
+  describe ".pca" do
+    let(:pca) { described_class.pca(matrix, dim: 4, seed: 1) }
+
+    it "keeps the directions of largest variance first" do
+      expect(pca.kind).to eq :pca
+      expect(pca.input_dim).to eq 24
+      expect(pca.dim).to eq 4
+
+      variances = pca.explained_variance
+      expect(variances).to eq variances.sort.reverse
+      expect(variances.first).to be > 5 * variances[1] / 4
+      expect(variances[2]).to be < 0.01
+    end
+
+    it "projects an embedding onto the components" do
+      first = pca.apply(RagEmbeddings::Embedding.from_array([1.0] + [0.0] * 23))
+      origin = pca.apply(RagEmbeddings::Embedding.from_array([0.0] * 24))
+
+      expect(first.dim).to eq 4
+      expect((first - origin).to_a.first.abs).to be_within(1e-3).of(1.0)
+    end
+
+    it "accepts a list of embeddings and is reproducible" do
+      expect(described_class.pca(rows, dim: 4, seed: 1).to_blob).to eq pca.to_blob
+    end
+
+    it "rejects a dimension above the input one and a single embedding" do
+      expect { described_class.pca(matrix, dim: 25) }.to raise_error(ArgumentError)
+      expect { described_class.pca(rows.first(1), dim: 2) }.to raise_error(ArgumentError)
+    end
+  end
+
+  describe ".random" do
+    it "is seeded and roughly preserves distances" do
+      projection = described_class.random(24, dim: 16, seed: 3)
+      expect(projection.kind).to eq :random
+      expect(projection.explained_variance).to be_nil
+      expect(projection.to_blob).to eq described_class.random(24, dim: 16, seed: 3).to_blob
+      expect(projection.to_blob).not_to eq described_class.random(24, dim: 16, seed: 4).to_blob
+
+      a, b = rows.first(2).map { |row| RagEmbeddings::Embedding.from_array(row) }
+      ratio = projection.apply(a).euclidean_distance(projection.apply(b)) / a.euclidean_distance(b)
+      expect(ratio).to be_between(0.3, 3.0)
+    end
+
+    it "rejects a negative input dimension" do
+      expect { described_class.random(-3, dim: 2) }.to raise_error(ArgumentError, /must be positive/)
+    end
+  end
+
+  it "projects a whole matrix like its rows one by one" do
+    projection = described_class.pca(matrix, dim: 3, seed: 1)
+    projected = projection.apply(matrix)
+    query = RagEmbeddings::Embedding.from_array(rows[5])
+
+    expect(projected.dim).to eq 3
+    expect(projected.size).to eq 400
+    expect(projected.top_k(projection.apply(query), 1, metric: :euclidean).first.first).to eq 5
+  end
+
+  it "saves and loads the projection" do
+    projection = described_class.pca(matrix, dim: 4, seed: 1)
+
+    Dir.mktmpdir do |dir|
+      path = File.join(dir, "embeddings.projection")
+      projection.save(path)
+      loaded = described_class.load(path)
+
+      expect(loaded.to_blob).to eq projection.to_blob
+      expect(loaded.apply(rows.first)).to eq projection.apply(rows.first)
+    end
+
+    expect { described_class.from_blob("RGPJ") }.to raise_error(ArgumentError)
+  end
+end
@@ -0,0 +1,55 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe RagEmbeddings::QuantizedEmbedding do
+  let(:values) { Array.new(256) { rand - 0.5 } }
+  let(:other_values) { Array.new(256) { rand - 0.5 } }
+  let(:embedding) { RagEmbeddings::Embedding.from_array(values) }
+  let(:other) { RagEmbeddings::Embedding.from_array(other_values) }
+  let(:quantized) { embedding.quantize(:int8) }
+
+  it "stores one int8 code per dimension with a per-vector scale and offset" do
+    expect(quantized.dim).to eq 256
+    expect(quantized.codes).to all(be_between(-127, 127))
+    expect(quantized.scale).to be > 0
+  end
+
+  it "approximates the original values" do
+    quantized.to_a.zip(values).each do |approx, exact|
+      expect(approx).to be_within(quantized.scale).of(exact)
+    end
+    expect(quantized.dequantize).to be_a(RagEmbeddings::Embedding)
+  end
+
+  it "approximates cosine and dot between quantized vectors" do
+    other_quantized = other.quantize(:int8)
+    expect(quantized.cosine_similarity(other_quantized)).to be_within(0.01).of(embedding.cosine_similarity(other))
+    expect(quantized.dot_product(other_quantized)).to be_within(0.05).of(embedding.dot_product(other))
+  end
+
+  it "approximates cosine between a float query and a quantized row" do
+    expect(quantized.cosine_similarity(other)).to be_within(0.01).of(embedding.cosine_similarity(other))
+  end
+
+  it "round-trips through blobs at 1 byte per value" do
+    blob = quantized.to_blob
+    expect(blob.bytesize).to eq 8 + 256
+    expect(described_class.from_blob(blob).to_a).to eq quantized.to_a
+  end
+
+  it "ranks quantized blobs against a float query" do
+    rows = Array.new(50) { Array.new(256) { rand - 0.5 } }
+    blobs = rows.map { |row| RagEmbeddings::Embedding.from_array(row).quantize.to_blob }
+    exact = RagEmbeddings::EmbeddingMatrix.from_arrays(rows).top_k(other, 5).map(&:first)
+
+    result = described_class.top_k(other, blobs, 5)
+    expect(result.size).to eq 5
+    expect((result.map(&:first) & exact).size).to be >= 4
+    expect { described_class.top_k(other, blobs, 5, metric: :euclidean) }.to raise_error(ArgumentError)
+  end
+
+  it "handles constant vectors" do
+    flat = RagEmbeddings::Embedding.from_array([0.5] * 8).quantize
+    expect(flat.to_a).to eq [0.5] * 8
+  end
+end
@@ -0,0 +1,112 @@
+require "spec_helper"
+require "rag_embeddings"
+
+RSpec.describe "Frozen and Ractor-shareable objects" do
+  let(:data) { Array.new(100) { Array.new(16) { rand - 0.5 } } }
+  let(:query) { Ractor.make_shareable(RagEmbeddings::Embedding.from_array(Array.new(16) { rand - 0.5 })) }
+
+  around do |example|
+    experimental = Warning[:experimental]
+    Warning[:experimental] = false
+    example.run
+  ensure
+    Warning[:experimental] = experimental
+  end
+
+  # Ractor#take became Ractor#value in newer Rubies
+  def ractor_result(ractor)
+    ractor.respond_to?(:value) ? ractor.value : ractor.take
+  end
+
+  describe RagEmbeddings::Embedding do
+    let(:embedding) { RagEmbeddings::Embedding.from_array([3.0, 4.0]) }
+
+    it "refuses normalize! once frozen" do
+      embedding.freeze
+      expect { embedding.normalize! }.to raise_error(FrozenError)
+      expect(embedding.to_a).to eq [3.0, 4.0]
+    end
+
+    it "still builds new embeddings from a frozen one" do
+      embedding.freeze
+      normalized = embedding.normalize
+      expect(normalized).not_to be_frozen
+      expect(normalized.magnitude).to be_within(1e-6).of(1.0)
+    end
+
+    it "can be shared with another Ractor" do
+      shared = Ractor.make_shareable(embedding)
+      expect(Ractor.shareable?(shared)).to be true
+
+      ractor = Ractor.new(shared, query) { |a, b| a.cosine_similarity(b) }
+      expect(ractor_result(ractor)).to eq embedding.cosine_similarity(query)
+    end
+  end
+
+  describe RagEmbeddings::EmbeddingMatrix do
+    let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(data) }
+
+    it "refuses push once frozen" do
+      matrix.freeze
+      expect { matrix << data.first }.to raise_error(FrozenError)
+      expect(matrix.size).to eq 100
+    end
+
+    it "is searched from other Ractors without copying" do
+      expected = matrix.top_k(query, 5)
+      prefiltered = matrix.top_k(query, 5, prefilter: :binary, candidates: 20)
+      shared = Ractor.make_shareable(matrix)
+
+      ractors = Array.new(2) do
+        Ractor.new(shared, query) do |m, q|
+          [m.top_k(q, 5), m.top_k(q, 5, prefilter: :binary, candidates: 20)]
+        end
+      end
+      ractors.each { |ractor| expect(ractor_result(ractor)).to eq [expected, prefiltered] }
+    end
+  end
+
+  describe "indexes" do
+    let(:matrix) { RagEmbeddings::EmbeddingMatrix.from_arrays(data) }
+
+    it "share a frozen HNSW index" do
+      index = RagEmbeddings::HNSWIndex.create(16, seed: 1)
+      data.each_with_index { |row, id| index.add(id, row) }
+      expected = index.top_k(query, 5)
+
+      shared = Ractor.make_shareable(index)
+      expect { shared.add(100, data.first) }.to raise_error(FrozenError)
+      expect { shared.delete(0) }.to raise_error(FrozenError)
+
+      ractor = Ractor.new(shared, query) { |i, q| i.top_k(q, 5) }
+      expect(ractor_result(ractor)).to eq expected
+    end
+
+    it "share frozen PQ and IVF indexes" do
+      pq = RagEmbeddings::PQIndex.train(matrix, m: 4, ksub: 16, seed: 1)
+      ivf = RagEmbeddings::IVFIndex.train(matrix, nlist: 4, seed: 1)
+      data.each_with_index do |row, id|
+        pq.add(id, row)
+        ivf.add(id, row)
+      end
+      expected = [pq.top_k(query, 5), ivf.top_k(query, 5)]
+
+      Ractor.make_shareable(pq)
+      Ractor.make_shareable(ivf)
+      expect { pq.add(100, data.first) }.to raise_error(FrozenError)
+      expect { ivf.nprobe = 2 }.to raise_error(FrozenError)
+
+      ractor = Ractor.new(pq, ivf, query) { |p, i, q| [p.top_k(q, 5), i.top_k(q, 5)] }
+      expect(ractor_result(ractor)).to eq expected
+    end
+  end
+
+  it "keeps process-wide settings to the main Ractor" do
+    ractor = Ractor.new do
+      RagEmbeddings.threads = 2
+    rescue Ractor::IsolationError => e
+      e.class
+    end
+    expect(ractor_result(ractor)).to eq Ractor::IsolationError
+  end
+end
@@ -32,7 +32,9 @@ RSpec.describe RagEmbeddings do
     end
   end
 
-  after(:each) { File.delete(db_path) if File.exist?(db_path) }
+  after(:each) do
+    [db_path, "#{db_path}.ivf", "#{db_path}.projection"].each { |path| File.delete(path) if File.exist?(path) }
+  end
 
   it "generates an embedding for text" do
     embedding = RagEmbeddings.embed(text1)
@@ -79,4 +81,282 @@ RSpec.describe RagEmbeddings do
     expect(result.first[1]).to eq(text1)
     expect(result.first[2]).to be_a(Float)
   end
+
+  it "searches with the metric configured on the database" do
+    l2_db = RagEmbeddings::Database.new(db_path, metric: :euclidean)
+    l2_db.insert(text1, RagEmbeddings.embed(text1))
+    l2_db.insert(text2, RagEmbeddings.embed(text2))
+    result = l2_db.top_k_similar(text1, k: 2)
+    expect(result.first[1]).to eq(text1)
+    expect(result.first[2]).to be_within(1e-6).of(0.0)
+  end
+
+  it "inserts Embedding objects as blobs" do
+    emb = RagEmbeddings.embed(text1)
+    db.insert(text1, RagEmbeddings::Embedding.from_array(emb))
+    _, _, loaded_emb = db.all.first
+    expect(loaded_emb.size).to eq emb.size
+    expect(loaded_emb.first).to be_within(1e-6).of(emb.first)
+  end
+
+  it "stores half the bytes per row with a 16-bit dtype" do
+    f16_db = RagEmbeddings::Database.new(db_path, dtype: :f16)
+    emb = RagEmbeddings.embed(text1)
+    f16_db.insert(text1, emb)
+    f16_db.insert(text2, RagEmbeddings.embed(text2))
+
+    _, _, loaded_emb = f16_db.all.first
+    expect(loaded_emb.size).to eq emb.size
+    expect(f16_db.top_k_similar(text1, k: 1).first[1]).to eq(text1)
+  end
+
+  it "stores int8 quantized embeddings" do
+    int8_db = RagEmbeddings::Database.new(db_path, quantization: :int8)
+    emb = RagEmbeddings.embed(text1)
+    int8_db.insert(text1, emb)
+    int8_db.insert(text2, RagEmbeddings.embed(text2))
+
+    _, _, loaded_emb = int8_db.all.first
+    expect(loaded_emb.size).to eq emb.size
+    expect(int8_db.top_k_similar(text1, k: 1).first[1]).to eq(text1)
+  end
+
+  it "rejects quantization with a distance metric when opened" do
+    expect { RagEmbeddings::Database.new(db_path, quantization: :int8, metric: :euclidean) }
+      .to raise_error(ArgumentError, /:cosine and :dot/)
+  end
+
+  it "measures the recall of quantized search against the exact one" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+    expect(db.quantization_recall([text1, text2], k: 1)).to eq 1.0
+  end
+
+  it "prefilters candidates with binary codes before rescoring" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    result = db.top_k_similar(text1, k: 1, prefilter: :binary, candidates: 1)
+    expect(result.first[1]).to eq(text1)
+    expect(result.first[2]).to be_within(1e-6).of(1.0)
+  end
+
+  it "keeps the matrix and its sign bits between searches until the table changes" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+
+    # Once for the first two searches, once more after the insert
+    expect(RagEmbeddings::EmbeddingMatrix).to receive(:from_blobs).twice.and_call_original
+    db.top_k_similar(text1, k: 1, prefilter: :binary, candidates: 1)
+    expect(db.top_k_similar(text2, k: 1, prefilter: :binary, candidates: 1).first[1]).to eq(text1)
+
+    db.insert(text2, RagEmbeddings.embed(text2))
+    expect(db.top_k_similar(text2, k: 1, prefilter: :binary, candidates: 1).first[1]).to eq(text2)
+  end
+
+  it "searches a product quantization index built from the database" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    index = db.build_pq_index(m: 1, ksub: 2)
+    expect(index.size).to eq 2
+    expect(db.top_k_similar(text1, k: 1, index:).first[1]).to eq(text1)
+  end
+
+  it "searches an HNSW index built from the database" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    index = db.build_hnsw_index(m: 4)
+    expect(index.size).to eq 2
+    expect(db.top_k_similar(text1, k: 1, index:).first[1]).to eq(text1)
+  end
+
+  it "persists the IVF index next to the database file" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    index = db.ivf_index(nlist: 2, nprobe: 2)
+    expect(File.exist?("#{db_path}.ivf")).to be true
+    expect(db.top_k_similar(text1, k: 1, index:).first[1]).to eq(text1)
+
+    # Reopening loads the saved index instead of training it again
+    expect(RagEmbeddings::IVFIndex).not_to receive(:train)
+    reloaded = RagEmbeddings::Database.new(db_path).ivf_index(nlist: 2, nprobe: 2)
+    expect(reloaded.to_blob).to eq index.to_blob
+  end
+
+  it "retrains the saved IVF index when the rows or the metric changed" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+    db.ivf_index(nlist: 2)
+
+    # A new collection with the same row count next to the old .ivf file
+    File.delete(db_path)
+    other = RagEmbeddings::Database.new(db_path)
+    other.insert("A third sentence.", RagEmbeddings.embed("A third sentence."))
+    other.insert(text2, RagEmbeddings.embed(text2))
+    expect(RagEmbeddings::IVFIndex).to receive(:train).and_call_original
+    expect(other.ivf_index(nlist: 2).size).to eq 2
+
+    expect(RagEmbeddings::IVFIndex).to receive(:train).and_call_original
+    euclidean = RagEmbeddings::Database.new(db_path, metric: :euclidean).ivf_index(nlist: 2)
+    expect(euclidean.metric).to eq :euclidean
+  end
+
+  it "retrains the saved IVF index when the file is truncated" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+    db.ivf_index(nlist: 2)
+
+    File.binwrite("#{db_path}.ivf", File.binread("#{db_path}.ivf")[0, 10])
+    expect(RagEmbeddings::IVFIndex).to receive(:train).and_call_original
+    expect(db.ivf_index(nlist: 2).size).to eq 2
+  end
+
+  it "clusters the collection and writes the cluster of each row" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    result = db.cluster(k: 2, write: true)
+    expect(result[:centroids].size).to eq 2
+    expect(result[:assignments].values.sort).to eq [0, 1]
+
+    stored = SQLite3::Database.new(db_path).execute("SELECT id, cluster FROM embeddings").to_h
+    expect(stored).to eq result[:assignments]
+  end
+
+  it "stores and searches truncated embeddings" do
+    small_db = RagEmbeddings::Database.new(db_path, dimensions: 64)
+    small_db.insert(text1, RagEmbeddings.embed(text1))
+    small_db.insert(text2, RagEmbeddings.embed(text2))
+
+    _, _, loaded_emb = small_db.all.first
+    expect(loaded_emb.size).to eq 64
+    result = small_db.top_k_similar(text1, k: 1).first
+    expect(result[1]).to eq(text1)
+    expect(result[2]).to be_within(1e-5).of(1.0)
+  end
+
+  it "truncates the existing rows in place" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+    db.ivf_index(nlist: 2)
+
+    db.truncate_embeddings!(64)
+    expect(db.dimensions).to eq 64
+    expect(db.all.map { |_, _, emb| emb.size }).to eq [64, 64]
+    expect(File.exist?("#{db_path}.ivf")).to be false
+    expect(db.top_k_similar(text1, k: 1).first[1]).to eq(text1)
+
+    expect { db.truncate_embeddings!(128) }.to raise_error(ArgumentError)
+  end
+
+  it "projects the collection and keeps projecting after a restart" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    projection = db.fit_projection(dim: 16, method: :random, seed: 1)
+    db.project_embeddings!(projection)
+    expect(db.all.map { |_, _, emb| emb.size }).to eq [16, 16]
+    expect(File.exist?("#{db_path}.projection")).to be true
+
+    reopened = RagEmbeddings::Database.new(db_path)
+    expect(reopened.projection.to_blob).to eq projection.to_blob
+    reopened.insert(text1, RagEmbeddings.embed(text1))
+    expect(reopened.top_k_similar(text1, k: 1).first[2]).to be_within(1e-5).of(1.0)
+    expect { reopened.project_embeddings!(projection) }.to raise_error(ArgumentError)
+  end
+
+  it "fits a PCA projection on the stored embeddings" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    projection = db.fit_projection(dim: 1)
+    expect(projection.kind).to eq :pca
+    expect(projection.input_dim).to eq RagEmbeddings.embed(text1).size
+  end
+
+  it "searches an LSH index built from the database" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    index = db.build_lsh_index(tables: 4, bits: 8)
+    expect(index.size).to eq 2
+    expect(db.top_k_similar(text1, k: 1, index:).first[1]).to eq(text1)
+  end
+
+  it "keeps LSH buckets in the database and hashes new rows" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.build_lsh_index(tables: 4, bits: 8, persist: true)
+
+    reopened = RagEmbeddings::Database.new(db_path)
+    reopened.insert(text2, RagEmbeddings.embed(text2))
+    buckets = SQLite3::Database.new(db_path).get_first_value("SELECT COUNT(*) FROM lsh_buckets")
+    expect(buckets).to eq 8
+
+    result = reopened.top_k_similar(text2, k: 1, index: :lsh).first
+    expect(result[1]).to eq(text2)
+    expect(result[2]).to be_within(1e-5).of(1.0)
+  end
+
+  it "returns diverse results with MMR" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert("#{text1} (copy)", RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    expect(db.top_k_similar(text1, k: 2).map { |row| row[1] }).not_to include(text2)
+
+    result = db.mmr_search(text1, k: 2, fetch_k: 3, lambda: 0.3)
+    expect(result.map { |row| row[1] }).to include(text2)
+    expect(result.first[2]).to be_within(1e-5).of(1.0)
+  end
+
+  it "finds near-duplicate rows" do
+    db.insert(text1, RagEmbeddings.embed(text1))
+    db.insert(text2, RagEmbeddings.embed(text2))
+    db.insert("#{text1} (copy)", RagEmbeddings.embed(text1))
+
+    expect(db.near_duplicates(threshold: 0.99)).to eq [[[1, text1], [3, "#{text1} (copy)"]]]
+  end
+
+  it "skips or merges near-duplicates on insert" do
+    id = db.insert(text1, RagEmbeddings.embed(text1))
+    expect(db.insert("#{text1} (copy)", RagEmbeddings.embed(text1), on_duplicate: :skip)).to eq id
+    expect(db.all.size).to eq 1
+
+    db.insert("Same meaning", RagEmbeddings.embed(text1), on_duplicate: :merge, threshold: 0.99)
+    db.insert(text2, RagEmbeddings.embed(text2), on_duplicate: :merge, threshold: 0.99)
+    expect(db.all.map { |row| row[1] }).to eq ["#{text1}\nSame meaning", text2]
+
+    expect { db.insert(text2, RagEmbeddings.embed(text2), on_duplicate: :replace) }.to raise_error(ArgumentError)
+  end
+
+  it "normalizes rows on insert and searches them by dot product" do
+    scaled = RagEmbeddings.embed(text1).map { |v| v * 3 }
+    db.insert(text1, scaled)
+    db.insert(text2, RagEmbeddings.embed(text2))
+
+    expect(db).to be_unit_rows
+    expect(RagEmbeddings::Embedding.from_array(db.all.first[2]).magnitude).to be_within(1e-5).of(1.0)
+    expect(db.top_k_similar(scaled, k: 1).first[2]).to be_within(1e-5).of(1.0)
+  end
+
+  it "normalizes the rows of an older database" do
+    l2_db = RagEmbeddings::Database.new(db_path, metric: :euclidean)
+    l2_db.insert(text1, RagEmbeddings.embed(text1).map { |v| v * 3 })
+    l2_db.insert(text2, RagEmbeddings.embed(text2))
+    expect(l2_db).not_to be_unit_rows
+    expect { l2_db.normalize_embeddings! }.to raise_error(ArgumentError)
+
+    expected = db.top_k_similar(text2, k: 2)
+    expect(db).not_to be_unit_rows
+
+    db.normalize_embeddings!
+    expect(db).to be_unit_rows
+    expect(RagEmbeddings::Database.new(db_path)).to be_unit_rows
+    db.top_k_similar(text2, k: 2).zip(expected).each do |(id, content, score), (expected_id, expected_content, expected_score)|
+      expect([id, content]).to eq [expected_id, expected_content]
+      expect(score).to be_within(1e-5).of(expected_score)
+    end
+  end
 end